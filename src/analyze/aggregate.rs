@@ -0,0 +1,636 @@
+use crate::datagen::dataset::DataType;
+use super::batch::{Batch, Value};
+use super::expr::{col, Expr};
+use super::hll::{HyperLogLog, DEFAULT_PRECISION};
+use super::kernels::{aggregate_values, NumericAggregate};
+use super::operator::{Operator, BATCH_ROWS};
+use super::quantile::{exact_percentile, numeric_value, TDigest};
+use super::sample::{SampleMethod, SampleRate, SampleSums, Scaled};
+use super::spill::{memory_budget, Partitions, SpillFile, SpillScan, MAX_SPILL_DEPTH};
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use std::collections::{hash_map, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggregateFunction {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+    CountDistinct,
+    // HyperLogLog estimate at the given precision, see hll
+    ApproxCountDistinct(u8),
+    // Fractions are in millionths so these stay Copy and Eq. Percentile holds every value and
+    // gives the exact answer, ApproxPercentile goes through a t-digest, see quantile.
+    Percentile(u32),
+    ApproxPercentile(u32),
+    // count, sum or avg over a TABLESAMPLE scaled to the whole table, and the half width of its
+    // 95% confidence interval. The SQL layer swaps these in, see sample.
+    Estimate(Scaled, SampleRate),
+    Margin(Scaled, SampleRate),
+}
+
+impl AggregateFunction {
+    pub fn from_name(name: &str) -> Option<AggregateFunction> {
+        match name.to_lowercase().as_str() {
+            "count" => Some(AggregateFunction::Count),
+            "sum" => Some(AggregateFunction::Sum),
+            "min" => Some(AggregateFunction::Min),
+            "max" => Some(AggregateFunction::Max),
+            "avg" => Some(AggregateFunction::Avg),
+            "count_distinct" => Some(AggregateFunction::CountDistinct),
+            "approx_count_distinct" => Some(AggregateFunction::ApproxCountDistinct(DEFAULT_PRECISION)),
+            // percentile(x, fraction) gets its fraction from the parser
+            "median" | "percentile" => Some(AggregateFunction::Percentile(MEDIAN)),
+            "approx_median" | "approx_percentile" => Some(AggregateFunction::ApproxPercentile(MEDIAN)),
+            _ => None,
+        }
+    }
+
+    // What count, sum and avg turn into over a sample
+    pub fn scaled(&self) -> Option<Scaled> {
+        match self {
+            AggregateFunction::Count => Some(Scaled::Count),
+            AggregateFunction::Sum => Some(Scaled::Sum),
+            AggregateFunction::Avg => Some(Scaled::Avg),
+            _ => None,
+        }
+    }
+
+    fn sampling(&self) -> Option<SampleMethod> {
+        match self {
+            AggregateFunction::Estimate(_, rate) | AggregateFunction::Margin(_, rate) => Some(rate.method),
+            _ => None,
+        }
+    }
+}
+
+pub const MEDIAN: u32 = 500_000;
+
+// A fraction like 0.99 as the millionths the percentile functions keep
+pub fn millionths(fraction: f64) -> u32 {
+    (fraction.clamp(0.0, 1.0) * 1_000_000.0).round() as u32
+}
+
+impl fmt::Display for AggregateFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AggregateFunction::Count => "count",
+            AggregateFunction::Sum => "sum",
+            AggregateFunction::Min => "min",
+            AggregateFunction::Max => "max",
+            AggregateFunction::Avg => "avg",
+            AggregateFunction::CountDistinct => "count_distinct",
+            AggregateFunction::ApproxCountDistinct(_) => "approx_count_distinct",
+            AggregateFunction::Percentile(_) => "percentile",
+            AggregateFunction::ApproxPercentile(_) => "approx_percentile",
+            AggregateFunction::Estimate(Scaled::Count, _) => "estimated_count",
+            AggregateFunction::Estimate(Scaled::Sum, _) => "estimated_sum",
+            AggregateFunction::Estimate(Scaled::Avg, _) => "estimated_avg",
+            AggregateFunction::Margin(Scaled::Count, _) => "count_margin",
+            AggregateFunction::Margin(Scaled::Sum, _) => "sum_margin",
+            AggregateFunction::Margin(Scaled::Avg, _) => "avg_margin",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// One output column of an aggregation, e.g. sum(price_per * quantity) AS total
+#[derive(Debug, Clone)]
+pub struct AggregateExpr {
+    pub function: AggregateFunction,
+    pub expr: Expr,
+    pub name: String,
+}
+
+impl AggregateExpr {
+    pub fn new(function: AggregateFunction, expr: Expr, name: &str) -> AggregateExpr {
+        AggregateExpr { function, expr, name: name.to_string() }
+    }
+
+    pub fn count_rows(name: &str) -> AggregateExpr {
+        AggregateExpr::new(AggregateFunction::Count, Expr::Literal(Value::Bool(true)), name)
+    }
+
+    // Turns a parsed `sum(x) AS name` into an AggregateExpr
+    pub fn from_named(name: &str, expr: &Expr) -> Result<AggregateExpr, String> {
+        match expr {
+            Expr::Aggregate(function, inner) => Ok(AggregateExpr::new(*function, *inner.clone(), name)),
+            _ => Err(format!("{} is not an aggregate", expr)),
+        }
+    }
+}
+
+impl fmt::Display for AggregateExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} AS {}", Expr::Aggregate(self.function, Box::new(self.expr.clone())), self.name)
+    }
+}
+
+// Running sum that stays an integer until a Decimal shows up
+#[derive(Debug, Clone, Default)]
+pub struct Sum {
+    integer: i128,
+    decimal: Decimal,
+    is_decimal: bool,
+    seen: bool,
+}
+
+impl Sum {
+    pub fn add(&mut self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::Null => return Ok(()),
+            Value::Decimal(val) => {
+                self.decimal += *val;
+                self.is_decimal = true;
+            },
+            _ => match value.as_i128() {
+                Some(val) => self.integer += val,
+                None => return Err(format!("Can't sum {}", value)),
+            },
+        }
+        self.seen = true;
+        Ok(())
+    }
+
+    // Takes back a value that was added, for window frames sliding along. Whoever calls it keeps
+    // track of whether anything's left.
+    pub fn remove(&mut self, value: &Value) -> Result<(), String> {
+        match value {
+            Value::Null => {},
+            Value::Decimal(val) => self.decimal -= *val,
+            _ => match value.as_i128() {
+                Some(val) => self.integer -= val,
+                None => return Err(format!("Can't sum {}", value)),
+            },
+        }
+        Ok(())
+    }
+
+    pub fn total(&self) -> Value {
+        if !self.seen {
+            return Value::Null;
+        }
+        if self.is_decimal {
+            return Value::Decimal(self.decimal + Decimal::from_i128_with_scale(self.integer, 0));
+        }
+        integer_value(self.integer)
+    }
+}
+
+fn integer_value(val: i128) -> Value {
+    if let Ok(val) = i64::try_from(val) {
+        return Value::Int64(val);
+    }
+    if let Ok(val) = u64::try_from(val) {
+        return Value::UInt64(val);
+    }
+    Value::Decimal(Decimal::from_i128_with_scale(val, 0))
+}
+
+// Build a Value of the same type the kernel read so min/max keep their type
+fn typed_value(val: i128, data_type: DataType) -> Value {
+    match data_type {
+        DataType::UInt64 => Value::UInt64(val as u64),
+        DataType::UInt8 => Value::UInt8(val as u8),
+        DataType::Int8 => Value::Int8(val as i8),
+        DataType::DateTime => Utc.timestamp_millis_opt(val as i64).single().map(Value::DateTime).unwrap_or(Value::Null),
+        _ => Value::Int64(val as i64),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Count(u64),
+    Sum(Sum),
+    Min(Option<Value>),
+    Max(Option<Value>),
+    Avg(Sum, u64),
+    CountDistinct(HashSet<Value>),
+    ApproxCountDistinct(HyperLogLog),
+    Percentile(Vec<Decimal>, f64),
+    ApproxPercentile(TDigest, f64),
+    Sampled(SampleSums, AggregateFunction),
+}
+
+// Which cluster of a sample each row of a batch is from: the batch's page for SYSTEM, each row
+// its own for BERNOULLI. See sample.
+#[derive(Debug, Clone, Copy)]
+struct Clusters {
+    first: u64,
+    per_row: bool,
+}
+
+impl Clusters {
+    fn of(&self, row: usize) -> u64 {
+        match self.per_row {
+            true => self.first + row as u64,
+            false => self.first,
+        }
+    }
+}
+
+impl Accumulator {
+    fn new(function: AggregateFunction) -> Accumulator {
+        match function {
+            AggregateFunction::Count => Accumulator::Count(0),
+            AggregateFunction::Sum => Accumulator::Sum(Sum::default()),
+            AggregateFunction::Min => Accumulator::Min(None),
+            AggregateFunction::Max => Accumulator::Max(None),
+            AggregateFunction::Avg => Accumulator::Avg(Sum::default(), 0),
+            AggregateFunction::CountDistinct => Accumulator::CountDistinct(HashSet::new()),
+            AggregateFunction::ApproxCountDistinct(precision) => Accumulator::ApproxCountDistinct(HyperLogLog::new(precision)),
+            AggregateFunction::Percentile(fraction) => Accumulator::Percentile(Vec::new(), fraction as f64 / 1_000_000.0),
+            AggregateFunction::ApproxPercentile(fraction) => Accumulator::ApproxPercentile(TDigest::new(), fraction as f64 / 1_000_000.0),
+            AggregateFunction::Estimate(_, _) | AggregateFunction::Margin(_, _) => Accumulator::Sampled(SampleSums::default(), function),
+        }
+    }
+
+    fn update(&mut self, value: &Value) -> Result<(), String> {
+        if value.is_null() {
+            return Ok(());
+        }
+        match self {
+            Accumulator::Count(count) => *count += 1,
+            Accumulator::Sum(sum) => sum.add(value)?,
+            Accumulator::Min(current) => {
+                if current.as_ref().map(|cur| value.compare(cur) == Some(std::cmp::Ordering::Less)).unwrap_or(true) {
+                    *current = Some(value.clone());
+                }
+            },
+            Accumulator::Max(current) => {
+                if current.as_ref().map(|cur| value.compare(cur) == Some(std::cmp::Ordering::Greater)).unwrap_or(true) {
+                    *current = Some(value.clone());
+                }
+            },
+            Accumulator::Avg(sum, count) => {
+                sum.add(value)?;
+                *count += 1;
+            },
+            Accumulator::CountDistinct(seen) => {
+                if !seen.contains(value) {
+                    seen.insert(value.clone());
+                }
+            },
+            Accumulator::ApproxCountDistinct(sketch) => sketch.insert(value),
+            Accumulator::Percentile(values, _) => values.push(value.as_decimal().ok_or(format!("Can't take a percentile of {}", value))?),
+            Accumulator::ApproxPercentile(digest, _) => {
+                if let Some(val) = numeric_value(value)? {
+                    digest.add(val);
+                }
+            },
+            Accumulator::Sampled(_, _) => return self.update_in(value, 0),
+        }
+        Ok(())
+    }
+
+    // Same as update but saying which cluster of a sample the value came from
+    fn update_in(&mut self, value: &Value, cluster: u64) -> Result<(), String> {
+        match self {
+            Accumulator::Sampled(sums, AggregateFunction::Estimate(scaled, _) | AggregateFunction::Margin(scaled, _)) => sums.add(*scaled, value, cluster),
+            _ => self.update(value),
+        }
+    }
+
+    // Whole batch at once, which is where the kernels get their chance
+    fn update_batch(&mut self, values: &[Value], clusters: Clusters) -> Result<(), String> {
+        if let Accumulator::Sampled(_, _) = self {
+            for (row, value) in values.iter().enumerate() {
+                self.update_in(value, clusters.of(row))?;
+            }
+            return Ok(());
+        }
+        if matches!(self, Accumulator::Count(_) | Accumulator::Sum(_) | Accumulator::Min(_) | Accumulator::Max(_) | Accumulator::Avg(_, _)) {
+            // When a whole batch of values is one of the fixed width numeric types, hand it to the
+            // vectorized kernels instead of going value by value
+            if let Some((numeric, data_type)) = aggregate_values(values) {
+                if data_type == DataType::DateTime && matches!(self, Accumulator::Sum(_) | Accumulator::Avg(_, _)) {
+                    return Err("Can't sum DateTime values".to_string());
+                }
+                return self.merge_numeric(&numeric, data_type);
+            }
+        }
+        // A digest per batch merged into the running one, the same way partial digests from
+        // separate workers would come together
+        if let Accumulator::ApproxPercentile(digest, _) = self {
+            let mut batch_digest = TDigest::new();
+            for value in values {
+                if let Some(val) = numeric_value(value)? {
+                    batch_digest.add(val);
+                }
+            }
+            digest.merge(&batch_digest);
+            return Ok(());
+        }
+        for value in values {
+            self.update(value)?;
+        }
+        Ok(())
+    }
+
+    fn merge_numeric(&mut self, numeric: &NumericAggregate, data_type: DataType) -> Result<(), String> {
+        if numeric.count == 0 {
+            return Ok(());
+        }
+        match self {
+            Accumulator::Count(count) => *count += numeric.count,
+            Accumulator::Sum(sum) => sum.add(&integer_value(numeric.sum))?,
+            Accumulator::Min(_) => self.update(&typed_value(numeric.min, data_type))?,
+            Accumulator::Max(_) => self.update(&typed_value(numeric.max, data_type))?,
+            Accumulator::Avg(sum, count) => {
+                sum.add(&integer_value(numeric.sum))?;
+                *count += numeric.count;
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // Values kept one by one, which is what makes these grow with the input
+    fn values_held(&self) -> usize {
+        match self {
+            Accumulator::CountDistinct(seen) => seen.len(),
+            Accumulator::Percentile(values, _) => values.len(),
+            _ => 0,
+        }
+    }
+
+    fn finish(&self) -> Value {
+        match self {
+            Accumulator::Count(count) => Value::UInt64(*count),
+            Accumulator::Sum(sum) => sum.total(),
+            Accumulator::Min(current) | Accumulator::Max(current) => current.clone().unwrap_or(Value::Null),
+            Accumulator::Avg(sum, count) => match (sum.total().as_decimal(), *count) {
+                (Some(total), count) if count > 0 => Value::Decimal(total / Decimal::from(count)),
+                _ => Value::Null,
+            },
+            Accumulator::CountDistinct(seen) => Value::UInt64(seen.len() as u64),
+            Accumulator::ApproxCountDistinct(sketch) => Value::UInt64(sketch.estimate()),
+            Accumulator::Percentile(values, fraction) => exact_percentile(&mut values.clone(), *fraction),
+            Accumulator::ApproxPercentile(digest, fraction) => digest.clone().percentile(*fraction),
+            Accumulator::Sampled(sums, AggregateFunction::Estimate(scaled, rate)) => sums.estimate(*scaled, *rate),
+            Accumulator::Sampled(sums, AggregateFunction::Margin(scaled, rate)) => sums.margin(*scaled, *rate),
+            Accumulator::Sampled(_, _) => Value::Null,
+        }
+    }
+}
+
+// GROUP BY over any set of key expressions with any list of aggregates. The whole input gets
+// drained into a hash table on the first call, then the groups are handed out BATCH_ROWS at a
+// time. With no group keys it's a single row, even over empty input, the way SQL does it.
+//
+// Once the groups outgrow the memory budget it goes hybrid: groups already in the table keep
+// aggregating in memory, and rows with any new key get written out (keys plus aggregate inputs)
+// to partition files by the hash of the key. After the in-memory groups are handed out, each
+// partition gets aggregated on its own by a fresh HashAggregate, which can spill again itself.
+pub struct HashAggregate {
+    input: Box<dyn Operator>,
+    group_by: Vec<(String, Expr)>,
+    aggregates: Vec<AggregateExpr>,
+    groups: HashMap<Vec<Value>, Vec<Accumulator>>,
+    output: Option<hash_map::IntoIter<Vec<Value>, Vec<Accumulator>>>,
+    // Running estimate of what the groups take up, kept as they grow so it's cheap to ask
+    held: usize,
+    budget: usize,
+    // How many times the rows coming in have already been through a spill
+    depth: usize,
+    spill: Option<Partitions>,
+    partitions: VecDeque<SpillFile>,
+    current: Option<Box<HashAggregate>>,
+    bytes_spilled: u64,
+    // Clusters of a sample seen so far, for estimates over a TABLESAMPLE
+    sampling: Option<SampleMethod>,
+    clusters: u64,
+}
+
+impl HashAggregate {
+    pub fn new(input: Box<dyn Operator>, group_by: Vec<(String, Expr)>, aggregates: Vec<AggregateExpr>) -> HashAggregate {
+        let sampling = aggregates.iter().find_map(|agg| agg.function.sampling());
+        HashAggregate {
+            input,
+            group_by,
+            aggregates,
+            groups: HashMap::new(),
+            output: None,
+            held: 0,
+            budget: memory_budget(),
+            depth: 0,
+            spill: None,
+            partitions: VecDeque::new(),
+            current: None,
+            bytes_spilled: 0,
+            sampling,
+            clusters: 0,
+        }
+    }
+
+    fn new_accumulators(&self) -> Vec<Accumulator> {
+        self.aggregates.iter().map(|agg| Accumulator::new(agg.function)).collect()
+    }
+
+    fn group_size(&self, key: &[Value]) -> usize {
+        key.iter().map(|val| val.size()).sum::<usize>() + self.aggregates.len() * std::mem::size_of::<Accumulator>()
+    }
+
+    fn consume(&mut self, batch: &Batch) -> Result<(), String> {
+        let mut agg_values: Vec<Vec<Value>> = Vec::new();
+        for agg in &self.aggregates {
+            agg_values.push(agg.expr.eval(batch)?);
+        }
+        // A SYSTEM sample's clusters are the scan's pages, however many batches skipping splits
+        // one into. Past something that doesn't carry the page along, at least no batch crosses one.
+        let clusters = match (self.sampling, batch.page) {
+            (Some(SampleMethod::System), Some(page)) => Clusters { first: page, per_row: false },
+            (Some(SampleMethod::System), None) => {
+                self.clusters += 1;
+                Clusters { first: self.clusters, per_row: false }
+            },
+            _ => {
+                let clusters = Clusters { first: self.clusters, per_row: true };
+                self.clusters += batch.num_rows() as u64;
+                clusters
+            },
+        };
+        if self.group_by.is_empty() {
+            let accumulators = match self.groups.contains_key(&Vec::new()) {
+                true => self.groups.get_mut(&Vec::new()).unwrap(),
+                false => {
+                    let fresh = self.new_accumulators();
+                    self.groups.entry(Vec::new()).or_insert(fresh)
+                },
+            };
+            let mut grown = 0;
+            for (accumulator, values) in accumulators.iter_mut().zip(&agg_values) {
+                let before = accumulator.values_held();
+                accumulator.update_batch(values, clusters)?;
+                grown += (accumulator.values_held() - before) * std::mem::size_of::<Value>();
+            }
+            self.held += grown;
+            return Ok(());
+        }
+
+        let mut key_values: Vec<Vec<Value>> = Vec::new();
+        for (_, expr) in &self.group_by {
+            key_values.push(expr.eval(batch)?);
+        }
+        for row in 0..batch.num_rows() {
+            let key: Vec<Value> = key_values.iter().map(|col| col[row].clone()).collect();
+            if !self.groups.contains_key(&key) {
+                if let Some(spill) = self.spill.as_mut() {
+                    let spilled: Vec<Value> = key.iter().cloned().chain(agg_values.iter().map(|col| col[row].clone())).collect();
+                    spill.write_row(&key, &spilled)?;
+                    continue;
+                }
+                let fresh = self.new_accumulators();
+                self.held += self.group_size(&key);
+                self.groups.insert(key.clone(), fresh);
+                if self.held > self.budget && self.depth < MAX_SPILL_DEPTH {
+                    self.spill = Some(Partitions::new(self.depth)?);
+                }
+            }
+            let accumulators = self.groups.get_mut(&key).unwrap();
+            for (accumulator, values) in accumulators.iter_mut().zip(&agg_values) {
+                let before = accumulator.values_held();
+                accumulator.update_in(&values[row], clusters.of(row))?;
+                if accumulator.values_held() > before {
+                    self.held += values[row].size();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Aggregates one spilled partition, whose rows are the group keys then the aggregate inputs
+    fn partition_aggregate(&self, file: SpillFile) -> Result<HashAggregate, String> {
+        let key_names: Vec<String> = (0..self.group_by.len()).map(|index| format!("__key{}", index)).collect();
+        let value_names: Vec<String> = (0..self.aggregates.len()).map(|index| format!("__value{}", index)).collect();
+        let input = SpillScan::new(file, key_names.iter().chain(&value_names).cloned().collect())?;
+        let group_by = self.group_by.iter().zip(&key_names).map(|((name, _), key)| (name.clone(), col(key))).collect();
+        let aggregates = self.aggregates.iter().zip(&value_names)
+            .map(|(agg, value)| AggregateExpr::new(agg.function, col(value), &agg.name))
+            .collect();
+        let mut aggregate = HashAggregate::new(Box::new(input), group_by, aggregates);
+        aggregate.depth = self.depth + 1;
+        Ok(aggregate)
+    }
+
+    fn output_names(&self) -> Vec<String> {
+        self.group_by.iter().map(|(name, _)| name.clone())
+            .chain(self.aggregates.iter().map(|agg| agg.name.clone()))
+            .collect()
+    }
+}
+
+impl Operator for HashAggregate {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.output.is_none() {
+            while let Some(batch) = self.input.next_batch()? {
+                self.consume(&batch)?;
+            }
+            if self.group_by.is_empty() && self.groups.is_empty() {
+                let fresh = self.new_accumulators();
+                self.groups.insert(Vec::new(), fresh);
+            }
+            if let Some(spill) = self.spill.take() {
+                for file in spill.finish()? {
+                    self.bytes_spilled += file.bytes;
+                    if file.rows > 0 {
+                        self.partitions.push_back(file);
+                    }
+                }
+            }
+            self.output = Some(std::mem::take(&mut self.groups).into_iter());
+        }
+
+        let names = self.output_names();
+        let mut batch = Batch::new(names.clone(), vec![Vec::new(); names.len()]);
+        let output = self.output.as_mut().unwrap();
+        for (key, accumulators) in output.by_ref().take(BATCH_ROWS) {
+            for (col, value) in batch.columns.iter_mut().zip(key.into_iter().chain(accumulators.iter().map(|acc| acc.finish()))) {
+                col.push(value);
+            }
+        }
+        if batch.num_rows() > 0 {
+            return Ok(Some(batch));
+        }
+        // Everything that stayed in memory is out, on to whatever got spilled
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(batch) = current.next_batch()? {
+                    return Ok(Some(batch));
+                }
+                self.bytes_spilled += current.bytes_spilled;
+                self.current = None;
+            }
+            match self.partitions.pop_front() {
+                Some(file) => self.current = Some(Box::new(self.partition_aggregate(file)?)),
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.output_names()
+    }
+
+    fn name(&self) -> String {
+        let group_by: Vec<String> = self.group_by.iter().map(|(name, expr)| format!("{} AS {}", expr, name)).collect();
+        let aggregates: Vec<String> = self.aggregates.iter().map(|agg| agg.to_string()).collect();
+        format!("HashAggregate [{}] group by [{}]", aggregates.join(", "), group_by.join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.held + self.current.as_ref().map(|current| current.memory_bytes()).unwrap_or(0)
+    }
+
+    fn bytes_spilled(&self) -> u64 {
+        self.bytes_spilled + self.current.as_ref().map(|current| current.bytes_spilled).unwrap_or(0)
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        match self.group_by.is_empty() {
+            true => Some(1),
+            false => self.input.estimated_rows(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::operator::{collect, BatchSource};
+
+    fn page(page: u64, values: &[i64]) -> Batch {
+        Batch { page: Some(page), ..Batch::new(vec!["x".to_string()], vec![values.iter().map(|val| Value::Int64(*val)).collect()]) }
+    }
+
+    fn estimate(batches: Vec<Batch>) -> Batch {
+        let rate = SampleRate { method: SampleMethod::System, millionths: 100_000 };
+        let mut aggregate = HashAggregate::new(Box::new(BatchSource::new(batches)), Vec::new(), vec![
+            AggregateExpr::new(AggregateFunction::Estimate(Scaled::Sum, rate), col("x"), "sum"),
+            AggregateExpr::new(AggregateFunction::Margin(Scaled::Sum, rate), col("x"), "sum_margin"),
+            AggregateExpr::new(AggregateFunction::Margin(Scaled::Count, rate), col("x"), "count_margin"),
+        ]);
+        collect(&mut aggregate).unwrap()
+    }
+
+    #[test]
+    fn system_sample_page_split_over_batches_is_one_cluster() {
+        let whole = estimate(vec![page(0, &[1, 2, 3]), page(4, &[10, 20, 30, 40]), page(9, &[5])]);
+        let split = estimate(vec![page(0, &[1, 2, 3]), page(4, &[10]), page(4, &[20, 30]), page(4, &[40]), page(9, &[5])]);
+        assert_eq!(whole.columns, split.columns);
+        // Counting each batch as a page of its own would make the pages look more alike than they are
+        let untagged = estimate(vec![page(0, &[1, 2, 3]), page(4, &[10]), page(5, &[20, 30]), page(6, &[40]), page(9, &[5])]);
+        assert_ne!(whole.columns[1], untagged.columns[1]);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::analyze::batch::Value;
+use std::fmt;
+
+// Arrays are a shape and the values in row major order. Scalars have an empty shape, vectors one
+// dimension and outer products make matrices. Nothing is nested, each value is a plain Value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Array {
+    pub shape: Vec<usize>,
+    pub data: Vec<Value>,
+}
+
+impl Array {
+    pub fn scalar(value: Value) -> Array {
+        Array { shape: Vec::new(), data: vec![value] }
+    }
+
+    pub fn vector(data: Vec<Value>) -> Array {
+        Array { shape: vec![data.len()], data }
+    }
+
+    pub fn rank(&self) -> usize {
+        self.shape.len()
+    }
+
+    pub fn is_scalar(&self) -> bool {
+        self.shape.is_empty()
+    }
+
+    // Length along the first axis, how many items a reduce or index sees
+    pub fn tally(&self) -> usize {
+        self.shape.first().copied().unwrap_or(1)
+    }
+
+    pub fn same_shape(&self, data: Vec<Value>) -> Array {
+        Array { shape: self.shape.clone(), data }
+    }
+}
+
+// Booleans print the APL way, as 1 and 0
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Bool(val) => (*val as u8).to_string(),
+        _ => value.to_string(),
+    }
+}
+
+impl Array {
+    // Printable form with only the first max_items values, columns from file can be huge
+    pub fn format(&self, max_items: usize) -> String {
+        let cells: Vec<String> = self.data.iter().take(max_items).map(format_value).collect();
+        let more = match self.data.len() > max_items {
+            true => format!(" … ({} values)", self.data.len()),
+            false => String::new(),
+        };
+        match self.rank() {
+            0 | 1 => cells.join(" ") + &more,
+            _ => {
+                let width = cells.iter().map(|cell| cell.chars().count()).max().unwrap_or(0);
+                let row_length = *self.shape.last().unwrap();
+                let rows: Vec<String> = cells.chunks(row_length.max(1))
+                    .map(|row| row.iter().map(|cell| format!("{:>width$}", cell, width = width)).collect::<Vec<String>>().join(" "))
+                    .collect();
+                rows.join("\n") + &more
+            },
+        }
+    }
+}
+
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.format(self.data.len()))
+    }
+}
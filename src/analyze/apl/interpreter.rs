@@ -0,0 +1,588 @@
+use crate::analyze::batch::Value;
+use crate::analyze::expr::{arithmetic, compare_values, parse_datetime, ArithmeticOp, CompareOp};
+use crate::analyze::join::normalize_key;
+use crate::analyze::lineage::LineageNode;
+use crate::analyze::operator::{collect, Operator};
+use crate::analyze::scan::TableScan;
+use super::array::Array;
+use super::parser::{parse_program, Function, Node};
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+// Scalar functions apply item by item, with a scalar on either side stretched to fit the other
+const SCALAR_GLYPHS: &str = "+-×÷⌈⌊=≠<>≤≥∧∨";
+
+// Safe to run as a left to right running total for scan, everything else gets each prefix reduced
+const ASSOCIATIVE_GLYPHS: &str = "+×⌈⌊∧∨";
+
+// Most values one primitive will make up out of counts, ⍳ 1e14 should be an error rather than
+// the allocator giving up. Well past any column here.
+const MAX_ITEMS: u128 = 1 << 26;
+
+fn limited(items: u128, what: &str) -> Result<usize, String> {
+    match items <= MAX_ITEMS {
+        true => Ok(items as usize),
+        false => Err(format!("Limit error: {} would make {} values, more than {}", what, items, MAX_ITEMS)),
+    }
+}
+
+// Booleans count as 1 and 0 so `+/ x > 5` counts
+fn numeric(value: &Value) -> Value {
+    match value {
+        Value::Bool(val) => Value::Int64(*val as i64),
+        _ => value.clone(),
+    }
+}
+
+fn truthy(value: &Value) -> Result<bool, String> {
+    match numeric(value).as_i128() {
+        Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        _ => Err(format!("Domain error: expected a boolean, got {}", value)),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    numeric(value).as_i128()
+        .and_then(|val| i64::try_from(val).ok())
+        .ok_or(format!("Domain error: expected an integer, got {}", value))
+}
+
+// Strings against dates compare as dates, so `orders.created > '2022-09-01'` does what it says
+fn comparable(left: &Value, right: &Value) -> (Value, Value) {
+    let coerce = |text: &str, other: &Value| match (other, parse_datetime(text)) {
+        (Value::DateTime(_), Some(datetime)) => Value::DateTime(datetime),
+        _ => Value::String(text.to_string()),
+    };
+    match (left, right) {
+        (Value::String(text), Value::DateTime(_)) => (coerce(text, right), right.clone()),
+        (Value::DateTime(_), Value::String(text)) => (left.clone(), coerce(text, left)),
+        _ => (numeric(left), numeric(right)),
+    }
+}
+
+fn rank_values(left: &Value, right: &Value) -> Ordering {
+    left.compare(right).unwrap_or_else(|| left.cmp(right))
+}
+
+pub fn scalar_dyadic(glyph: char, left: &Value, right: &Value) -> Result<Value, String> {
+    let compare = |op: CompareOp| {
+        let (left, right) = comparable(left, right);
+        compare_values(op, &left, &right)
+    };
+    let arith = |op: ArithmeticOp| arithmetic(op, &numeric(left), &numeric(right));
+    match glyph {
+        '+' => arith(ArithmeticOp::Add),
+        '-' => arith(ArithmeticOp::Subtract),
+        '×' => arith(ArithmeticOp::Multiply),
+        // Division is always exact, integers or not
+        '÷' => match (numeric(left).as_decimal(), numeric(right).as_decimal()) {
+            (Some(_), Some(r)) if r.is_zero() => Err("Domain error: divide by zero".to_string()),
+            (Some(l), Some(r)) => l.checked_div(r).map(Value::Decimal).ok_or(format!("Overflow computing {} ÷ {}", left, right)),
+            _ => arith(ArithmeticOp::Divide),
+        },
+        '⌈' | '⌊' => {
+            let (l, r) = comparable(left, right);
+            let left_wins = match rank_values(&l, &r) {
+                Ordering::Greater => glyph == '⌈',
+                Ordering::Less => glyph == '⌊',
+                Ordering::Equal => true,
+            };
+            Ok(if left_wins { l } else { r })
+        },
+        '=' => Ok(compare(CompareOp::Eq)),
+        '≠' => Ok(compare(CompareOp::NotEq)),
+        '<' => Ok(compare(CompareOp::Lt)),
+        '>' => Ok(compare(CompareOp::Gt)),
+        '≤' => Ok(compare(CompareOp::LtEq)),
+        '≥' => Ok(compare(CompareOp::GtEq)),
+        '∧' => Ok(Value::Bool(truthy(left)? && truthy(right)?)),
+        '∨' => Ok(Value::Bool(truthy(left)? || truthy(right)?)),
+        _ => Err(format!("{} isn't a scalar function", glyph)),
+    }
+}
+
+fn scalar_monadic(glyph: char, value: &Value) -> Result<Value, String> {
+    match glyph {
+        '+' => Ok(value.clone()),
+        '-' => scalar_dyadic('-', &Value::Int64(0), value),
+        '÷' => scalar_dyadic('÷', &Value::Int64(1), value),
+        '⌈' | '⌊' => match value {
+            Value::Decimal(val) => Ok(Value::Decimal(match glyph {
+                '⌈' => val.ceil(),
+                _ => val.floor(),
+            })),
+            _ => Ok(value.clone()),
+        },
+        '~' => Ok(Value::Bool(!truthy(value)?)),
+        _ => Err(format!("{} has no monadic form", glyph)),
+    }
+}
+
+// Identity values so reducing an empty array still means something
+fn identity(glyph: char) -> Result<Value, String> {
+    match glyph {
+        '+' | '-' | '∨' => Ok(Value::Int64(0)),
+        '×' | '÷' | '∧' => Ok(Value::Int64(1)),
+        _ => Err(format!("Domain error: {}/ of an empty array", glyph)),
+    }
+}
+
+fn pervasive(left: &Array, right: &Array, apply: impl Fn(&Value, &Value) -> Result<Value, String>) -> Result<Array, String> {
+    if left.is_scalar() {
+        let data = right.data.iter().map(|val| apply(&left.data[0], val)).collect::<Result<Vec<Value>, String>>()?;
+        return Ok(right.same_shape(data));
+    }
+    if right.is_scalar() {
+        let data = left.data.iter().map(|val| apply(val, &right.data[0])).collect::<Result<Vec<Value>, String>>()?;
+        return Ok(left.same_shape(data));
+    }
+    if left.shape != right.shape {
+        return Err(format!("Length error: shapes {:?} and {:?} don't match", left.shape, right.shape));
+    }
+    let data = left.data.iter().zip(right.data.iter()).map(|(l, r)| apply(l, r)).collect::<Result<Vec<Value>, String>>()?;
+    Ok(left.same_shape(data))
+}
+
+// Splits along the last axis, each row of a matrix or the one row of a vector
+fn rows(array: &Array) -> Vec<&[Value]> {
+    match array.rank() {
+        0 => vec![&array.data[..]],
+        _ => {
+            let length = *array.shape.last().unwrap();
+            match length {
+                0 => vec![&[]; array.shape[..array.rank() - 1].iter().product()],
+                _ => array.data.chunks(length).collect(),
+            }
+        },
+    }
+}
+
+fn vector_of(array: &Array, name: &str) -> Result<Vec<Value>, String> {
+    match array.rank() {
+        0 | 1 => Ok(array.data.clone()),
+        _ => Err(format!("Rank error: {} takes a vector, got shape {:?}", name, array.shape)),
+    }
+}
+
+fn grade(array: &Array, descending: bool) -> Result<Array, String> {
+    let values = vector_of(array, "grade")?;
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    // Stable, so equal values keep their original order either way
+    order.sort_by(|a, b| {
+        let ordering = rank_values(&values[*a], &values[*b]);
+        match descending {
+            true => ordering.reverse(),
+            false => ordering,
+        }
+    });
+    Ok(Array::vector(order.into_iter().map(|index| Value::Int64(index as i64)).collect()))
+}
+
+fn index_of(left: &Array, right: &Array) -> Result<Array, String> {
+    let haystack = vector_of(left, "index of")?;
+    let mut positions: HashMap<Value, usize> = HashMap::new();
+    for (index, value) in haystack.iter().enumerate().rev() {
+        positions.insert(normalize_key(value), index);
+    }
+    // Missing values come back as one past the end, like APL
+    let data = right.data.iter()
+        .map(|value| Value::Int64(*positions.get(&normalize_key(value)).unwrap_or(&haystack.len()) as i64))
+        .collect();
+    Ok(right.same_shape(data))
+}
+
+fn reshape(left: &Array, right: &Array) -> Result<Array, String> {
+    let shape = left.data.iter().map(|val| integer(val).and_then(|val| usize::try_from(val).map_err(|_| format!("Domain error: negative shape {}", val)))).collect::<Result<Vec<usize>, String>>()?;
+    let size = limited(shape.iter().try_fold(1u128, |size, len| size.checked_mul(*len as u128)).unwrap_or(u128::MAX), "reshape")?;
+    if size > 0 && right.data.is_empty() {
+        return Err("Domain error: can't reshape an empty array".to_string());
+    }
+    let data = right.data.iter().cycle().take(size).cloned().collect();
+    Ok(Array { shape, data })
+}
+
+// Replicate, with booleans it's the usual filter
+fn compress(left: &Array, right: &Array) -> Result<Array, String> {
+    let values = vector_of(right, "compress")?;
+    let counts: Vec<i64> = match left.is_scalar() {
+        true => vec![integer(&left.data[0])?; values.len()],
+        false => left.data.iter().map(integer).collect::<Result<Vec<i64>, String>>()?,
+    };
+    if counts.len() != values.len() {
+        return Err(format!("Length error: {} counts for {} values", counts.len(), values.len()));
+    }
+    if let Some(count) = counts.iter().find(|count| **count < 0) {
+        return Err(format!("Domain error: can't replicate {} times", count));
+    }
+    limited(counts.iter().map(|count| *count as u128).sum(), "replicate")?;
+    let mut data: Vec<Value> = Vec::new();
+    for (count, value) in counts.iter().zip(values) {
+        for _ in 0..*count {
+            data.push(value.clone());
+        }
+    }
+    Ok(Array::vector(data))
+}
+
+// What overtake pads with, going by the first value: zero of the same type for numbers and an
+// empty string for strings. There's no sensible one for dates or ids.
+fn fill(values: &[Value]) -> Result<Value, String> {
+    match values.first() {
+        None | Some(Value::Int64(_)) => Ok(Value::Int64(0)),
+        Some(Value::Bool(_)) => Ok(Value::Bool(false)),
+        Some(Value::Int8(_)) => Ok(Value::Int8(0)),
+        Some(Value::UInt64(_)) => Ok(Value::UInt64(0)),
+        Some(Value::UInt8(_)) => Ok(Value::UInt8(0)),
+        Some(Value::Decimal(_)) => Ok(Value::Decimal(Decimal::ZERO)),
+        Some(Value::String(_)) => Ok(Value::String(String::new())),
+        Some(value) => Err(format!("Domain error: nothing to pad {} out with", value)),
+    }
+}
+
+// A negative count works from the end. Taking more than there is pads with fill values, dropping
+// more than there is leaves nothing.
+fn take_drop(left: &Array, right: &Array, take: bool) -> Result<Array, String> {
+    if !left.is_scalar() {
+        return Err("Rank error: take and drop need a single count".to_string());
+    }
+    let values = vector_of(right, "take/drop")?;
+    let count = integer(&left.data[0])?;
+    let length = values.len() as i64;
+    if take && count.unsigned_abs() > length as u64 {
+        let padding = vec![fill(&values)?; limited(count.unsigned_abs() as u128, "take")? - values.len()];
+        return Ok(Array::vector(match count >= 0 {
+            true => values.into_iter().chain(padding).collect(),
+            false => padding.into_iter().chain(values).collect(),
+        }));
+    }
+    let n = count.unsigned_abs().min(length as u64) as i64;
+    let (start, end) = match (take, count >= 0) {
+        (true, true) => (0, n),
+        (true, false) => (length - n, length),
+        (false, true) => (n, length),
+        (false, false) => (0, length - n),
+    };
+    Ok(Array::vector(values[start as usize..end as usize].to_vec()))
+}
+
+fn unique(array: &Array) -> Result<Array, String> {
+    let mut seen: HashSet<Value> = HashSet::new();
+    let data = vector_of(array, "unique")?.into_iter().filter(|value| seen.insert(normalize_key(value))).collect();
+    Ok(Array::vector(data))
+}
+
+fn catenate(left: &Array, right: &Array) -> Result<Array, String> {
+    let mut data = vector_of(left, "catenate")?;
+    data.extend(vector_of(right, "catenate")?);
+    Ok(Array::vector(data))
+}
+
+fn index(array: &Array, indices: &Array) -> Result<Array, String> {
+    let values = vector_of(array, "indexing")?;
+    let data = indices.data.iter()
+        .map(|index| {
+            let position = integer(index)?;
+            usize::try_from(position).ok().and_then(|position| values.get(position)).cloned()
+                .ok_or(format!("Index error: {} out of {} values", position, values.len()))
+        })
+        .collect::<Result<Vec<Value>, String>>()?;
+    Ok(indices.same_shape(data))
+}
+
+// Puts per item results back together, scalars keep the argument's shape and equal length
+// vectors become the rows of a matrix
+fn assemble(shape: &[usize], results: Vec<Array>) -> Result<Array, String> {
+    if results.iter().all(|result| result.is_scalar()) {
+        return Ok(Array { shape: shape.to_vec(), data: results.into_iter().map(|result| result.data[0].clone()).collect() });
+    }
+    let row_shape = results[0].shape.clone();
+    if shape.len() > 1 || row_shape.len() != 1 || results.iter().any(|result| result.shape != row_shape) {
+        return Err("Rank error: each can only build a matrix out of equal length vectors".to_string());
+    }
+    let mut data: Vec<Value> = Vec::new();
+    for result in results.iter() {
+        data.extend(result.data.iter().cloned());
+    }
+    Ok(Array { shape: vec![results.len(), row_shape[0]], data })
+}
+
+// Runs programs against the stored tables. Variables stay around between calls to run, and each
+// stored column only gets read off disk once. Every variable keeps the lineage of whatever built
+// it, so a result traces back through assignments to the columns underneath.
+pub struct Interpreter {
+    variables: HashMap<String, Array>,
+    lineages: HashMap<String, LineageNode>,
+    columns: HashMap<String, Array>,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter { variables: HashMap::new(), lineages: HashMap::new(), columns: HashMap::new() }
+    }
+
+    // Value and lineage of the last statement, or None when it was an assignment
+    pub fn run(&mut self, text: &str) -> Result<Option<(Array, LineageNode)>, String> {
+        let mut result: Option<(Array, LineageNode)> = None;
+        for statement in parse_program(text)? {
+            let lineage = self.lineage(&statement)?;
+            let value = self.eval(&statement)?;
+            result = match statement {
+                Node::Assign(_, _) => None,
+                _ => Some((value, lineage)),
+            };
+        }
+        Ok(result)
+    }
+
+    // The operation tree a statement compiles to, assignments along the way get remembered
+    fn lineage(&mut self, node: &Node) -> Result<LineageNode, String> {
+        let leaf = |operator: String| LineageNode::new(operator, Vec::new(), Vec::new());
+        Ok(match node {
+            Node::Literal(value) => leaf(format!("Literal {}", value)),
+            Node::Strand(nodes) => {
+                let mut children: Vec<LineageNode> = Vec::new();
+                for node in nodes {
+                    children.push(self.lineage(node)?);
+                }
+                LineageNode::new("Strand".to_string(), Vec::new(), children)
+            },
+            Node::Name(name) if name.contains('.') => {
+                let (table, column) = name.split_once('.').unwrap();
+                TableScan::new(table, &[column.to_string()])?.lineage()
+            },
+            Node::Name(name) => self.lineages.get(name).cloned().ok_or(format!("Value error: {} isn't defined", name))?,
+            Node::Index(array, indices) => {
+                let indices = self.lineage(indices)?;
+                LineageNode::new("Index".to_string(), Vec::new(), vec![self.lineage(array)?, indices])
+            },
+            Node::Monadic(function, right) => LineageNode::new(format!("Monadic {}", function), Vec::new(), vec![self.lineage(right)?]),
+            Node::Dyadic(function, left, right) => {
+                let right = self.lineage(right)?;
+                LineageNode::new(format!("Dyadic {}", function), Vec::new(), vec![self.lineage(left)?, right])
+            },
+            Node::Assign(name, node) => {
+                let lineage = LineageNode::new(format!("Assign {}", name), Vec::new(), vec![self.lineage(node)?]);
+                self.lineages.insert(name.clone(), lineage.clone());
+                lineage
+            },
+        })
+    }
+
+    fn load_column(&mut self, name: &str) -> Result<Array, String> {
+        if let Some(column) = self.columns.get(name) {
+            return Ok(column.clone());
+        }
+        let (table, column) = name.split_once('.').unwrap();
+        let batch = collect(&mut TableScan::new(table, &[column.to_string()])?)?;
+        let column = Array::vector(batch.columns.into_iter().next().unwrap_or_default());
+        self.columns.insert(name.to_string(), column.clone());
+        Ok(column)
+    }
+
+    fn eval(&mut self, node: &Node) -> Result<Array, String> {
+        match node {
+            Node::Literal(value) => Ok(Array::scalar(value.clone())),
+            Node::Strand(nodes) => {
+                let mut data: Vec<Value> = Vec::new();
+                for node in nodes {
+                    data.extend(self.eval(node)?.data);
+                }
+                Ok(Array::vector(data))
+            },
+            Node::Name(name) if name.contains('.') => self.load_column(name),
+            Node::Name(name) => self.variables.get(name).cloned().ok_or(format!("Value error: {} isn't defined", name)),
+            Node::Index(array, indices) => {
+                // Right to left here too
+                let indices = self.eval(indices)?;
+                let array = self.eval(array)?;
+                index(&array, &indices)
+            },
+            Node::Monadic(function, right) => {
+                let right = self.eval(right)?;
+                self.monadic(function, &right)
+            },
+            Node::Dyadic(function, left, right) => {
+                let right = self.eval(right)?;
+                let left = self.eval(left)?;
+                self.dyadic(function, &left, &right)
+            },
+            Node::Assign(name, node) => {
+                let value = self.eval(node)?;
+                self.variables.insert(name.clone(), value.clone());
+                Ok(value)
+            },
+        }
+    }
+
+    fn monadic(&self, function: &Function, right: &Array) -> Result<Array, String> {
+        match function {
+            Function::Primitive(glyph) => match glyph {
+                '⍳' => {
+                    let count = match right.is_scalar() {
+                        true => integer(&right.data[0])?,
+                        false => return Err("Rank error: ⍳ takes a single count".to_string()),
+                    };
+                    let count = limited(count.max(0) as u128, "⍳")?;
+                    Ok(Array::vector((0..count as i64).map(Value::Int64).collect()))
+                },
+                '⍴' => Ok(Array::vector(right.shape.iter().map(|len| Value::Int64(*len as i64)).collect())),
+                '≢' => Ok(Array::scalar(Value::Int64(right.tally() as i64))),
+                '⍋' => grade(right, false),
+                '⍒' => grade(right, true),
+                '∪' => unique(right),
+                ',' => Ok(Array::vector(right.data.clone())),
+                _ => Ok(right.same_shape(right.data.iter().map(|val| scalar_monadic(*glyph, val)).collect::<Result<Vec<Value>, String>>()?)),
+            },
+            Function::Reduce(inner) => {
+                let mut data: Vec<Value> = Vec::new();
+                for row in rows(right) {
+                    data.push(self.reduce(inner, row)?);
+                }
+                match right.rank() {
+                    0 | 1 => Ok(Array::scalar(data.pop().unwrap())),
+                    _ => Ok(Array { shape: right.shape[..right.rank() - 1].to_vec(), data }),
+                }
+            },
+            Function::Scan(inner) => {
+                let mut data: Vec<Value> = Vec::with_capacity(right.data.len());
+                for row in rows(right) {
+                    data.extend(self.scan(inner, row)?);
+                }
+                Ok(right.same_shape(data))
+            },
+            Function::Each(inner) => {
+                let results = right.data.iter()
+                    .map(|value| self.monadic(inner, &Array::scalar(value.clone())))
+                    .collect::<Result<Vec<Array>, String>>()?;
+                assemble(&right.shape, results)
+            },
+            Function::Outer(_) => Err("Syntax error: outer product needs a left argument".to_string()),
+        }
+    }
+
+    fn dyadic(&self, function: &Function, left: &Array, right: &Array) -> Result<Array, String> {
+        match function {
+            Function::Primitive(glyph) if SCALAR_GLYPHS.contains(*glyph) => pervasive(left, right, |l, r| scalar_dyadic(*glyph, l, r)),
+            Function::Primitive(glyph) => match glyph {
+                '⍳' => index_of(left, right),
+                '⍴' => reshape(left, right),
+                '/' => compress(left, right),
+                '↑' => take_drop(left, right, true),
+                '↓' => take_drop(left, right, false),
+                ',' => catenate(left, right),
+                _ => Err(format!("{} has no dyadic form", glyph)),
+            },
+            Function::Each(inner) => {
+                let pairs: Vec<(Value, Value)> = match (left.is_scalar(), right.is_scalar()) {
+                    (true, _) => right.data.iter().map(|r| (left.data[0].clone(), r.clone())).collect(),
+                    (_, true) => left.data.iter().map(|l| (l.clone(), right.data[0].clone())).collect(),
+                    _ if left.shape == right.shape => left.data.iter().cloned().zip(right.data.iter().cloned()).collect(),
+                    _ => return Err(format!("Length error: shapes {:?} and {:?} don't match", left.shape, right.shape)),
+                };
+                let shape = match left.is_scalar() {
+                    true => right.shape.clone(),
+                    false => left.shape.clone(),
+                };
+                let results = pairs.into_iter()
+                    .map(|(l, r)| self.dyadic(inner, &Array::scalar(l), &Array::scalar(r)))
+                    .collect::<Result<Vec<Array>, String>>()?;
+                assemble(&shape, results)
+            },
+            Function::Outer(inner) => {
+                let mut data: Vec<Value> = Vec::with_capacity(limited(left.data.len() as u128 * right.data.len() as u128, "outer product")?);
+                for l in left.data.iter() {
+                    for r in right.data.iter() {
+                        data.push(self.apply_scalars(inner, l, r)?);
+                    }
+                }
+                Ok(Array { shape: left.shape.iter().chain(right.shape.iter()).cloned().collect(), data })
+            },
+            Function::Reduce(_) | Function::Scan(_) => Err("Syntax error: reduce and scan only take a right argument".to_string()),
+        }
+    }
+
+    // A function between two single values, which has to come back as a single value
+    fn apply_scalars(&self, function: &Function, left: &Value, right: &Value) -> Result<Value, String> {
+        if let Function::Primitive(glyph) = function {
+            if SCALAR_GLYPHS.contains(*glyph) {
+                return scalar_dyadic(*glyph, left, right);
+            }
+        }
+        let result = self.dyadic(function, &Array::scalar(left.clone()), &Array::scalar(right.clone()))?;
+        match result.is_scalar() {
+            true => Ok(result.data[0].clone()),
+            false => Err("Rank error: reduce and outer product need functions with scalar results".to_string()),
+        }
+    }
+
+    // Folds from the right like APL, so -/ is an alternating sum
+    fn reduce(&self, function: &Function, values: &[Value]) -> Result<Value, String> {
+        match values.split_last() {
+            None => match function {
+                Function::Primitive(glyph) => identity(*glyph),
+                _ => Err("Domain error: reduce of an empty array".to_string()),
+            },
+            Some((last, rest)) => {
+                let mut acc = last.clone();
+                for value in rest.iter().rev() {
+                    acc = self.apply_scalars(function, value, &acc)?;
+                }
+                Ok(acc)
+            },
+        }
+    }
+
+    fn scan(&self, function: &Function, values: &[Value]) -> Result<Vec<Value>, String> {
+        let associative = matches!(function, Function::Primitive(glyph) if ASSOCIATIVE_GLYPHS.contains(*glyph));
+        if !associative {
+            return (1..=values.len()).map(|end| self.reduce(function, &values[..end])).collect();
+        }
+        let mut data: Vec<Value> = Vec::with_capacity(values.len());
+        for value in values {
+            let next = match data.last() {
+                Some(acc) => self.apply_scalars(function, acc, value)?,
+                None => value.clone(),
+            };
+            data.push(next);
+        }
+        Ok(data)
+    }
+}
+
+// Decimal division results get long, this trims them down for printing
+pub fn tidy(array: Array) -> Array {
+    let data = array.data.iter().map(|value| match value {
+        Value::Decimal(val) => Value::Decimal(val.round_dp(6).normalize()),
+        _ => value.clone(),
+    }).collect();
+    Array { data, ..array }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(program: &str) -> Result<String, String> {
+        Ok(Interpreter::new().run(program)?.map(|(array, _)| array.to_string()).unwrap_or_default())
+    }
+
+    #[test]
+    fn overtake_pads_with_fill() {
+        assert_eq!(run("3 ↑ 1 2").unwrap(), "1 2 0");
+        assert_eq!(run("¯4 ↑ 1 2").unwrap(), "0 0 1 2");
+        assert_eq!(run("2 ↑ ⍳ 0").unwrap(), "0 0");
+        assert_eq!(run("5 ↓ 1 2").unwrap(), "");
+        assert_eq!(run("¯1 ↑ 1 2").unwrap(), "2");
+    }
+
+    #[test]
+    fn huge_arrays_are_an_error() {
+        for program in ["⍳ 99999999999999", "99999999 99999999 ⍴ 1", "99999999999 ↑ 1 2", "(⍳ 10000) ∘.+ ⍳ 10000", "99999999999 / 1"] {
+            let error = run(program).unwrap_err();
+            assert!(error.starts_with("Limit error"), "{}: {}", program, error);
+        }
+        assert_eq!(run("≢ ⍳ 100000").unwrap(), "100000");
+    }
+}
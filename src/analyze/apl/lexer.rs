@@ -0,0 +1,150 @@
+use crate::analyze::batch::Value;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+// Tokens of the array language. Glyphs are the APL characters, with ASCII spellings mapped onto
+// them here so nobody needs an APL keyboard to write `+/ order_products.quantity > 5`.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Number(Value),
+    Str(String),
+    // Variables and `table.column` references
+    Name(String),
+    Glyph(char),
+    Assign,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Separator,
+}
+
+// Every glyph the parser knows about, functions and operators both
+pub const GLYPHS: &str = "+-×÷⌈⌊=≠<>≤≥∧∨~⍳⍴⍋⍒≢∪,↑↓/\\¨∘.";
+
+// Words standing in for glyphs, they're reserved so can't be used as variable names
+fn word_glyph(word: &str) -> Option<char> {
+    Some(match word {
+        "max" => '⌈',
+        "min" => '⌊',
+        "and" => '∧',
+        "or" => '∨',
+        "not" => '~',
+        "iota" => '⍳',
+        "rho" => '⍴',
+        "grade" => '⍋',
+        "gradedown" => '⍒',
+        "count" => '≢',
+        "unique" => '∪',
+        "take" => '↑',
+        "drop" => '↓',
+        "each" => '¨',
+        "outer" => '∘',
+        _ => return None,
+    })
+}
+
+fn symbol_glyph(symbol: &str) -> Option<char> {
+    Some(match symbol {
+        "<=" => '≤',
+        ">=" => '≥',
+        "!=" => '≠',
+        "*" => '×',
+        "%" => '÷',
+        _ => return None,
+    })
+}
+
+fn number(text: &str) -> Result<Value, String> {
+    // APL writes negative literals with a high minus so they can't be mistaken for subtraction
+    let text = text.replace('¯', "-");
+    match text.contains('.') {
+        true => Decimal::from_str(&text).map(Value::Decimal).map_err(|_| format!("Bad number {:?}", text)),
+        false => i64::from_str(&text).map(Value::Int64).map_err(|_| format!("Bad number {:?}", text)),
+    }
+}
+
+pub fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        let next = chars.get(pos + 1).copied();
+        if c == '\n' || c == '⋄' || c == ';' {
+            tokens.push(Token::Separator);
+            pos += 1;
+        } else if c.is_whitespace() {
+            pos += 1;
+        } else if c == '⍝' {
+            // Comment to the end of the line
+            while pos < chars.len() && chars[pos] != '\n' {
+                pos += 1;
+            }
+        } else if c.is_ascii_digit() || (c == '¯' && next.map(|n| n.is_ascii_digit()).unwrap_or(false)) {
+            let start = pos;
+            pos += 1;
+            while pos < chars.len() && (chars[pos].is_ascii_digit() || chars[pos] == '.') {
+                pos += 1;
+            }
+            tokens.push(Token::Number(number(&chars[start..pos].iter().collect::<String>())?));
+        } else if c.is_alphabetic() || c == '_' {
+            // Names can be dotted to reach a stored column, `orders.created`
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '.') {
+                pos += 1;
+            }
+            let word: String = chars[start..pos].iter().collect();
+            match word_glyph(&word) {
+                Some(glyph) => tokens.push(Token::Glyph(glyph)),
+                None => tokens.push(Token::Name(word)),
+            }
+        } else if c == '\'' {
+            // Same quoting as the expression parser, doubled quote for a literal one
+            let mut text = String::new();
+            pos += 1;
+            loop {
+                if pos >= chars.len() {
+                    return Err("Unterminated string literal".to_string());
+                }
+                if chars[pos] == '\'' {
+                    if chars.get(pos + 1) == Some(&'\'') {
+                        text.push('\'');
+                        pos += 2;
+                        continue;
+                    }
+                    pos += 1;
+                    break;
+                }
+                text.push(chars[pos]);
+                pos += 1;
+            }
+            tokens.push(Token::Str(text));
+        } else {
+            let two: String = chars[pos..(pos + 2).min(chars.len())].iter().collect();
+            if two == "<-" {
+                tokens.push(Token::Assign);
+                pos += 2;
+            } else if let Some(glyph) = symbol_glyph(&two) {
+                tokens.push(Token::Glyph(glyph));
+                pos += 2;
+            } else {
+                tokens.push(match c {
+                    '←' => Token::Assign,
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    _ => match symbol_glyph(&c.to_string()) {
+                        Some(glyph) => Token::Glyph(glyph),
+                        None if GLYPHS.contains(c) => Token::Glyph(c),
+                        None => return Err(format!("Unexpected character {:?} at position {}", c, pos)),
+                    },
+                });
+                pos += 1;
+            }
+        }
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,4 @@
+pub mod lexer;
+pub mod parser;
+pub mod array;
+pub mod interpreter;
@@ -0,0 +1,191 @@
+use crate::analyze::batch::Value;
+use super::lexer::{tokenize, Token};
+use std::fmt;
+
+// Functions get applied right to left with no precedence, like APL: `2 × 3 + 4` is 14. A
+// function with nothing on its left is monadic, and the operators (/ \ ¨ ∘.) bind to the
+// function just before them to make a new one.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Function {
+    Primitive(char),
+    Reduce(Box<Function>),
+    Scan(Box<Function>),
+    Each(Box<Function>),
+    Outer(Box<Function>),
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Function::Primitive(glyph) => write!(f, "{}", glyph),
+            Function::Reduce(inner) => write!(f, "{}/", inner),
+            Function::Scan(inner) => write!(f, "{}\\", inner),
+            Function::Each(inner) => write!(f, "{}¨", inner),
+            Function::Outer(inner) => write!(f, "∘.{}", inner),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Literal(Value),
+    // Atoms written side by side, `1 2 3` or `x y`
+    Strand(Vec<Node>),
+    // A variable, or a stored column when it's dotted
+    Name(String),
+    Index(Box<Node>, Box<Node>),
+    Monadic(Function, Box<Node>),
+    Dyadic(Function, Box<Node>, Box<Node>),
+    Assign(String, Box<Node>),
+}
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(tokens: Vec<Token>) -> Parser {
+        Parser { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            other => Err(format!("Expected {:?}, got {:?}", expected, other)),
+        }
+    }
+
+    // One statement per separator, empty ones are fine
+    pub fn parse_program(&mut self) -> Result<Vec<Node>, String> {
+        let mut statements: Vec<Node> = Vec::new();
+        while self.peek().is_some() {
+            if self.peek() == Some(&Token::Separator) {
+                self.next();
+                continue;
+            }
+            statements.push(self.parse_statement()?);
+            match self.next() {
+                None | Some(Token::Separator) => {},
+                Some(token) => return Err(format!("Unexpected {:?}", token)),
+            }
+        }
+        Ok(statements)
+    }
+
+    fn parse_statement(&mut self) -> Result<Node, String> {
+        if let (Some(Token::Name(name)), Some(Token::Assign)) = (self.tokens.get(self.pos).cloned(), self.tokens.get(self.pos + 1)) {
+            if name.contains('.') {
+                return Err(format!("Can't assign to stored column {}", name));
+            }
+            self.pos += 2;
+            return Ok(Node::Assign(name, Box::new(self.parse_expr()?)));
+        }
+        self.parse_expr()
+    }
+
+    fn at_end(&self) -> bool {
+        matches!(self.peek(), None | Some(Token::RParen) | Some(Token::RBracket) | Some(Token::Separator))
+    }
+
+    fn starts_atom(&self) -> bool {
+        matches!(self.peek(), Some(Token::Number(_)) | Some(Token::Str(_)) | Some(Token::Name(_)) | Some(Token::LParen))
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Node, String> {
+        if self.at_end() {
+            return Err(format!("Expected a value, got {:?}", self.peek()));
+        }
+        if !self.starts_atom() {
+            let function = self.parse_function()?;
+            let right = self.parse_expr()?;
+            return Ok(Node::Monadic(function, Box::new(right)));
+        }
+        let left = self.parse_strand()?;
+        if self.at_end() {
+            return Ok(left);
+        }
+        let function = self.parse_function()?;
+        let right = self.parse_expr()?;
+        Ok(Node::Dyadic(function, Box::new(left), Box::new(right)))
+    }
+
+    fn parse_function(&mut self) -> Result<Function, String> {
+        let mut function = match self.next() {
+            Some(Token::Glyph('∘')) => {
+                if self.peek() == Some(&Token::Glyph('.')) {
+                    self.next();
+                }
+                match self.next() {
+                    Some(Token::Glyph(glyph)) if !"/\\¨∘.".contains(glyph) => return Ok(Function::Outer(Box::new(Function::Primitive(glyph)))),
+                    other => return Err(format!("Outer product needs a function after ∘., got {:?}", other)),
+                }
+            },
+            // Compress when it's the function itself rather than following one
+            Some(Token::Glyph('/')) => return Ok(Function::Primitive('/')),
+            Some(Token::Glyph(glyph)) if !"\\¨.".contains(glyph) => Function::Primitive(glyph),
+            other => return Err(format!("Expected a function, got {:?}", other)),
+        };
+        loop {
+            function = match self.peek() {
+                Some(Token::Glyph('/')) => Function::Reduce(Box::new(function)),
+                Some(Token::Glyph('\\')) => Function::Scan(Box::new(function)),
+                Some(Token::Glyph('¨')) => Function::Each(Box::new(function)),
+                _ => return Ok(function),
+            };
+            self.next();
+        }
+    }
+
+    fn parse_strand(&mut self) -> Result<Node, String> {
+        let mut atoms: Vec<Node> = Vec::new();
+        while self.starts_atom() {
+            atoms.push(self.parse_atom()?);
+        }
+        match atoms.len() {
+            1 => Ok(atoms.pop().unwrap()),
+            _ => Ok(Node::Strand(atoms)),
+        }
+    }
+
+    fn parse_atom(&mut self) -> Result<Node, String> {
+        let mut atom = match self.next() {
+            Some(Token::Number(value)) => Node::Literal(value),
+            Some(Token::Str(text)) => Node::Literal(Value::String(text)),
+            // Assignment in the middle of an expression takes everything to its right
+            Some(Token::Name(_)) if self.peek() == Some(&Token::Assign) => {
+                self.pos -= 1;
+                return self.parse_statement();
+            },
+            Some(Token::Name(name)) => Node::Name(name),
+            Some(Token::LParen) => {
+                let inner = self.parse_expr()?;
+                self.expect(Token::RParen)?;
+                inner
+            },
+            other => return Err(format!("Expected a value, got {:?}", other)),
+        };
+        while self.peek() == Some(&Token::LBracket) {
+            self.next();
+            let index = self.parse_expr()?;
+            self.expect(Token::RBracket)?;
+            atom = Node::Index(Box::new(atom), Box::new(index));
+        }
+        Ok(atom)
+    }
+}
+
+pub fn parse_program(text: &str) -> Result<Vec<Node>, String> {
+    Parser::new(tokenize(text)?).parse_program()
+}
@@ -0,0 +1,182 @@
+use crate::datagen::catalog::{table_def, TableDef};
+use crate::datagen::partition;
+use crate::datagen::stats::SegmentStats;
+use super::aggregate::{AggregateExpr, AggregateFunction};
+use super::batch::{Batch, Value};
+use super::expr::Expr;
+use super::hll::{HyperLogLog, DEFAULT_PRECISION};
+use super::lineage::{column_segments, LineageNode};
+use super::operator::Operator;
+use super::scan::{must_match, TableScan};
+use std::collections::HashMap;
+use std::ops::Range;
+
+// approx_count_distinct of table columns with no GROUP BY. Segments whose zone maps say the
+// filter keeps every row (all of them, with no filter) get their stored sketches merged in without
+// being read, and a scan that skips those rows picks up the rest. Sketches are stored at
+// DEFAULT_PRECISION, anything finer has to read every row.
+pub struct ApproxDistinctScan {
+    table: TableDef,
+    filter: Option<Expr>,
+    // Output name, column and precision for each aggregate
+    aggregates: Vec<(String, String, u8)>,
+    done: bool,
+    bytes_read: u64,
+    segments_skipped: u64,
+}
+
+impl ApproxDistinctScan {
+    // None unless every aggregate is approx_count_distinct of one of the table's columns
+    pub fn new(table_name: &str, filter: Option<Expr>, aggregates: &[AggregateExpr]) -> Option<ApproxDistinctScan> {
+        let table = table_def(table_name).ok()?;
+        let prefix = format!("{}.", table.name);
+        let aggregates: Vec<(String, String, u8)> = aggregates.iter()
+            .map(|agg| match (agg.function, &agg.expr) {
+                (AggregateFunction::ApproxCountDistinct(precision), Expr::Column(name)) => {
+                    let name = name.strip_prefix(&prefix).unwrap_or(name);
+                    table.column(name).map(|_| (agg.name.clone(), name.to_string(), precision))
+                },
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+        if aggregates.is_empty() {
+            return None;
+        }
+        Some(ApproxDistinctScan { table, filter, aggregates, done: false, bytes_read: 0, segments_skipped: 0 })
+    }
+
+    fn local<'a>(&self, name: &'a str) -> &'a str {
+        name.strip_prefix(&format!("{}.", self.table.name)).unwrap_or(name)
+    }
+
+    fn stats(&self, column: &str) -> Option<Vec<SegmentStats>> {
+        let data_type = self.table.column(column)?.data_type;
+        Some(partition::current_stats(&self.table, column, data_type)?.into_iter().map(|(_, segment)| segment).collect())
+    }
+
+    // The stored sketches of every segment the filter keeps whole, merged, and the rows they cover.
+    // None when the stats needed to tell aren't there.
+    fn stored_sketches(&self, column: &str, precision: u8) -> Option<(HyperLogLog, Vec<Range<u64>>)> {
+        if precision > DEFAULT_PRECISION {
+            return None;
+        }
+        let segments = self.stats(column)?;
+        let total: u64 = segments.iter().map(|segment| segment.rows).sum();
+        // Row range, min and max of each segment of the filtered columns, by the name the filter uses
+        let mut filter_stats: HashMap<String, Vec<(Range<u64>, Value, Value)>> = HashMap::new();
+        for name in self.filter.iter().flat_map(|filter| filter.columns()) {
+            let filter_segments = self.stats(self.local(&name))?;
+            if filter_segments.iter().map(|segment| segment.rows).sum::<u64>() != total {
+                return None;
+            }
+            let mut start = 0;
+            let ranges = filter_segments.into_iter().map(|segment| {
+                start += segment.rows;
+                (start - segment.rows..start, segment.min, segment.max)
+            }).collect();
+            filter_stats.insert(name, ranges);
+        }
+        let mut sketch = HyperLogLog::new(precision);
+        let mut covered: Vec<Range<u64>> = Vec::new();
+        let mut start = 0;
+        for segment in &segments {
+            let range = start..start + segment.rows;
+            start = range.end;
+            let stored = match &segment.distinct {
+                Some(stored) => stored,
+                None => continue,
+            };
+            // Widest min and max over the filtered column's segments that share rows with this one
+            let range_stats = |name: &str| -> Option<(Value, Value)> {
+                let overlapping: Vec<&(Range<u64>, Value, Value)> = filter_stats.get(name)?.iter()
+                    .filter(|(other, _, _)| other.start < range.end && other.end > range.start)
+                    .collect();
+                if overlapping.iter().any(|(_, min, max)| min.is_null() || max.is_null()) {
+                    return None;
+                }
+                let min = overlapping.iter().map(|(_, min, _)| min).min()?;
+                let max = overlapping.iter().map(|(_, _, max)| max).max()?;
+                Some((min.clone(), max.clone()))
+            };
+            let keeps_all = match &self.filter {
+                Some(filter) => must_match(filter, &range_stats),
+                None => true,
+            };
+            if keeps_all && segment.rows > 0 {
+                sketch.merge(&stored.fold(precision).ok()?).ok()?;
+                covered.push(range);
+            }
+        }
+        Some((sketch, covered))
+    }
+
+    fn distinct(&mut self, column: &str, precision: u8) -> Result<Value, String> {
+        let (mut sketch, covered) = self.stored_sketches(column, precision)
+            .unwrap_or_else(|| (HyperLogLog::new(precision), Vec::new()));
+        let mut columns: Vec<String> = vec![column.to_string()];
+        for name in self.filter.iter().flat_map(|filter| filter.columns()) {
+            let name = self.local(&name).to_string();
+            if !columns.contains(&name) {
+                columns.push(name);
+            }
+        }
+        let mut scan = TableScan::skipping(&self.table.name, &columns, self.filter.clone(), covered)?;
+        while let Some(batch) = scan.next_batch()? {
+            for value in batch.column(column)? {
+                sketch.insert(value);
+            }
+        }
+        // The covered segments are among what the scan skipped
+        self.bytes_read += scan.bytes_read();
+        self.segments_skipped += scan.segments_skipped();
+        Ok(Value::UInt64(sketch.estimate()))
+    }
+}
+
+impl Operator for ApproxDistinctScan {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.done {
+            return Ok(None);
+        }
+        self.done = true;
+        let mut columns: Vec<Vec<Value>> = Vec::new();
+        for (_, column, precision) in self.aggregates.clone() {
+            columns.push(vec![self.distinct(&column, precision)?]);
+        }
+        Ok(Some(Batch::new(self.schema(), columns)))
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.aggregates.iter().map(|(name, _, _)| name.clone()).collect()
+    }
+
+    fn name(&self) -> String {
+        let aggregates: Vec<String> = self.aggregates.iter()
+            .map(|(name, column, precision)| format!("{} AS {}", Expr::Aggregate(AggregateFunction::ApproxCountDistinct(*precision), Box::new(Expr::Column(column.clone()))), name))
+            .collect();
+        match &self.filter {
+            Some(filter) => format!("ApproxDistinctScan {} [{}] filter {}", self.table.name, aggregates.join(", "), filter),
+            None => format!("ApproxDistinctScan {} [{}]", self.table.name, aggregates.join(", ")),
+        }
+    }
+
+    fn lineage(&self) -> LineageNode {
+        let mut node = LineageNode::new(self.name(), self.schema(), Vec::new());
+        for (_, column, _) in &self.aggregates {
+            node.segments.extend(column_segments(&self.table, column));
+        }
+        node
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        Some(1)
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    fn segments_skipped(&self) -> u64 {
+        self.segments_skipped
+    }
+}
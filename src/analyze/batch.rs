@@ -0,0 +1,194 @@
+use crate::datagen::dataset::DataType;
+use chrono::{DateTime, SecondsFormat, Utc};
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::fmt;
+use uuid::Uuid;
+
+// Query side values. Storage keeps typed Columns, but once data is flowing through operators it's
+// a Value per cell so expressions, group keys and joins don't need a match arm per type combo.
+// Null only shows up from things like outer joins or dividing by zero.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Int64(i64),
+    Int8(i8),
+    UInt64(u64),
+    UInt8(u8),
+    Decimal(Decimal),
+    DateTime(DateTime<Utc>),
+    String(String),
+    Uuid(Uuid),
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn data_type(&self) -> Option<DataType> {
+        match self {
+            Value::Null => None,
+            Value::Bool(_) => Some(DataType::Bool),
+            Value::Int64(_) => Some(DataType::Int64),
+            Value::Int8(_) => Some(DataType::Int8),
+            Value::UInt64(_) => Some(DataType::UInt64),
+            Value::UInt8(_) => Some(DataType::UInt8),
+            Value::Decimal(_) => Some(DataType::Decimal),
+            Value::DateTime(_) => Some(DataType::DateTime),
+            Value::String(_) => Some(DataType::String),
+            Value::Uuid(_) => Some(DataType::Uuid),
+        }
+    }
+
+    // Integer types all fit in an i128, which makes mixed comparisons and math easy
+    pub fn as_i128(&self) -> Option<i128> {
+        match self {
+            Value::Int64(val) => Some(*val as i128),
+            Value::Int8(val) => Some(*val as i128),
+            Value::UInt64(val) => Some(*val as i128),
+            Value::UInt8(val) => Some(*val as i128),
+            _ => None,
+        }
+    }
+
+    pub fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            Value::Decimal(val) => Some(*val),
+            _ => self.as_i128().and_then(|val| Decimal::try_from_i128_with_scale(val, 0).ok()),
+        }
+    }
+
+    // Rough bytes held in memory, what the memory figures are all built from
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Value>() + match self {
+            Value::String(val) => val.capacity(),
+            _ => 0,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(val) => Some(*val),
+            _ => None,
+        }
+    }
+
+    // Comparison the way a query means it: numbers compare across integer and Decimal types,
+    // anything against Null is unknown, and otherwise types have to match.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        if self.is_null() || other.is_null() {
+            return None;
+        }
+        if let (Some(left), Some(right)) = (self.as_i128(), other.as_i128()) {
+            return Some(left.cmp(&right));
+        }
+        if let (Some(left), Some(right)) = (self.as_decimal(), other.as_decimal()) {
+            return Some(left.cmp(&right));
+        }
+        if std::mem::discriminant(self) == std::mem::discriminant(other) {
+            return Some(self.cmp(other));
+        }
+        None
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Bool(val) => write!(f, "{}", val),
+            Value::Int64(val) => write!(f, "{}", val),
+            Value::Int8(val) => write!(f, "{}", val),
+            Value::UInt64(val) => write!(f, "{}", val),
+            Value::UInt8(val) => write!(f, "{}", val),
+            Value::Decimal(val) => match f.precision() {
+                Some(precision) => write!(f, "{:.*}", precision, val),
+                None => write!(f, "{}", val),
+            },
+            Value::DateTime(val) => write!(f, "{}", val.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            Value::String(val) => write!(f, "{}", val),
+            Value::Uuid(val) => write!(f, "{}", val),
+        }
+    }
+}
+
+// A set of rows stored column by column, the unit every operator passes along. Names coming from
+// scans are qualified as table.column, lookups accept either the full or the bare column name.
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    pub names: Vec<String>,
+    pub columns: Vec<Vec<Value>>,
+    // The page of a sampled table every row is from, see sample. Filters and projections keep it.
+    pub page: Option<u64>,
+}
+
+impl Batch {
+    pub fn new(names: Vec<String>, columns: Vec<Vec<Value>>) -> Batch {
+        Batch { names, columns, page: None }
+    }
+
+    pub fn num_rows(&self) -> usize {
+        self.columns.first().map(|col| col.len()).unwrap_or(0)
+    }
+
+    pub fn index_of(&self, name: &str) -> Result<usize, String> {
+        if let Some(index) = self.names.iter().position(|col_name| col_name == name) {
+            return Ok(index);
+        }
+        let suffix = format!(".{}", name);
+        let matches: Vec<usize> = self.names.iter()
+            .enumerate()
+            .filter(|(_, col_name)| col_name.ends_with(&suffix) || name.ends_with(&format!(".{}", col_name)))
+            .map(|(index, _)| index)
+            .collect();
+        match matches.len() {
+            1 => Ok(matches[0]),
+            0 => Err(format!("Column with name {:?} not in batch {:?}", name, self.names)),
+            _ => Err(format!("Column name {:?} is ambiguous in {:?}", name, self.names)),
+        }
+    }
+
+    pub fn column(&self, name: &str) -> Result<&Vec<Value>, String> {
+        Ok(&self.columns[self.index_of(name)?])
+    }
+
+    pub fn empty_like(&self) -> Batch {
+        Batch::new(self.names.clone(), vec![Vec::new(); self.names.len()])
+    }
+
+    pub fn filter(&self, mask: &[bool]) -> Batch {
+        let columns = self.columns.iter()
+            .map(|col| col.iter().zip(mask).filter(|(_, keep)| **keep).map(|(val, _)| val.clone()).collect())
+            .collect();
+        Batch { page: self.page, ..Batch::new(self.names.clone(), columns) }
+    }
+
+    pub fn size(&self) -> usize {
+        self.columns.iter().map(|col| col.iter().map(|val| val.size()).sum::<usize>()).sum()
+    }
+
+    pub fn append(&mut self, other: Batch) {
+        for (col, other_col) in self.columns.iter_mut().zip(other.columns) {
+            col.extend(other_col);
+        }
+    }
+
+    pub fn print(&self) {
+        let rendered: Vec<Vec<String>> = self.columns.iter()
+            .map(|col| col.iter().map(|val| val.to_string()).collect())
+            .collect();
+        let widths: Vec<usize> = self.names.iter()
+            .zip(&rendered)
+            .map(|(name, col)| col.iter().map(|val| val.len()).chain([name.len()]).max().unwrap_or(0))
+            .collect();
+        let header: Vec<String> = self.names.iter().zip(&widths).map(|(name, width)| format!("{:width$}", name, width = width)).collect();
+        println!("{}", header.join(" | "));
+        println!("{}", widths.iter().map(|width| "-".repeat(*width)).collect::<Vec<String>>().join("-+-"));
+        for row in 0..self.num_rows() {
+            let line: Vec<String> = rendered.iter().zip(&widths).map(|(col, width)| format!("{:width$}", col[row], width = width)).collect();
+            println!("{}", line.join(" | "));
+        }
+    }
+}
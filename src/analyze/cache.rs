@@ -0,0 +1,308 @@
+use crate::datagen::catalog::KeyOrder;
+use crate::datagen::constants::DATA_DIRECTORY;
+use super::batch::{Batch, Value};
+use super::lineage::LineageNode;
+use super::operator::{Operator, BATCH_ROWS};
+use super::spill::{read_value, write_value};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+// Results of queries and the aggregates inside them, kept under _cache/ so running the same thing
+// over the same data again reads them back instead. An entry's key is the lineage id of what made
+// it, which covers the operators with all their arguments and the size of every segment file they
+// read, so anything written since makes a different key. Entries also say which tables they read
+// and get thrown out as soon as one of those is written to, rather than sitting there until
+// they're evicted. The least recently used ones go once the cache is over its size limit.
+
+pub const DEFAULT_CACHE_LIMIT: usize = 256 << 20;
+
+// 0 turns the cache off
+static CACHE_LIMIT: AtomicUsize = AtomicUsize::new(DEFAULT_CACHE_LIMIT);
+
+pub fn cache_limit() -> usize {
+    CACHE_LIMIT.load(Ordering::Relaxed)
+}
+
+pub fn set_cache_limit(bytes: usize) {
+    CACHE_LIMIT.store(bytes, Ordering::Relaxed);
+}
+
+fn cache_directory() -> String {
+    DATA_DIRECTORY.to_owned() + "_cache/"
+}
+
+// Which tables a result read, going by where its segment files are
+fn tables_read(lineage: &LineageNode, tables: &mut Vec<String>) {
+    for segment in &lineage.segments {
+        let table = segment.path.strip_prefix(DATA_DIRECTORY).and_then(|path| path.split('/').next());
+        if let Some(table) = table.filter(|table| !tables.iter().any(|known| known == table)) {
+            tables.push(table.to_string());
+        }
+    }
+    for child in &lineage.children {
+        tables_read(child, tables);
+    }
+}
+
+// Entries are the tables read joined with commas, the column count and names, then every row's
+// values one after the other, all in the spill format
+fn read_header(reader: &mut impl std::io::Read, path: &str) -> Result<(Vec<String>, Vec<String>), String> {
+    let bad = || format!("Cache entry {} is cut short", path);
+    let tables = match read_value(reader)? {
+        Some(Value::String(tables)) => tables.split(',').filter(|table| !table.is_empty()).map(|table| table.to_string()).collect(),
+        _ => return Err(bad()),
+    };
+    let count = match read_value(reader)? {
+        Some(Value::UInt64(count)) => count,
+        _ => return Err(bad()),
+    };
+    let mut names: Vec<String> = Vec::new();
+    for _ in 0..count {
+        match read_value(reader)? {
+            Some(Value::String(name)) => names.push(name),
+            _ => return Err(bad()),
+        }
+    }
+    Ok((tables, names))
+}
+
+// Entry files with their sizes and when they were last used, in that order
+fn entries() -> Vec<(String, u64, SystemTime)> {
+    let mut entries: Vec<(String, u64, SystemTime)> = match fs::read_dir(cache_directory()) {
+        Ok(dir) => dir.filter_map(|entry| entry.ok())
+            .filter(|entry| !entry.file_name().to_string_lossy().contains('.'))
+            .filter_map(|entry| {
+                let meta = entry.metadata().ok()?;
+                Some((entry.path().to_string_lossy().to_string(), meta.len(), meta.modified().ok()?))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    entries.sort_by_key(|(_, _, used)| *used);
+    entries
+}
+
+// Throws out every entry that read the table, Table::write_data calls it after appending
+pub fn invalidate_table(table: &str) {
+    for (path, _, _) in entries() {
+        let reads_table = File::open(&path).ok()
+            .and_then(|file| read_header(&mut BufReader::new(file), &path).ok())
+            .is_none_or(|(tables, _)| tables.iter().any(|read| read == table));
+        if reads_table {
+            let _ = fs::remove_file(&path);
+        }
+    }
+}
+
+// Oldest used first until everything fits
+fn evict() {
+    let mut entries = entries();
+    let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+    entries.reverse();
+    while total > cache_limit() as u64 {
+        match entries.pop() {
+            Some((path, size, _)) => {
+                let _ = fs::remove_file(&path);
+                total -= size;
+            },
+            None => break,
+        }
+    }
+}
+
+enum State {
+    // Nothing's been asked for yet
+    Unopened,
+    Hit(BufReader<File>),
+    // Passing the input's batches on and writing them to the temporary file as they go
+    Recording(BufWriter<File>, String, usize),
+    // Too big to keep, or it couldn't be written
+    Passing,
+}
+
+// Reads a result back when there's one for this exact input, otherwise runs the input and keeps
+// what comes out. Lineage is the input's, a result read back came from the same place.
+pub struct ResultCache {
+    input: Box<dyn Operator>,
+    key: String,
+    lineage: LineageNode,
+    names: Vec<String>,
+    state: State,
+    bytes_read: u64,
+}
+
+impl ResultCache {
+    // The input as it is when the cache is off or it wouldn't give the same rows again
+    pub fn wrap(input: Box<dyn Operator>) -> Box<dyn Operator> {
+        if cache_limit() == 0 || input.schema().is_empty() || !input.repeatable() {
+            return input;
+        }
+        let lineage = input.lineage();
+        Box::new(ResultCache { key: lineage.id(), lineage, names: input.schema(), input, state: State::Unopened, bytes_read: 0 })
+    }
+
+    fn path(&self) -> String {
+        cache_directory() + &self.key
+    }
+
+    fn open(&mut self) -> State {
+        if let Ok(file) = File::open(self.path()) {
+            let mut reader = BufReader::new(file);
+            if read_header(&mut reader, &self.path()).is_ok_and(|(_, names)| names == self.names) {
+                // Touched so eviction goes by when it was last used
+                let _ = File::options().write(true).open(self.path()).and_then(|file| file.set_modified(SystemTime::now()));
+                return State::Hit(reader);
+            }
+        }
+        let temporary = format!("{}.{}", self.path(), std::process::id());
+        let created = fs::create_dir_all(cache_directory()).and_then(|_| File::create(&temporary));
+        let mut writer = match created {
+            Ok(file) => BufWriter::new(file),
+            Err(_) => return State::Passing,
+        };
+        let mut tables: Vec<String> = Vec::new();
+        tables_read(&self.lineage, &mut tables);
+        let header = [Value::String(tables.join(",")), Value::UInt64(self.names.len() as u64)].into_iter()
+            .chain(self.names.iter().map(|name| Value::String(name.clone())));
+        for value in header {
+            if write_value(&mut writer, &value).is_err() {
+                let _ = fs::remove_file(&temporary);
+                return State::Passing;
+            }
+        }
+        State::Recording(writer, temporary, 0)
+    }
+
+    fn replay(&mut self) -> Result<Option<Batch>, String> {
+        let path = self.path();
+        let reader = match &mut self.state {
+            State::Hit(reader) => reader,
+            _ => return Ok(None),
+        };
+        let mut columns: Vec<Vec<Value>> = vec![Vec::new(); self.names.len()];
+        let mut rows = 0;
+        while rows < BATCH_ROWS {
+            for (index, column) in columns.iter_mut().enumerate() {
+                match read_value(reader)? {
+                    Some(value) => {
+                        self.bytes_read += value.size() as u64;
+                        column.push(value);
+                    },
+                    None if index == 0 => break,
+                    None => return Err(format!("Cache entry {} is cut short", path)),
+                }
+            }
+            if columns[0].len() == rows {
+                break;
+            }
+            rows += 1;
+        }
+        match rows {
+            0 => Ok(None),
+            _ => Ok(Some(Batch::new(self.names.clone(), columns))),
+        }
+    }
+
+    // Writes a batch into the entry, giving up on it once it'd take more than a quarter of the
+    // cache, so one big result can't push out everything else
+    fn record(&mut self, batch: &Batch) {
+        let give_up = match &mut self.state {
+            State::Recording(writer, _, bytes) => {
+                *bytes += batch.size();
+                *bytes > cache_limit() / 4 || (0..batch.num_rows())
+                    .flat_map(|row| batch.columns.iter().map(move |column| &column[row]))
+                    .any(|value| write_value(writer, value).is_err())
+            },
+            _ => false,
+        };
+        if give_up {
+            self.abandon();
+        }
+    }
+
+    fn abandon(&mut self) {
+        if let State::Recording(_, temporary, _) = std::mem::replace(&mut self.state, State::Passing) {
+            let _ = fs::remove_file(temporary);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let State::Recording(mut writer, temporary, _) = std::mem::replace(&mut self.state, State::Passing) {
+            match writer.flush().and_then(|_| fs::rename(&temporary, self.path())) {
+                Ok(()) => evict(),
+                Err(_) => {
+                    let _ = fs::remove_file(temporary);
+                },
+            }
+        }
+    }
+}
+
+impl Operator for ResultCache {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if let State::Unopened = self.state {
+            self.state = self.open();
+        }
+        if let State::Hit(_) = self.state {
+            return self.replay();
+        }
+        match self.input.next_batch() {
+            Ok(Some(batch)) => {
+                self.record(&batch);
+                Ok(Some(batch))
+            },
+            Ok(None) => {
+                self.finish();
+                Ok(None)
+            },
+            Err(err) => {
+                self.abandon();
+                Err(err)
+            },
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    fn name(&self) -> String {
+        match self.state {
+            State::Hit(_) => format!("ResultCache {} (hit)", self.key),
+            _ => format!("ResultCache {}", self.key),
+        }
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn lineage(&self) -> LineageNode {
+        self.lineage.clone()
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        self.input.estimated_rows()
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        self.input.orderings()
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+// Whatever didn't get read to the end isn't a whole result
+impl Drop for ResultCache {
+    fn drop(&mut self) {
+        self.abandon();
+    }
+}
@@ -0,0 +1,213 @@
+use chrono::{DateTime, Datelike, Duration, FixedOffset, LocalResult, Months, NaiveDate, NaiveDateTime, Offset, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+// Calendar buckets for timestamps: date_trunc to the start of its second, minute, hour, day, week,
+// month, quarter or year, and date_bin to strides like '15 minutes' or '2 weeks' counted from an
+// origin. Both go by the wall clock of an IANA time zone, so a month in America/New_York starts at
+// its local midnight whatever UTC says. Days around DST changes are 23 or 25 hours long, and the
+// hour the clocks go back over gets two buckets, one for each offset.
+
+// Where date_bin counts from unless it's told otherwise, a Monday at midnight like Postgres uses
+pub fn default_origin() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2001, 1, 1).and_then(|day| day.and_hms_opt(0, 0, 0)).unwrap()
+}
+
+pub fn time_zone(name: &str) -> Result<Tz, String> {
+    name.parse::<Tz>().map_err(|_| format!("Unknown time zone {:?}, expected an IANA name like Europe/Berlin", name))
+}
+
+pub fn weekday(name: &str) -> Result<Weekday, String> {
+    name.parse::<Weekday>().map_err(|_| format!("Unknown weekday {:?}", name))
+}
+
+// The instant a wall clock time in the zone stands for. When the clocks pass it twice it's the
+// one with `offset` if that's one of them, otherwise the earlier. When the clocks skip over it,
+// it's the moment they jump, which is where anything starting then really starts.
+fn resolve(zone: Tz, local: NaiveDateTime, offset: Option<FixedOffset>) -> Result<DateTime<Utc>, String> {
+    match zone.from_local_datetime(&local) {
+        LocalResult::Single(datetime) => Ok(datetime.with_timezone(&Utc)),
+        LocalResult::Ambiguous(earlier, later) => match Some(later.offset().fix()) == offset {
+            true => Ok(later.with_timezone(&Utc)),
+            false => Ok(earlier.with_timezone(&Utc)),
+        },
+        LocalResult::None => skipped_to(zone, local),
+    }
+}
+
+// Searches between the wall clock read with the offset from after the jump and from before it
+fn skipped_to(zone: Tz, local: NaiveDateTime) -> Result<DateTime<Utc>, String> {
+    let offsets = [-2, 2].map(|days| zone.offset_from_utc_datetime(&(local + Duration::days(days))).fix().local_minus_utc() as i64);
+    let as_utc = Utc.from_utc_datetime(&local).timestamp();
+    let (mut low, mut high) = (as_utc - offsets[0].max(offsets[1]), as_utc - offsets[0].min(offsets[1]));
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match zone.timestamp_opt(middle, 0).single().map(|datetime| datetime.naive_local() < local) {
+            Some(true) => low = middle + 1,
+            _ => high = middle,
+        }
+    }
+    Utc.timestamp_opt(low, 0).single().ok_or(format!("Can't find {} in {}", local, zone))
+}
+
+pub fn date_trunc(unit: &str, datetime: &DateTime<Utc>, zone: Tz, week_start: Weekday) -> Result<DateTime<Utc>, String> {
+    let local = datetime.with_timezone(&zone);
+    let date = local.date_naive();
+    let truncated = match unit {
+        "second" => date.and_hms_opt(local.hour(), local.minute(), local.second()),
+        "minute" => date.and_hms_opt(local.hour(), local.minute(), 0),
+        "hour" => date.and_hms_opt(local.hour(), 0, 0),
+        "day" => date.and_hms_opt(0, 0, 0),
+        "week" => {
+            let days = (date.weekday().num_days_from_monday() + 7 - week_start.num_days_from_monday()) % 7;
+            (date - Duration::days(days as i64)).and_hms_opt(0, 0, 0)
+        },
+        "month" => date.with_day(1).and_then(|day| day.and_hms_opt(0, 0, 0)),
+        "quarter" => NaiveDate::from_ymd_opt(date.year(), (date.month0() / 3) * 3 + 1, 1).and_then(|day| day.and_hms_opt(0, 0, 0)),
+        "year" => NaiveDate::from_ymd_opt(date.year(), 1, 1).and_then(|day| day.and_hms_opt(0, 0, 0)),
+        _ => return Err(format!("Unknown date_trunc unit {:?}", unit)),
+    };
+    let truncated = truncated.ok_or(format!("Can't truncate {} to {}", datetime, unit))?;
+    // Anything under a day stays on the offset it was in, so the repeated hour splits in two
+    let offset = match unit {
+        "second" | "minute" | "hour" => Some(local.offset().fix()),
+        _ => None,
+    };
+    resolve(zone, truncated, offset)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stride {
+    Millis(i64),
+    Months(u32),
+}
+
+// '15 minutes', '1 hour', '2 weeks', 'quarter' and so on
+pub fn parse_stride(text: &str) -> Result<Stride, String> {
+    let bad = || format!("Bad date_bin stride {:?}, expected something like '15 minutes' or '1 month'", text);
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    let (count, unit) = match parts[..] {
+        [unit] => (1, unit),
+        [count, unit] => (count.parse::<u32>().map_err(|_| bad())?, unit),
+        _ => return Err(bad()),
+    };
+    if count == 0 {
+        return Err(bad());
+    }
+    let millis = |each: i64| Ok(Stride::Millis(count as i64 * each));
+    match unit.to_lowercase().trim_end_matches('s') {
+        "second" => millis(1_000),
+        "minute" => millis(60_000),
+        "hour" => millis(3_600_000),
+        "day" => millis(86_400_000),
+        "week" => millis(7 * 86_400_000),
+        "month" => Ok(Stride::Months(count)),
+        "quarter" => Ok(Stride::Months(count * 3)),
+        "year" => Ok(Stride::Months(count * 12)),
+        _ => Err(bad()),
+    }
+}
+
+// Start of the stride the datetime falls in, counting strides of wall clock time from `origin` in
+// the zone. Weekly strides start on whatever weekday the origin is.
+pub fn date_bin(stride: Stride, datetime: &DateTime<Utc>, zone: Tz, origin: NaiveDateTime) -> Result<DateTime<Utc>, String> {
+    let local = datetime.with_timezone(&zone);
+    let naive = local.naive_local();
+    let failed = || format!("Can't bin {} by {:?}", datetime, stride);
+    let (start, offset) = match stride {
+        Stride::Millis(millis) => {
+            let strides = (naive - origin).num_milliseconds().div_euclid(millis);
+            let start = origin.checked_add_signed(Duration::milliseconds(strides * millis)).ok_or_else(failed)?;
+            (start, Some(local.offset().fix()).filter(|_| millis < 86_400_000))
+        },
+        Stride::Months(months) => {
+            let between = (naive.year() - origin.year()) * 12 + naive.month() as i32 - origin.month() as i32;
+            let step = |strides: i32| match strides >= 0 {
+                true => origin.checked_add_months(Months::new(strides as u32 * months)),
+                false => origin.checked_sub_months(Months::new(strides.unsigned_abs() * months)),
+            };
+            let mut strides = between.div_euclid(months as i32);
+            // The origin's day and time of day can put the start after the datetime
+            while step(strides).ok_or_else(failed)? > naive {
+                strides -= 1;
+            }
+            (step(strides).ok_or_else(failed)?, None)
+        },
+    };
+    resolve(zone, start, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(text: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(text).unwrap().with_timezone(&Utc)
+    }
+
+    fn trunc(unit: &str, at: &str, zone: &str) -> DateTime<Utc> {
+        date_trunc(unit, &utc(at), time_zone(zone).unwrap(), Weekday::Mon).unwrap()
+    }
+
+    fn bin(stride: &str, at: &str, zone: &str) -> DateTime<Utc> {
+        date_bin(parse_stride(stride).unwrap(), &utc(at), time_zone(zone).unwrap(), default_origin()).unwrap()
+    }
+
+    // New York springs forward at 02:00 on 2026-03-08 and falls back at 02:00 on 2026-11-01
+    #[test]
+    fn new_york() {
+        let zone = "America/New_York";
+        // The spring day is 23 hours, the fall one 25
+        assert_eq!(trunc("day", "2026-03-08T16:00:00Z", zone), utc("2026-03-08T00:00:00-05:00"));
+        assert_eq!(trunc("day", "2026-03-09T16:00:00Z", zone) - trunc("day", "2026-03-08T16:00:00Z", zone), Duration::hours(23));
+        assert_eq!(trunc("day", "2026-11-02T16:00:00Z", zone) - trunc("day", "2026-11-01T16:00:00Z", zone), Duration::hours(25));
+        assert_eq!(trunc("hour", "2026-03-08T03:30:00-04:00", zone), utc("2026-03-08T03:00:00-04:00"));
+        // 01:30 happens twice on the way back, and each gets its own hour
+        assert_eq!(trunc("hour", "2026-11-01T01:30:00-04:00", zone), utc("2026-11-01T01:00:00-04:00"));
+        assert_eq!(trunc("hour", "2026-11-01T01:30:00-05:00", zone), utc("2026-11-01T01:00:00-05:00"));
+        assert_eq!(trunc("day", "2026-11-01T01:30:00-05:00", zone), utc("2026-11-01T00:00:00-04:00"));
+        assert_eq!(trunc("month", "2026-11-01T01:30:00-05:00", zone), utc("2026-11-01T00:00:00-04:00"));
+
+        assert_eq!(bin("1 hour", "2026-11-01T01:30:00-04:00", zone), utc("2026-11-01T01:00:00-04:00"));
+        assert_eq!(bin("1 hour", "2026-11-01T01:30:00-05:00", zone), utc("2026-11-01T01:00:00-05:00"));
+        assert_eq!(bin("15 minutes", "2026-03-08T03:10:00-04:00", zone), utc("2026-03-08T03:00:00-04:00"));
+        // Two hour bins from midnight put one at 02:00, which the clocks skip, so it starts at the jump
+        assert_eq!(bin("2 hours", "2026-03-08T03:30:00-04:00", zone), utc("2026-03-08T03:00:00-04:00"));
+        assert_eq!(bin("1 day", "2026-11-01T23:00:00-05:00", zone), utc("2026-11-01T00:00:00-04:00"));
+    }
+
+    // Santiago changes at midnight: it goes back from 00:00 to 23:00 on 2026-04-05, and on
+    // 2026-09-06 jumps from 00:00 straight to 01:00, so that day has no midnight
+    #[test]
+    fn santiago() {
+        let zone = "America/Santiago";
+        assert_eq!(trunc("day", "2026-09-06T12:00:00-03:00", zone), utc("2026-09-06T01:00:00-03:00"));
+        assert_eq!(trunc("day", "2026-09-07T12:00:00-03:00", zone) - trunc("day", "2026-09-06T12:00:00-03:00", zone), Duration::hours(23));
+        assert_eq!(trunc("hour", "2026-09-06T01:20:00-03:00", zone), utc("2026-09-06T01:00:00-03:00"));
+        // Sunday weeks start on the day that's missing its midnight
+        let sunday = date_trunc("week", &utc("2026-09-09T12:00:00-03:00"), time_zone(zone).unwrap(), Weekday::Sun).unwrap();
+        assert_eq!(sunday, utc("2026-09-06T01:00:00-03:00"));
+        assert_eq!(bin("1 day", "2026-09-06T12:00:00-03:00", zone), utc("2026-09-06T01:00:00-03:00"));
+        assert_eq!(bin("1 week", "2026-09-06T12:00:00-03:00", zone), utc("2026-08-31T00:00:00-04:00"));
+
+        // The hour before midnight on 2026-04-04 comes around twice, both times still on the 4th
+        assert_eq!(trunc("day", "2026-04-04T23:30:00-03:00", zone), utc("2026-04-04T00:00:00-03:00"));
+        assert_eq!(trunc("day", "2026-04-04T23:30:00-04:00", zone), utc("2026-04-04T00:00:00-03:00"));
+        assert_eq!(trunc("hour", "2026-04-04T23:30:00-03:00", zone), utc("2026-04-04T23:00:00-03:00"));
+        assert_eq!(trunc("hour", "2026-04-04T23:30:00-04:00", zone), utc("2026-04-04T23:00:00-04:00"));
+        assert_eq!(trunc("day", "2026-04-05T12:00:00-04:00", zone) - trunc("day", "2026-04-04T12:00:00-03:00", zone), Duration::hours(25));
+        assert_eq!(trunc("month", "2026-04-04T23:30:00-04:00", zone), utc("2026-04-01T00:00:00-03:00"));
+        assert_eq!(bin("30 minutes", "2026-04-04T23:40:00-04:00", zone), utc("2026-04-04T23:30:00-04:00"));
+    }
+
+    #[test]
+    fn strides() {
+        assert_eq!(parse_stride("15 minutes"), Ok(Stride::Millis(15 * 60_000)));
+        assert_eq!(parse_stride("quarter"), Ok(Stride::Months(3)));
+        assert_eq!(parse_stride("2 Years"), Ok(Stride::Months(24)));
+        assert!(parse_stride("0 days").is_err());
+        assert!(parse_stride("3 fortnights").is_err());
+        // Months go by the calendar, not a fixed length
+        assert_eq!(bin("1 month", "2026-03-31T12:00:00Z", "UTC"), utc("2026-03-01T00:00:00Z"));
+        assert_eq!(bin("quarter", "2026-08-15T00:00:00Z", "UTC"), utc("2026-07-01T00:00:00Z"));
+    }
+}
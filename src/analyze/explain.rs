@@ -0,0 +1,181 @@
+use super::batch::Batch;
+use super::lineage::LineageNode;
+use super::operator::{BatchSource, Operator};
+use crate::datagen::catalog::KeyOrder;
+use cpu_time::ThreadTime;
+use std::time::{Duration, Instant};
+
+// EXPLAIN prints the operator tree as it's about to run. EXPLAIN ANALYZE wraps every operator in
+// the tree in a Metered, runs it, and prints what each one did. Times are measured around each
+// next_batch call, so they include the inputs, and the printout takes the children back out.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExplainMode {
+    Plan,
+    Analyze,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct OperatorMetrics {
+    pub rows_out: u64,
+    pub batches: u64,
+    pub wall: Duration,
+    pub cpu: Duration,
+    pub peak_memory: usize,
+}
+
+impl OperatorMetrics {
+    // Pulls one batch out of an operator, timing it and noting what it's holding onto after
+    fn pull(&mut self, operator: &mut dyn Operator) -> Result<Option<Batch>, String> {
+        let (wall_start, cpu_start) = (Instant::now(), ThreadTime::now());
+        let batch = operator.next_batch()?;
+        self.wall += wall_start.elapsed();
+        self.cpu += cpu_start.elapsed();
+        let batch_size = match &batch {
+            Some(batch) => {
+                self.rows_out += batch.num_rows() as u64;
+                self.batches += 1;
+                batch.size()
+            },
+            None => 0,
+        };
+        self.peak_memory = self.peak_memory.max(operator.memory_bytes() + batch_size);
+        Ok(batch)
+    }
+}
+
+pub struct Metered {
+    inner: Box<dyn Operator>,
+    metrics: OperatorMetrics,
+}
+
+impl Operator for Metered {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        self.metrics.pull(self.inner.as_mut())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.inner.schema()
+    }
+
+    fn name(&self) -> String {
+        self.inner.name()
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        self.inner.children()
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        self.inner.inputs_mut()
+    }
+
+    fn lineage(&self) -> LineageNode {
+        self.inner.lineage()
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        self.inner.estimated_rows()
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        self.inner.orderings()
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.inner.bytes_read()
+    }
+
+    fn segments_skipped(&self) -> u64 {
+        self.inner.segments_skipped()
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.inner.memory_bytes()
+    }
+
+    fn bytes_spilled(&self) -> u64 {
+        self.inner.bytes_spilled()
+    }
+
+    fn metrics(&self) -> Option<OperatorMetrics> {
+        Some(self.metrics.clone())
+    }
+}
+
+// Wraps everything under an operator (but not the operator itself, whoever drives it does that)
+pub fn meter_inputs(operator: &mut dyn Operator) {
+    for input in operator.inputs_mut() {
+        meter_inputs(input.as_mut());
+        let inner = std::mem::replace(input, Box::new(BatchSource::new(Vec::new())));
+        *input = Box::new(Metered { inner, metrics: OperatorMetrics::default() });
+    }
+}
+
+pub fn explain(operator: &dyn Operator) -> String {
+    let mut text = String::new();
+    explain_node(operator, 0, &mut text);
+    text
+}
+
+fn explain_node(operator: &dyn Operator, depth: usize, text: &mut String) {
+    let estimate = match operator.estimated_rows() {
+        Some(rows) => format!(" (~{} rows)", rows),
+        None => String::new(),
+    };
+    *text += &format!("{}{}{}\n", "  ".repeat(depth), operator.name(), estimate);
+    for child in operator.children() {
+        explain_node(child, depth + 1, text);
+    }
+}
+
+// Runs the operator to the end for EXPLAIN ANALYZE, handing back its output and the report
+pub fn explain_analyze(operator: &mut dyn Operator) -> Result<(Batch, String), String> {
+    meter_inputs(operator);
+    let mut metrics = OperatorMetrics::default();
+    let mut result: Option<Batch> = None;
+    while let Some(batch) = metrics.pull(operator)? {
+        match result.as_mut() {
+            Some(existing) => existing.append(batch),
+            None => result = Some(batch),
+        }
+    }
+    let mut text = String::new();
+    analyze_node(operator, &metrics, 0, &mut text);
+    text += &format!("Total: {} rows in {}, {} cpu\n", metrics.rows_out, duration(metrics.wall), duration(metrics.cpu));
+    Ok((result.unwrap_or_default(), text))
+}
+
+fn analyze_node(operator: &dyn Operator, metrics: &OperatorMetrics, depth: usize, text: &mut String) {
+    let indent = "  ".repeat(depth);
+    let children: Vec<(&dyn Operator, OperatorMetrics)> = operator.children().into_iter()
+        .map(|child| (child, child.metrics().unwrap_or_default()))
+        .collect();
+    let rows_in: u64 = children.iter().map(|(_, child)| child.rows_out).sum();
+    // Own time is whatever wasn't spent waiting on the inputs
+    let wall = children.iter().fold(metrics.wall, |wall, (_, child)| wall.saturating_sub(child.wall));
+    let cpu = children.iter().fold(metrics.cpu, |cpu, (_, child)| cpu.saturating_sub(child.cpu));
+    let spilled = match operator.bytes_spilled() {
+        0 => String::new(),
+        spilled => format!(", spilled {}", bytes(spilled as usize)),
+    };
+    *text += &format!("{}{}\n", indent, operator.name());
+    *text += &format!("{}  rows in {}, out {} | read {}, {} segments skipped | wall {}, cpu {} | peak memory {}{}\n",
+        indent, rows_in, metrics.rows_out, bytes(operator.bytes_read() as usize), operator.segments_skipped(),
+        duration(wall), duration(cpu), bytes(metrics.peak_memory), spilled);
+    for (child, child_metrics) in &children {
+        analyze_node(*child, child_metrics, depth + 1, text);
+    }
+}
+
+fn duration(duration: Duration) -> String {
+    format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+fn bytes(bytes: usize) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
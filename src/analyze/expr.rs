@@ -0,0 +1,476 @@
+use crate::datagen::dataset::DataType;
+use super::aggregate::AggregateFunction;
+use super::batch::{Batch, Value};
+use super::calendar::{self, default_origin, parse_stride, time_zone, weekday};
+use super::hll::DEFAULT_PRECISION;
+use super::sort::SortKey;
+use super::window::WindowExpr;
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+// Expression trees that get evaluated against a whole Batch at once, giving back one Value per
+// row. Filters are just expressions that come out Bool, projections are a list of named ones.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScalarFunction {
+    // date_trunc('month', created[, 'Europe/Berlin'[, 'sunday']]), UTC and Monday weeks by default
+    DateTrunc,
+    // date_bin('15 minutes', created[, 'Europe/Berlin'[, origin]]), see calendar
+    DateBin,
+}
+
+impl ScalarFunction {
+    pub fn from_name(name: &str) -> Option<ScalarFunction> {
+        match name.to_lowercase().as_str() {
+            "date_trunc" => Some(ScalarFunction::DateTrunc),
+            "date_bin" => Some(ScalarFunction::DateBin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Column(String),
+    Literal(Value),
+    Compare(CompareOp, Box<Expr>, Box<Expr>),
+    Arithmetic(ArithmeticOp, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    IsNull(Box<Expr>),
+    Cast(Box<Expr>, DataType),
+    Call(ScalarFunction, Vec<Expr>),
+    // Only meaningful as the top of an aggregate list, evaluating one directly is an error
+    Aggregate(AggregateFunction, Box<Expr>),
+    // Same, only a Window operator can compute these
+    Window(Box<WindowExpr>),
+}
+
+pub fn col(name: &str) -> Expr {
+    Expr::Column(name.to_string())
+}
+
+pub fn lit(value: Value) -> Expr {
+    Expr::Literal(value)
+}
+
+impl Expr {
+    pub fn compare(self, op: CompareOp, other: Expr) -> Expr {
+        Expr::Compare(op, Box::new(self), Box::new(other))
+    }
+
+    pub fn arithmetic(self, op: ArithmeticOp, other: Expr) -> Expr {
+        Expr::Arithmetic(op, Box::new(self), Box::new(other))
+    }
+
+    pub fn and(self, other: Expr) -> Expr {
+        Expr::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Expr) -> Expr {
+        Expr::Or(Box::new(self), Box::new(other))
+    }
+
+    pub fn cast(self, data_type: DataType) -> Expr {
+        Expr::Cast(Box::new(self), data_type)
+    }
+
+    // Copy of the expression with nodes swapped out, replace gets a look at each node before its
+    // children and anything it hands back is used as is
+    pub fn rewrite(&self, replace: &mut dyn FnMut(&Expr) -> Option<Expr>) -> Expr {
+        if let Some(replaced) = replace(self) {
+            return replaced;
+        }
+        let mut boxed = |expr: &Expr| Box::new(expr.rewrite(replace));
+        match self {
+            Expr::Column(_) | Expr::Literal(_) => self.clone(),
+            Expr::Compare(op, left, right) => Expr::Compare(*op, boxed(left), boxed(right)),
+            Expr::Arithmetic(op, left, right) => Expr::Arithmetic(*op, boxed(left), boxed(right)),
+            Expr::And(left, right) => Expr::And(boxed(left), boxed(right)),
+            Expr::Or(left, right) => Expr::Or(boxed(left), boxed(right)),
+            Expr::Not(inner) => Expr::Not(boxed(inner)),
+            Expr::IsNull(inner) => Expr::IsNull(boxed(inner)),
+            Expr::Cast(inner, data_type) => Expr::Cast(boxed(inner), *data_type),
+            Expr::Call(function, args) => Expr::Call(*function, args.iter().map(|arg| *boxed(arg)).collect()),
+            Expr::Aggregate(function, inner) => Expr::Aggregate(*function, boxed(inner)),
+            Expr::Window(window) => {
+                let mut rewritten = window.as_ref().clone();
+                rewritten.args = window.args.iter().map(|arg| *boxed(arg)).collect();
+                rewritten.partition_by = window.partition_by.iter().map(|expr| *boxed(expr)).collect();
+                rewritten.order_by = window.order_by.iter().map(|key| SortKey::new(*boxed(&key.expr), key.descending)).collect();
+                Expr::Window(Box::new(rewritten))
+            },
+        }
+    }
+
+    pub fn contains_aggregate(&self) -> bool {
+        let mut found = false;
+        self.rewrite(&mut |expr| match expr {
+            Expr::Aggregate(_, _) => {
+                found = true;
+                Some(expr.clone())
+            },
+            _ => None,
+        });
+        found
+    }
+
+    pub fn contains_window(&self) -> bool {
+        let mut found = false;
+        self.rewrite(&mut |expr| match expr {
+            Expr::Window(_) => {
+                found = true;
+                Some(expr.clone())
+            },
+            _ => None,
+        });
+        found
+    }
+
+    // Every column name the expression reads, repeats included
+    pub fn columns(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        self.rewrite(&mut |expr| match expr {
+            Expr::Column(name) => {
+                names.push(name.clone());
+                Some(expr.clone())
+            },
+            _ => None,
+        });
+        names
+    }
+
+    // Guess at the fraction of rows the predicate keeps, for row estimates. Nothing looks at the
+    // data, these are the usual textbook constants.
+    pub fn selectivity(&self) -> f64 {
+        match self {
+            Expr::Literal(Value::Bool(keep)) => if *keep { 1.0 } else { 0.0 },
+            Expr::Literal(Value::Null) => 0.0,
+            Expr::Compare(CompareOp::Eq, _, _) => 0.1,
+            Expr::Compare(CompareOp::NotEq, _, _) => 0.9,
+            Expr::Compare(_, _, _) => 1.0 / 3.0,
+            Expr::And(left, right) => left.selectivity() * right.selectivity(),
+            Expr::Or(left, right) => {
+                let (left, right) = (left.selectivity(), right.selectivity());
+                left + right - left * right
+            },
+            Expr::Not(inner) => 1.0 - inner.selectivity(),
+            Expr::IsNull(_) => 0.05,
+            _ => 0.5,
+        }
+    }
+
+    pub fn eval(&self, batch: &Batch) -> Result<Vec<Value>, String> {
+        let rows = batch.num_rows();
+        match self {
+            Expr::Column(name) => Ok(batch.column(name)?.clone()),
+            Expr::Literal(value) => Ok(vec![value.clone(); rows]),
+            Expr::Compare(op, left, right) => {
+                let (left, right) = (left.eval(batch)?, right.eval(batch)?);
+                Ok(left.iter().zip(&right).map(|(l, r)| compare_values(*op, l, r)).collect())
+            },
+            Expr::Arithmetic(op, left, right) => {
+                let (left, right) = (left.eval(batch)?, right.eval(batch)?);
+                left.iter().zip(&right).map(|(l, r)| arithmetic(*op, l, r)).collect()
+            },
+            Expr::And(left, right) => {
+                let (left, right) = (left.eval(batch)?, right.eval(batch)?);
+                Ok(left.iter().zip(&right).map(|(l, r)| match (l.as_bool(), r.as_bool()) {
+                    (Some(false), _) | (_, Some(false)) => Value::Bool(false),
+                    (Some(true), Some(true)) => Value::Bool(true),
+                    _ => Value::Null,
+                }).collect())
+            },
+            Expr::Or(left, right) => {
+                let (left, right) = (left.eval(batch)?, right.eval(batch)?);
+                Ok(left.iter().zip(&right).map(|(l, r)| match (l.as_bool(), r.as_bool()) {
+                    (Some(true), _) | (_, Some(true)) => Value::Bool(true),
+                    (Some(false), Some(false)) => Value::Bool(false),
+                    _ => Value::Null,
+                }).collect())
+            },
+            Expr::Not(inner) => {
+                Ok(inner.eval(batch)?.iter().map(|val| match val.as_bool() {
+                    Some(b) => Value::Bool(!b),
+                    None => Value::Null,
+                }).collect())
+            },
+            Expr::IsNull(inner) => Ok(inner.eval(batch)?.iter().map(|val| Value::Bool(val.is_null())).collect()),
+            Expr::Cast(inner, data_type) => inner.eval(batch)?.iter().map(|val| cast(val, *data_type)).collect(),
+            Expr::Call(function, args) => {
+                let mut arg_values: Vec<Vec<Value>> = Vec::new();
+                for arg in args {
+                    arg_values.push(arg.eval(batch)?);
+                }
+                (0..rows).map(|row| {
+                    let row_args: Vec<&Value> = arg_values.iter().map(|col| &col[row]).collect();
+                    call(*function, &row_args)
+                }).collect()
+            },
+            Expr::Aggregate(function, _) => Err(format!("{:?} can only be used as an aggregate", function)),
+            Expr::Window(window) => Err(format!("{} can only be computed by a window", window)),
+        }
+    }
+
+    // Evaluate as a filter, Null counts as false
+    pub fn eval_mask(&self, batch: &Batch) -> Result<Vec<bool>, String> {
+        Ok(self.eval(batch)?.iter().map(|val| val.as_bool().unwrap_or(false)).collect())
+    }
+}
+
+pub fn compare_values(op: CompareOp, left: &Value, right: &Value) -> Value {
+    match left.compare(right) {
+        None => Value::Null,
+        Some(ordering) => Value::Bool(match op {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::NotEq => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::LtEq => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::GtEq => ordering != Ordering::Less,
+        }),
+    }
+}
+
+fn is_unsigned(value: &Value) -> bool {
+    matches!(value, Value::UInt64(_) | Value::UInt8(_))
+}
+
+pub fn arithmetic(op: ArithmeticOp, left: &Value, right: &Value) -> Result<Value, String> {
+    if left.is_null() || right.is_null() {
+        return Ok(Value::Null);
+    }
+    let overflow = || format!("Overflow computing {} {} {}", left, op, right);
+
+    // DateTime math is done in millis, the way they're stored
+    match (left, right, op) {
+        (Value::DateTime(l), Value::DateTime(r), ArithmeticOp::Subtract) => {
+            return Ok(Value::Int64((*l - *r).num_milliseconds()));
+        },
+        (Value::DateTime(l), r, ArithmeticOp::Add) | (r, Value::DateTime(l), ArithmeticOp::Add) if r.as_i128().is_some() => {
+            let millis = i64::try_from(r.as_i128().unwrap()).map_err(|_| overflow())?;
+            return l.checked_add_signed(Duration::milliseconds(millis)).map(Value::DateTime).ok_or_else(overflow);
+        },
+        (Value::DateTime(l), r, ArithmeticOp::Subtract) if r.as_i128().is_some() => {
+            let millis = i64::try_from(r.as_i128().unwrap()).map_err(|_| overflow())?;
+            return l.checked_sub_signed(Duration::milliseconds(millis)).map(Value::DateTime).ok_or_else(overflow);
+        },
+        _ => {},
+    }
+
+    if let (Some(l), Some(r)) = (left.as_i128(), right.as_i128()) {
+        let result = match op {
+            ArithmeticOp::Add => l.checked_add(r),
+            ArithmeticOp::Subtract => l.checked_sub(r),
+            ArithmeticOp::Multiply => l.checked_mul(r),
+            ArithmeticOp::Divide | ArithmeticOp::Modulo if r == 0 => return Ok(Value::Null),
+            ArithmeticOp::Divide => l.checked_div(r),
+            ArithmeticOp::Modulo => l.checked_rem(r),
+        }.ok_or_else(overflow)?;
+        // Unsigned stays unsigned as long as both sides were and the result still fits
+        if is_unsigned(left) && is_unsigned(right) && result >= 0 {
+            return u64::try_from(result).map(Value::UInt64).map_err(|_| overflow());
+        }
+        return i64::try_from(result).map(Value::Int64).map_err(|_| overflow());
+    }
+
+    if let (Some(l), Some(r)) = (left.as_decimal(), right.as_decimal()) {
+        let result = match op {
+            ArithmeticOp::Add => l.checked_add(r),
+            ArithmeticOp::Subtract => l.checked_sub(r),
+            ArithmeticOp::Multiply => l.checked_mul(r),
+            ArithmeticOp::Divide | ArithmeticOp::Modulo if r.is_zero() => return Ok(Value::Null),
+            ArithmeticOp::Divide => l.checked_div(r),
+            ArithmeticOp::Modulo => l.checked_rem(r),
+        }.ok_or_else(overflow)?;
+        return Ok(Value::Decimal(result));
+    }
+
+    if let (Value::String(l), Value::String(r), ArithmeticOp::Add) = (left, right, op) {
+        return Ok(Value::String(l.to_owned() + r));
+    }
+
+    Err(format!("Can't compute {} {} {}", left, op, right))
+}
+
+fn zone_arg(arg: Option<&&Value>) -> Result<Tz, String> {
+    match arg {
+        None => Ok(Tz::UTC),
+        Some(Value::String(name)) => time_zone(name),
+        Some(other) => Err(format!("Expected a time zone name but got {}", other)),
+    }
+}
+
+pub fn call(function: ScalarFunction, args: &[&Value]) -> Result<Value, String> {
+    match function {
+        ScalarFunction::DateTrunc => {
+            let (unit, datetime) = match args {
+                [Value::String(unit), Value::DateTime(datetime), ..] if args.len() <= 4 => (unit.to_lowercase(), datetime),
+                [_, Value::Null, ..] => return Ok(Value::Null),
+                _ => return Err(format!("date_trunc takes a unit, a datetime, and optionally a time zone and the day weeks start on, got {:?}", args)),
+            };
+            let week_start = match args.get(3) {
+                None => Weekday::Mon,
+                Some(Value::String(name)) => weekday(name)?,
+                Some(other) => return Err(format!("Expected a weekday but got {}", other)),
+            };
+            calendar::date_trunc(&unit, datetime, zone_arg(args.get(2))?, week_start).map(Value::DateTime)
+        },
+        ScalarFunction::DateBin => {
+            let (stride, datetime) = match args {
+                [Value::String(stride), Value::DateTime(datetime), ..] if args.len() <= 4 => (parse_stride(stride)?, datetime),
+                [_, Value::Null, ..] => return Ok(Value::Null),
+                _ => return Err(format!("date_bin takes a stride, a datetime, and optionally a time zone and an origin, got {:?}", args)),
+            };
+            // The origin's wall clock time, read in the zone
+            let origin = match args.get(3) {
+                None => default_origin(),
+                Some(Value::DateTime(origin)) => origin.naive_utc(),
+                Some(Value::String(text)) => parse_datetime(text).map(|origin| origin.naive_utc()).ok_or(format!("Bad date_bin origin {:?}", text))?,
+                Some(other) => return Err(format!("Expected a date_bin origin but got {}", other)),
+            };
+            calendar::date_bin(stride, datetime, zone_arg(args.get(2))?, origin).map(Value::DateTime)
+        },
+    }
+}
+
+pub fn parse_datetime(text: &str) -> Option<DateTime<Utc>> {
+    if let Ok(datetime) = DateTime::parse_from_rfc3339(text) {
+        return Some(datetime.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(text, "%Y-%m-%d").ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|naive| Utc.from_utc_datetime(&naive))
+}
+
+pub fn cast(value: &Value, data_type: DataType) -> Result<Value, String> {
+    if value.is_null() || value.data_type() == Some(data_type) {
+        return Ok(value.clone());
+    }
+    let failed = || format!("Can't cast {} to {:?}", value, data_type);
+    let text = value.to_string();
+    let integer = match value {
+        Value::String(text) => i128::from_str(text.trim()).ok(),
+        Value::Decimal(val) => val.trunc().to_string().parse::<i128>().ok(),
+        Value::DateTime(val) => Some(val.timestamp_millis() as i128),
+        Value::Bool(val) => Some(*val as i128),
+        _ => value.as_i128(),
+    };
+    match data_type {
+        DataType::Bool => match value {
+            Value::String(text) => bool::from_str(&text.to_lowercase()).map(Value::Bool).map_err(|_| failed()),
+            _ => integer.map(|val| Value::Bool(val != 0)).ok_or_else(failed),
+        },
+        DataType::String => Ok(Value::String(text)),
+        DataType::Int64 => integer.and_then(|val| i64::try_from(val).ok()).map(Value::Int64).ok_or_else(failed),
+        DataType::Int8 => integer.and_then(|val| i8::try_from(val).ok()).map(Value::Int8).ok_or_else(failed),
+        DataType::UInt64 => integer.and_then(|val| u64::try_from(val).ok()).map(Value::UInt64).ok_or_else(failed),
+        DataType::UInt8 => integer.and_then(|val| u8::try_from(val).ok()).map(Value::UInt8).ok_or_else(failed),
+        DataType::Decimal => match value {
+            Value::String(text) => Decimal::from_str(text.trim()).map(Value::Decimal).map_err(|_| failed()),
+            _ => value.as_decimal().map(Value::Decimal).ok_or_else(failed),
+        },
+        DataType::DateTime => match value {
+            Value::String(text) => parse_datetime(text.trim()).map(Value::DateTime).ok_or_else(failed),
+            _ => integer.and_then(|millis| Utc.timestamp_millis_opt(millis as i64).single()).map(Value::DateTime).ok_or_else(failed),
+        },
+        DataType::Uuid => match value {
+            Value::String(text) => Uuid::parse_str(text.trim()).map(Value::Uuid).map_err(|_| failed()),
+            _ => Err(failed()),
+        },
+    }
+}
+
+impl fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            CompareOp::Eq => "=",
+            CompareOp::NotEq => "!=",
+            CompareOp::Lt => "<",
+            CompareOp::LtEq => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::GtEq => ">=",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            ArithmeticOp::Add => "+",
+            ArithmeticOp::Subtract => "-",
+            ArithmeticOp::Multiply => "*",
+            ArithmeticOp::Divide => "/",
+            ArithmeticOp::Modulo => "%",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for ScalarFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScalarFunction::DateTrunc => write!(f, "date_trunc"),
+            ScalarFunction::DateBin => write!(f, "date_bin"),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Column(name) => write!(f, "{}", name),
+            Expr::Literal(Value::String(text)) => write!(f, "'{}'", text.replace('\'', "''")),
+            Expr::Literal(value) => write!(f, "{}", value),
+            Expr::Compare(op, left, right) => write!(f, "({} {} {})", left, op, right),
+            Expr::Arithmetic(op, left, right) => write!(f, "({} {} {})", left, op, right),
+            Expr::And(left, right) => write!(f, "({} AND {})", left, right),
+            Expr::Or(left, right) => write!(f, "({} OR {})", left, right),
+            Expr::Not(inner) => write!(f, "(NOT {})", inner),
+            Expr::IsNull(inner) => write!(f, "({} IS NULL)", inner),
+            Expr::Cast(inner, data_type) => write!(f, "CAST({} AS {:?})", inner, data_type),
+            Expr::Call(function, args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", function, args.join(", "))
+            },
+            Expr::Aggregate(AggregateFunction::Count, inner) if **inner == Expr::Literal(Value::Bool(true)) => write!(f, "count(*)"),
+            Expr::Aggregate(AggregateFunction::ApproxCountDistinct(precision), inner) if *precision != DEFAULT_PRECISION => {
+                write!(f, "approx_count_distinct({}, {})", inner, precision)
+            },
+            Expr::Aggregate(AggregateFunction::Percentile(fraction), inner) => {
+                write!(f, "percentile({}, {})", inner, Decimal::new(*fraction as i64, 6).normalize())
+            },
+            Expr::Aggregate(AggregateFunction::ApproxPercentile(fraction), inner) => {
+                write!(f, "approx_percentile({}, {})", inner, Decimal::new(*fraction as i64, 6).normalize())
+            },
+            Expr::Aggregate(function, inner) => write!(f, "{}({})", function, inner),
+            Expr::Window(window) => write!(f, "{}", window),
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use super::batch::Value;
+use super::lineage::fnv1a;
+use super::spill::write_value;
+
+// HyperLogLog sketches for approximate distinct counts. Each value gets hashed to 64 bits, the top
+// `precision` bits pick a register and the register keeps the longest run of leading zeros seen in
+// the rest. Memory is 2^precision bytes however many values go in, and two sketches of the same
+// values merge by taking the max of each register, so per segment sketches can be kept on disk and
+// combined later. Standard error is about 1.04 / sqrt(2^precision), 1.6% at the default.
+
+pub const MIN_PRECISION: u8 = 4;
+pub const MAX_PRECISION: u8 = 18;
+pub const DEFAULT_PRECISION: u8 = 12;
+
+// Hash that comes out the same on every run and every machine, unlike the std hasher. FNV-1a over
+// the same bytes spill files use for the value, then the splitmix64 finalizer since FNV's bits
+// don't spread out well enough on short inputs and HLL cares about the top ones.
+pub fn hash_value(value: &Value) -> u64 {
+    let mut bytes: Vec<u8> = Vec::new();
+    let _ = write_value(&mut bytes, value);
+    let mut hash = fnv1a(&bytes);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new(precision: u8) -> HyperLogLog {
+        let precision = precision.clamp(MIN_PRECISION, MAX_PRECISION);
+        HyperLogLog { precision, registers: vec![0; 1 << precision] }
+    }
+
+    // Nulls aren't values, same as count(DISTINCT x)
+    pub fn insert(&mut self, value: &Value) {
+        if !value.is_null() {
+            self.insert_hash(hash_value(value));
+        }
+    }
+
+    pub fn insert_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as usize;
+        let rest = hash << self.precision;
+        let rank = (rest.leading_zeros() as u8).min(64 - self.precision) + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) -> Result<(), String> {
+        if other.precision != self.precision {
+            return Err(format!("Can't merge HyperLogLog sketches of precision {} and {}", self.precision, other.precision));
+        }
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+        Ok(())
+    }
+
+    // The same sketch at a lower precision, as if it had been built that way. The index bits that
+    // get dropped become the leading bits of what the register ranks.
+    pub fn fold(&self, precision: u8) -> Result<HyperLogLog, String> {
+        if precision > self.precision || precision < MIN_PRECISION {
+            return Err(format!("Can't fold a precision {} HyperLogLog to {}", self.precision, precision));
+        }
+        let dropped = self.precision - precision;
+        let mut folded = HyperLogLog::new(precision);
+        for (index, rank) in self.registers.iter().enumerate() {
+            if *rank == 0 {
+                continue;
+            }
+            let low = index & ((1 << dropped) - 1);
+            let rank = match low {
+                0 => dropped + rank,
+                _ => dropped - (usize::BITS - low.leading_zeros()) as u8 + 1,
+            };
+            let register = &mut folded.registers[index >> dropped];
+            *register = (*register).max(rank);
+        }
+        Ok(folded)
+    }
+
+    pub fn estimate(&self) -> u64 {
+        let registers = self.registers.len() as f64;
+        let alpha = match self.registers.len() {
+            16 => 0.673,
+            32 => 0.697,
+            64 => 0.709,
+            _ => 0.7213 / (1.0 + 1.079 / registers),
+        };
+        let sum: f64 = self.registers.iter().map(|rank| 2f64.powi(-(*rank as i32))).sum();
+        let estimate = alpha * registers * registers / sum;
+        // Small counts leave registers empty, linear counting is much closer there
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        if estimate <= 2.5 * registers && empty > 0 {
+            return (registers * (registers / empty as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+
+    // `<precision> <registers as hex>`, how zone maps store them
+    pub fn to_text(&self) -> String {
+        let hex: String = self.registers.iter().map(|rank| format!("{:02x}", rank)).collect();
+        format!("{} {}", self.precision, hex)
+    }
+
+    pub fn from_text(text: &str) -> Result<HyperLogLog, String> {
+        let bad = || format!("Bad HyperLogLog {:?}", text);
+        let (precision, hex) = text.split_once(' ').ok_or_else(bad)?;
+        let precision: u8 = precision.parse().map_err(|_| bad())?;
+        if !(MIN_PRECISION..=MAX_PRECISION).contains(&precision) || hex.len() != 2 << precision {
+            return Err(bad());
+        }
+        let registers = (0..hex.len()).step_by(2)
+            .map(|pos| u8::from_str_radix(&hex[pos..pos + 2], 16).map_err(|_| bad()))
+            .collect::<Result<Vec<u8>, String>>()?;
+        Ok(HyperLogLog { precision, registers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(precision: u8, values: impl Iterator<Item = i64>) -> HyperLogLog {
+        let mut hll = HyperLogLog::new(precision);
+        for value in values {
+            hll.insert(&Value::Int64(value));
+        }
+        hll
+    }
+
+    #[test]
+    fn fold_matches_building_at_the_lower_precision() {
+        for count in [0, 1, 10, 1000, 100_000] {
+            let full = sketch(MAX_PRECISION, 0..count);
+            for precision in MIN_PRECISION..=MAX_PRECISION {
+                assert_eq!(full.fold(precision).unwrap(), sketch(precision, 0..count), "{} values at {}", count, precision);
+            }
+        }
+    }
+
+    #[test]
+    fn fold_only_goes_down() {
+        let hll = sketch(10, 0..100);
+        assert_eq!(hll.fold(10).unwrap(), hll);
+        assert!(hll.fold(11).is_err());
+        assert!(hll.fold(MIN_PRECISION - 1).is_err());
+    }
+
+    #[test]
+    fn merge_is_the_sketch_of_the_union() {
+        let mut left = sketch(DEFAULT_PRECISION, 0..60_000);
+        let right = sketch(DEFAULT_PRECISION, 40_000..100_000);
+        left.merge(&right).unwrap();
+        assert_eq!(left, sketch(DEFAULT_PRECISION, 0..100_000));
+        // Merging in the same values again changes nothing
+        let before = left.clone();
+        left.merge(&right).unwrap();
+        assert_eq!(left, before);
+        assert!(left.merge(&sketch(DEFAULT_PRECISION + 1, 0..10)).is_err());
+    }
+
+    #[test]
+    fn folded_sketches_merge() {
+        // Segments can be sketched at different precisions and still get combined
+        let mut low = sketch(10, 0..5000);
+        low.merge(&sketch(14, 5000..10_000).fold(10).unwrap()).unwrap();
+        assert_eq!(low, sketch(10, 0..10_000));
+    }
+
+    #[test]
+    fn estimates() {
+        assert_eq!(HyperLogLog::new(DEFAULT_PRECISION).estimate(), 0);
+        let mut nulls = HyperLogLog::new(DEFAULT_PRECISION);
+        nulls.insert(&Value::Null);
+        assert_eq!(nulls.estimate(), 0);
+        // Linear counting is close to exact while most registers are empty
+        let small = sketch(DEFAULT_PRECISION, 0..100).estimate();
+        assert!((95..=105).contains(&small), "{}", small);
+        for count in [10_000i64, 1_000_000] {
+            let estimate = sketch(DEFAULT_PRECISION, 0..count).estimate() as f64;
+            let error = (estimate - count as f64).abs() / count as f64;
+            assert!(error < 0.05, "{} for {}", estimate, count);
+        }
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let hll = sketch(MIN_PRECISION, 0..1000);
+        assert_eq!(HyperLogLog::from_text(&hll.to_text()).unwrap(), hll);
+        assert!(HyperLogLog::from_text("4 00").is_err());
+        assert!(HyperLogLog::from_text("3 00000000000000000000000000000000").is_err());
+        assert!(HyperLogLog::from_text(&hll.to_text().replace(' ', "")).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use crate::datagen::catalog::KeyOrder;
+use super::batch::{Batch, Value};
+use super::expr::Expr;
+use super::merge_join::MergeJoin;
+use super::operator::{ordered_on, Operator, BATCH_ROWS};
+use super::spill::{memory_budget, Partitions, SpillFile, SpillScan, MAX_SPILL_DEPTH};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+// Left is always the side that gets kept for Left, Semi and Anti joins. Semi and Anti only hand
+// back the left columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinType {
+    Inner,
+    Left,
+    Semi,
+    Anti,
+}
+
+impl JoinType {
+    pub fn from_name(name: &str) -> Result<JoinType, String> {
+        match name.to_lowercase().as_str() {
+            "inner" => Ok(JoinType::Inner),
+            "left" => Ok(JoinType::Left),
+            "semi" => Ok(JoinType::Semi),
+            "anti" => Ok(JoinType::Anti),
+            _ => Err(format!("Unknown join type {:?}", name)),
+        }
+    }
+}
+
+impl fmt::Display for JoinType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            JoinType::Inner => "Inner",
+            JoinType::Left => "Left",
+            JoinType::Semi => "Semi",
+            JoinType::Anti => "Anti",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// Keys that compare equal have to hash the same. Integers of any width and Decimals with nothing
+// after the point all become Int64, or a Decimal past what that holds, so 3, 3u8 and 3.00 find
+// each other. Other Decimals already hash the same whatever their scale.
+pub fn normalize_key(value: &Value) -> Value {
+    let integral = match value {
+        Value::Decimal(val) if val.fract().is_zero() => val.to_i128(),
+        _ => value.as_i128(),
+    };
+    match integral {
+        Some(val) => match i64::try_from(val) {
+            Ok(val) => Value::Int64(val),
+            Err(_) => Decimal::try_from_i128_with_scale(val, 0).map(Value::Decimal).unwrap_or_else(|_| value.clone()),
+        },
+        None => value.clone(),
+    }
+}
+
+pub fn join_schema(left: &[String], right: &[String], join_type: JoinType) -> Vec<String> {
+    match join_type {
+        JoinType::Inner | JoinType::Left => left.iter().chain(right.iter()).cloned().collect(),
+        JoinType::Semi | JoinType::Anti => left.to_vec(),
+    }
+}
+
+// The drained build side: every row plus a hash table from key to row numbers
+struct BuildSide {
+    rows: Batch,
+    table: HashMap<Value, Vec<usize>>,
+    matched: Vec<bool>,
+    size: usize,
+}
+
+impl BuildSide {
+    fn new(names: Vec<String>) -> BuildSide {
+        let columns = vec![Vec::new(); names.len()];
+        BuildSide { rows: Batch::new(names, columns), table: HashMap::new(), matched: Vec::new(), size: 0 }
+    }
+
+    fn add(&mut self, batch: Batch, key: &Expr) -> Result<(), String> {
+        let keys = key.eval(&batch)?;
+        let offset = self.rows.num_rows();
+        for (row, key) in keys.iter().enumerate() {
+            // Null never matches anything
+            if !key.is_null() {
+                self.table.entry(normalize_key(key)).or_default().push(offset + row);
+            }
+        }
+        // Rows, plus a key and a row number per row in the table
+        self.size += batch.size() + batch.num_rows() * (std::mem::size_of::<Value>() + std::mem::size_of::<usize>() + 1);
+        self.rows.append(batch);
+        Ok(())
+    }
+}
+
+// Writes rows out to the partition their join key hashes to
+fn partition_batch(partitions: &mut Partitions, batch: &Batch, key: &Expr) -> Result<(), String> {
+    let keys = key.eval(batch)?;
+    for (row, key) in keys.iter().enumerate() {
+        let values: Vec<Value> = batch.columns.iter().map(|col| col[row].clone()).collect();
+        partitions.write_row(&[normalize_key(key)], &values)?;
+    }
+    Ok(())
+}
+
+// Equi-join that drains whichever input looks smaller into a hash table and streams the other
+// one past it. When the kept (left) side is the one that got built, the unmatched or matched
+// rows it owes are handed out once the probe side runs dry.
+//
+// If the build side won't fit in the memory budget it turns into a grace hash join: both inputs
+// get split into partition files by the hash of their key, so matching rows always land in the
+// same pair of files, and each pair gets joined by its own HashJoin (which can split again).
+pub struct HashJoin {
+    left: Box<dyn Operator>,
+    right: Box<dyn Operator>,
+    left_key: Expr,
+    right_key: Expr,
+    join_type: JoinType,
+    build_left: bool,
+    build: Option<BuildSide>,
+    pending: Batch,
+    finished: bool,
+    budget: usize,
+    // How many times the rows coming in have already been through a spill
+    depth: usize,
+    spilled: bool,
+    partitions: VecDeque<(SpillFile, SpillFile)>,
+    current: Option<Box<HashJoin>>,
+    bytes_spilled: u64,
+}
+
+impl HashJoin {
+    pub fn new(left: Box<dyn Operator>, right: Box<dyn Operator>, left_key: Expr, right_key: Expr, join_type: JoinType) -> HashJoin {
+        // Unknown sizes fall back to building on the right like most engines do
+        let build_left = match (left.estimated_rows(), right.estimated_rows()) {
+            (Some(left_rows), Some(right_rows)) => left_rows < right_rows,
+            _ => false,
+        };
+        let names = join_schema(&left.schema(), &right.schema(), join_type);
+        HashJoin {
+            left,
+            right,
+            left_key,
+            right_key,
+            join_type,
+            build_left,
+            build: None,
+            pending: Batch::new(names.clone(), vec![Vec::new(); names.len()]),
+            finished: false,
+            budget: memory_budget(),
+            depth: 0,
+            spilled: false,
+            partitions: VecDeque::new(),
+            current: None,
+            bytes_spilled: 0,
+        }
+    }
+
+    fn build(&mut self) -> Result<(), String> {
+        let (input, key) = match self.build_left {
+            true => (self.left.as_mut(), &self.left_key),
+            false => (self.right.as_mut(), &self.right_key),
+        };
+        let mut build = BuildSide::new(input.schema());
+        while let Some(batch) = input.next_batch()? {
+            build.add(batch, key)?;
+            if build.size > self.budget && self.depth < MAX_SPILL_DEPTH {
+                return self.spill(build.rows);
+            }
+        }
+        build.matched = vec![false; build.rows.num_rows()];
+        self.build = Some(build);
+        Ok(())
+    }
+
+    // Splits what's been built so far, the rest of the build side and then all of the probe side
+    // into partition files. Pairs where nothing could come out (no left rows, or no right rows
+    // for the joins that need a match) get dropped right away.
+    fn spill(&mut self, built: Batch) -> Result<(), String> {
+        let mut left_partitions = Partitions::new(self.depth)?;
+        let mut right_partitions = Partitions::new(self.depth)?;
+        let (build_input, build_key, build_partitions, probe_input, probe_key, probe_partitions) = match self.build_left {
+            true => (&mut self.left, &self.left_key, &mut left_partitions, &mut self.right, &self.right_key, &mut right_partitions),
+            false => (&mut self.right, &self.right_key, &mut right_partitions, &mut self.left, &self.left_key, &mut left_partitions),
+        };
+        partition_batch(build_partitions, &built, build_key)?;
+        while let Some(batch) = build_input.next_batch()? {
+            partition_batch(build_partitions, &batch, build_key)?;
+        }
+        while let Some(batch) = probe_input.next_batch()? {
+            partition_batch(probe_partitions, &batch, probe_key)?;
+        }
+        for (left, right) in left_partitions.finish()?.into_iter().zip(right_partitions.finish()?) {
+            self.bytes_spilled += left.bytes + right.bytes;
+            let needs_match = matches!(self.join_type, JoinType::Inner | JoinType::Semi);
+            if left.rows > 0 && (right.rows > 0 || !needs_match) {
+                self.partitions.push_back((left, right));
+            }
+        }
+        self.spilled = true;
+        Ok(())
+    }
+
+    // Joins the spilled partitions one pair at a time
+    fn next_spilled_batch(&mut self) -> Result<Option<Batch>, String> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                if let Some(batch) = current.next_batch()? {
+                    return Ok(Some(batch));
+                }
+                self.bytes_spilled += current.bytes_spilled;
+                self.current = None;
+            }
+            let (left, right) = match self.partitions.pop_front() {
+                Some(pair) => pair,
+                None => return Ok(None),
+            };
+            let left = SpillScan::new(left, self.left.schema())?;
+            let right = SpillScan::new(right, self.right.schema())?;
+            let mut join = HashJoin::new(Box::new(left), Box::new(right), self.left_key.clone(), self.right_key.clone(), self.join_type);
+            join.build_left = self.build_left;
+            join.depth = self.depth + 1;
+            self.current = Some(Box::new(join));
+        }
+    }
+
+    fn push_row(&mut self, left: Vec<Value>, right: Option<Vec<Value>>, right_width: usize) {
+        let row: Vec<Value> = match self.join_type {
+            JoinType::Inner | JoinType::Left => left.into_iter()
+                .chain(right.unwrap_or_else(|| vec![Value::Null; right_width]))
+                .collect(),
+            JoinType::Semi | JoinType::Anti => left,
+        };
+        for (col, value) in self.pending.columns.iter_mut().zip(row) {
+            col.push(value);
+        }
+    }
+
+    fn probe(&mut self, batch: &Batch) -> Result<(), String> {
+        let build = self.build.as_mut().unwrap();
+        let keys = match self.build_left {
+            true => self.right_key.eval(batch)?,
+            false => self.left_key.eval(batch)?,
+        };
+        let mut output: Vec<(Vec<Value>, Option<Vec<Value>>)> = Vec::new();
+        for (row, key) in keys.iter().enumerate() {
+            let matches = match key.is_null() {
+                true => None,
+                false => build.table.get(&normalize_key(key)),
+            };
+            let probe_row = || batch.columns.iter().map(|col| col[row].clone()).collect::<Vec<Value>>();
+            let build_row = |index: usize| build.rows.columns.iter().map(|col| col[index].clone()).collect::<Vec<Value>>();
+            if self.build_left {
+                // Probing with right rows, the left rows owed to Semi/Anti/Left get settled at the end
+                if let Some(matches) = matches {
+                    for index in matches {
+                        if matches!(self.join_type, JoinType::Inner | JoinType::Left) {
+                            output.push((build_row(*index), Some(probe_row())));
+                        }
+                        build.matched[*index] = true;
+                    }
+                }
+            } else {
+                match (self.join_type, matches) {
+                    (JoinType::Inner, Some(matches)) | (JoinType::Left, Some(matches)) => {
+                        for index in matches {
+                            output.push((probe_row(), Some(build_row(*index))));
+                        }
+                    },
+                    (JoinType::Left, None) => output.push((probe_row(), None)),
+                    (JoinType::Semi, Some(_)) | (JoinType::Anti, None) => output.push((probe_row(), None)),
+                    _ => {},
+                }
+            }
+        }
+        let right_width = self.right.schema().len();
+        for (left, right) in output {
+            self.push_row(left, right, right_width);
+        }
+        Ok(())
+    }
+
+    // Rows of the built left side that are owed once probing is done
+    fn finish_build_left(&mut self) {
+        let build = self.build.take().unwrap();
+        let right_width = self.right.schema().len();
+        for (index, matched) in build.matched.iter().enumerate() {
+            let owed = match self.join_type {
+                JoinType::Inner => false,
+                JoinType::Left | JoinType::Anti => !matched,
+                JoinType::Semi => *matched,
+            };
+            if owed {
+                let row = build.rows.columns.iter().map(|col| col[index].clone()).collect();
+                self.push_row(row, None, right_width);
+            }
+        }
+    }
+
+    fn take_pending(&mut self) -> Batch {
+        let empty = self.pending.empty_like();
+        std::mem::replace(&mut self.pending, empty)
+    }
+}
+
+impl Operator for HashJoin {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.build.is_none() && !self.finished && !self.spilled {
+            self.build()?;
+        }
+        if self.spilled {
+            return self.next_spilled_batch();
+        }
+        while !self.finished && self.pending.num_rows() < BATCH_ROWS {
+            let batch = match self.build_left {
+                true => self.right.next_batch()?,
+                false => self.left.next_batch()?,
+            };
+            match batch {
+                Some(batch) => self.probe(&batch)?,
+                None => {
+                    if self.build_left {
+                        self.finish_build_left();
+                    }
+                    self.build = None;
+                    self.finished = true;
+                },
+            }
+        }
+        match self.pending.num_rows() {
+            0 => Ok(None),
+            _ => Ok(Some(self.take_pending())),
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        join_schema(&self.left.schema(), &self.right.schema(), self.join_type)
+    }
+
+    fn name(&self) -> String {
+        format!("HashJoin {} on {} = {}", self.join_type, self.left_key, self.right_key)
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.left.as_ref(), self.right.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.left, &mut self.right]
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.build.as_ref().map(|build| build.size).unwrap_or(0) + self.pending.size()
+            + self.current.as_ref().map(|current| current.memory_bytes()).unwrap_or(0)
+    }
+
+    fn bytes_spilled(&self) -> u64 {
+        self.bytes_spilled + self.current.as_ref().map(|current| current.bytes_spilled).unwrap_or(0)
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        // Key/foreign key joins come out about the size of the bigger side
+        match (self.left.estimated_rows(), self.right.estimated_rows()) {
+            (Some(left), Some(right)) => Some(match self.join_type {
+                JoinType::Inner => left.max(right),
+                _ => left,
+            }),
+            _ => None,
+        }
+    }
+}
+
+// Picks how to run an equi-join: one streaming merge pass when both inputs are laid out the same
+// way on their keys, otherwise a hash join
+pub fn plan_join(left: Box<dyn Operator>, right: Box<dyn Operator>, left_key: Expr, right_key: Expr, join_type: JoinType) -> Box<dyn Operator> {
+    let right_orders: Vec<KeyOrder> = right.orderings().into_iter().filter(|order| ordered_on(order, &right_key)).collect();
+    let merge = left.orderings().into_iter()
+        .filter(|order| ordered_on(order, &left_key) && (order.clustering.is_none() || order.primary))
+        .find(|order| right_orders.iter().any(|right_order| order.compatible(right_order)));
+    if let Some(order) = merge {
+        let sorted = order.clustering.is_none();
+        return Box::new(MergeJoin::new(left, right, left_key, right_key, join_type, sorted));
+    }
+    Box::new(HashJoin::new(left, right, left_key, right_key, join_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn equal_numbers_normalize_the_same() {
+        let decimal = |text: &str| Value::Decimal(Decimal::from_str(text).unwrap());
+        let same = [
+            vec![Value::Int64(3), Value::Int8(3), Value::UInt64(3), Value::UInt8(3), decimal("3"), decimal("3.00")],
+            vec![Value::Int64(-7), Value::Int8(-7), decimal("-7.0")],
+            vec![Value::UInt64(u64::MAX), decimal("18446744073709551615"), decimal("18446744073709551615.000")],
+            vec![decimal("2.5"), decimal("2.50")],
+        ];
+        for values in &same {
+            for value in values {
+                assert_eq!(normalize_key(value), normalize_key(&values[0]), "{:?}", value);
+                assert_eq!(partition_of_key(value), partition_of_key(&values[0]), "{:?}", value);
+            }
+        }
+        assert_ne!(normalize_key(&decimal("3.5")), normalize_key(&Value::Int64(3)));
+        assert_eq!(normalize_key(&Value::String("3".to_string())), Value::String("3".to_string()));
+    }
+
+    fn partition_of_key(value: &Value) -> usize {
+        crate::analyze::spill::partition_of(&[normalize_key(value)], 0, 16)
+    }
+}
@@ -0,0 +1,340 @@
+use chrono::{DateTime, Utc};
+use crate::datagen::dataset::{Column, DataType};
+use super::batch::Value;
+
+// Aggregate kernels for the fixed width numeric columns. Every input type gets widened into a
+// u64 lane (signed types are biased by flipping the sign bit so unsigned ordering still holds),
+// folded eight lanes at a time, and then un-biased at the end. The lane loop is written so the
+// compiler can turn it into vector instructions, and on x86_64 we compile a second copy with AVX2
+// enabled and pick between the two at runtime. On aarch64 NEON is always there, so the portable
+// version is already the vectorized one.
+//
+// The smallest value above zero comes out of the same pass: with zero's lane value subtracted (and
+// one more), everything above zero wraps around to below everything that isn't, so it's one more
+// min per lane.
+
+const LANES: usize = 8;
+const SIGN_BIT: u64 = 1 << 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NumericAggregate {
+    pub count: u64,
+    pub sum: i128,
+    pub min: i128,
+    pub max: i128,
+    // i128::MAX when nothing was above zero
+    pub min_positive: i128,
+}
+
+impl NumericAggregate {
+    pub fn empty() -> NumericAggregate {
+        NumericAggregate {
+            count: 0,
+            sum: 0,
+            min: i128::MAX,
+            max: i128::MIN,
+            min_positive: i128::MAX,
+        }
+    }
+
+    pub fn merge(&mut self, other: &NumericAggregate) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.min_positive = self.min_positive.min(other.min_positive);
+    }
+
+    pub fn average(&self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(self.sum as f64 / self.count as f64)
+    }
+}
+
+// Raw result of a fold in the biased u64 space
+struct Folded {
+    count: u64,
+    sum: u128,
+    min: u64,
+    max: u64,
+    // Min of value - zero - 1, wrapping, see the top
+    above: u64,
+}
+
+#[inline(always)]
+fn fold_lanes<T, F: Fn(&T) -> u64>(values: &[T], zero: u64, widen: F) -> Folded {
+    let mut sum = [0u64; LANES];
+    let mut carry = [0u64; LANES];
+    let mut min = [u64::MAX; LANES];
+    let mut max = [0u64; LANES];
+    let mut above = [u64::MAX; LANES];
+    let past_zero = zero.wrapping_add(1);
+
+    let chunks = values.chunks_exact(LANES);
+    let remainder = chunks.remainder();
+    for chunk in chunks {
+        for i in 0..LANES {
+            let value = widen(&chunk[i]);
+            let (total, overflowed) = sum[i].overflowing_add(value);
+            sum[i] = total;
+            carry[i] += overflowed as u64;
+            min[i] = min[i].min(value);
+            max[i] = max[i].max(value);
+            above[i] = above[i].min(value.wrapping_sub(past_zero));
+        }
+    }
+    for (i, item) in remainder.iter().enumerate() {
+        let value = widen(item);
+        let (total, overflowed) = sum[i].overflowing_add(value);
+        sum[i] = total;
+        carry[i] += overflowed as u64;
+        min[i] = min[i].min(value);
+        max[i] = max[i].max(value);
+        above[i] = above[i].min(value.wrapping_sub(past_zero));
+    }
+
+    let mut folded = Folded {
+        count: values.len() as u64,
+        sum: 0,
+        min: u64::MAX,
+        max: 0,
+        above: u64::MAX,
+    };
+    for i in 0..LANES {
+        folded.sum += sum[i] as u128 + ((carry[i] as u128) << 64);
+        folded.min = folded.min.min(min[i]);
+        folded.max = folded.max.max(max[i]);
+        folded.above = folded.above.min(above[i]);
+    }
+    folded
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn fold_lanes_avx2<T, F: Fn(&T) -> u64>(values: &[T], zero: u64, widen: F) -> Folded {
+    fold_lanes(values, zero, widen)
+}
+
+fn fold<T, F: Fn(&T) -> u64>(values: &[T], zero: u64, widen: F) -> Folded {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safe because we just checked the CPU supports it
+            return unsafe { fold_lanes_avx2(values, zero, widen) };
+        }
+    }
+    fold_lanes(values, zero, widen)
+}
+
+// The lane value of the smallest one above zero, if there was one
+fn positive(folded: &Folded, zero: u64) -> Option<u64> {
+    match folded.above < u64::MAX - zero {
+        true => Some(folded.above.wrapping_add(zero).wrapping_add(1)),
+        false => None,
+    }
+}
+
+fn unsigned(folded: Folded) -> NumericAggregate {
+    if folded.count == 0 {
+        return NumericAggregate::empty();
+    }
+    NumericAggregate {
+        count: folded.count,
+        sum: folded.sum as i128,
+        min: folded.min as i128,
+        max: folded.max as i128,
+        min_positive: positive(&folded, 0).map_or(i128::MAX, |value| value as i128),
+    }
+}
+
+fn signed(folded: Folded) -> NumericAggregate {
+    if folded.count == 0 {
+        return NumericAggregate::empty();
+    }
+    NumericAggregate {
+        count: folded.count,
+        sum: folded.sum as i128 - (folded.count as i128) * (SIGN_BIT as i128),
+        min: ((folded.min ^ SIGN_BIT) as i64) as i128,
+        max: ((folded.max ^ SIGN_BIT) as i64) as i128,
+        min_positive: positive(&folded, SIGN_BIT).map_or(i128::MAX, |value| ((value ^ SIGN_BIT) as i64) as i128),
+    }
+}
+
+pub fn aggregate_u64(values: &[u64]) -> NumericAggregate {
+    unsigned(fold(values, 0, |v| *v))
+}
+
+pub fn aggregate_i64(values: &[i64]) -> NumericAggregate {
+    signed(fold(values, SIGN_BIT, |v| *v as u64 ^ SIGN_BIT))
+}
+
+pub fn aggregate_u8(values: &[u8]) -> NumericAggregate {
+    unsigned(fold(values, 0, |v| *v as u64))
+}
+
+pub fn aggregate_i8(values: &[i8]) -> NumericAggregate {
+    signed(fold(values, SIGN_BIT, |v| *v as i64 as u64 ^ SIGN_BIT))
+}
+
+pub fn aggregate_datetime(values: &[DateTime<Utc>]) -> NumericAggregate {
+    signed(fold(values, SIGN_BIT, |v| v.timestamp_millis() as u64 ^ SIGN_BIT))
+}
+
+// Entry point for anything working on a whole Column, returns None for the non-numeric types
+pub fn aggregate_column(column: &Column) -> Option<NumericAggregate> {
+    match column {
+        Column::UInt64(val) => Some(aggregate_u64(val)),
+        Column::Int64(val) => Some(aggregate_i64(val)),
+        Column::UInt8(val) => Some(aggregate_u8(val)),
+        Column::Int8(val) => Some(aggregate_i8(val)),
+        Column::DateTime(val) => Some(aggregate_datetime(val)),
+        _ => None,
+    }
+}
+
+// Same for a batch's values when they're all one of those types, folded where they are rather
+// than copied out into a typed Vec first. None for anything else, NULLs included.
+pub fn aggregate_values(values: &[Value]) -> Option<(NumericAggregate, DataType)> {
+    let data_type = values.first()?.data_type()?;
+    let all = |is: fn(&Value) -> bool| values.iter().all(is);
+    let numeric = match data_type {
+        DataType::UInt64 if all(|val| matches!(val, Value::UInt64(_))) => unsigned(fold(values, 0, |val| match val { Value::UInt64(v) => *v, _ => 0 })),
+        DataType::Int64 if all(|val| matches!(val, Value::Int64(_))) => signed(fold(values, SIGN_BIT, |val| match val { Value::Int64(v) => *v as u64 ^ SIGN_BIT, _ => 0 })),
+        DataType::UInt8 if all(|val| matches!(val, Value::UInt8(_))) => unsigned(fold(values, 0, |val| match val { Value::UInt8(v) => *v as u64, _ => 0 })),
+        DataType::Int8 if all(|val| matches!(val, Value::Int8(_))) => signed(fold(values, SIGN_BIT, |val| match val { Value::Int8(v) => *v as i64 as u64 ^ SIGN_BIT, _ => 0 })),
+        DataType::DateTime if all(|val| matches!(val, Value::DateTime(_))) => signed(fold(values, SIGN_BIT, |val| match val { Value::DateTime(v) => v.timestamp_millis() as u64 ^ SIGN_BIT, _ => 0 })),
+        _ => return None,
+    };
+    Some((numeric, data_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::SmallRng;
+    use rand::{Rng, SeedableRng};
+
+    // What every path has to agree with, one value at a time in i128
+    fn reference(values: impl Iterator<Item = i128>) -> NumericAggregate {
+        values.fold(NumericAggregate::empty(), |mut agg, value| {
+            agg.count += 1;
+            agg.sum += value;
+            agg.min = agg.min.min(value);
+            agg.max = agg.max.max(value);
+            if value > 0 {
+                agg.min_positive = agg.min_positive.min(value);
+            }
+            agg
+        })
+    }
+
+    // The portable lane loop and, where the CPU has it, the AVX2 copy, both un-biased the same way
+    // the public entry points do
+    fn paths<T, F: Fn(&T) -> u64 + Copy>(values: &[T], zero: u64, widen: F, finish: fn(Folded) -> NumericAggregate) -> Vec<NumericAggregate> {
+        let mut results = vec![finish(fold_lanes(values, zero, widen)), finish(fold(values, zero, widen))];
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                // Safe for the same reason as in fold
+                results.push(finish(unsafe { fold_lanes_avx2(values, zero, widen) }));
+            }
+        }
+        results
+    }
+
+    fn check_i64(values: &[i64]) {
+        let expected = reference(values.iter().map(|value| *value as i128));
+        for result in paths(values, SIGN_BIT, |v: &i64| *v as u64 ^ SIGN_BIT, signed) {
+            assert_eq!(result, expected, "{:?}", values);
+        }
+        assert_eq!(aggregate_i64(values), expected);
+        let boxed: Vec<Value> = values.iter().map(|value| Value::Int64(*value)).collect();
+        assert_eq!(aggregate_values(&boxed), (!values.is_empty()).then_some((expected, DataType::Int64)));
+    }
+
+    fn check_u64(values: &[u64]) {
+        let expected = reference(values.iter().map(|value| *value as i128));
+        for result in paths(values, 0, |v: &u64| *v, unsigned) {
+            assert_eq!(result, expected, "{:?}", values);
+        }
+        assert_eq!(aggregate_u64(values), expected);
+        let boxed: Vec<Value> = values.iter().map(|value| Value::UInt64(*value)).collect();
+        assert_eq!(aggregate_values(&boxed), (!values.is_empty()).then_some((expected, DataType::UInt64)));
+    }
+
+    #[test]
+    fn empty_input() {
+        check_i64(&[]);
+        check_u64(&[]);
+        assert_eq!(aggregate_i8(&[]), NumericAggregate::empty());
+        assert_eq!(aggregate_u8(&[]), NumericAggregate::empty());
+        assert_eq!(aggregate_datetime(&[]), NumericAggregate::empty());
+        assert_eq!(aggregate_i64(&[]).average(), None);
+    }
+
+    #[test]
+    fn signed_extremes() {
+        check_i64(&[i64::MIN]);
+        check_i64(&[i64::MAX]);
+        check_i64(&[i64::MIN, i64::MAX]);
+        check_i64(&[-1, 0, 1]);
+        // Enough of each to carry out of every lane, with a remainder that isn't a whole chunk
+        check_i64(&vec![i64::MAX; 8 * 5 + 3]);
+        check_i64(&vec![i64::MIN; 8 * 5 + 3]);
+        let mixed: Vec<i64> = (0..101).map(|i| if i % 3 == 0 { i64::MIN } else { i64::MAX - i }).collect();
+        check_i64(&mixed);
+    }
+
+    #[test]
+    fn unsigned_extremes() {
+        check_u64(&[0]);
+        check_u64(&[u64::MAX]);
+        check_u64(&vec![u64::MAX; 8 * 4 + 7]);
+        check_u64(&[u64::MAX, 0, 1, u64::MAX - 1]);
+    }
+
+    #[test]
+    fn random_lengths() {
+        let mut rng = SmallRng::seed_from_u64(26);
+        for len in (0..40).chain([255, 256, 257, 1000]) {
+            let signed: Vec<i64> = (0..len).map(|_| rng.gen()).collect();
+            check_i64(&signed);
+            let unsigned: Vec<u64> = (0..len).map(|_| rng.gen()).collect();
+            check_u64(&unsigned);
+        }
+    }
+
+    #[test]
+    fn narrow_types() {
+        let bytes: Vec<i8> = (i8::MIN..=i8::MAX).collect();
+        assert_eq!(aggregate_i8(&bytes), reference(bytes.iter().map(|value| *value as i128)));
+        let bytes: Vec<u8> = (0..=u8::MAX).rev().collect();
+        assert_eq!(aggregate_u8(&bytes), reference(bytes.iter().map(|value| *value as i128)));
+        let times: Vec<DateTime<Utc>> = [0i64, -86_400_000, 1_790_000_000_000].iter()
+            .map(|millis| DateTime::from_timestamp_millis(*millis).unwrap())
+            .collect();
+        assert_eq!(aggregate_datetime(&times), reference(times.iter().map(|time| time.timestamp_millis() as i128)));
+    }
+
+    #[test]
+    fn smallest_above_zero() {
+        assert_eq!(aggregate_u64(&[0, 0, 0]).min_positive, i128::MAX);
+        assert_eq!(aggregate_u64(&[0, 7, 0, 3, u64::MAX]).min_positive, 3);
+        assert_eq!(aggregate_u64(&[u64::MAX]).min_positive, u64::MAX as i128);
+        assert_eq!(aggregate_i64(&[i64::MIN, -1, 0]).min_positive, i128::MAX);
+        assert_eq!(aggregate_i64(&[-5, 1, 0]).min_positive, 1);
+        assert_eq!(aggregate_i64(&[i64::MAX, i64::MIN]).min_positive, i64::MAX as i128);
+        assert_eq!(aggregate_i8(&[-3, 0, 2, 9]).min_positive, 2);
+    }
+
+    #[test]
+    fn values_only_when_all_one_type() {
+        assert_eq!(aggregate_values(&[]), None);
+        assert_eq!(aggregate_values(&[Value::UInt64(1), Value::Null]), None);
+        assert_eq!(aggregate_values(&[Value::UInt64(1), Value::Int64(2)]), None);
+        assert_eq!(aggregate_values(&[Value::Bool(true)]), None);
+        assert_eq!(aggregate_values(&[Value::Int8(-2), Value::Int8(4)]), Some((aggregate_i8(&[-2, 4]), DataType::Int8)));
+    }
+}
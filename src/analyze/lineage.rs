@@ -0,0 +1,261 @@
+use crate::datagen::catalog::TableDef;
+use crate::datagen::constants::DATA_DIRECTORY;
+use crate::datagen::partition;
+use chrono::{SecondsFormat, Utc};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::time::UNIX_EPOCH;
+
+// Where every result set came from: the operators that made it, the columns each one handed out
+// and, at the leaves, exactly which segment files were read and which version of each it was.
+// Identical subtrees hash to the same id, so a result's nodes get stored once each as a DAG and
+// results that were built from the same inputs can be spotted by id.
+
+// A segment file as it was when it got read. Writes only append, but compaction writes the same
+// file names over again and can come out the same size, so the version is the size along with
+// when the file was last modified (in nanoseconds). Lineage saved before modified times were kept
+// has None and goes by the size alone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub path: String,
+    pub size: u64,
+    pub modified: Option<u64>,
+}
+
+fn version(path: &str) -> Option<(u64, Option<u64>)> {
+    let meta = fs::metadata(path).ok()?;
+    let modified = meta.modified().ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_nanos() as u64);
+    Some((meta.len(), modified))
+}
+
+impl Segment {
+    fn current(&self) -> Option<(u64, Option<u64>)> {
+        version(&self.path)
+    }
+
+    fn unchanged(&self, size: u64, modified: Option<u64>) -> bool {
+        size == self.size && (self.modified.is_none() || modified == self.modified)
+    }
+}
+
+pub fn column_segments(table: &TableDef, column: &str) -> Vec<Segment> {
+    partition::column_paths(table, column).into_iter()
+        .map(|path| {
+            let (size, modified) = version(&path).unwrap_or((0, None));
+            Segment { path, size, modified }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineageNode {
+    pub operator: String,
+    pub columns: Vec<String>,
+    pub segments: Vec<Segment>,
+    pub children: Vec<LineageNode>,
+}
+
+// FNV-1a, stable across runs and builds unlike the std hasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+impl LineageNode {
+    pub fn new(operator: String, columns: Vec<String>, children: Vec<LineageNode>) -> LineageNode {
+        LineageNode { operator, columns, segments: Vec::new(), children }
+    }
+
+    pub fn id(&self) -> String {
+        let mut text = format!("{}\n{}\n", self.operator, self.columns.join(","));
+        for segment in &self.segments {
+            text += &format!("{} {}", segment.path, segment.size);
+            if let Some(modified) = segment.modified {
+                text += &format!(" {}", modified);
+            }
+            text += "\n";
+        }
+        for child in &self.children {
+            text += &child.id();
+            text += "\n";
+        }
+        format!("{:016x}", fnv1a(text.as_bytes()))
+    }
+
+    // Every node once, children before parents, with its children as ids
+    fn flatten<'a>(&'a self, nodes: &mut Vec<(String, &'a LineageNode, Vec<String>)>, seen: &mut HashSet<String>) -> String {
+        let child_ids: Vec<String> = self.children.iter().map(|child| child.flatten(nodes, seen)).collect();
+        let id = self.id();
+        if seen.insert(id.clone()) {
+            nodes.push((id.clone(), self, child_ids));
+        }
+        id
+    }
+}
+
+fn lineage_directory() -> String {
+    DATA_DIRECTORY.to_owned() + "_lineage/"
+}
+
+// Results are named like `analyze.orders_per_month`, keep them to something that's a safe file name
+fn result_path(result: &str) -> Result<String, String> {
+    if result.is_empty() || !result.chars().all(|c| c.is_alphanumeric() || "._-".contains(c)) || result.starts_with('.') {
+        return Err(format!("Bad result name {:?}", result));
+    }
+    Ok(lineage_directory() + result)
+}
+
+// Ad hoc results (sql, query, scan) each get a file named after when they ran, only this many of
+// the latest are kept. analyze's results have fixed names and replace themselves.
+const AD_HOC_KEPT: usize = 100;
+
+// The run time of an ad hoc result's name, e.g. 20221004T153012123 for scan.20221004T153012123
+fn ad_hoc_time(result: &str) -> Option<&str> {
+    let (_, time) = result.rsplit_once('.')?;
+    let (date, clock) = time.split_once('T')?;
+    match date.len() == 8 && clock.len() == 9 && date.chars().chain(clock.chars()).all(|c| c.is_ascii_digit()) {
+        true => Some(time),
+        false => None,
+    }
+}
+
+// Takes out all but the latest AD_HOC_KEPT ad hoc results
+fn prune() -> Result<(), String> {
+    let mut ad_hoc: Vec<(&str, String)> = Vec::new();
+    let results = stored_results();
+    for result in &results {
+        if let Some(time) = ad_hoc_time(result) {
+            ad_hoc.push((time, result.clone()));
+        }
+    }
+    ad_hoc.sort();
+    let excess = ad_hoc.len().saturating_sub(AD_HOC_KEPT);
+    for (_, result) in &ad_hoc[..excess] {
+        let path = result_path(result)?;
+        fs::remove_file(&path).map_err(|err| format!("Can't remove old lineage {}: {}", path, err))?;
+    }
+    Ok(())
+}
+
+// Plain text, one `key value` per line so it reads fine without this tool too:
+//   result <name> / created <time> / root <id>
+//   node <id> / operator <text> / column <name> / segment <size> <modified> <path> / child <id>
+pub fn save(result: &str, root: &LineageNode) -> Result<(), String> {
+    let path = result_path(result)?;
+    fs::create_dir_all(lineage_directory()).map_err(|err| format!("Can't create lineage directory: {}", err))?;
+    let mut nodes: Vec<(String, &LineageNode, Vec<String>)> = Vec::new();
+    let root_id = root.flatten(&mut nodes, &mut HashSet::new());
+    let mut text = format!("result {}\ncreated {}\nroot {}\n", result, Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true), root_id);
+    for (id, node, children) in nodes {
+        text += &format!("node {}\noperator {}\n", id, node.operator.replace('\n', " "));
+        for column in &node.columns {
+            text += &format!("column {}\n", column);
+        }
+        for segment in &node.segments {
+            let modified = segment.modified.map(|modified| modified.to_string()).unwrap_or("-".to_string());
+            text += &format!("segment {} {} {}\n", segment.size, modified, segment.path);
+        }
+        for child in children {
+            text += &format!("child {}\n", child);
+        }
+    }
+    fs::write(&path, text).map_err(|err| format!("Can't write lineage {}: {}", path, err))?;
+    prune()
+}
+
+pub struct StoredLineage {
+    pub created: String,
+    pub root: LineageNode,
+}
+
+pub fn load(result: &str) -> Result<StoredLineage, String> {
+    let path = result_path(result)?;
+    let text = fs::read_to_string(&path).map_err(|_| format!("No lineage stored for {:?}", result))?;
+    let mut created = String::new();
+    let mut root_id = String::new();
+    // Nodes with their children still as ids
+    let mut nodes: HashMap<String, (LineageNode, Vec<String>)> = HashMap::new();
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        let node = current.as_ref().and_then(|id| nodes.get_mut(id));
+        match (key, node) {
+            ("result", _) => {},
+            ("created", _) => created = value.to_string(),
+            ("root", _) => root_id = value.to_string(),
+            ("node", _) => {
+                nodes.insert(value.to_string(), (LineageNode::new(String::new(), Vec::new(), Vec::new()), Vec::new()));
+                current = Some(value.to_string());
+            },
+            ("operator", Some((node, _))) => node.operator = value.to_string(),
+            ("column", Some((node, _))) => node.columns.push(value.to_string()),
+            ("segment", Some((node, _))) => {
+                let bad = || format!("Bad segment line {:?}", line);
+                let (size, rest) = value.split_once(' ').ok_or_else(bad)?;
+                let size = size.parse::<u64>().map_err(|_| bad())?;
+                // Older files have just `segment <size> <path>`, paths start with the data directory
+                let (modified, path) = match rest.split_once(' ') {
+                    Some(("-", path)) => (None, path),
+                    Some((modified, path)) if modified.chars().all(|c| c.is_ascii_digit()) => (Some(modified.parse::<u64>().map_err(|_| bad())?), path),
+                    _ => (None, rest),
+                };
+                node.segments.push(Segment { path: path.to_string(), size, modified });
+            },
+            ("child", Some((_, children))) => children.push(value.to_string()),
+            _ => return Err(format!("Bad lineage line {:?} in {}", line, path)),
+        }
+    }
+    Ok(StoredLineage { created, root: assemble(&root_id, &nodes)? })
+}
+
+fn assemble(id: &str, nodes: &HashMap<String, (LineageNode, Vec<String>)>) -> Result<LineageNode, String> {
+    let (node, children) = nodes.get(id).ok_or(format!("Lineage node {} missing", id))?;
+    let mut children_nodes: Vec<LineageNode> = Vec::new();
+    for child in children {
+        children_nodes.push(assemble(child, nodes)?);
+    }
+    Ok(LineageNode { children: children_nodes, ..node.clone() })
+}
+
+pub fn stored_results() -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(lineage_directory()) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().to_string()).collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names
+}
+
+// Tree form for the lineage command. A node that shows up more than once only gets its inputs
+// written out the first time. Segments that have changed or gone since are flagged, since the
+// result wouldn't come out the same anymore.
+pub fn print_tree(node: &LineageNode, depth: usize, printed: &mut HashSet<String>) {
+    let indent = "    ".repeat(depth);
+    let id = node.id();
+    println!("{}{} [{}]", indent, node.operator, id);
+    if !printed.insert(id) {
+        println!("{}    (shown above)", indent);
+        return;
+    }
+    if !node.columns.is_empty() {
+        println!("{}    columns: {}", indent, node.columns.join(", "));
+    }
+    for segment in &node.segments {
+        let status = match segment.current() {
+            Some((size, modified)) if segment.unchanged(size, modified) => String::new(),
+            Some((size, _)) if size == segment.size => " (rewritten since)".to_string(),
+            Some((size, _)) => format!(" (now {} bytes)", size),
+            None => " (gone)".to_string(),
+        };
+        println!("{}    segment: {} {} bytes{}", indent, segment.path, segment.size, status);
+    }
+    for child in &node.children {
+        print_tree(child, depth + 1, printed);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::datagen::catalog::KeyOrder;
+use super::batch::{Batch, Value};
+use super::expr::Expr;
+use super::join::{join_schema, normalize_key, JoinType};
+use super::operator::{Operator, BATCH_ROWS};
+use std::cmp::Ordering;
+
+// One input of a merge join, walked a row at a time across its batches
+struct Cursor {
+    input: Box<dyn Operator>,
+    key: Expr,
+    batch: Option<Batch>,
+    keys: Vec<Value>,
+    row: usize,
+    done: bool,
+}
+
+impl Cursor {
+    fn new(input: Box<dyn Operator>, key: Expr) -> Cursor {
+        Cursor { input, key, batch: None, keys: Vec::new(), row: 0, done: false }
+    }
+
+    // Key of the current row, pulling the next batch once this one is used up. None at the end.
+    fn peek(&mut self) -> Result<Option<Value>, String> {
+        while !self.done && self.row >= self.keys.len() {
+            match self.input.next_batch()? {
+                Some(batch) => {
+                    self.keys = self.key.eval(&batch)?.iter().map(normalize_key).collect();
+                    self.batch = Some(batch);
+                    self.row = 0;
+                },
+                None => {
+                    self.batch = None;
+                    self.keys = Vec::new();
+                    self.done = true;
+                },
+            }
+        }
+        Ok(self.keys.get(self.row).cloned())
+    }
+
+    fn take_row(&mut self) -> Vec<Value> {
+        let batch = self.batch.as_ref().unwrap();
+        let row = batch.columns.iter().map(|col| col[self.row].clone()).collect();
+        self.row += 1;
+        row
+    }
+
+    fn skip_row(&mut self) {
+        self.row += 1;
+    }
+}
+
+// Equi-join over two inputs that are already laid out the same way on their keys, so it's one
+// pass over both and only the right rows for the current key are ever held. Sorted inputs skip
+// past right keys that are too small. Clustered inputs can't tell small from missing, so the
+// left has to be the primary of the clustering, then any right key that isn't the current left
+// key must belong to a later left row.
+pub struct MergeJoin {
+    left: Cursor,
+    right: Cursor,
+    join_type: JoinType,
+    sorted: bool,
+    left_names: Vec<String>,
+    right_names: Vec<String>,
+    run_key: Option<Value>,
+    run: Vec<Vec<Value>>,
+    pending: Batch,
+    finished: bool,
+}
+
+impl MergeJoin {
+    pub fn new(left: Box<dyn Operator>, right: Box<dyn Operator>, left_key: Expr, right_key: Expr, join_type: JoinType, sorted: bool) -> MergeJoin {
+        let left_names = left.schema();
+        let right_names = right.schema();
+        let names = join_schema(&left_names, &right_names, join_type);
+        MergeJoin {
+            left: Cursor::new(left, left_key),
+            right: Cursor::new(right, right_key),
+            join_type,
+            sorted,
+            left_names,
+            right_names,
+            run_key: None,
+            run: Vec::new(),
+            pending: Batch::new(names.clone(), vec![Vec::new(); names.len()]),
+            finished: false,
+        }
+    }
+
+    // Pulls every right row with this key into the run, dropping whatever comes before it
+    fn gather(&mut self, key: &Value) -> Result<(), String> {
+        self.run.clear();
+        self.run_key = Some(key.clone());
+        while let Some(right_key) = self.right.peek()? {
+            if right_key.is_null() || (self.sorted && right_key.compare(key) == Some(Ordering::Less)) {
+                self.right.skip_row();
+            } else if right_key == *key {
+                let row = self.right.take_row();
+                self.run.push(row);
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn push_row(&mut self, row: Vec<Value>) {
+        for (col, value) in self.pending.columns.iter_mut().zip(row) {
+            col.push(value);
+        }
+    }
+
+    // Joins the current left row against the run and moves past it
+    fn join_row(&mut self, key: &Value) -> Result<(), String> {
+        if !key.is_null() && self.run_key.as_ref() != Some(key) {
+            self.gather(key)?;
+        }
+        let matched = !key.is_null() && !self.run.is_empty();
+        let left = self.left.take_row();
+        match (self.join_type, matched) {
+            (JoinType::Inner, true) | (JoinType::Left, true) => {
+                let rows: Vec<Vec<Value>> = self.run.iter()
+                    .map(|right| left.iter().chain(right.iter()).cloned().collect())
+                    .collect();
+                for row in rows {
+                    self.push_row(row);
+                }
+            },
+            (JoinType::Left, false) => {
+                let row = left.into_iter().chain(vec![Value::Null; self.right_names.len()]).collect();
+                self.push_row(row);
+            },
+            (JoinType::Semi, true) | (JoinType::Anti, false) => self.push_row(left),
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn take_pending(&mut self) -> Batch {
+        let empty = self.pending.empty_like();
+        std::mem::replace(&mut self.pending, empty)
+    }
+}
+
+impl Operator for MergeJoin {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        while !self.finished && self.pending.num_rows() < BATCH_ROWS {
+            match self.left.peek()? {
+                Some(key) => self.join_row(&key)?,
+                None => {
+                    // Anything left over on a clustered right side never lined up with the left,
+                    // so the results so far can't be trusted either
+                    if !self.sorted {
+                        while let Some(right_key) = self.right.peek()? {
+                            if !right_key.is_null() {
+                                return Err(format!("Merge join inputs aren't clustered the same way, {} ran out before {} did", self.left_names.join(", "), self.right_names.join(", ")));
+                            }
+                            self.right.skip_row();
+                        }
+                    }
+                    self.finished = true;
+                },
+            }
+        }
+        match self.pending.num_rows() {
+            0 => Ok(None),
+            _ => Ok(Some(self.take_pending())),
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        join_schema(&self.left_names, &self.right_names, self.join_type)
+    }
+
+    fn name(&self) -> String {
+        let layout = match self.sorted {
+            true => "sorted",
+            false => "clustered",
+        };
+        format!("MergeJoin {} on {} = {} ({})", self.join_type, self.left.key, self.right.key, layout)
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.left.input.as_ref(), self.right.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.left.input, &mut self.right.input]
+    }
+
+    fn memory_bytes(&self) -> usize {
+        // Only the right rows for the current key, past the batch each side is on
+        let run: usize = self.run.iter().map(|row| row.iter().map(|val| val.size()).sum::<usize>()).sum();
+        let batches: usize = [&self.left.batch, &self.right.batch].iter().map(|batch| batch.as_ref().map(|batch| batch.size()).unwrap_or(0)).sum();
+        run + batches + self.pending.size()
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        match (self.left.input.estimated_rows(), self.right.input.estimated_rows()) {
+            (Some(left), Some(right)) => Some(match self.join_type {
+                JoinType::Inner => left.max(right),
+                _ => left,
+            }),
+            _ => None,
+        }
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        // Rows come out in left order, but keys can now repeat or go missing
+        self.left.input.orderings().into_iter().map(|ordering| KeyOrder { primary: false, ..ordering }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::expr::col;
+    use crate::analyze::join::HashJoin;
+    use crate::analyze::operator::{collect, BatchSource};
+
+    fn source(names: &[&str], rows: &[Vec<Value>], batch_rows: usize) -> Box<dyn Operator> {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let batches = rows.chunks(batch_rows)
+            .map(|chunk| Batch::new(names.clone(), (0..names.len()).map(|index| chunk.iter().map(|row| row[index].clone()).collect()).collect()))
+            .collect();
+        Box::new(BatchSource::new(batches))
+    }
+
+    fn rows(mut operator: Box<dyn Operator>) -> Vec<Vec<Value>> {
+        let batch = collect(operator.as_mut()).unwrap();
+        let mut rows: Vec<Vec<Value>> = (0..batch.num_rows()).map(|row| batch.columns.iter().map(|col| col[row].clone()).collect()).collect();
+        rows.sort();
+        rows
+    }
+
+    // Orders with their ids in no particular order and the products of each in that same order,
+    // some orders without any
+    fn clustered(join_type: JoinType, batch_rows: usize) -> (Vec<Vec<Value>>, Vec<Vec<Value>>) {
+        let id = |id: i64| Value::Int64(id);
+        let orders: Vec<Vec<Value>> = [50, 20, 90, 70, 10, 30].iter().map(|order| vec![id(*order)]).collect();
+        let products: Vec<Vec<Value>> = [(50, 1), (50, 2), (90, 3), (70, 4), (70, 5), (70, 6), (30, 7)].iter()
+            .map(|(order, product)| vec![id(*order), id(*product)])
+            .collect();
+        let merged = MergeJoin::new(
+            source(&["o.id"], &orders, batch_rows), source(&["p.order_id", "p.product"], &products, batch_rows),
+            col("o.id"), col("p.order_id"), join_type, false,
+        );
+        let hashed = HashJoin::new(
+            source(&["o.id"], &orders, batch_rows), source(&["p.order_id", "p.product"], &products, batch_rows),
+            col("o.id"), col("p.order_id"), join_type,
+        );
+        (rows(Box::new(merged)), rows(Box::new(hashed)))
+    }
+
+    #[test]
+    fn clustered_inputs_join_like_a_hash_join() {
+        for join_type in [JoinType::Inner, JoinType::Left, JoinType::Semi, JoinType::Anti] {
+            for batch_rows in [1, 2, 100] {
+                let (merged, hashed) = clustered(join_type, batch_rows);
+                assert_eq!(merged, hashed, "{} join, {} rows a batch", join_type, batch_rows);
+            }
+        }
+        assert_eq!(clustered(JoinType::Inner, 3).0.len(), 7);
+        assert_eq!(clustered(JoinType::Left, 3).0.len(), 9);
+    }
+
+    #[test]
+    fn clustered_inputs_out_of_step_are_an_error() {
+        let id = |id: i64| vec![Value::Int64(id)];
+        let mut join = MergeJoin::new(
+            source(&["o.id"], &[id(1), id(2)], 10), source(&["p.order_id"], &[id(2), id(1)], 10),
+            col("o.id"), col("p.order_id"), JoinType::Inner, false,
+        );
+        let mut result = Ok(None);
+        for _ in 0..3 {
+            result = join.next_batch();
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(result.is_err());
+    }
+}
@@ -1 +1,26 @@
-pub mod process;
\ No newline at end of file
+pub mod process;
+pub mod kernels;
+pub mod batch;
+pub mod expr;
+pub mod parse;
+pub mod operator;
+pub mod scan;
+pub mod aggregate;
+pub mod join;
+pub mod merge_join;
+pub mod top_k;
+pub mod sort;
+pub mod sql;
+pub mod planner;
+pub mod explain;
+pub mod spill;
+pub mod hll;
+pub mod approx_distinct;
+pub mod quantile;
+pub mod calendar;
+pub mod window;
+pub mod sample;
+pub mod view;
+pub mod cache;
+pub mod lineage;
+pub mod apl;
@@ -0,0 +1,313 @@
+use crate::datagen::catalog::KeyOrder;
+use super::batch::Batch;
+use super::explain::OperatorMetrics;
+use super::expr::Expr;
+use super::lineage::LineageNode;
+
+// Rows per batch handed between operators
+pub const BATCH_ROWS: usize = 8192;
+
+// Pull based operators, each call hands back the next batch of rows until there's none left.
+// Trees of these get built up with the inputs boxed inside each operator.
+pub trait Operator {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String>;
+
+    // Column names of every batch this hands out
+    fn schema(&self) -> Vec<String>;
+
+    // Short description with the operator's arguments, e.g. `Filter (quantity > 5)`
+    fn name(&self) -> String;
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        Vec::new()
+    }
+
+    // The same inputs as children, so they can be swapped out (wrapped to be metered and so on)
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        Vec::new()
+    }
+
+    // Tree of everything that went into this operator's output, leaves say which files got read
+    fn lineage(&self) -> LineageNode {
+        LineageNode::new(self.name(), self.schema(), self.children().iter().map(|child| child.lineage()).collect())
+    }
+
+    // Whether running it again over the same segment files hands out the same rows, which is what
+    // lets its output be cached
+    fn repeatable(&self) -> bool {
+        self.children().iter().all(|child| child.repeatable())
+    }
+
+    // Rough row count if there's any way to know it up front, joins use it to pick a build side
+    fn estimated_rows(&self) -> Option<u64> {
+        None
+    }
+
+    // How the rows come out laid out on columns of the schema, as far as that's known. Joins use it
+    // to merge instead of hash.
+    fn orderings(&self) -> Vec<KeyOrder> {
+        Vec::new()
+    }
+
+    // What this operator read off disk itself, not counting its inputs
+    fn bytes_read(&self) -> u64 {
+        0
+    }
+
+    fn segments_skipped(&self) -> u64 {
+        0
+    }
+
+    // Rows and state being held right now, for anything that buffers more than a batch
+    fn memory_bytes(&self) -> usize {
+        0
+    }
+
+    // What went out to scratch files once memory_bytes would have gone over the budget
+    fn bytes_spilled(&self) -> u64 {
+        0
+    }
+
+    // Only metered operators have any, see explain
+    fn metrics(&self) -> Option<OperatorMetrics> {
+        None
+    }
+}
+
+// Whether a key expression is just the column the ordering is on, by full or unqualified name
+pub fn ordered_on(ordering: &KeyOrder, key: &Expr) -> bool {
+    match key {
+        Expr::Column(name) => ordering.column == *name || ordering.column.ends_with(&format!(".{}", name)),
+        _ => false,
+    }
+}
+
+// Rows dropped out of a primary input leave keys that other clustered inputs still have
+fn without_primary(orderings: Vec<KeyOrder>) -> Vec<KeyOrder> {
+    orderings.into_iter().map(|ordering| KeyOrder { primary: false, ..ordering }).collect()
+}
+
+// Drains an operator into a single batch, for results small enough to hold at once
+pub fn collect(operator: &mut dyn Operator) -> Result<Batch, String> {
+    let mut result: Option<Batch> = None;
+    while let Some(batch) = operator.next_batch()? {
+        match result.as_mut() {
+            Some(existing) => existing.append(batch),
+            None => result = Some(batch),
+        }
+    }
+    Ok(result.unwrap_or_default())
+}
+
+// Replays batches that are already in memory, for feeding one result into another operator
+pub struct BatchSource {
+    names: Vec<String>,
+    rows: u64,
+    batches: std::vec::IntoIter<Batch>,
+    source: Option<LineageNode>,
+}
+
+impl BatchSource {
+    pub fn new(batches: Vec<Batch>) -> BatchSource {
+        BatchSource {
+            names: batches.first().map(|batch| batch.names.clone()).unwrap_or_default(),
+            rows: batches.iter().map(|batch| batch.num_rows() as u64).sum(),
+            batches: batches.into_iter(),
+            source: None,
+        }
+    }
+
+    // Batches that came out of some other operator, so lineage carries on through them
+    pub fn from_result(batches: Vec<Batch>, source: LineageNode) -> BatchSource {
+        BatchSource { source: Some(source), ..BatchSource::new(batches) }
+    }
+}
+
+impl Operator for BatchSource {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        Ok(self.batches.next())
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    fn name(&self) -> String {
+        format!("BatchSource {} rows", self.rows)
+    }
+
+    fn lineage(&self) -> LineageNode {
+        match &self.source {
+            Some(source) => source.clone(),
+            None => LineageNode::new(self.name(), self.schema(), Vec::new()),
+        }
+    }
+
+    // Without a source there's nothing saying where the rows came from
+    fn repeatable(&self) -> bool {
+        self.source.is_some()
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        Some(self.rows)
+    }
+}
+
+pub struct Filter {
+    input: Box<dyn Operator>,
+    predicate: Expr,
+}
+
+impl Filter {
+    pub fn new(input: Box<dyn Operator>, predicate: Expr) -> Filter {
+        Filter { input, predicate }
+    }
+}
+
+impl Operator for Filter {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        // Keep pulling until something passes so callers don't get a stream of empty batches
+        while let Some(batch) = self.input.next_batch()? {
+            let mask = self.predicate.eval_mask(&batch)?;
+            let filtered = batch.filter(&mask);
+            if filtered.num_rows() > 0 {
+                return Ok(Some(filtered));
+            }
+        }
+        Ok(None)
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.input.schema()
+    }
+
+    fn name(&self) -> String {
+        format!("Filter {}", self.predicate)
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        self.input.estimated_rows().map(|rows| (rows as f64 * self.predicate.selectivity()).round() as u64)
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        without_primary(self.input.orderings())
+    }
+}
+
+pub struct Project {
+    input: Box<dyn Operator>,
+    exprs: Vec<(String, Expr)>,
+}
+
+impl Project {
+    pub fn new(input: Box<dyn Operator>, exprs: Vec<(String, Expr)>) -> Project {
+        Project { input, exprs }
+    }
+}
+
+impl Operator for Project {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        match self.input.next_batch()? {
+            None => Ok(None),
+            Some(batch) => {
+                let mut columns = Vec::new();
+                for (_, expr) in &self.exprs {
+                    columns.push(expr.eval(&batch)?);
+                }
+                let names = self.exprs.iter().map(|(name, _)| name.clone()).collect();
+                Ok(Some(Batch { page: batch.page, ..Batch::new(names, columns) }))
+            },
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.exprs.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn name(&self) -> String {
+        format!("Project {}", self.exprs.iter().map(|(name, expr)| format!("{} AS {}", expr, name)).collect::<Vec<String>>().join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        self.input.estimated_rows()
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        // Each survives as long as its column gets passed through as is
+        self.input.orderings().into_iter()
+            .filter_map(|ordering| self.exprs.iter()
+                .find(|(_, expr)| ordered_on(&ordering, expr))
+                .map(|(name, _)| KeyOrder { column: name.clone(), ..ordering.clone() }))
+            .collect()
+    }
+}
+
+// Stops the stream after a number of rows
+pub struct Limit {
+    input: Box<dyn Operator>,
+    limit: usize,
+    seen: usize,
+}
+
+impl Limit {
+    pub fn new(input: Box<dyn Operator>, limit: usize) -> Limit {
+        Limit { input, limit, seen: 0 }
+    }
+}
+
+impl Operator for Limit {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.seen >= self.limit {
+            return Ok(None);
+        }
+        match self.input.next_batch()? {
+            None => Ok(None),
+            Some(batch) => {
+                let keep = (self.limit - self.seen).min(batch.num_rows());
+                self.seen += keep;
+                let mask: Vec<bool> = (0..batch.num_rows()).map(|row| row < keep).collect();
+                Ok(Some(batch.filter(&mask)))
+            },
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.input.schema()
+    }
+
+    fn name(&self) -> String {
+        format!("Limit {}", self.limit)
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        Some(self.input.estimated_rows().map(|rows| rows.min(self.limit as u64)).unwrap_or(self.limit as u64))
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        without_primary(self.input.orderings())
+    }
+}
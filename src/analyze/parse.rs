@@ -0,0 +1,525 @@
+use crate::datagen::dataset::DataType;
+use super::batch::Value;
+use super::aggregate::{millionths, AggregateFunction};
+use super::expr::{cast, ArithmeticOp, CompareOp, Expr, ScalarFunction};
+use super::hll::{MAX_PRECISION, MIN_PRECISION};
+use super::sort::SortKey;
+use super::window::{FrameBound, FrameUnits, WindowExpr, WindowFrame, WindowFunction};
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::str::FromStr;
+
+// Text form of expressions, e.g. `price_per * quantity > 100 AND created >= DATE '2022-09-01'`.
+// Keywords are case insensitive and come through as plain identifiers, so anything that wants
+// to parse a bigger language on top (statements and so on) can reuse the same tokens and Parser.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Number(String),
+    Str(String),
+    Symbol(String),
+}
+
+pub fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            tokens.push(Token::Ident(chars[start..pos].iter().collect()));
+        } else if c.is_ascii_digit() {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_digit() || chars[pos] == '.') {
+                pos += 1;
+            }
+            tokens.push(Token::Number(chars[start..pos].iter().collect()));
+        } else if c == '\'' {
+            // Strings are single quoted, a doubled quote is a literal quote
+            let mut text = String::new();
+            pos += 1;
+            loop {
+                if pos >= chars.len() {
+                    return Err("Unterminated string literal".to_string());
+                }
+                if chars[pos] == '\'' {
+                    if pos + 1 < chars.len() && chars[pos + 1] == '\'' {
+                        text.push('\'');
+                        pos += 2;
+                        continue;
+                    }
+                    pos += 1;
+                    break;
+                }
+                text.push(chars[pos]);
+                pos += 1;
+            }
+            tokens.push(Token::Str(text));
+        } else {
+            let two: String = chars[pos..(pos + 2).min(chars.len())].iter().collect();
+            if ["<=", ">=", "!=", "<>", "=="].contains(&two.as_str()) {
+                tokens.push(Token::Symbol(two));
+                pos += 2;
+            } else if "=<>+-*/%(),.;".contains(c) {
+                tokens.push(Token::Symbol(c.to_string()));
+                pos += 1;
+            } else {
+                return Err(format!("Unexpected character {:?} at position {}", c, pos));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+pub fn parse_data_type(name: &str) -> Result<DataType, String> {
+    match name.to_uppercase().as_str() {
+        "BOOL" | "BOOLEAN" => Ok(DataType::Bool),
+        "STRING" | "TEXT" | "VARCHAR" => Ok(DataType::String),
+        "INT64" | "BIGINT" | "INT" | "INTEGER" => Ok(DataType::Int64),
+        "INT8" | "TINYINT" => Ok(DataType::Int8),
+        "UINT64" => Ok(DataType::UInt64),
+        "UINT8" => Ok(DataType::UInt8),
+        "DATETIME" | "TIMESTAMP" | "DATE" => Ok(DataType::DateTime),
+        "DECIMAL" | "NUMERIC" => Ok(DataType::Decimal),
+        "UUID" => Ok(DataType::Uuid),
+        _ => Err(format!("Unknown type {:?}", name)),
+    }
+}
+
+pub struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    pub fn new(text: &str) -> Result<Parser, String> {
+        Ok(Parser { tokens: tokenize(text)?, pos: 0 })
+    }
+
+    pub fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    pub fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    pub fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Ident(ident)) if ident.eq_ignore_ascii_case(keyword))
+    }
+
+    pub fn is_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(sym)) if sym == symbol)
+    }
+
+    pub fn accept_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    pub fn accept_symbol(&mut self, symbol: &str) -> bool {
+        if self.is_symbol(symbol) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    pub fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.accept_keyword(keyword) {
+            true => Ok(()),
+            false => Err(format!("Expected {} but found {:?}", keyword, self.peek())),
+        }
+    }
+
+    pub fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        match self.accept_symbol(symbol) {
+            true => Ok(()),
+            false => Err(format!("Expected {:?} but found {:?}", symbol, self.peek())),
+        }
+    }
+
+    pub fn expect_ident(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(ident)) => Ok(ident),
+            other => Err(format!("Expected a name but found {:?}", other)),
+        }
+    }
+
+    pub fn expect_end(&mut self) -> Result<(), String> {
+        self.accept_symbol(";");
+        match self.peek() {
+            None => Ok(()),
+            Some(token) => Err(format!("Unexpected {:?} after the end of the expression", token)),
+        }
+    }
+
+    pub fn parse_expr(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.accept_keyword("OR") {
+            expr = expr.or(self.parse_and()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_not()?;
+        while self.accept_keyword("AND") {
+            expr = expr.and(self.parse_not()?);
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        if self.accept_keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let left = self.parse_additive()?;
+        if self.accept_keyword("IS") {
+            let negated = self.accept_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            let is_null = Expr::IsNull(Box::new(left));
+            return Ok(match negated {
+                true => Expr::Not(Box::new(is_null)),
+                false => is_null,
+            });
+        }
+        let op = match self.peek() {
+            Some(Token::Symbol(sym)) => match sym.as_str() {
+                "=" | "==" => Some(CompareOp::Eq),
+                "!=" | "<>" => Some(CompareOp::NotEq),
+                "<" => Some(CompareOp::Lt),
+                "<=" => Some(CompareOp::LtEq),
+                ">" => Some(CompareOp::Gt),
+                ">=" => Some(CompareOp::GtEq),
+                _ => None,
+            },
+            _ => None,
+        };
+        match op {
+            Some(op) => {
+                self.pos += 1;
+                Ok(left.compare(op, self.parse_additive()?))
+            },
+            None => Ok(left),
+        }
+    }
+
+    fn parse_additive(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_multiplicative()?;
+        loop {
+            if self.accept_symbol("+") {
+                expr = expr.arithmetic(ArithmeticOp::Add, self.parse_multiplicative()?);
+            } else if self.accept_symbol("-") {
+                expr = expr.arithmetic(ArithmeticOp::Subtract, self.parse_multiplicative()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_multiplicative(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            if self.accept_symbol("*") {
+                expr = expr.arithmetic(ArithmeticOp::Multiply, self.parse_unary()?);
+            } else if self.accept_symbol("/") {
+                expr = expr.arithmetic(ArithmeticOp::Divide, self.parse_unary()?);
+            } else if self.accept_symbol("%") {
+                expr = expr.arithmetic(ArithmeticOp::Modulo, self.parse_unary()?);
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.accept_symbol("-") {
+            let inner = self.parse_unary()?;
+            return Ok(match inner {
+                Expr::Literal(Value::Int64(val)) => Expr::Literal(Value::Int64(-val)),
+                Expr::Literal(Value::Decimal(val)) => Expr::Literal(Value::Decimal(-val)),
+                other => Expr::Literal(Value::Int64(0)).arithmetic(ArithmeticOp::Subtract, other),
+            });
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Number(text)) => parse_number(&text).map(Expr::Literal),
+            Some(Token::Str(text)) => Ok(Expr::Literal(Value::String(text))),
+            Some(Token::Symbol(sym)) if sym == "(" => {
+                let expr = self.parse_expr()?;
+                self.expect_symbol(")")?;
+                Ok(expr)
+            },
+            Some(Token::Ident(ident)) => {
+                let upper = ident.to_uppercase();
+                match upper.as_str() {
+                    "NULL" => return Ok(Expr::Literal(Value::Null)),
+                    "TRUE" => return Ok(Expr::Literal(Value::Bool(true))),
+                    "FALSE" => return Ok(Expr::Literal(Value::Bool(false))),
+                    "CAST" if self.is_symbol("(") => {
+                        self.expect_symbol("(")?;
+                        let inner = self.parse_expr()?;
+                        self.expect_keyword("AS")?;
+                        let data_type = parse_data_type(&self.expect_ident()?)?;
+                        self.expect_symbol(")")?;
+                        return Ok(inner.cast(data_type));
+                    },
+                    _ => {},
+                }
+                if self.is_symbol("(") {
+                    return self.parse_call(&ident);
+                }
+                // Typed literals like DATE '2022-09-01' or UUID '...' get converted right away
+                if let Some(Token::Str(text)) = self.peek().cloned() {
+                    if let Ok(data_type) = parse_data_type(&ident) {
+                        self.pos += 1;
+                        return cast(&Value::String(text), data_type).map(Expr::Literal);
+                    }
+                }
+                let mut name = ident;
+                while self.is_symbol(".") {
+                    self.pos += 1;
+                    name = name + "." + &self.expect_ident()?;
+                }
+                Ok(Expr::Column(name))
+            },
+            other => Err(format!("Unexpected {:?} in expression", other)),
+        }
+    }
+
+    fn parse_call(&mut self, name: &str) -> Result<Expr, String> {
+        self.expect_symbol("(")?;
+        if let Some(function) = AggregateFunction::from_name(name) {
+            // count(*) counts rows, and count(DISTINCT x) is its own aggregate
+            if function == AggregateFunction::Count && self.accept_symbol("*") {
+                self.expect_symbol(")")?;
+                let rows = Expr::Literal(Value::Bool(true));
+                if self.is_keyword("OVER") {
+                    return self.parse_over(WindowFunction::Aggregate(function), vec![rows]);
+                }
+                return Ok(Expr::Aggregate(function, Box::new(rows)));
+            }
+            let function = match function == AggregateFunction::Count && self.accept_keyword("DISTINCT") {
+                true => AggregateFunction::CountDistinct,
+                false => function,
+            };
+            let inner = self.parse_expr()?;
+            // approx_count_distinct(x, precision)
+            let function = match function {
+                AggregateFunction::ApproxCountDistinct(_) if self.accept_symbol(",") => {
+                    let precision = match self.parse_expr()? {
+                        Expr::Literal(value) => value.as_i128(),
+                        _ => None,
+                    };
+                    match precision {
+                        Some(precision) if (MIN_PRECISION as i128..=MAX_PRECISION as i128).contains(&precision) => {
+                            AggregateFunction::ApproxCountDistinct(precision as u8)
+                        },
+                        _ => return Err(format!("approx_count_distinct precision has to be a number from {} to {}", MIN_PRECISION, MAX_PRECISION)),
+                    }
+                },
+                // percentile(x, 0.9), median is just percentile(x, 0.5)
+                AggregateFunction::Percentile(_) | AggregateFunction::ApproxPercentile(_) if name.ends_with("percentile") => {
+                    self.expect_symbol(",")?;
+                    let fraction = match self.parse_expr()? {
+                        Expr::Literal(value) => value.as_decimal().and_then(|val| val.to_f64()),
+                        _ => None,
+                    };
+                    match (function, fraction) {
+                        (AggregateFunction::Percentile(_), Some(fraction)) if (0.0..=1.0).contains(&fraction) => AggregateFunction::Percentile(millionths(fraction)),
+                        (_, Some(fraction)) if (0.0..=1.0).contains(&fraction) => AggregateFunction::ApproxPercentile(millionths(fraction)),
+                        _ => return Err(format!("{} needs a fraction from 0 to 1, e.g. {}(x, 0.9)", name, name)),
+                    }
+                },
+                function => function,
+            };
+            self.expect_symbol(")")?;
+            // sum(x) OVER (...) is the running kind
+            if self.is_keyword("OVER") {
+                let window = WindowFunction::from_aggregate(function).ok_or(format!("{} can't be used as a window function", function))?;
+                return self.parse_over(window, vec![inner]);
+            }
+            return Ok(Expr::Aggregate(function, Box::new(inner)));
+        }
+        if let Some(function) = WindowFunction::from_name(name) {
+            let args = self.parse_args()?;
+            return self.parse_window_call(name, function, args);
+        }
+        let function = ScalarFunction::from_name(name).ok_or(format!("Unknown function {:?}", name))?;
+        Ok(Expr::Call(function, self.parse_args()?))
+    }
+
+    // Comma separated up to the closing paren, the opening one's already gone
+    fn parse_args(&mut self) -> Result<Vec<Expr>, String> {
+        let mut args: Vec<Expr> = Vec::new();
+        if !self.accept_symbol(")") {
+            loop {
+                args.push(self.parse_expr()?);
+                if self.accept_symbol(")") {
+                    break;
+                }
+                self.expect_symbol(",")?;
+            }
+        }
+        Ok(args)
+    }
+
+    fn parse_window_call(&mut self, name: &str, function: WindowFunction, mut args: Vec<Expr>) -> Result<Expr, String> {
+        match function {
+            // lag(x, offset, default), the offset has to be a plain number
+            WindowFunction::Lag(_) | WindowFunction::Lead(_) => {
+                if args.is_empty() || args.len() > 3 {
+                    return Err(format!("{} takes a value, an optional offset and an optional default", name));
+                }
+                let offset = match args.len() {
+                    1 => 1,
+                    _ => match args.remove(1) {
+                        Expr::Literal(value) => value.as_i128().and_then(|offset| usize::try_from(offset).ok())
+                            .ok_or(format!("{} offset has to be a row count, got {}", name, value))?,
+                        other => return Err(format!("{} offset has to be a row count, got {}", name, other)),
+                    },
+                };
+                let function = match function {
+                    WindowFunction::Lag(_) => WindowFunction::Lag(offset),
+                    _ => WindowFunction::Lead(offset),
+                };
+                self.parse_over(function, args)
+            },
+            _ if !args.is_empty() => Err(format!("{} doesn't take arguments", name)),
+            _ => self.parse_over(function, args),
+        }
+    }
+
+    // `OVER ([PARTITION BY expr, ...] [ORDER BY expr [DESC], ...] [ROWS|RANGE frame])`
+    fn parse_over(&mut self, function: WindowFunction, args: Vec<Expr>) -> Result<Expr, String> {
+        self.expect_keyword("OVER")?;
+        self.expect_symbol("(")?;
+        let mut partition_by: Vec<Expr> = Vec::new();
+        if self.accept_keyword("PARTITION") {
+            self.expect_keyword("BY")?;
+            loop {
+                partition_by.push(self.parse_expr()?);
+                if !self.accept_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let mut order_by: Vec<SortKey> = Vec::new();
+        if self.accept_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let expr = self.parse_expr()?;
+                let descending = self.accept_keyword("DESC");
+                if !descending {
+                    self.accept_keyword("ASC");
+                }
+                order_by.push(SortKey::new(expr, descending));
+                if !self.accept_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let units = match (self.accept_keyword("ROWS"), self.accept_keyword("RANGE")) {
+            (true, _) => Some(FrameUnits::Rows),
+            (_, true) => Some(FrameUnits::Range),
+            _ => None,
+        };
+        let frame = match units {
+            // A lone bound is the start, the frame ends at the current row
+            Some(units) => Some(match self.accept_keyword("BETWEEN") {
+                true => {
+                    let start = self.parse_frame_bound()?;
+                    self.expect_keyword("AND")?;
+                    WindowFrame::new(units, start, self.parse_frame_bound()?, &order_by)?
+                },
+                false => WindowFrame::new(units, self.parse_frame_bound()?, FrameBound::CurrentRow, &order_by)?,
+            }),
+            None => None,
+        };
+        self.expect_symbol(")")?;
+        Ok(Expr::Window(Box::new(WindowExpr { function, args, partition_by, order_by, frame })))
+    }
+
+    fn parse_frame_bound(&mut self) -> Result<FrameBound, String> {
+        if self.accept_keyword("UNBOUNDED") {
+            return match (self.accept_keyword("PRECEDING"), self.accept_keyword("FOLLOWING")) {
+                (true, _) => Ok(FrameBound::UnboundedPreceding),
+                (_, true) => Ok(FrameBound::UnboundedFollowing),
+                _ => Err(format!("Expected PRECEDING or FOLLOWING but found {:?}", self.peek())),
+            };
+        }
+        if self.accept_keyword("CURRENT") {
+            self.expect_keyword("ROW")?;
+            return Ok(FrameBound::CurrentRow);
+        }
+        let offset = match self.next() {
+            Some(Token::Number(text)) => parse_number(&text)?,
+            other => return Err(format!("Expected a window frame bound but found {:?}", other)),
+        };
+        match (self.accept_keyword("PRECEDING"), self.accept_keyword("FOLLOWING")) {
+            (true, _) => Ok(FrameBound::Preceding(offset)),
+            (_, true) => Ok(FrameBound::Following(offset)),
+            _ => Err(format!("Expected PRECEDING or FOLLOWING but found {:?}", self.peek())),
+        }
+    }
+
+    // `expr [AS name], ...` with the name defaulting to the expression text
+    pub fn parse_named_exprs(&mut self) -> Result<Vec<(String, Expr)>, String> {
+        let mut exprs: Vec<(String, Expr)> = Vec::new();
+        loop {
+            let expr = self.parse_expr()?;
+            let name = match self.accept_keyword("AS") {
+                true => self.expect_ident()?,
+                false => expr.to_string(),
+            };
+            exprs.push((name, expr));
+            if !self.accept_symbol(",") {
+                return Ok(exprs);
+            }
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Result<Value, String> {
+    if text.contains('.') {
+        return Decimal::from_str(text).map(Value::Decimal).map_err(|_| format!("Bad number {:?}", text));
+    }
+    if let Ok(val) = i64::from_str(text) {
+        return Ok(Value::Int64(val));
+    }
+    u64::from_str(text).map(Value::UInt64).map_err(|_| format!("Bad number {:?}", text))
+}
+
+pub fn parse_expr(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser::new(text)?;
+    let expr = parser.parse_expr()?;
+    parser.expect_end()?;
+    Ok(expr)
+}
+
+pub fn parse_named_exprs(text: &str) -> Result<Vec<(String, Expr)>, String> {
+    let mut parser = Parser::new(text)?;
+    let exprs = parser.parse_named_exprs()?;
+    parser.expect_end()?;
+    Ok(exprs)
+}
@@ -0,0 +1,447 @@
+use crate::datagen::catalog::table_def;
+use crate::datagen::index;
+use super::approx_distinct::ApproxDistinctScan;
+use super::aggregate::{AggregateExpr, HashAggregate};
+use super::cache::ResultCache;
+use super::expr::Expr;
+use super::join::{join_schema, plan_join, JoinType};
+use super::operator::{Filter, Limit, Operator, Project};
+use super::sample::TableSample;
+use super::scan::TableScan;
+use super::sort::{Sort, SortKey};
+use super::view;
+use super::window::{plan_window, WindowExpr};
+
+// Query layers say what they want as a LogicalPlan, the rules below rewrite it, and to_physical
+// turns the result into operators. Every rule is a plain function from plan to plan, so each one
+// can be run and looked at by itself.
+
+#[derive(Debug, Clone)]
+pub enum LogicalPlan {
+    // Filters here get evaluated by the scan itself, which is what lets it skip segments
+    Scan { table: String, columns: Vec<String>, filters: Vec<Expr>, sample: Option<TableSample> },
+    Filter { input: Box<LogicalPlan>, predicate: Expr },
+    Project { input: Box<LogicalPlan>, exprs: Vec<(String, Expr)> },
+    Join { left: Box<LogicalPlan>, right: Box<LogicalPlan>, left_key: Expr, right_key: Expr, join_type: JoinType },
+    Aggregate { input: Box<LogicalPlan>, group_by: Vec<(String, Expr)>, aggregates: Vec<AggregateExpr> },
+    Sort { input: Box<LogicalPlan>, keys: Vec<SortKey> },
+    Limit { input: Box<LogicalPlan>, limit: usize },
+    // Window functions that share their PARTITION BY and ORDER BY, added as columns
+    Window { input: Box<LogicalPlan>, functions: Vec<(String, WindowExpr)> },
+}
+
+impl LogicalPlan {
+    // Every column of the table, pruning takes out whatever isn't needed later
+    pub fn scan(table: &str) -> Result<LogicalPlan, String> {
+        LogicalPlan::sampled_scan(table, None)
+    }
+
+    pub fn sampled_scan(table: &str, sample: Option<TableSample>) -> Result<LogicalPlan, String> {
+        let table = table_def(table)?;
+        let columns = table.columns.iter().map(|col| col.name.clone()).collect();
+        Ok(LogicalPlan::Scan { table: table.name, columns, filters: Vec::new(), sample })
+    }
+
+    pub fn filter(self, predicate: Expr) -> LogicalPlan {
+        LogicalPlan::Filter { input: Box::new(self), predicate }
+    }
+
+    pub fn project(self, exprs: Vec<(String, Expr)>) -> LogicalPlan {
+        LogicalPlan::Project { input: Box::new(self), exprs }
+    }
+
+    pub fn join(self, right: LogicalPlan, left_key: Expr, right_key: Expr, join_type: JoinType) -> LogicalPlan {
+        LogicalPlan::Join { left: Box::new(self), right: Box::new(right), left_key, right_key, join_type }
+    }
+
+    pub fn aggregate(self, group_by: Vec<(String, Expr)>, aggregates: Vec<AggregateExpr>) -> LogicalPlan {
+        LogicalPlan::Aggregate { input: Box::new(self), group_by, aggregates }
+    }
+
+    pub fn sort(self, keys: Vec<SortKey>) -> LogicalPlan {
+        LogicalPlan::Sort { input: Box::new(self), keys }
+    }
+
+    pub fn limit(self, limit: usize) -> LogicalPlan {
+        LogicalPlan::Limit { input: Box::new(self), limit }
+    }
+
+    pub fn window(self, functions: Vec<(String, WindowExpr)>) -> LogicalPlan {
+        LogicalPlan::Window { input: Box::new(self), functions }
+    }
+
+    // Same names the physical operator will hand out
+    pub fn schema(&self) -> Vec<String> {
+        match self {
+            LogicalPlan::Scan { table, columns, .. } => columns.iter().map(|col| format!("{}.{}", table, col)).collect(),
+            LogicalPlan::Filter { input, .. } | LogicalPlan::Sort { input, .. } | LogicalPlan::Limit { input, .. } => input.schema(),
+            LogicalPlan::Project { exprs, .. } => exprs.iter().map(|(name, _)| name.clone()).collect(),
+            LogicalPlan::Join { left, right, join_type, .. } => join_schema(&left.schema(), &right.schema(), *join_type),
+            LogicalPlan::Aggregate { group_by, aggregates, .. } => group_by.iter().map(|(name, _)| name.clone())
+                .chain(aggregates.iter().map(|agg| agg.name.clone()))
+                .collect(),
+            LogicalPlan::Window { input, functions } => input.schema().into_iter().chain(functions.iter().map(|(name, _)| name.clone())).collect(),
+        }
+    }
+}
+
+// Name lookups work like Batch::index_of, exact or by the bare column name
+fn matches_name(column: &str, name: &str) -> bool {
+    column == name || column.ends_with(&format!(".{}", name)) || name.ends_with(&format!(".{}", column))
+}
+
+fn resolves(schema: &[String], name: &str) -> bool {
+    schema.iter().any(|column| matches_name(column, name))
+}
+
+pub fn split_conjuncts(expr: Expr) -> Vec<Expr> {
+    match expr {
+        Expr::And(left, right) => {
+            let mut conjuncts = split_conjuncts(*left);
+            conjuncts.extend(split_conjuncts(*right));
+            conjuncts
+        },
+        other => vec![other],
+    }
+}
+
+pub fn conjoin(exprs: Vec<Expr>) -> Option<Expr> {
+    exprs.into_iter().reduce(|left, right| left.and(right))
+}
+
+// Swaps column references for the expressions that produce them below a Project or Aggregate.
+// None if the predicate uses anything else.
+fn substitute(predicate: &Expr, outputs: &[(String, Expr)]) -> Option<Expr> {
+    let mut missing = false;
+    let substituted = predicate.rewrite(&mut |expr| match expr {
+        Expr::Column(name) => {
+            let found = outputs.iter().filter(|(output, _)| matches_name(output, name)).collect::<Vec<_>>();
+            match found[..] {
+                [(_, source)] => Some(source.clone()),
+                _ => {
+                    missing = true;
+                    Some(expr.clone())
+                },
+            }
+        },
+        _ => None,
+    });
+    match missing {
+        true => None,
+        false => Some(substituted),
+    }
+}
+
+fn map_inputs(plan: LogicalPlan, rule: &dyn Fn(LogicalPlan) -> LogicalPlan) -> LogicalPlan {
+    let apply = |input: Box<LogicalPlan>| Box::new(rule(*input));
+    match plan {
+        LogicalPlan::Scan { .. } => plan,
+        LogicalPlan::Filter { input, predicate } => LogicalPlan::Filter { input: apply(input), predicate },
+        LogicalPlan::Project { input, exprs } => LogicalPlan::Project { input: apply(input), exprs },
+        LogicalPlan::Join { left, right, left_key, right_key, join_type } => LogicalPlan::Join { left: apply(left), right: apply(right), left_key, right_key, join_type },
+        LogicalPlan::Aggregate { input, group_by, aggregates } => LogicalPlan::Aggregate { input: apply(input), group_by, aggregates },
+        LogicalPlan::Sort { input, keys } => LogicalPlan::Sort { input: apply(input), keys },
+        LogicalPlan::Limit { input, limit } => LogicalPlan::Limit { input: apply(input), limit },
+        LogicalPlan::Window { input, functions } => LogicalPlan::Window { input: apply(input), functions },
+    }
+}
+
+// Rule: filters sink as far as they'll go, one AND'd condition at a time, ending up inside scans
+// where possible. They go through projections and onto group keys by substituting the
+// expressions, and into whichever side of a join has every column they use, but never below a
+// Limit, a Window or an aggregate without a GROUP BY, or into the right side of anything but an
+// inner join.
+pub fn push_down_filters(plan: LogicalPlan) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Filter { input, predicate } => {
+            let mut plan = push_down_filters(*input);
+            for conjunct in split_conjuncts(predicate) {
+                plan = push_filter(plan, conjunct);
+            }
+            plan
+        },
+        other => map_inputs(other, &push_down_filters),
+    }
+}
+
+fn push_filter(plan: LogicalPlan, predicate: Expr) -> LogicalPlan {
+    let columns = predicate.columns();
+    let only_in = |schema: &[String], other: &[String]| columns.iter().all(|name| resolves(schema, name) && !resolves(other, name));
+    match plan {
+        LogicalPlan::Scan { table, columns: scanned, mut filters, sample } => {
+            let schema: Vec<String> = scanned.iter().map(|col| format!("{}.{}", table, col)).collect();
+            if columns.iter().all(|name| resolves(&schema, name)) {
+                filters.push(predicate);
+                return LogicalPlan::Scan { table, columns: scanned, filters, sample };
+            }
+            LogicalPlan::Scan { table, columns: scanned, filters, sample }.filter(predicate)
+        },
+        LogicalPlan::Filter { input, predicate: existing } => push_filter(*input, predicate).filter(existing),
+        LogicalPlan::Sort { input, keys } => push_filter(*input, predicate).sort(keys),
+        LogicalPlan::Project { input, exprs } => match substitute(&predicate, &exprs) {
+            Some(substituted) => push_filter(*input, substituted).project(exprs),
+            None => input.project(exprs).filter(predicate),
+        },
+        // Without a GROUP BY there's one row out even with no rows in, so HAVING 1 = 0 has to stay
+        // above it
+        LogicalPlan::Aggregate { input, group_by, aggregates } => match (group_by.is_empty(), substitute(&predicate, &group_by)) {
+            (false, Some(substituted)) => push_filter(*input, substituted).aggregate(group_by, aggregates),
+            _ => input.aggregate(group_by, aggregates).filter(predicate),
+        },
+        LogicalPlan::Join { left, right, left_key, right_key, join_type } => {
+            let (left_schema, right_schema) = (left.schema(), right.schema());
+            if only_in(&left_schema, &right_schema) {
+                push_filter(*left, predicate).join(*right, left_key, right_key, join_type)
+            } else if join_type == JoinType::Inner && only_in(&right_schema, &left_schema) {
+                left.join(push_filter(*right, predicate), left_key, right_key, join_type)
+            } else {
+                left.join(*right, left_key, right_key, join_type).filter(predicate)
+            }
+        },
+        // Taking rows out would change what's in the other rows' frames
+        stopped @ (LogicalPlan::Limit { .. } | LogicalPlan::Window { .. }) => stopped.filter(predicate),
+    }
+}
+
+fn required_by(exprs: &[&Expr]) -> Vec<String> {
+    exprs.iter().flat_map(|expr| expr.columns()).collect()
+}
+
+// Rule: only columns something above actually uses get read. required is what the parent needs
+// from this node's output, prune_columns on a whole plan keeps every output column.
+pub fn prune_columns(plan: LogicalPlan) -> LogicalPlan {
+    let required = plan.schema();
+    prune_to(plan, &required)
+}
+
+fn prune_to(plan: LogicalPlan, required: &[String]) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Scan { table, columns, filters, sample } => {
+            let mut needed = required.to_vec();
+            needed.extend(required_by(&filters.iter().collect::<Vec<_>>()));
+            let mut kept: Vec<String> = columns.iter()
+                .filter(|col| resolves(&needed, &format!("{}.{}", table, col)))
+                .cloned()
+                .collect();
+            // count(*) and friends don't need any column, but the rows still have to come from
+            // somewhere, so read the cheapest one
+            if kept.is_empty() {
+                let def = table_def(&table).ok();
+                let cheapest = columns.iter()
+                    .min_by_key(|col| def.as_ref().and_then(|def| def.column(col)).and_then(|col| col.data_type.stored_size()).unwrap_or(usize::MAX));
+                kept.extend(cheapest.cloned());
+            }
+            LogicalPlan::Scan { table, columns: kept, filters, sample }
+        },
+        LogicalPlan::Filter { input, predicate } => {
+            let mut needed = required.to_vec();
+            needed.extend(predicate.columns());
+            prune_to(*input, &needed).filter(predicate)
+        },
+        LogicalPlan::Project { input, exprs } => {
+            // A batch needs at least one column to have any rows
+            let mut kept: Vec<(String, Expr)> = exprs.iter().filter(|(name, _)| resolves(required, name)).cloned().collect();
+            if kept.is_empty() {
+                kept.extend(exprs.first().cloned());
+            }
+            let needed = required_by(&kept.iter().map(|(_, expr)| expr).collect::<Vec<_>>());
+            prune_to(*input, &needed).project(kept)
+        },
+        LogicalPlan::Join { left, right, left_key, right_key, join_type } => {
+            let mut needed = required.to_vec();
+            needed.extend(required_by(&[&left_key, &right_key]));
+            prune_to(*left, &needed).join(prune_to(*right, &needed), left_key, right_key, join_type)
+        },
+        LogicalPlan::Aggregate { input, group_by, aggregates } => {
+            let mut exprs: Vec<&Expr> = group_by.iter().map(|(_, expr)| expr).collect();
+            exprs.extend(aggregates.iter().map(|agg| &agg.expr));
+            prune_to(*input, &required_by(&exprs)).aggregate(group_by, aggregates)
+        },
+        LogicalPlan::Sort { input, keys } => {
+            let mut needed = required.to_vec();
+            needed.extend(required_by(&keys.iter().map(|key| &key.expr).collect::<Vec<_>>()));
+            prune_to(*input, &needed).sort(keys)
+        },
+        LogicalPlan::Limit { input, limit } => prune_to(*input, required).limit(limit),
+        LogicalPlan::Window { input, functions } => {
+            let kept: Vec<(String, WindowExpr)> = functions.into_iter().filter(|(name, _)| resolves(required, name)).collect();
+            if kept.is_empty() {
+                return prune_to(*input, required);
+            }
+            let mut needed = required.to_vec();
+            needed.extend(kept.iter().flat_map(|(_, window)| Expr::Window(Box::new(window.clone())).columns()));
+            prune_to(*input, &needed).window(kept)
+        },
+    }
+}
+
+pub fn optimize(plan: LogicalPlan) -> LogicalPlan {
+    prune_columns(push_down_filters(plan))
+}
+
+pub fn to_physical(plan: LogicalPlan) -> Result<Box<dyn Operator>, String> {
+    Ok(match plan {
+        LogicalPlan::Scan { table, columns, filters, sample } => match sample {
+            Some(sample) => Box::new(TableScan::sampled(&table, &columns, conjoin(filters), sample)?),
+            // A condition on an indexed column that picks out a few rows goes straight to them
+            None => match index::matching_rows(&table, &filters)? {
+                Some((column, rows)) => Box::new(TableScan::indexed(&table, &columns, conjoin(filters), &column, &rows)?),
+                None => Box::new(TableScan::filtered(&table, &columns, conjoin(filters))?),
+            },
+        },
+        LogicalPlan::Filter { input, predicate } => Box::new(Filter::new(to_physical(*input)?, predicate)),
+        LogicalPlan::Project { input, exprs } => Box::new(Project::new(to_physical(*input)?, exprs)),
+        LogicalPlan::Join { left, right, left_key, right_key, join_type } => plan_join(to_physical(*left)?, to_physical(*right)?, left_key, right_key, join_type),
+        LogicalPlan::Aggregate { input, group_by, aggregates } => {
+            // Distinct estimates straight off a table can use the sketches kept per segment
+            if let (true, LogicalPlan::Scan { table, filters, sample: None, .. }) = (group_by.is_empty(), input.as_ref()) {
+                if let Some(scan) = ApproxDistinctScan::new(table, conjoin(filters.clone()), &aggregates) {
+                    return Ok(Box::new(scan));
+                }
+            }
+            // So can anything a materialized view already keeps
+            if let LogicalPlan::Scan { table, filters, sample: None, .. } = input.as_ref() {
+                if let Some(scan) = view::answer(table, filters, &group_by, &aggregates)? {
+                    return Ok(Box::new(scan));
+                }
+            }
+            // Aggregates are small next to what goes into them, worth keeping for the next query
+            // that needs the same one
+            ResultCache::wrap(Box::new(HashAggregate::new(to_physical(*input)?, group_by, aggregates)))
+        },
+        LogicalPlan::Sort { input, keys } => Box::new(Sort::new(to_physical(*input)?, keys)),
+        LogicalPlan::Limit { input, limit } => Box::new(Limit::new(to_physical(*input)?, limit)),
+        LogicalPlan::Window { input, functions } => plan_window(to_physical(*input)?, functions),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::aggregate::AggregateFunction;
+    use crate::analyze::batch::Value;
+    use crate::analyze::expr::{col, lit, CompareOp};
+
+    // One line per node, indented under its parent, with just what the rules change
+    fn outline(plan: &LogicalPlan) -> String {
+        let mut lines = Vec::new();
+        outline_node(plan, 0, &mut lines);
+        lines.join("\n")
+    }
+
+    fn outline_node(plan: &LogicalPlan, depth: usize, lines: &mut Vec<String>) {
+        let exprs = |exprs: &[Expr]| exprs.iter().map(|expr| expr.to_string()).collect::<Vec<_>>().join(", ");
+        let (line, inputs): (String, Vec<&LogicalPlan>) = match plan {
+            LogicalPlan::Scan { table, columns, filters, .. } => (format!("Scan {} [{}] where [{}]", table, columns.join(", "), exprs(filters)), vec![]),
+            LogicalPlan::Filter { input, predicate } => (format!("Filter {}", predicate), vec![input]),
+            LogicalPlan::Project { input, exprs } => (format!("Project [{}]", exprs.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>().join(", ")), vec![input]),
+            LogicalPlan::Join { left, right, .. } => ("Join".to_string(), vec![left, right]),
+            LogicalPlan::Aggregate { input, group_by, .. } => (format!("Aggregate [{}]", group_by.iter().map(|(name, _)| name.clone()).collect::<Vec<_>>().join(", ")), vec![input]),
+            LogicalPlan::Sort { input, .. } => ("Sort".to_string(), vec![input]),
+            LogicalPlan::Limit { input, limit } => (format!("Limit {}", limit), vec![input]),
+            LogicalPlan::Window { input, .. } => ("Window".to_string(), vec![input]),
+        };
+        lines.push(format!("{}{}", "  ".repeat(depth), line));
+        for input in inputs {
+            outline_node(input, depth + 1, lines);
+        }
+    }
+
+    fn orders() -> LogicalPlan {
+        LogicalPlan::scan("orders").unwrap()
+    }
+
+    fn order_products() -> LogicalPlan {
+        LogicalPlan::scan("order_products").unwrap()
+    }
+
+    fn count() -> Vec<AggregateExpr> {
+        vec![AggregateExpr::count_rows("count")]
+    }
+
+    #[test]
+    fn filters_sink_into_scans() {
+        let plan = orders()
+            .project(vec![("customer".to_string(), col("customer_id")), ("created".to_string(), col("created"))])
+            .filter(col("customer").compare(CompareOp::Eq, lit(Value::Int64(1))).and(col("created").compare(CompareOp::Gt, lit(Value::Int64(2)))));
+        assert_eq!(outline(&push_down_filters(plan)), [
+            "Project [customer, created]",
+            "  Scan orders [id, customer_id, created, tax_percent, discount_amount] where [(customer_id = 1), (created > 2)]",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn filters_go_to_the_side_of_a_join_they_use() {
+        let join = |join_type| orders().join(order_products(), col("orders.id"), col("order_products.order_id"), join_type)
+            .filter(col("orders.created").compare(CompareOp::Gt, lit(Value::Int64(1))))
+            .filter(col("order_products.quantity").compare(CompareOp::Gt, lit(Value::Int64(2))))
+            .filter(col("orders.tax_percent").compare(CompareOp::Lt, col("order_products.price_per")));
+        let outline_of = |join_type| outline(&prune_columns(push_down_filters(join(join_type))));
+        assert_eq!(outline_of(JoinType::Inner), [
+            "Filter (orders.tax_percent < order_products.price_per)",
+            "  Join",
+            "    Scan orders [id, customer_id, created, tax_percent, discount_amount] where [(orders.created > 1)]",
+            "    Scan order_products [order_id, product_id, quantity, price_per] where [(order_products.quantity > 2)]",
+        ].join("\n"));
+        // Taking rows out of the right side of a left join would turn them into NULLs instead
+        assert_eq!(outline_of(JoinType::Left), [
+            "Filter (order_products.quantity > 2)",
+            "  Filter (orders.tax_percent < order_products.price_per)",
+            "    Join",
+            "      Scan orders [id, customer_id, created, tax_percent, discount_amount] where [(orders.created > 1)]",
+            "      Scan order_products [order_id, product_id, quantity, price_per] where []",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn having_moves_onto_group_keys_but_not_below_a_global_aggregate() {
+        let grouped = orders()
+            .aggregate(vec![("customer".to_string(), col("customer_id"))], count())
+            .filter(col("customer").compare(CompareOp::Eq, lit(Value::Int64(1))).and(col("count").compare(CompareOp::Gt, lit(Value::Int64(2)))));
+        assert_eq!(outline(&push_down_filters(grouped)), [
+            "Filter (count > 2)",
+            "  Aggregate [customer]",
+            "    Scan orders [id, customer_id, created, tax_percent, discount_amount] where [(customer_id = 1)]",
+        ].join("\n"));
+        // count(*) of nothing is still a row, HAVING 1 = 0 is what takes it out
+        let global = orders().aggregate(Vec::new(), count())
+            .filter(lit(Value::Int64(1)).compare(CompareOp::Eq, lit(Value::Int64(0))));
+        assert_eq!(outline(&push_down_filters(global)), [
+            "Filter (1 = 0)",
+            "  Aggregate []",
+            "    Scan orders [id, customer_id, created, tax_percent, discount_amount] where []",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn filters_stop_at_limits() {
+        let plan = orders().limit(10).filter(col("created").compare(CompareOp::Gt, lit(Value::Int64(1))));
+        assert_eq!(outline(&push_down_filters(plan)), [
+            "Filter (created > 1)",
+            "  Limit 10",
+            "    Scan orders [id, customer_id, created, tax_percent, discount_amount] where []",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn scans_read_only_what_gets_used() {
+        let plan = orders()
+            .join(order_products(), col("orders.id"), col("order_products.order_id"), JoinType::Inner)
+            .filter(col("orders.created").compare(CompareOp::Gt, lit(Value::Int64(1))))
+            .aggregate(vec![("customer".to_string(), col("orders.customer_id"))], vec![
+                AggregateExpr::new(AggregateFunction::Sum, col("order_products.quantity"), "quantity"),
+            ]);
+        assert_eq!(outline(&optimize(plan)), [
+            "Aggregate [customer]",
+            "  Join",
+            "    Scan orders [id, customer_id, created] where [(orders.created > 1)]",
+            "    Scan order_products [order_id, quantity] where []",
+        ].join("\n"));
+        // Nothing needed still reads one column, the narrowest
+        let counted = order_products().aggregate(Vec::new(), count());
+        assert_eq!(outline(&optimize(counted)), [
+            "Aggregate []",
+            "  Scan order_products [quantity] where []",
+        ].join("\n"));
+    }
+}
@@ -1,126 +1,36 @@
 use crate::datagen;
 use datagen::constants::{DATA_DIRECTORY};
-use fake::faker::time::raw::Date;
-use uuid::{Uuid, Builder};
-use chrono::{DateTime, Utc, TimeZone, Datelike, Duration};
+use datagen::dataset::Column;
+use datagen::file::{decode_u64, segment_file_name};
+use super::apl::interpreter::{tidy, Interpreter};
+use super::aggregate::{millionths, AggregateExpr, AggregateFunction, HashAggregate};
+use super::batch::{Batch, Value};
+use super::explain::{explain, explain_analyze, ExplainMode};
+use super::cache::ResultCache;
+use super::calendar::{date_trunc, time_zone};
+use super::expr::{col, lit, CompareOp, Expr};
+use super::hll::DEFAULT_PRECISION;
+use super::join::{plan_join, JoinType};
+use super::lineage::{self, LineageNode};
+use super::kernels::{aggregate_column, NumericAggregate};
+use super::operator::{BatchSource, Filter, Operator, collect};
+use super::parse::{parse_expr, parse_named_exprs};
+use super::scan::{TableScan, BYTES_READ};
+use super::planner::{optimize, to_physical, LogicalPlan};
+use super::sql::{parse_statement, plan_select, Statement};
+use super::view;
+use super::top_k::TopK;
+use chrono::{DateTime, Utc, Datelike, Duration, SecondsFormat, Weekday};
+use chrono_tz::Tz;
 use itertools::Itertools;
 use rust_decimal::Decimal;
-use std::{collections::{HashMap, BinaryHeap, HashSet}, io::Read, cmp::{Ord,Ordering}};
+use std::collections::HashSet;
+use std::io::Read;
 use std::fs::OpenOptions;
-use std::mem;
 use std::path::Path;
+use std::sync::atomic::Ordering as AtomicOrdering;
 
 
-#[derive(Debug)]
-pub struct OrderStats {
-    pub orders_count: u64,
-    pub min_products: u64,
-    pub max_products: u64,
-    pub min_total_price: u64,
-    pub max_total_price: u64,
-}
-
-#[derive(Debug)]
-pub struct OrderMeta {
-    pub customer_id: Uuid,
-    pub count_products: u8,
-    pub product_kinds: u8,
-    pub qty_products: u8,
-    pub total_price: Decimal,
-    pub discount: Decimal,
-}
-
-#[derive(Debug)]
-pub struct CustomerMeta {
-    pub count_orders: u8,
-    pub product_kinds: u8,
-    pub qty_products: u8,
-    pub total_price: Decimal,
-}
-
-impl CustomerMeta {
-    pub fn gen_with_orders(orders: u8) -> CustomerMeta {
-        CustomerMeta {
-            count_orders: orders, 
-            product_kinds: 0, 
-            qty_products: 0, 
-            total_price: Decimal::new(0,0),
-        }
-    }
-}
-
-#[derive(Debug)]
-pub struct UuidHeapCount {
-    pub order: Uuid,
-    pub count: u8,
-}
-
-impl Ord for UuidHeapCount {
-    fn cmp(&self, other: &Self) -> Ordering{
-        if self.count > other.count { return Ordering::Greater }
-        if self.count < other.count { return Ordering::Less }
-        Ordering::Equal
-    }
-    fn max(self, other: Self) -> Self { 
-        if self.count >= other.count { return self }
-        other
-    }
-    fn min(self, other: Self) -> Self { 
-        if self.count <= other.count { return self }
-        other
-    }
-}
-
-impl PartialOrd for UuidHeapCount {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for UuidHeapCount {
-    fn eq(&self, other: &Self) -> bool {
-        self.count == other.count
-    }
-}
-
-impl Eq for UuidHeapCount {}
-
-#[derive(Debug)]
-pub struct UuidHeapDecimal {
-    pub order: Uuid,
-    pub count: u8,
-}
-
-impl Ord for UuidHeapDecimal {
-    fn cmp(&self, other: &Self) -> Ordering{
-        if self.count > other.count { return Ordering::Greater }
-        if self.count < other.count { return Ordering::Less }
-        Ordering::Equal
-    }
-    fn max(self, other: Self) -> Self { 
-        if self.count >= other.count { return self }
-        other
-    }
-    fn min(self, other: Self) -> Self { 
-        if self.count <= other.count { return self }
-        other
-    }
-}
-
-impl PartialOrd for UuidHeapDecimal {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for UuidHeapDecimal {
-    fn eq(&self, other: &Self) -> bool {
-        self.count == other.count
-    }
-}
-
-impl Eq for UuidHeapDecimal {}
-
 pub fn get_file_as_bytes(filepath: String) -> Vec<u8> {
     let mut file = OpenOptions::new()
         .read(true)
@@ -131,297 +41,263 @@ pub fn get_file_as_bytes(filepath: String) -> Vec<u8> {
     buffer
 }
 
-pub fn process_data() {
-    // This is all going to be hardcoded because I don't want to build a metadata file format for
-    // this PoC, though all of that is trivial to determine at runtime dynamically.
+pub fn process_data(explain_analyze: bool, keep_view: bool, zone: &str) {
+    if let Err(error) = time_zone(zone).and_then(|zone| analyze_orders(explain_analyze, keep_view, zone)) {
+        println!("Analysis failed: {}", error);
+    }
+}
 
-    let customers_dir = DATA_DIRECTORY.to_owned()+"customers/";
-    let orders_dir = DATA_DIRECTORY.to_owned()+"orders/";
-    let products_dir = DATA_DIRECTORY.to_owned()+"products/";
-    let order_products_dir = DATA_DIRECTORY.to_owned()+"order_products/";
+// Pulls a single value out of a one row aggregate result
+fn single_value(batch: &Batch, name: &str) -> Result<Value, String> {
+    batch.column(name)?.first().cloned().ok_or(format!("No result for {:?}", name))
+}
 
-    // Only use the current month if we're over 3 days into it
-    let last_month = match Utc::now().date_naive().day() <= 3 {
-        true => { 
-            let last_month = Utc::now() - Duration::weeks(1);
-            Utc.ymd(last_month.date_naive().year(), last_month.date_naive().month(), 1).and_hms(0,0,0) 
+// Collects one of analyze's result sets, keeping its lineage under analyze.<name>. With
+// explain_analyze on, it also prints what each operator in it did.
+fn collect_result(explain_analyze: bool, name: &str, operator: Box<dyn Operator>) -> Result<(Batch, LineageNode), String> {
+    // Read back from the result cache when nothing it read has changed since last time
+    let mut operator = ResultCache::wrap(operator);
+    let lineage = operator.lineage();
+    let batch = match explain_analyze {
+        true => {
+            let (batch, report) = super::explain::explain_analyze(operator.as_mut())?;
+            println!("EXPLAIN ANALYZE {}:\n{}", name, report);
+            batch
         },
-        _ => { 
-            Utc.ymd(Utc::now().date_naive().year(), Utc::now().date_naive().month(), 1).and_hms(0,0,0) 
-        }
+        false => collect(operator.as_mut())?,
     };
+    lineage::save(&format!("analyze.{}", name), &lineage)?;
+    Ok((batch, lineage))
+}
 
-    let mut order_metadata: HashMap<Uuid, OrderMeta> = HashMap::new();
-    let mut customer_metadata: HashMap<Uuid, CustomerMeta> = HashMap::new();
-
-    let mut bytes_scanned: u64 = 0;
-    let mut time_start: DateTime<Utc> = Utc::now();
-
-    let mut customer_count: u64 = 0;
-    let mut orders_count: u64 = 0;
-    let mut customer_purchases_last_month: u64 = 0;
-    let mut customers_last_month: HashSet<Uuid>= HashSet::new();
-    let mut orders_per_month: HashMap<DateTime<Utc>, u64> = HashMap::new();
-    let mut min_orders_per_customer: u8 = u8::MAX;
-    let mut max_orders_per_customer: u8 = 0;
-    let mut min_quantity_per_order: u8 = u8::MAX;
-    let mut max_quantity_per_order: u8 = 0;
-    let mut total_quantity_per_order: u64 = 0;
-    let mut min_kinds_per_order: u8 = u8::MAX;
-    let mut max_kinds_per_order: u8 = 0;
-    let mut total_kinds_per_order: u64 = 0;
-    let mut min_total_per_order: Decimal = Decimal::new(i64::MAX,0);
-    let mut max_total_per_order: Decimal = Decimal::new(0,0);
-    let mut total_total_per_order: Decimal = Decimal::new(0,0);
-    {
-        println!("Beginning Customers Processing: {}", Utc::now());
-        // How many customers do we have?
-        let id_path: String = customers_dir + "id/";
-        let id_size = mem::size_of::<Uuid>();
-        let mut id_file_offset: usize = 0;
-        let mut id_file_num: u64 = 0;
-        while Path::new(&(id_path.to_owned() + "id_" + &format!("{:020}", id_file_num))).is_file() {
-            let buffer = get_file_as_bytes(id_path.to_owned() + "id_" + &format!("{:020}", id_file_num));
-            bytes_scanned += buffer.len() as u64;
-            while id_file_offset < buffer.len() {
-                let customer_id: Uuid = Builder::from_bytes(buffer[id_file_offset..id_file_offset+id_size].try_into().unwrap()).into_uuid();
-                // if customer_count % 10000 == 0 {
-                //     println!("Customer ID: {}", customer_id);
-                // }
-                customer_metadata.entry(customer_id).or_insert(CustomerMeta::gen_with_orders(0));
-                customer_count += 1;
-                id_file_offset += 16
-            }
-            id_file_num += 1;
-            id_file_offset = 0;
-        }
-        // println!("Customers: {}", customer_count);
-    }
-
-    {
-        println!("Beginning Orders Processing: {}", Utc::now());
-        // Min/Max/Avg products per order
-        // Min/Max/Avg total per order
-        // Min/Max/Avg orders per customers
-        let id_path: String = orders_dir.to_string() + "id/";
-        let created_path: String = orders_dir.to_string() + "created/";
-        let customer_id_path: String = orders_dir.to_string() + "customer_id/";
-        let mut id_file_num: u64 = 0;
-        let mut created_file_num: u64 = 0;
-        let mut customer_id_file_num: u64 = 0;
-        let mut id_file_offset: usize = 0;
-        let mut created_file_offset: usize = 0;
-        let mut customer_id_file_offset: usize = 0;
-        let id_size = mem::size_of::<Uuid>();
-        let created_size = mem::size_of::<i64>();
-        let customer_id_size = mem::size_of::<Uuid>();
-
-        while Path::new(&(id_path.to_owned() + "id_" + &format!("{:020}", id_file_num))).is_file() {
-            let mut id_file_path = id_path.to_owned() + "id_" + &format!("{:020}", id_file_num);
-            // println!("ID Path: {}", id_file_path);
-            let mut id_buffer = get_file_as_bytes(id_file_path.to_owned());
-            bytes_scanned += id_buffer.len() as u64;
-            let mut created_file_path = created_path.to_owned() + "created_" + &format!("{:020}", created_file_num);
-            // println!("Created Path: {}", created_file_path);
-            let mut created_buffer: Vec<u8> = get_file_as_bytes(created_file_path.to_owned());
-            bytes_scanned += created_buffer.len() as u64;
-            let mut customer_id_file_path = customer_id_path.to_owned() + "customer_id_" + &format!("{:020}", customer_id_file_num);
-            // println!("Customer ID Path: {}", customer_id_file_path);
-            let mut customer_id_buffer: Vec<u8> = get_file_as_bytes(customer_id_file_path.to_owned());
-            bytes_scanned += customer_id_buffer.len() as u64;
-            while id_file_offset < id_buffer.len() {
-                // println!("Created size: {}", ((row_num * created_size) - (created_prev_files_total)));
-                // println!("Created Reader pos: {}", created_file.seek(SeekFrom::Current (0)).unwrap());
-                // println!("Created filesize: {}", (&created_file.metadata().unwrap()).len());
-                // if row_num % 100_000 == 0 {
-                //     println!("Orders row: {}", row_num);
-                // }
-                if created_file_offset >= created_buffer.len() {
-                    created_file_num += 1;
-                    created_file_offset = 0;
-                    created_file_path = created_path.to_owned() + "created_" + &format!("{:020}", created_file_num);
-                    println!("Order Created Path: {}", created_file_path);
-
-                    created_buffer = get_file_as_bytes(created_file_path.to_owned());
-                    bytes_scanned += created_buffer.len() as u64;
-                }
-                if customer_id_file_offset >= customer_id_buffer.len() {
-                    customer_id_file_num += 1;
-                    customer_id_file_offset = 0;
-                    customer_id_file_path = customer_id_path.to_owned() + "customer_id_" + &format!("{:020}", customer_id_file_num);
-                    // println!("Customer ID Path: {}", customer_id_file_path);
-
-                    customer_id_buffer = get_file_as_bytes(customer_id_file_path.to_owned());
-                    bytes_scanned += customer_id_buffer.len() as u64;
-                }
-                // Now increment customer order counts
-                let order_id = Builder::from_bytes(id_buffer[id_file_offset..id_file_offset+id_size].try_into().unwrap()).into_uuid();
-                id_file_offset += id_size;
-                let customer_id = Builder::from_bytes(customer_id_buffer[customer_id_file_offset..customer_id_file_offset+customer_id_size].try_into().unwrap()).into_uuid();
-                order_metadata.entry(order_id).or_insert(OrderMeta {
-                    customer_id: customer_id, 
-                    count_products: 0, 
-                    product_kinds: 0, 
-                    qty_products: 0, 
-                    total_price: Decimal::new(0,0), 
-                    discount: Decimal::new(0,0), 
-                });
-                customer_id_file_offset += customer_id_size;
-                customer_metadata.entry(customer_id).and_modify(|meta| meta.count_orders += 1).or_insert(CustomerMeta::gen_with_orders(1));
-                // Now increment month order counts
-                let milli_bytes = &mut created_buffer[created_file_offset..created_file_offset+created_size];
-                let millis = i64::from_le_bytes(milli_bytes.try_into().unwrap());
-                created_file_offset += created_size;
-                let timestamp_result = Utc.timestamp_millis_opt(millis);
-                let datetime =match timestamp_result {
-                    chrono::LocalResult::None => {
-                        println!("Bad time: {:#?}", milli_bytes);
-                        Utc::now()
-                    },
-                    chrono::LocalResult::Single(t) => {t},
-                    chrono::LocalResult::Ambiguous(t, _) => {
-                        println!("Ambiguous time: {:#?}", milli_bytes);
-                        t
-                    },
-                };
-                let month = Utc.ymd(datetime.date_naive().year(), datetime.date_naive().month(), 1).and_hms(0,0,0);
-                // println!("Created month: {}", month);
-                orders_per_month.entry(month).and_modify(|counter| *counter += 1).or_insert(1);
-                if month == last_month {
-                    customer_purchases_last_month += 1;
-                    customers_last_month.insert(customer_id);
-                }
-                orders_count += 1;
-            }
-            id_file_num += 1;
-            id_file_offset = 0;
-        }
+fn average(total: &Value, count: &Value) -> Decimal {
+    match (total.as_decimal(), count.as_decimal()) {
+        (Some(total), Some(count)) if !count.is_zero() => total / count,
+        _ => Decimal::ZERO,
     }
+}
 
-    {
-        println!("Beginning OrderProducts Processing: {}", Utc::now());
-        // Min/Max/Avg products per order
-        // Min/Max/Avg total per order
-        // Min/Max/Avg orders per customers
-        let order_id_path: String = order_products_dir.to_string() + "order_id/";
-        let price_per_path: String = order_products_dir.to_string() + "price_per/";
-        let quantity_path: String = order_products_dir.to_string() + "quantity/";
-        let mut order_id_file_num: u64 = 0;
-        let mut price_per_file_num: u64 = 0;
-        let mut quantity_file_num: u64 = 0;
-        let mut order_id_file_offset: usize = 0;
-        let mut price_per_file_offset: usize = 0;
-        let mut quantity_file_offset: usize = 0;
-        let order_id_size = mem::size_of::<Uuid>();
-        let price_per_size = mem::size_of::<Decimal>();
-        let quantity_size = mem::size_of::<u64>();
-
-        while Path::new(&(order_id_path.to_owned() + "order_id_" + &format!("{:020}", order_id_file_num))).is_file() {
-            let mut order_id_file_path = order_id_path.to_owned() + "order_id_" + &format!("{:020}", order_id_file_num);
-            let mut order_id_buffer = get_file_as_bytes(order_id_file_path.to_owned());
-            bytes_scanned += order_id_buffer.len() as u64;
-            let mut price_per_file_path = price_per_path.to_owned() + "price_per_" + &format!("{:020}", price_per_file_num);
-            let mut price_per_buffer: Vec<u8> = get_file_as_bytes(price_per_file_path.to_owned());
-            bytes_scanned += price_per_buffer.len() as u64;
-            let mut quantity_file_path = quantity_path.to_owned() + "quantity_" + &format!("{:020}", quantity_file_offset);
-            let mut quantity_buffer: Vec<u8> = get_file_as_bytes(quantity_file_path.to_owned());
-            bytes_scanned += quantity_buffer.len() as u64;
-            while order_id_file_offset < order_id_buffer.len() {
-                // println!("Created size: {}", ((row_num * created_size) - (created_prev_files_total)));
-                // println!("Created Reader pos: {}", created_file.seek(SeekFrom::Current (0)).unwrap());
-                // println!("Created filesize: {}", (&created_file.metadata().unwrap()).len());
-                // if row_num % 100_000 == 0 {
-                //     println!("Order_Products row: {}", row_num);
-                // }
-                if price_per_file_offset >= price_per_buffer.len() {
-                    price_per_file_num += 1;
-                    price_per_file_offset = 0;
-                    price_per_file_path = price_per_path.to_owned() + "price_per_" + &format!("{:020}", price_per_file_num);
-                    println!("Price Per Path: {}", price_per_file_path);
-
-                    price_per_buffer = get_file_as_bytes(price_per_file_path.to_owned());
-                    bytes_scanned += price_per_buffer.len() as u64;
-                }
-                if quantity_file_offset >= quantity_buffer.len() {
-                    quantity_file_num += 1;
-                    quantity_file_offset = 0;
-                    quantity_file_path = quantity_path.to_owned() + "quantity_" + &format!("{:020}", quantity_file_num);
-                    // println!("Customer ID Path: {}", quantity_file_path);
-
-                    quantity_buffer = get_file_as_bytes(quantity_file_path.to_owned());
-                    bytes_scanned += quantity_buffer.len() as u64;
-                }
-                // Now increment customer order counts
-                let order_id = Builder::from_bytes(order_id_buffer[order_id_file_offset..order_id_file_offset+order_id_size].try_into().unwrap()).into_uuid();
-                order_id_file_offset += order_id_size;
-                let quantity = u64::from_le_bytes(quantity_buffer[quantity_file_offset..quantity_file_offset+quantity_size].try_into().unwrap());
-                order_metadata.entry(order_id).and_modify(|val| val.qty_products += quantity as u8);
-                order_metadata.entry(order_id).and_modify(|val| val.product_kinds += 1 as u8);
-                quantity_file_offset += quantity_size;
-                let price_per = Decimal::deserialize(price_per_buffer[price_per_file_offset..price_per_file_offset+price_per_size].try_into().unwrap());
-                order_metadata.entry(order_id).and_modify(|val| val.total_price += price_per * Decimal::new(quantity as i64, 0));
-                price_per_file_offset += price_per_size;
+fn analyze_orders(explain_analyze: bool, keep_view: bool, zone: Tz) -> Result<(), String> {
+    // This is all going to be hardcoded because I don't want to build a metadata file format for
+    // this PoC, though all of that is trivial to determine at runtime dynamically.
 
-            }
-            order_id_file_num += 1;
-            order_id_file_offset = 0;
-        }
+    // Only use the current month if we're over 3 days into it. Months are the zone's calendar
+    // months, starting at its local midnight.
+    let last_month = match Utc::now().with_timezone(&zone).day() <= 3 {
+        true => date_trunc("month", &(Utc::now() - Duration::weeks(1)), zone, Weekday::Mon)?,
+        _ => date_trunc("month", &Utc::now(), zone, Weekday::Mon)?,
+    };
+    let month = parse_expr(&format!("date_trunc('month', created, '{}')", zone.name()))?;
+
+    let bytes_start = BYTES_READ.load(AtomicOrdering::Relaxed);
+    let time_start: DateTime<Utc> = Utc::now();
+
+    println!("Beginning Customers Processing: {}", Utc::now());
+    // How many customers do we have?
+    let (customers, _) = collect_result(explain_analyze, "customers", Box::new(HashAggregate::new(
+        Box::new(TableScan::new("customers", &["id".to_string()])?),
+        vec![],
+        vec![AggregateExpr::count_rows("customers")],
+    )))?;
+
+    println!("Beginning Orders Processing: {}", Utc::now());
+    // One pass over orders down to a count per customer per month, everything else about orders
+    // rolls up from there without touching the files again. With --keep-view it's kept as a
+    // materialized view for the zone, so later runs only read orders written since. Otherwise a
+    // view that's already there still gets used, but analyze doesn't leave anything behind.
+    if keep_view {
+        let view_name = format!("customer_months_{}", zone.name().replace(|c: char| !c.is_ascii_alphanumeric(), "_"));
+        view::ensure(&view_name, &format!("SELECT customer_id, {month} AS month, count(*) AS orders FROM orders GROUP BY customer_id, {month}"))?;
     }
+    let (customer_months, customer_months_lineage) = collect_result(explain_analyze, "customer_months", to_physical(optimize(
+        LogicalPlan::scan("orders")?.aggregate(
+            vec![
+                ("customer_id".to_string(), col("customer_id")),
+                ("month".to_string(), month.clone()),
+            ],
+            vec![AggregateExpr::count_rows("orders")],
+        ),
+    ))?)?;
+    // Orders per month for the last year
+    let (orders_per_month, _) = collect_result(explain_analyze, "orders_per_month", Box::new(HashAggregate::new(
+        Box::new(BatchSource::from_result(vec![customer_months.clone()], customer_months_lineage.clone())),
+        vec![("month".to_string(), col("month"))],
+        vec![AggregateExpr::new(AggregateFunction::Sum, col("orders"), "orders")],
+    )))?;
+    // Min/Max/Avg orders per customers
+    let (orders_per_customer, _) = collect_result(explain_analyze, "orders_per_customer", Box::new(HashAggregate::new(
+        Box::new(HashAggregate::new(
+            Box::new(BatchSource::from_result(vec![customer_months.clone()], customer_months_lineage.clone())),
+            vec![("customer_id".to_string(), col("customer_id"))],
+            vec![AggregateExpr::new(AggregateFunction::Sum, col("orders"), "orders")],
+        )),
+        vec![],
+        vec![
+            AggregateExpr::new(AggregateFunction::Min, col("orders"), "min_orders"),
+            AggregateExpr::new(AggregateFunction::Max, col("orders"), "max_orders"),
+            AggregateExpr::new(AggregateFunction::Sum, col("orders"), "orders"),
+        ],
+    )))?;
+    // Customers who haven't ordered at all, matched on orders.customer_id -> customers.id
+    let (customers_without_orders, _) = collect_result(explain_analyze, "customers_without_orders", Box::new(HashAggregate::new(
+        plan_join(
+            Box::new(TableScan::new("customers", &["id".to_string()])?),
+            Box::new(BatchSource::from_result(vec![customer_months.clone()], customer_months_lineage.clone())),
+            col("id"),
+            col("customer_id"),
+            JoinType::Anti,
+        ),
+        vec![],
+        vec![AggregateExpr::count_rows("customers")],
+    )))?;
+    // How many purchased in the last month? The distinct customers are a HyperLogLog estimate so
+    // memory stays fixed however many there are
+    let (last_month_orders, _) = collect_result(explain_analyze, "last_month_orders", Box::new(HashAggregate::new(
+        Box::new(Filter::new(
+            Box::new(BatchSource::from_result(vec![customer_months], customer_months_lineage)),
+            col("month").compare(CompareOp::Eq, lit(Value::DateTime(last_month))),
+        )),
+        vec![],
+        vec![
+            AggregateExpr::new(AggregateFunction::Sum, col("orders"), "purchases"),
+            AggregateExpr::new(AggregateFunction::ApproxCountDistinct(DEFAULT_PRECISION), col("customer_id"), "customers"),
+        ],
+    )))?;
+
+    println!("Beginning OrderProducts Processing: {}", Utc::now());
+    // Min/Max/Avg products per order
+    // Min/Max/Avg total per order
+    // P50/P90/P99 of both, from t-digests so it's the same few KB for any number of orders
+    let (order_stats, _) = collect_result(explain_analyze, "order_stats", Box::new(HashAggregate::new(
+        Box::new(HashAggregate::new(
+            Box::new(TableScan::new("order_products", &["order_id".to_string(), "quantity".to_string(), "price_per".to_string()])?),
+            vec![("order_id".to_string(), col("order_id"))],
+            vec![
+                AggregateExpr::new(AggregateFunction::Sum, col("quantity"), "quantity"),
+                AggregateExpr::count_rows("kinds"),
+                AggregateExpr::new(AggregateFunction::Sum, parse_expr("price_per * quantity")?, "total"),
+            ],
+        )),
+        vec![],
+        vec![
+            AggregateExpr::new(AggregateFunction::Min, col("quantity"), "min_quantity"),
+            AggregateExpr::new(AggregateFunction::Max, col("quantity"), "max_quantity"),
+            AggregateExpr::new(AggregateFunction::Sum, col("quantity"), "quantity"),
+            AggregateExpr::new(AggregateFunction::Min, col("kinds"), "min_kinds"),
+            AggregateExpr::new(AggregateFunction::Max, col("kinds"), "max_kinds"),
+            AggregateExpr::new(AggregateFunction::Sum, col("kinds"), "kinds"),
+            AggregateExpr::new(AggregateFunction::Min, col("total"), "min_total"),
+            AggregateExpr::new(AggregateFunction::Max, col("total"), "max_total"),
+            AggregateExpr::new(AggregateFunction::Sum, col("total"), "total"),
+            AggregateExpr::new(AggregateFunction::ApproxPercentile(millionths(0.5)), col("quantity"), "p50_quantity"),
+            AggregateExpr::new(AggregateFunction::ApproxPercentile(millionths(0.9)), col("quantity"), "p90_quantity"),
+            AggregateExpr::new(AggregateFunction::ApproxPercentile(millionths(0.99)), col("quantity"), "p99_quantity"),
+            AggregateExpr::new(AggregateFunction::ApproxPercentile(millionths(0.5)), col("total"), "p50_total"),
+            AggregateExpr::new(AggregateFunction::ApproxPercentile(millionths(0.9)), col("total"), "p90_total"),
+            AggregateExpr::new(AggregateFunction::ApproxPercentile(millionths(0.99)), col("total"), "p99_total"),
+        ],
+    )))?;
+
+    // Revenue per month needs the order's date next to its products. Once both tables are
+    // compacted, order_products is in the same order id sequence as orders and this is a single
+    // merge pass over both, until then it's a hash join
+    let (revenue_per_month, _) = collect_result(explain_analyze, "revenue_per_month", Box::new(HashAggregate::new(
+        plan_join(
+            Box::new(TableScan::new("orders", &["id".to_string(), "created".to_string()])?),
+            Box::new(TableScan::new("order_products", &["order_id".to_string(), "quantity".to_string(), "price_per".to_string()])?),
+            col("id"),
+            col("order_id"),
+            JoinType::Inner,
+        ),
+        vec![("month".to_string(), month.clone())],
+        vec![AggregateExpr::new(AggregateFunction::Sum, parse_expr("price_per * quantity")?, "revenue")],
+    )))?;
+    let (orders_without_products, _) = collect_result(explain_analyze, "orders_without_products", Box::new(HashAggregate::new(
+        plan_join(
+            Box::new(TableScan::new("orders", &["id".to_string()])?),
+            Box::new(TableScan::new("order_products", &["order_id".to_string()])?),
+            col("id"),
+            col("order_id"),
+            JoinType::Anti,
+        ),
+        vec![],
+        vec![AggregateExpr::count_rows("orders")],
+    )))?;
+
+    println!("Beginning Top Products Processing: {}", Utc::now());
+    // Top ten products by quantity sold, by how many orders they're on and by revenue. One pass
+    // over order_products gets all three measures, then each ranking is a top-k over that.
+    let (product_stats, product_stats_lineage) = collect_result(explain_analyze, "product_stats", plan_join(
+        Box::new(HashAggregate::new(
+            Box::new(TableScan::new("order_products", &["order_id".to_string(), "product_id".to_string(), "quantity".to_string(), "price_per".to_string()])?),
+            vec![("product_id".to_string(), col("product_id"))],
+            vec![
+                AggregateExpr::new(AggregateFunction::Sum, col("quantity"), "quantity"),
+                AggregateExpr::new(AggregateFunction::CountDistinct, col("order_id"), "orders"),
+                AggregateExpr::new(AggregateFunction::Sum, parse_expr("price_per * quantity")?, "revenue"),
+            ],
+        )),
+        Box::new(TableScan::new("products", &["id".to_string(), "display_name".to_string()])?),
+        col("product_id"),
+        col("id"),
+        JoinType::Inner,
+    ))?;
+    let top_products = |measure: &str| -> Result<Batch, String> {
+        let source = BatchSource::from_result(vec![product_stats.clone()], product_stats_lineage.clone());
+        Ok(collect_result(explain_analyze, &format!("top_products_by_{}", measure), Box::new(TopK::new(Box::new(source), col(measure), true, 10)))?.0)
+    };
+    let top_by_quantity = top_products("quantity")?;
+    let top_by_orders = top_products("orders")?;
+    let top_by_revenue = top_products("revenue")?;
 
-    println!("Final Tallying: {}", Utc::now());
-    // let mut top_ten_products: BinaryHeap<UuidHeapCount> = BinaryHeap::new();
-    for (_, order_meta) in order_metadata.iter() {
-        if order_meta.qty_products > 0 && order_meta.qty_products < min_quantity_per_order { min_quantity_per_order = order_meta.qty_products}
-        if order_meta.qty_products > max_quantity_per_order { max_quantity_per_order = order_meta.qty_products}
-        total_quantity_per_order += order_meta.qty_products as u64;
-        if order_meta.product_kinds > 0 && order_meta.product_kinds < min_kinds_per_order { min_kinds_per_order = order_meta.product_kinds}
-        if order_meta.product_kinds > max_kinds_per_order { max_kinds_per_order = order_meta.product_kinds}
-        total_kinds_per_order += order_meta.product_kinds as u64;
-        if order_meta.total_price > Decimal::ZERO && order_meta.total_price < min_total_per_order { min_total_per_order = order_meta.total_price}
-        if order_meta.total_price > max_total_per_order { max_total_per_order = order_meta.total_price}
-        total_total_per_order += order_meta.total_price;
-    }
-    for (_, customer_meta) in customer_metadata.iter() {
-        if customer_meta.count_orders > 0 && customer_meta.count_orders < min_orders_per_customer { min_orders_per_customer = customer_meta.count_orders}
-        if customer_meta.count_orders > max_orders_per_customer { max_orders_per_customer = customer_meta.count_orders}
-    }
-     
-    // TODO: Things we want to analyze:
-    // - How many customers do we have?
-    // - How many purchased in the last month?
-    // - Min/Max/Avg products per order
-    // - Min/Max/Avg total per order
-    // - Min/Max/Avg orders per customers
-    // - Orders per month for the last year
-    // - Top ten selling products per count and per appearing on orders
     let time_spent = (Utc::now() - time_start).num_milliseconds();
+    let bytes_scanned = BYTES_READ.load(AtomicOrdering::Relaxed) - bytes_start;
     println!("Analysis complete: {}", Utc::now());
     println!("Time Spent: {:.4?}", (time_spent  as f64 / 1000.0));
     println!("Bytes Scanned: {}", bytes_scanned);
-    println!("Bytes per second: {:#?}", bytes_scanned / (time_spent / 1000) as u64);
-    
-    let last_months_orders = orders_per_month.get(&last_month).unwrap_or(&0);
+    println!("Bytes per second: {:#?}", bytes_scanned * 1000 / time_spent.max(1) as u64);
+
+    let customer_count = single_value(&customers, "customers")?;
+    let orders_count = single_value(&orders_per_customer, "orders")?;
+    let last_months_orders = orders_per_month.column("month")?.iter()
+        .position(|month| *month == Value::DateTime(last_month))
+        .map(|row| orders_per_month.columns[1][row].clone())
+        .unwrap_or(Value::UInt64(0));
+    let last_months_revenue = revenue_per_month.column("month")?.iter()
+        .position(|month| *month == Value::DateTime(last_month))
+        .map(|row| revenue_per_month.columns[1][row].clone())
+        .unwrap_or(Value::Decimal(Decimal::ZERO));
     println!("Customers: {}", customer_count);
-    println!("Orders Last Month: {:#?}", last_months_orders);
-    println!("Customer Purchases Last Month: {:#?}", customer_purchases_last_month);
-    println!("Unique Customers Last Month: {:#?}", customers_last_month.len());
-    println!("Min/Max/Avg total quantity per order: {}, {}, {}", min_quantity_per_order, max_quantity_per_order, (total_quantity_per_order/orders_count) as f64);
-    println!("Min/Max/Avg product_kinds per order: {}, {}, {}", min_kinds_per_order, max_kinds_per_order, (total_kinds_per_order/orders_count) as f64);
-    println!("Min/Max/Avg total per order: {:.2?}, {:.2?}, {:.2?}", min_total_per_order, max_total_per_order, (total_total_per_order/Decimal::new(orders_count as i64, 0)));
-    println!("Min/Max/Avg orders per customer: {}, {}, {}", min_orders_per_customer, max_orders_per_customer, (orders_count/customer_count) as f64);
-    println!("Orders: {:#?}", order_metadata.len());
-    println!("Customers: {:#?}", customer_metadata.len());
-
-    println!("Orders Per Month: {:#?}", orders_per_month.iter().sorted());
-    
-    //let id_paths = fs::read_dir(&id_path).unwrap();
-    //let mut customer_count2: u64 = 0;
-    //for path in id_paths {
-    //    let pd = format!("{}", &path.as_ref().unwrap().path().display());
-    //    customer_count2 += fs::metadata(&pd).unwrap().len()/mem::size_of::<Uuid>() as u64;
-    //}
-    //println!("Customers 2: {}", customer_count2);
+    println!("Orders Last Month: {}", last_months_orders);
+    println!("Revenue Last Month: {:.2}", last_months_revenue);
+    println!("Customer Purchases Last Month: {}", single_value(&last_month_orders, "purchases")?);
+    println!("Unique Customers Last Month: {}", single_value(&last_month_orders, "customers")?);
+    println!("Min/Max/Avg total quantity per order: {}, {}, {:.2}", single_value(&order_stats, "min_quantity")?, single_value(&order_stats, "max_quantity")?, average(&single_value(&order_stats, "quantity")?, &orders_count));
+    println!("Min/Max/Avg product_kinds per order: {}, {}, {:.2}", single_value(&order_stats, "min_kinds")?, single_value(&order_stats, "max_kinds")?, average(&single_value(&order_stats, "kinds")?, &orders_count));
+    println!("Min/Max/Avg total per order: {:.2}, {:.2}, {:.2}", single_value(&order_stats, "min_total")?, single_value(&order_stats, "max_total")?, average(&single_value(&order_stats, "total")?, &orders_count));
+    println!("P50/P90/P99 total quantity per order: {:.2}, {:.2}, {:.2}", single_value(&order_stats, "p50_quantity")?, single_value(&order_stats, "p90_quantity")?, single_value(&order_stats, "p99_quantity")?);
+    println!("P50/P90/P99 total per order: {:.2}, {:.2}, {:.2}", single_value(&order_stats, "p50_total")?, single_value(&order_stats, "p90_total")?, single_value(&order_stats, "p99_total")?);
+    println!("Min/Max/Avg orders per customer: {}, {}, {:.2}", single_value(&orders_per_customer, "min_orders")?, single_value(&orders_per_customer, "max_orders")?, average(&orders_count, &customer_count));
+    println!("Customers Without Orders: {}", single_value(&customers_without_orders, "customers")?);
+    println!("Orders Without Products: {}", single_value(&orders_without_products, "orders")?);
+    println!("Orders: {}", orders_count);
+
+    for (title, top, measure) in [("Quantity", &top_by_quantity, "quantity"), ("Orders", &top_by_orders, "orders"), ("Revenue", &top_by_revenue, "revenue")] {
+        println!("Top Products By {}:", title);
+        for (name, value) in top.column("display_name")?.iter().zip(top.column(measure)?) {
+            println!("    {}: {:.2}", name, value);
+        }
+    }
 
+    let months: Vec<(Value, Value)> = orders_per_month.columns[0].iter().cloned().zip(orders_per_month.columns[1].iter().cloned()).sorted().collect();
+    println!("Orders Per Month:");
+    for (month, orders) in months {
+        match month {
+            Value::DateTime(month) => println!("    {}: {}", month.with_timezone(&zone).to_rfc3339_opts(SecondsFormat::Millis, true), orders),
+            month => println!("    {}: {}", month, orders),
+        }
+    }
+    Ok(())
 }
 
 pub fn process_average() {
@@ -431,43 +307,28 @@ pub fn process_average() {
     let order_products_dir = DATA_DIRECTORY.to_owned()+"order_products/";
 
     let mut bytes_scanned: u64 = 0;
-    let mut time_start: DateTime<Utc> = Utc::now();
+    let time_start: DateTime<Utc> = Utc::now();
 
-    let mut min_quantity_per_order: u64 = u64::MAX;
-    let mut max_quantity_per_order: u64 = 0;
-    let mut total_quantity_per_order: u64 = 0;
-    let mut quantities_count: u64 = 0;
+    let mut quantities = NumericAggregate::empty();
 
     {
         println!("Beginning OrderProducts Processing: {}", Utc::now());
-        // Min/Max/Avg products per order
-        // Min/Max/Avg total per order
-        // Min/Max/Avg orders per customers
+        // Single column pass over quantity, each file gets decoded and handed to the vectorized
+        // kernel, then the per-file results are merged together
         let quantity_path: String = order_products_dir.to_string() + "quantity/";
         let mut quantity_file_num: u64 = 0;
-        let mut quantity_file_offset: usize = 0;
-        let quantity_size = mem::size_of::<u64>();
 
-        while Path::new(&(quantity_path.to_owned() + "quantity_" + &format!("{:020}", quantity_file_num))).is_file() {
-            let mut quantity_file_path = quantity_path.to_owned() + "quantity_" + &format!("{:020}", quantity_file_offset);
-            let mut quantity_buffer: Vec<u8> = get_file_as_bytes(quantity_file_path.to_owned());
+        while Path::new(&(quantity_path.to_owned() + &segment_file_name("quantity", quantity_file_num))).is_file() {
+            let quantity_file_path = quantity_path.to_owned() + &segment_file_name("quantity", quantity_file_num);
+            let quantity_buffer: Vec<u8> = get_file_as_bytes(quantity_file_path);
             bytes_scanned += quantity_buffer.len() as u64;
-            while quantity_file_offset < quantity_buffer.len() {
-                let quantity = u64::from_le_bytes(quantity_buffer[quantity_file_offset..quantity_file_offset+quantity_size].try_into().unwrap());
-                quantity_file_offset += quantity_size;
-
-                if quantity > 0 && quantity < min_quantity_per_order { min_quantity_per_order = quantity}
-                if quantity > max_quantity_per_order { max_quantity_per_order = quantity}
-                total_quantity_per_order += quantity;
-                quantities_count += 1;
-            }
-    
+            let quantity_values = decode_u64(&quantity_buffer);
+            quantities.merge(&aggregate_column(&Column::UInt64(quantity_values)).unwrap());
             quantity_file_num += 1;
-            quantity_file_offset = 0;
         }
     }
 
-    if quantities_count == 0 {
+    if quantities.count == 0 {
         println!("No quantities scanned for whatever reason, maybe your data is missing?");
         return;
     }
@@ -475,10 +336,141 @@ pub fn process_average() {
     println!("Analysis complete: {}", Utc::now());
     println!("Time Spent: {:.4?}", (time_spent  as f64 / 1000.0));
     println!("Bytes Scanned: {}", bytes_scanned);
-    println!("Bytes per second: {:#?}", bytes_scanned / (time_spent / 1000) as u64);
+    println!("Bytes per second: {:#?}", bytes_scanned * 1000 / time_spent.max(1) as u64);
     
-    println!("Quantities read: {}", quantities_count);
-    println!("Min/Max/Avg total quantity per order: {}, {}, {:.2?}", min_quantity_per_order, max_quantity_per_order, (total_quantity_per_order as f64/quantities_count as f64));
+    println!("Quantities read: {}", quantities.count);
+    // Zero quantities count towards everything but the minimum
+    let min_quantity = match quantities.min_positive {
+        i128::MAX => u64::MAX as i128,
+        min => min,
+    };
+    println!("Min/Max/Avg total quantity per order: {}, {}, {:.2?}", min_quantity, quantities.max, quantities.average().unwrap());
  
 }
 
+pub struct ScanJoin {
+    pub table: String,
+    pub on: String,
+    pub join_type: String,
+}
+
+pub fn process_scan(table: &str, join: Option<ScanJoin>, select: &Option<String>, filter: &Option<String>, group_by: &Option<String>, limit: &Option<usize>, explain: Option<ExplainMode>) -> Result<(), String> {
+    let mut plan = LogicalPlan::scan(table)?;
+    if let Some(join) = join {
+        let (left_key, right_key) = match parse_expr(&join.on)? {
+            Expr::Compare(CompareOp::Eq, left, right) => (*left, *right),
+            other => return Err(format!("Join condition has to be an equality, got {}", other)),
+        };
+        plan = plan.join(LogicalPlan::scan(&join.table)?, left_key, right_key, JoinType::from_name(&join.join_type)?);
+    }
+    if let Some(filter) = filter {
+        plan = plan.filter(parse_expr(filter)?);
+    }
+    let select = match select {
+        Some(select) => Some(parse_named_exprs(select)?),
+        None => None,
+    };
+    let is_aggregate = select.as_ref().map(|exprs| exprs.iter().any(|(_, expr)| matches!(expr, Expr::Aggregate(_, _)))).unwrap_or(false);
+    if is_aggregate || group_by.is_some() {
+        // Group columns come out first on their own, so the select list is just the aggregates
+        let group_by = match group_by {
+            Some(group_by) => parse_named_exprs(group_by)?,
+            None => Vec::new(),
+        };
+        let mut aggregates: Vec<AggregateExpr> = Vec::new();
+        for (name, expr) in select.unwrap_or_default() {
+            aggregates.push(AggregateExpr::from_named(&name, &expr)?);
+        }
+        plan = plan.aggregate(group_by, aggregates);
+    } else if let Some(select) = select {
+        plan = plan.project(select);
+    }
+    if let Some(limit) = limit {
+        plan = plan.limit(*limit);
+    }
+    run_ad_hoc("scan", to_physical(optimize(plan))?, explain)
+}
+
+pub fn process_query(program: &str) -> Result<(), String> {
+    let time_start: DateTime<Utc> = Utc::now();
+    let bytes_start = BYTES_READ.load(AtomicOrdering::Relaxed);
+    let result = Interpreter::new().run(program)?;
+    if let Some((array, _)) = &result {
+        println!("{}", tidy(array.clone()).format(100));
+    }
+    println!("Time Spent: {:.4?}", ((Utc::now() - time_start).num_milliseconds() as f64 / 1000.0));
+    println!("Bytes Scanned: {}", BYTES_READ.load(AtomicOrdering::Relaxed) - bytes_start);
+    if let Some((_, lineage)) = result {
+        let name = result_name("query", &time_start);
+        lineage::save(&name, &lineage)?;
+        println!("Lineage: {}", name);
+    }
+    Ok(())
+}
+
+pub fn process_sql(statement: &str) -> Result<(), String> {
+    match parse_statement(statement)? {
+        Statement::Query { explain, select } => run_ad_hoc("sql", to_physical(optimize(plan_select(&select)?))?, explain),
+        Statement::CreateView { name, sql } => {
+            view::create(&name, &sql)?;
+            println!("Created materialized view {}", name);
+            Ok(())
+        },
+        Statement::DropView { name } => {
+            view::drop(&name)?;
+            println!("Dropped materialized view {}", name);
+            Ok(())
+        },
+    }
+}
+
+// Runs and prints a scan or sql result, keeping its lineage, or just explains it
+fn run_ad_hoc(kind: &str, operator: Box<dyn Operator>, explain_mode: Option<ExplainMode>) -> Result<(), String> {
+    let mut operator = ResultCache::wrap(operator);
+    let time_start: DateTime<Utc> = Utc::now();
+    let bytes_start = BYTES_READ.load(AtomicOrdering::Relaxed);
+    match explain_mode {
+        Some(ExplainMode::Plan) => {
+            print!("{}", explain(operator.as_ref()));
+            return Ok(());
+        },
+        Some(ExplainMode::Analyze) => {
+            let (_, report) = explain_analyze(operator.as_mut())?;
+            print!("{}", report);
+            return Ok(());
+        },
+        None => {},
+    }
+    let lineage = operator.lineage();
+    let result = collect(operator.as_mut())?;
+    result.print();
+    println!("Rows: {}", result.num_rows());
+    println!("Time Spent: {:.4?}", ((Utc::now() - time_start).num_milliseconds() as f64 / 1000.0));
+    println!("Bytes Scanned: {}", BYTES_READ.load(AtomicOrdering::Relaxed) - bytes_start);
+    let name = result_name(kind, &time_start);
+    lineage::save(&name, &lineage)?;
+    println!("Lineage: {}", name);
+    Ok(())
+}
+
+// Ad hoc results get named after when they ran, e.g. scan.20221004T153012123
+fn result_name(kind: &str, time: &DateTime<Utc>) -> String {
+    format!("{}.{}", kind, time.format("%Y%m%dT%H%M%S%3f"))
+}
+
+pub fn process_lineage(result: &Option<String>) -> Result<(), String> {
+    match result {
+        None => {
+            for name in lineage::stored_results() {
+                println!("{}", name);
+            }
+        },
+        Some(result) => {
+            let stored = lineage::load(result)?;
+            println!("Result: {}", result);
+            println!("Created: {}", stored.created);
+            lineage::print_tree(&stored.root, 0, &mut HashSet::new());
+        },
+    }
+    Ok(())
+}
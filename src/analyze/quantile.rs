@@ -0,0 +1,240 @@
+use super::batch::Value;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use std::f64::consts::PI;
+
+// Percentiles, exactly by holding every value or approximately with a t-digest. The digest keeps
+// a few hundred centroids (a mean and how many values went into it), small ones near either end
+// and big ones in the middle, so the tails the p99s come from stay sharp. Digests of different
+// parts of the data merge into one for the whole, same as if it had all gone into one digest.
+
+// Bigger keeps more centroids and gets closer, around 1% of the way between neighbouring values
+// at the tails is typical for 100
+const COMPRESSION: f64 = 100.0;
+
+// Values collected before they get folded into the centroids
+const BUFFER_SIZE: usize = 500;
+
+// Where the quantile q sits on the scale centroid sizes are limited by, see compress
+fn scale(q: f64) -> f64 {
+    COMPRESSION / (2.0 * PI) * (2.0 * q - 1.0).asin()
+}
+
+fn unscale(k: f64) -> f64 {
+    ((k * 2.0 * PI / COMPRESSION).sin() + 1.0) / 2.0
+}
+
+// Percentiles come back as Decimals whatever went in, they're usually between two values
+pub fn numeric_value(value: &Value) -> Result<Option<f64>, String> {
+    match value {
+        Value::Null => Ok(None),
+        _ => match value.as_decimal().and_then(|val| val.to_f64()) {
+            Some(val) => Ok(Some(val)),
+            None => Err(format!("Can't take a percentile of {}", value)),
+        },
+    }
+}
+
+pub fn decimal_value(val: f64) -> Value {
+    match Decimal::from_f64(val) {
+        Some(val) => Value::Decimal(val.round_dp(6).normalize()),
+        None => Value::Null,
+    }
+}
+
+// Linear interpolation between the closest ranks, what Postgres' percentile_cont does
+pub fn exact_percentile(values: &mut [Decimal], fraction: f64) -> Value {
+    if values.is_empty() {
+        return Value::Null;
+    }
+    values.sort();
+    let position = fraction * (values.len() - 1) as f64;
+    let (below, above) = (position.floor() as usize, position.ceil() as usize);
+    let weight = Decimal::from_f64(position - below as f64).unwrap_or(Decimal::ZERO);
+    Value::Decimal((values[below] + (values[above] - values[below]) * weight).normalize())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TDigest {
+    // Sorted by mean, (mean, weight)
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<(f64, f64)>,
+    total: f64,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    pub fn new() -> TDigest {
+        TDigest { min: f64::INFINITY, max: f64::NEG_INFINITY, ..TDigest::default() }
+    }
+
+    pub fn add(&mut self, val: f64) {
+        self.buffer.push((val, 1.0));
+        self.total += 1.0;
+        self.min = self.min.min(val);
+        self.max = self.max.max(val);
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.compress();
+        }
+    }
+
+    pub fn merge(&mut self, other: &TDigest) {
+        self.buffer.extend(other.centroids.iter().chain(&other.buffer));
+        self.total += other.total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.compress();
+    }
+
+    // One pass over everything in mean order, folding each centroid into the one before it for as
+    // long as that one stays within a step of the scale. Steps cover less of the data near 0 and
+    // 1, which is what keeps the tail centroids small.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = std::mem::take(&mut self.centroids);
+        all.append(&mut self.buffer);
+        all.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(all.len());
+        let mut before = 0.0;
+        let mut limit = self.total * unscale(scale(0.0) + 1.0);
+        let mut current = all[0];
+        for next in all.into_iter().skip(1) {
+            if before + current.1 + next.1 <= limit {
+                let weight = current.1 + next.1;
+                current = (current.0 + (next.0 - current.0) * next.1 / weight, weight);
+            } else {
+                before += current.1;
+                merged.push(current);
+                limit = self.total * unscale(scale(before / self.total) + 1.0);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    pub fn quantile(&mut self, fraction: f64) -> Option<f64> {
+        self.compress();
+        let last = self.centroids.len().checked_sub(1)?;
+        if last == 0 {
+            return Some(self.centroids[0].0);
+        }
+        // Each centroid's mean sits at the middle of its weight, the ends are the min and max
+        let target = fraction * self.total;
+        let mut start = 0.0;
+        let mut previous = (self.min, 0.0);
+        for (index, (mean, weight)) in self.centroids.iter().enumerate() {
+            let middle = start + weight / 2.0;
+            if target < middle {
+                let span = middle - previous.1;
+                return Some(match span > 0.0 {
+                    true => previous.0 + (mean - previous.0) * (target - previous.1) / span,
+                    false => *mean,
+                });
+            }
+            start += weight;
+            previous = (*mean, middle);
+            if index == last {
+                let span = self.total - middle;
+                return Some(match span > 0.0 {
+                    true => mean + (self.max - mean) * (target - middle) / span,
+                    false => *mean,
+                });
+            }
+        }
+        None
+    }
+
+    pub fn percentile(&mut self, fraction: f64) -> Value {
+        match self.quantile(fraction) {
+            Some(val) => decimal_value(val),
+            None => Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decimals(values: &[i64]) -> Vec<Decimal> {
+        values.iter().map(|val| Decimal::from(*val)).collect()
+    }
+
+    #[test]
+    fn exact_percentiles_interpolate_between_ranks() {
+        let mut values = decimals(&[40, 10, 30, 20]);
+        assert_eq!(exact_percentile(&mut values, 0.0), Value::Decimal(Decimal::from(10)));
+        assert_eq!(exact_percentile(&mut values, 0.5), Value::Decimal(Decimal::from(25)));
+        assert_eq!(exact_percentile(&mut values, 0.9), Value::Decimal(Decimal::new(37, 0)));
+        assert_eq!(exact_percentile(&mut values, 1.0), Value::Decimal(Decimal::from(40)));
+        assert_eq!(exact_percentile(&mut decimals(&[7]), 0.99), Value::Decimal(Decimal::from(7)));
+        assert_eq!(exact_percentile(&mut [], 0.5), Value::Null);
+    }
+
+    // Long tailed, like order totals, squared so most values bunch up near zero
+    fn skewed(count: usize, seed: u64) -> Vec<f64> {
+        let mut state = seed;
+        (0..count).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let uniform = (state >> 11) as f64 / (1u64 << 53) as f64;
+            uniform * uniform * 1000.0
+        }).collect()
+    }
+
+    // Where the estimate lands among the real values, as a fraction of them
+    fn rank(sorted: &[f64], estimate: f64) -> f64 {
+        sorted.partition_point(|val| *val < estimate) as f64 / sorted.len() as f64
+    }
+
+    #[test]
+    fn digest_stays_close_in_rank() {
+        let values = skewed(200_000, 0x9e3779b97f4a7c15);
+        let mut digest = TDigest::new();
+        values.iter().for_each(|val| digest.add(*val));
+        let mut sorted = values.clone();
+        sorted.sort_by(f64::total_cmp);
+
+        // The low end is where the values bunch up, so a small miss there is a lot of ranks
+        for (fraction, bound) in [(0.001, 0.001), (0.01, 0.002), (0.25, 0.005), (0.5, 0.005), (0.9, 0.002), (0.99, 0.0005), (0.999, 0.0003)] {
+            let estimate = digest.quantile(fraction).unwrap();
+            let error = (rank(&sorted, estimate) - fraction).abs();
+            assert!(error <= bound, "p{} off by {} of the ranks, more than {}", fraction * 100.0, error, bound);
+        }
+        assert_eq!(digest.quantile(0.0), Some(sorted[0]));
+        assert_eq!(digest.quantile(1.0), Some(sorted[sorted.len() - 1]));
+        assert!(digest.centroids.len() < 10 * COMPRESSION as usize, "{} centroids", digest.centroids.len());
+    }
+
+    #[test]
+    fn merged_digests_do_as_well_as_one() {
+        let parts: Vec<Vec<f64>> = (0..8).map(|part| skewed(25_000, part * 31 + 1)).collect();
+        let mut sorted: Vec<f64> = parts.concat();
+        sorted.sort_by(f64::total_cmp);
+        let mut whole = TDigest::new();
+        for part in &parts {
+            let mut digest = TDigest::new();
+            part.iter().for_each(|val| digest.add(*val));
+            whole.merge(&digest);
+        }
+        for fraction in [0.01, 0.5, 0.99] {
+            let error = (rank(&sorted, whole.quantile(fraction).unwrap()) - fraction).abs();
+            assert!(error <= 0.005, "p{} off by {}", fraction * 100.0, error);
+        }
+    }
+
+    #[test]
+    fn nothing_in_no_percentile() {
+        let mut digest = TDigest::new();
+        assert_eq!(digest.percentile(0.5), Value::Null);
+        digest.merge(&TDigest::new());
+        assert_eq!(digest.quantile(0.5), None);
+        digest.add(4.25);
+        assert_eq!(digest.percentile(0.99), Value::Decimal(Decimal::new(425, 2)));
+    }
+}
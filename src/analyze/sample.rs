@@ -0,0 +1,255 @@
+use super::aggregate::Sum;
+use super::batch::Value;
+use super::quantile::decimal_value;
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+use std::fmt;
+
+// TABLESAMPLE: reading a fraction of a table and scaling what comes out of it back up. SYSTEM
+// picks whole pages of PAGE_ROWS rows, and the pages that aren't picked get seeked over without
+// being read. BERNOULLI picks every row on its own, which reads everything but gives tighter
+// answers when neighbouring rows are alike. Whether a page or row is in only depends on the seed
+// and where it is, so REPEATABLE gets the same rows back whatever else the query does.
+//
+// count, sum and avg over a sample get scaled to the whole table, with the half width of a 95%
+// confidence interval alongside. Each page (or row) is in with probability p independently, so the
+// Horvitz-Thompson variance of a scaled total is (1 - p) / p² times the sum of each picked page's
+// total squared, and avg's comes from the same sums as a ratio of two totals.
+
+// Small enough that even a small sample has plenty of pages to go by, big enough that a page is
+// still a decent sized read per column
+pub const PAGE_ROWS: u64 = 1024;
+
+// z for a two sided 95% interval
+const Z_95: f64 = 1.96;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleMethod {
+    System,
+    Bernoulli,
+}
+
+// Copy and Eq so aggregate functions can carry it, the fraction is in millionths like percentiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleRate {
+    pub method: SampleMethod,
+    pub millionths: u32,
+}
+
+impl SampleRate {
+    pub fn fraction(&self) -> f64 {
+        self.millionths as f64 / 1_000_000.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableSample {
+    pub method: SampleMethod,
+    pub percent: f64,
+    pub seed: u64,
+    // Whether the seed came from REPEATABLE, just for showing the sample back
+    pub repeatable: bool,
+}
+
+impl TableSample {
+    pub fn new(method: SampleMethod, percent: f64, seed: Option<u64>) -> Result<TableSample, String> {
+        if !(percent > 0.0 && percent <= 100.0) {
+            return Err(format!("TABLESAMPLE takes a percentage above 0 and up to 100, not {}", percent));
+        }
+        Ok(TableSample { method, percent, seed: seed.unwrap_or_else(rand::random), repeatable: seed.is_some() })
+    }
+
+    pub fn rate(&self) -> SampleRate {
+        SampleRate { method: self.method, millionths: ((self.percent * 10_000.0).round() as u32).max(1) }
+    }
+
+    fn picks(&self, index: u64) -> bool {
+        let threshold = (self.rate().fraction() * u64::MAX as f64) as u64;
+        mix(self.seed, index) <= threshold
+    }
+
+    pub fn keeps_page(&self, page: u64) -> bool {
+        self.method == SampleMethod::Bernoulli || self.picks(page)
+    }
+
+    pub fn keeps_row(&self, row: u64) -> bool {
+        self.method == SampleMethod::System || self.picks(row)
+    }
+}
+
+impl fmt::Display for TableSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let method = match self.method {
+            SampleMethod::System => "SYSTEM",
+            SampleMethod::Bernoulli => "BERNOULLI",
+        };
+        write!(f, "{} ({})", method, self.percent)?;
+        if self.repeatable {
+            write!(f, " REPEATABLE ({})", self.seed)?;
+        }
+        Ok(())
+    }
+}
+
+// splitmix64 of the seed and position, an even spread of bits with no state to carry along
+fn mix(seed: u64, index: u64) -> u64 {
+    let mut hash = seed ^ index.wrapping_mul(0x9e3779b97f4a7c15);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+// The aggregates that get scaled to the whole table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scaled {
+    Count,
+    Sum,
+    Avg,
+}
+
+// A sampled count, sum or avg as it goes. Values come in tagged with the page (or row) they came
+// from, and each cluster's count and total get folded into the sums of squares once the next one
+// starts. A cluster coming back after another one started counts as a new one, which only
+// narrows the interval a bit if something between the scan and the aggregate reordered rows.
+#[derive(Debug, Clone, Default)]
+pub struct SampleSums {
+    sum: Sum,
+    count: u64,
+    cluster: Option<u64>,
+    // How many clusters there have been, the spread between them needs at least two
+    clusters: u64,
+    // The current cluster's count and total
+    n: f64,
+    y: f64,
+    // Over the finished clusters, n², y² and n·y
+    nn: f64,
+    yy: f64,
+    ny: f64,
+}
+
+impl SampleSums {
+    pub fn add(&mut self, scaled: Scaled, value: &Value, cluster: u64) -> Result<(), String> {
+        if value.is_null() {
+            return Ok(());
+        }
+        if self.cluster != Some(cluster) {
+            self.close();
+            self.cluster = Some(cluster);
+            self.clusters += 1;
+        }
+        if scaled != Scaled::Count {
+            self.sum.add(value)?;
+            self.y += value.as_decimal().and_then(|val| val.to_f64()).unwrap_or(0.0);
+        }
+        self.count += 1;
+        self.n += 1.0;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        let (nn, yy, ny) = self.squares();
+        (self.nn, self.yy, self.ny) = (nn, yy, ny);
+        (self.n, self.y) = (0.0, 0.0);
+    }
+
+    // The sums of squares with the current cluster in
+    fn squares(&self) -> (f64, f64, f64) {
+        (self.nn + self.n * self.n, self.yy + self.y * self.y, self.ny + self.n * self.y)
+    }
+
+    pub fn estimate(&self, scaled: Scaled, rate: SampleRate) -> Value {
+        let scale = Decimal::from(1_000_000) / Decimal::from(rate.millionths);
+        match scaled {
+            Scaled::Count => Value::UInt64((Decimal::from(self.count) * scale).round().to_u64().unwrap_or(u64::MAX)),
+            Scaled::Sum => match self.sum.total() {
+                Value::Null => Value::Null,
+                Value::Decimal(total) => Value::Decimal((total * scale).round_dp(total.scale()).normalize()),
+                total => match total.as_decimal().and_then(|total| (total * scale).round().to_i64()) {
+                    Some(total) => Value::Int64(total),
+                    None => Value::Null,
+                },
+            },
+            Scaled::Avg => match (self.sum.total().as_decimal(), self.count) {
+                (Some(total), count) if count > 0 => Value::Decimal(total / Decimal::from(count)),
+                _ => Value::Null,
+            },
+        }
+    }
+
+    // Half the width of the 95% interval around estimate. NULL when there's no telling: a single
+    // cluster has nothing to compare it with, so its variance comes out as 0 rather than unknown.
+    // Reading the whole table is the exception, there's nothing left to be wrong about.
+    pub fn margin(&self, scaled: Scaled, rate: SampleRate) -> Value {
+        let p = rate.fraction();
+        if self.clusters < 2 && p < 1.0 {
+            return Value::Null;
+        }
+        let (nn, yy, ny) = self.squares();
+        let variance = match scaled {
+            Scaled::Count => (1.0 - p) / (p * p) * nn,
+            Scaled::Sum => (1.0 - p) / (p * p) * yy,
+            Scaled::Avg => {
+                if self.count == 0 {
+                    return Value::Null;
+                }
+                let count = self.count as f64;
+                let ratio = self.sum.total().as_decimal().and_then(|total| total.to_f64()).unwrap_or(0.0) / count;
+                // The deviations of each cluster's total from what its count would get at the mean
+                let deviations = yy - 2.0 * ratio * ny + ratio * ratio * nn;
+                (1.0 - p) * deviations.max(0.0) / (count * count)
+            },
+        };
+        let margin = Z_95 * variance.max(0.0).sqrt();
+        match scaled {
+            Scaled::Count => Value::UInt64(margin.round() as u64),
+            Scaled::Sum if self.count == 0 => Value::Null,
+            _ => decimal_value(margin),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TENTH: SampleRate = SampleRate { method: SampleMethod::System, millionths: 100_000 };
+
+    fn sums(pages: &[(u64, &[i64])]) -> SampleSums {
+        let mut sums = SampleSums::default();
+        for (page, values) in pages {
+            for value in values.iter() {
+                sums.add(Scaled::Sum, &Value::Int64(*value), *page).unwrap();
+            }
+        }
+        sums
+    }
+
+    #[test]
+    fn no_margin_without_two_clusters() {
+        let all = [Scaled::Count, Scaled::Sum, Scaled::Avg];
+        for scaled in all {
+            assert_eq!(SampleSums::default().margin(scaled, TENTH), Value::Null);
+            assert_eq!(sums(&[(3, &[1, 2, 3])]).margin(scaled, TENTH), Value::Null);
+        }
+        // NULLs don't start a cluster
+        let mut nulls = sums(&[(3, &[1, 2, 3])]);
+        nulls.add(Scaled::Sum, &Value::Null, 4).unwrap();
+        assert_eq!(nulls.margin(Scaled::Avg, TENTH), Value::Null);
+        // The whole table is exact however many clusters it had
+        let whole = SampleRate { millionths: 1_000_000, ..TENTH };
+        assert_eq!(sums(&[(3, &[1, 2, 3])]).margin(Scaled::Count, whole), Value::UInt64(0));
+    }
+
+    #[test]
+    fn margin_grows_with_the_spread_between_clusters() {
+        let margin = |pages: &[(u64, &[i64])]| match sums(pages).margin(Scaled::Sum, TENTH) {
+            Value::Decimal(margin) => margin,
+            other => panic!("{:?}", other),
+        };
+        let alike = margin(&[(0, &[5, 5]), (1, &[5, 5]), (2, &[5, 5])]);
+        let apart = margin(&[(0, &[1, 1]), (1, &[5, 5]), (2, &[9, 9])]);
+        assert!(alike < apart, "{} {}", alike, apart);
+        // Same totals, so a count's margin doesn't care what the values were
+        assert_eq!(sums(&[(0, &[1, 1]), (1, &[9, 9])]).margin(Scaled::Count, TENTH), sums(&[(0, &[5, 5]), (1, &[5, 5])]).margin(Scaled::Count, TENTH));
+    }
+}
@@ -0,0 +1,629 @@
+use crate::datagen::bloom;
+use crate::datagen::catalog::{table_def, ColumnDef, KeyOrder, TableDef};
+use crate::datagen::compact;
+use crate::datagen::dataset::DataType;
+use crate::datagen::file::decode_value;
+use crate::datagen::partition;
+use crate::datagen::stats::SegmentStats;
+use super::batch::{Batch, Value};
+use super::expr::{cast, CompareOp, Expr};
+use super::lineage::{column_segments, LineageNode};
+use super::operator::{Operator, BATCH_ROWS};
+use super::planner::split_conjuncts;
+use super::process::get_file_as_bytes;
+use super::sample::{SampleMethod, TableSample, PAGE_ROWS};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Every byte any ColumnReader has pulled off disk, for the bytes/second printouts
+pub static BYTES_READ: AtomicU64 = AtomicU64::new(0);
+
+// Streams one column's values across all of its segment files, decoding just the rows that get
+// asked for. Fixed width values come off disk a batch's worth at a time and rows that get skipped
+// are seeked over, text columns get read a whole file at a time since there's no telling where a
+// row starts without going through the ones before it.
+pub struct ColumnReader {
+    data_type: DataType,
+    paths: Vec<String>,
+    next_path: usize,
+    // The open fixed width file and how many of its bytes haven't been read yet
+    file: Option<File>,
+    file_left: u64,
+    buffer: Vec<u8>,
+    offset: usize,
+    // Rows handed out or skipped so far, and how many each file holds when that's known
+    row: u64,
+    file_rows: Vec<u64>,
+    pub bytes_read: u64,
+    pub segments_skipped: u64,
+}
+
+impl ColumnReader {
+    pub fn new(table: &TableDef, column: &ColumnDef) -> ColumnReader {
+        let paths = partition::column_paths(table, &column.name);
+        // Fixed width files hold as many rows as their size says, text ones need the stats
+        let file_rows = match column.data_type.stored_size() {
+            Some(size) => paths.iter().map(|path| fs::metadata(path).map(|meta| meta.len()).unwrap_or(0) / size as u64).collect(),
+            None => Vec::new(),
+        };
+        ColumnReader {
+            data_type: column.data_type,
+            paths,
+            next_path: 0,
+            file: None,
+            file_left: 0,
+            buffer: Vec::new(),
+            offset: 0,
+            row: 0,
+            file_rows,
+            bytes_read: 0,
+            segments_skipped: 0,
+        }
+    }
+
+    fn count_read(&mut self, bytes: usize) {
+        self.bytes_read += bytes as u64;
+        BYTES_READ.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    // Opens the next file, all of it goes into the buffer for text. False once there are no more.
+    fn open_next(&mut self) -> Result<bool, String> {
+        if self.next_path >= self.paths.len() {
+            return Ok(false);
+        }
+        let path = self.paths[self.next_path].clone();
+        self.next_path += 1;
+        self.offset = 0;
+        match self.data_type.stored_size() {
+            Some(_) => {
+                let file = File::open(&path).map_err(|err| format!("Can't open {}: {}", path, err))?;
+                self.file_left = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+                self.file = Some(file);
+                self.buffer.clear();
+            },
+            None => {
+                self.buffer = get_file_as_bytes(path);
+                self.count_read(self.buffer.len());
+            },
+        }
+        Ok(true)
+    }
+
+    // Makes sure there's something left in the buffer, reading up to `rows` more values of a fixed
+    // width column. False once every file is used up.
+    fn fill(&mut self, rows: usize) -> Result<bool, String> {
+        while self.offset >= self.buffer.len() {
+            if let (Some(file), Some(size)) = (self.file.as_mut(), self.data_type.stored_size()) {
+                // A trailing partial value is left behind, nothing sane to do with it
+                let bytes = (rows.max(1) as u64 * size as u64).min(self.file_left / size as u64 * size as u64) as usize;
+                if bytes > 0 {
+                    self.buffer.resize(bytes, 0);
+                    file.read_exact(&mut self.buffer).map_err(|err| format!("Can't read {}: {}", self.paths[self.next_path - 1], err))?;
+                    self.file_left -= bytes as u64;
+                    self.offset = 0;
+                    self.count_read(bytes);
+                    continue;
+                }
+                self.file = None;
+            }
+            if !self.open_next()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn read(&mut self, max_rows: usize) -> Result<Vec<Value>, String> {
+        let mut values: Vec<Value> = Vec::with_capacity(max_rows);
+        while values.len() < max_rows && self.fill(max_rows - values.len())? {
+            match self.data_type.stored_size() {
+                Some(size) => {
+                    let available = (self.buffer.len() - self.offset) / size;
+                    let take = available.min(max_rows - values.len());
+                    for _ in 0..take {
+                        values.push(decode_value(&self.buffer[self.offset..self.offset+size], self.data_type));
+                        self.offset += size;
+                    }
+                    self.row += take as u64;
+                },
+                None => {
+                    let end = self.buffer[self.offset..].iter()
+                        .position(|byte| *byte == b'\n')
+                        .map(|pos| self.offset + pos)
+                        .unwrap_or(self.buffer.len());
+                    values.push(Value::String(String::from_utf8_lossy(&self.buffer[self.offset..end]).to_string()));
+                    self.offset = end + 1;
+                    self.row += 1;
+                },
+            }
+        }
+        Ok(values)
+    }
+
+    // Moves past rows without decoding them. Fixed width rows get seeked over, and whole files in
+    // the way don't even get opened when their row counts are known.
+    pub fn skip_to(&mut self, target: u64) -> Result<(), String> {
+        while self.row < target {
+            if self.offset < self.buffer.len() {
+                match self.data_type.stored_size() {
+                    Some(size) => {
+                        let rows = (((self.buffer.len() - self.offset) / size) as u64).min(target - self.row);
+                        self.offset += rows as usize * size;
+                        self.row += rows;
+                    },
+                    None => {
+                        self.offset = self.buffer[self.offset..].iter()
+                            .position(|byte| *byte == b'\n')
+                            .map(|pos| self.offset + pos + 1)
+                            .unwrap_or(self.buffer.len());
+                        self.row += 1;
+                    },
+                }
+                continue;
+            }
+            if let (Some(file), Some(size)) = (self.file.as_mut(), self.data_type.stored_size()) {
+                let rows = (self.file_left / size as u64).min(target - self.row);
+                match rows {
+                    0 => self.file = None,
+                    _ => {
+                        file.seek(SeekFrom::Current((rows * size as u64) as i64))
+                            .map_err(|err| format!("Can't seek in {}: {}", self.paths[self.next_path - 1], err))?;
+                        self.file_left -= rows * size as u64;
+                        self.row += rows;
+                    },
+                }
+                continue;
+            }
+            match self.file_rows.get(self.next_path) {
+                Some(rows) if self.row + rows <= target => {
+                    self.row += rows;
+                    self.next_path += 1;
+                    self.segments_skipped += 1;
+                },
+                _ => {
+                    if !self.open_next()? {
+                        return Ok(());
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether any row in a segment could pass the predicate, going by each column's min and max
+// there. Only comparisons of a column against a literal can rule anything out, anything else is
+// assumed to match. stats gets a column name as the predicate has it.
+pub fn may_match(predicate: &Expr, stats: &dyn Fn(&str) -> Option<(Value, Value)>) -> bool {
+    match predicate {
+        Expr::And(left, right) => may_match(left, stats) && may_match(right, stats),
+        Expr::Or(left, right) => may_match(left, stats) || may_match(right, stats),
+        Expr::Compare(op, left, right) => {
+            let (op, name, value) = match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(name), Expr::Literal(value)) => (*op, name, value),
+                (Expr::Literal(value), Expr::Column(name)) => (flip(*op), name, value),
+                _ => return true,
+            };
+            let (min, max) = match stats(name) {
+                Some(range) => range,
+                None => return true,
+            };
+            // All null, so no comparison can come out true
+            if min.is_null() || max.is_null() {
+                return false;
+            }
+            let (low, high) = match (min.compare(value), max.compare(value)) {
+                (Some(low), Some(high)) => (low, high),
+                _ => return true,
+            };
+            match op {
+                CompareOp::Eq => low != CmpOrdering::Greater && high != CmpOrdering::Less,
+                CompareOp::NotEq => !(low == CmpOrdering::Equal && high == CmpOrdering::Equal),
+                CompareOp::Lt => low == CmpOrdering::Less,
+                CompareOp::LtEq => low != CmpOrdering::Greater,
+                CompareOp::Gt => high == CmpOrdering::Greater,
+                CompareOp::GtEq => high != CmpOrdering::Less,
+            }
+        },
+        _ => true,
+    }
+}
+
+// The other way around from may_match: whether every row in a segment is sure to pass. Anything
+// that isn't a comparison of a column against a literal might not.
+pub fn must_match(predicate: &Expr, stats: &dyn Fn(&str) -> Option<(Value, Value)>) -> bool {
+    match predicate {
+        Expr::And(left, right) => must_match(left, stats) && must_match(right, stats),
+        Expr::Or(left, right) => must_match(left, stats) || must_match(right, stats),
+        Expr::Compare(op, left, right) => {
+            let (op, name, value) = match (left.as_ref(), right.as_ref()) {
+                (Expr::Column(name), Expr::Literal(value)) => (*op, name, value),
+                (Expr::Literal(value), Expr::Column(name)) => (flip(*op), name, value),
+                _ => return false,
+            };
+            let (low, high) = match stats(name).map(|(min, max)| (min.compare(value), max.compare(value))) {
+                Some((Some(low), Some(high))) => (low, high),
+                _ => return false,
+            };
+            match op {
+                CompareOp::Eq => low == CmpOrdering::Equal && high == CmpOrdering::Equal,
+                CompareOp::NotEq => low == CmpOrdering::Greater || high == CmpOrdering::Less,
+                CompareOp::Lt => high == CmpOrdering::Less,
+                CompareOp::LtEq => high != CmpOrdering::Greater,
+                CompareOp::Gt => low == CmpOrdering::Greater,
+                CompareOp::GtEq => low != CmpOrdering::Less,
+            }
+        },
+        _ => false,
+    }
+}
+
+// `5 < x` is `x > 5`
+pub fn flip(op: CompareOp) -> CompareOp {
+    match op {
+        CompareOp::Lt => CompareOp::Gt,
+        CompareOp::LtEq => CompareOp::GtEq,
+        CompareOp::Gt => CompareOp::Lt,
+        CompareOp::GtEq => CompareOp::LtEq,
+        other => other,
+    }
+}
+
+// Reads the requested columns of a table in lockstep, BATCH_ROWS rows at a time. A pushed down
+// filter gets applied right here, and when every column has current stats, row ranges whose
+// segments can't match it (going by zone maps, or bloom filters for a key the filter wants) get
+// skipped without being decoded (or read, where a whole file is out).
+// With a TABLESAMPLE the pages that aren't picked get skipped the same way, and batches never cross
+// a page so whatever aggregates the sample can tell them apart. A primary key index lookup skips
+// everything but the rows it found.
+pub struct TableScan {
+    table: TableDef,
+    columns: Vec<String>,
+    readers: Vec<ColumnReader>,
+    filter: Option<Expr>,
+    sample: Option<TableSample>,
+    // The indexed column that picked the rows and how many it found
+    index: Option<(String, u64)>,
+    // Partitions the filter left out of how many, for a partitioned table
+    partitions: Option<(usize, usize)>,
+    skips: VecDeque<Range<u64>>,
+    // Rows the skips took out of the table when the scan was set up, next_batch uses the skips up
+    skipped: u64,
+    row: u64,
+}
+
+impl TableScan {
+    pub fn new(table_name: &str, columns: &[String]) -> Result<TableScan, String> {
+        TableScan::filtered(table_name, columns, None)
+    }
+
+    pub fn filtered(table_name: &str, columns: &[String], filter: Option<Expr>) -> Result<TableScan, String> {
+        TableScan::skipping(table_name, columns, filter, Vec::new())
+    }
+
+    // Also leaves out the given row ranges, for when the caller already has what's in them some
+    // other way
+    pub fn skipping(table_name: &str, columns: &[String], filter: Option<Expr>, skips: Vec<Range<u64>>) -> Result<TableScan, String> {
+        let table = table_def(table_name)?;
+        // Those rows would just go missing, compaction is what reads them
+        if partition::unpartitioned(&table).is_some() {
+            return Err(format!("Table {:?} has rows from before it was partitioned, `compact {}` moves them into partitions", table_name, table_name));
+        }
+        TableScan::over(table, columns, filter, skips)
+    }
+
+    // Works on a single partition of a table too
+    pub fn over(table: TableDef, columns: &[String], filter: Option<Expr>, mut skips: Vec<Range<u64>>) -> Result<TableScan, String> {
+        let columns: Vec<String> = match columns.is_empty() {
+            true => table.columns.iter().map(|col| col.name.clone()).collect(),
+            false => columns.to_vec(),
+        };
+        let mut readers: Vec<ColumnReader> = Vec::new();
+        for col_name in &columns {
+            let column = table.column(col_name)
+                .ok_or(format!("Column with name {:?} not in table {:?}", col_name, table.name))?;
+            readers.push(ColumnReader::new(&table, column));
+        }
+        let mut scan = TableScan { table, columns, readers, filter, sample: None, index: None, partitions: None, skips: VecDeque::new(), skipped: 0, row: 0 };
+        // Whole partitions the filter can't match go first, stats or not
+        if let (Some(partitioning), None, Some(filter)) = (scan.table.partitioning.clone(), scan.table.partition, scan.filter.as_ref()) {
+            let data_type = scan.table.column(partitioning.column()).map(|col| col.data_type)
+                .ok_or(format!("Table {:?} is partitioned on {:?}, which isn't one of its columns", scan.table.name, partitioning.column()))?;
+            let parts = partition::partition_rows(&scan.table);
+            let mut start = 0;
+            let mut kept = 0;
+            for (part, rows) in &parts {
+                match partitioning.may_match(partition::number(part), filter, data_type) {
+                    true => kept += 1,
+                    false => skips.push(start..start + rows),
+                }
+                start += rows;
+            }
+            scan.partitions = Some((kept, parts.len()));
+        }
+        if scan.filter.is_some() || !skips.is_empty() {
+            if let Some(ruled_out) = scan.skipped_ranges(scan.filter.clone().as_ref()) {
+                skips.extend(ruled_out);
+            }
+        }
+        // In order with overlaps joined up, so next_batch can take them one after the other
+        skips.sort_by_key(|skip| skip.start);
+        for skip in skips {
+            match scan.skips.back_mut() {
+                Some(last) if last.end >= skip.start => last.end = last.end.max(skip.end),
+                _ => scan.skips.push_back(skip),
+            }
+        }
+        let rows = scan.table_rows().unwrap_or(0);
+        scan.skipped = scan.skips.iter().map(|skip| skip.end.min(rows).saturating_sub(skip.start.min(rows))).sum();
+        Ok(scan)
+    }
+
+    // Only the given rows (in order), which an index found for a condition on the column
+    pub fn indexed(table_name: &str, columns: &[String], filter: Option<Expr>, column: &str, rows: &[u64]) -> Result<TableScan, String> {
+        let mut skips: Vec<Range<u64>> = Vec::new();
+        let mut next: u64 = 0;
+        for row in rows {
+            if *row > next {
+                skips.push(next..*row);
+            }
+            next = row + 1;
+        }
+        skips.push(next..u64::MAX);
+        let mut scan = TableScan::skipping(table_name, columns, filter, skips)?;
+        scan.index = Some((column.to_string(), rows.len() as u64));
+        Ok(scan)
+    }
+
+    pub fn sampled(table_name: &str, columns: &[String], filter: Option<Expr>, sample: TableSample) -> Result<TableScan, String> {
+        let mut skips: Vec<Range<u64>> = Vec::new();
+        if sample.method == SampleMethod::System {
+            let rows = TableScan::new(table_name, columns)?.estimated_rows()
+                .ok_or(format!("TABLESAMPLE SYSTEM needs a fixed width column in {:?} to tell where its pages are", table_name))?;
+            for page in 0..rows.div_ceil(PAGE_ROWS) {
+                if !sample.keeps_page(page) {
+                    skips.push(page * PAGE_ROWS..((page + 1) * PAGE_ROWS).min(rows));
+                }
+            }
+        }
+        let mut scan = TableScan::skipping(table_name, columns, filter, skips)?;
+        scan.sample = Some(sample);
+        Ok(scan)
+    }
+
+    // Any fixed width column gives the exact count from file sizes alone
+    fn table_rows(&self) -> Option<u64> {
+        self.table.columns.iter().find(|col| col.data_type.stored_size().is_some())?;
+        Some(partition::partition_rows(&self.table).iter().map(|(_, rows)| rows).sum())
+    }
+
+    // Rows the zone maps rule out for the filter. None when any scanned column is missing stats,
+    // readers need every file's row count to stay lined up while skipping.
+    fn skipped_ranges(&mut self, filter: Option<&Expr>) -> Option<Vec<Range<u64>>> {
+        let mut column_stats: HashMap<String, Vec<SegmentStats>> = HashMap::new();
+        // The partition directory each segment is in, for its bloom filter
+        let mut directories: HashMap<String, Vec<String>> = HashMap::new();
+        for (col_name, reader) in self.columns.iter().zip(self.readers.iter_mut()) {
+            let (dirs, segments): (Vec<String>, Vec<SegmentStats>) = partition::current_stats(&self.table, col_name, reader.data_type)?.into_iter().unzip();
+            reader.file_rows = segments.iter().map(|segment| segment.rows).collect();
+            column_stats.insert(col_name.clone(), segments);
+            directories.insert(col_name.clone(), dirs);
+        }
+        let totals: BTreeSet<u64> = self.readers.iter().map(|reader| reader.file_rows.iter().sum()).collect();
+        if totals.len() > 1 {
+            return None;
+        }
+        // Files of different columns end at different rows, so check every stretch between any
+        // two boundaries of the filtered columns
+        let prefix = format!("{}.", self.table.name);
+        let local = |name: &str| name.strip_prefix(&prefix).unwrap_or(name).to_string();
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return Some(Vec::new()),
+        };
+        let filtered: Vec<String> = filter.columns().iter().map(|name| local(name)).filter(|name| column_stats.contains_key(name)).collect();
+        let mut boundaries: BTreeSet<u64> = BTreeSet::new();
+        for name in &filtered {
+            let mut start = 0;
+            for segment in &column_stats[name] {
+                boundaries.insert(start);
+                start += segment.rows;
+                boundaries.insert(start);
+            }
+        }
+        let boundaries: Vec<u64> = boundaries.into_iter().collect();
+        let mut skips: Vec<Range<u64>> = Vec::new();
+        for stretch in boundaries.windows(2) {
+            let (start, end) = (stretch[0], stretch[1]);
+            let covering = |name: &str| -> Option<(Value, Value)> {
+                let mut first = 0;
+                for segment in column_stats.get(&local(name))? {
+                    if start < first + segment.rows {
+                        return Some((segment.min.clone(), segment.max.clone()));
+                    }
+                    first += segment.rows;
+                }
+                None
+            };
+            if !may_match(filter, &covering) {
+                match skips.last_mut() {
+                    Some(last) if last.end == start => last.end = end,
+                    _ => skips.push(start..end),
+                }
+            }
+        }
+        // Key columns with bloom filters can rule out whole segments for an equality the zone
+        // maps can't, a key that's somewhere between the min and max but not actually there
+        for conjunct in split_conjuncts(filter.clone()) {
+            let (name, value) = match &conjunct {
+                Expr::Compare(CompareOp::Eq, left, right) => match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(name), Expr::Literal(value)) | (Expr::Literal(value), Expr::Column(name)) => (local(name), value),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let (segments, column) = match (column_stats.get(&name), self.table.column(&name)) {
+                (Some(segments), Some(column)) if self.table.key_filters.contains(&name) => (segments, column),
+                _ => continue,
+            };
+            let value = match cast(value, column.data_type) {
+                Ok(value) if !value.is_null() => value,
+                _ => continue,
+            };
+            let mut start = 0;
+            for (segment, directory) in segments.iter().zip(&directories[&name]) {
+                if !bloom::may_contain(directory, &name, segment.file_num, segment.bytes, &value) {
+                    skips.push(start..start + segment.rows);
+                }
+                start += segment.rows;
+            }
+        }
+        Some(skips)
+    }
+}
+
+impl Operator for TableScan {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        loop {
+            while let Some(skip) = self.skips.front().cloned() {
+                if skip.start > self.row {
+                    break;
+                }
+                if skip.end > self.row {
+                    for reader in self.readers.iter_mut() {
+                        reader.skip_to(skip.end)?;
+                    }
+                    self.row = skip.end;
+                }
+                self.skips.pop_front();
+            }
+            // Stop short of the next skip so it can get jumped, and of the next page when sampling
+            let mut max_rows = match self.skips.front() {
+                Some(skip) => ((skip.start - self.row) as usize).min(BATCH_ROWS),
+                None => BATCH_ROWS,
+            };
+            if self.sample.is_some() {
+                max_rows = max_rows.min((PAGE_ROWS - self.row % PAGE_ROWS) as usize);
+            }
+            let mut columns: Vec<Vec<Value>> = Vec::new();
+            for reader in self.readers.iter_mut() {
+                columns.push(reader.read(max_rows)?);
+            }
+            let rows = columns.first().map(|col| col.len()).unwrap_or(0);
+            if rows == 0 {
+                return Ok(None);
+            }
+            if columns.iter().any(|col| col.len() != rows) {
+                return Err(format!("Columns of table {:?} have different row counts", self.table.name));
+            }
+            let first = self.row;
+            self.row += rows as u64;
+            let mut batch = Batch::new(self.schema(), columns);
+            if self.sample.is_some() {
+                batch.page = Some(first / PAGE_ROWS);
+            }
+            if let Some(sample) = self.sample.as_ref().filter(|sample| sample.method == SampleMethod::Bernoulli) {
+                let picked: Vec<bool> = (first..self.row).map(|row| sample.keeps_row(row)).collect();
+                batch = batch.filter(&picked);
+                if batch.num_rows() == 0 {
+                    continue;
+                }
+            }
+            let filter = match &self.filter {
+                Some(filter) => filter,
+                None => return Ok(Some(batch)),
+            };
+            let filtered = batch.filter(&filter.eval_mask(&batch)?);
+            if filtered.num_rows() > 0 {
+                return Ok(Some(filtered));
+            }
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.columns.iter().map(|col| format!("{}.{}", self.table.name, col)).collect()
+    }
+
+    fn name(&self) -> String {
+        let name = match &self.filter {
+            Some(filter) => format!("TableScan {} [{}] filter {}", self.table.name, self.columns.join(", "), filter),
+            None => format!("TableScan {}", self.table.name),
+        };
+        let name = match &self.index {
+            Some((column, rows)) => format!("{} index {} ({} rows)", name, column, rows),
+            None => name,
+        };
+        let name = match self.partitions {
+            Some((kept, total)) => format!("{} partitions {}/{}", name, kept, total),
+            None => name,
+        };
+        match &self.sample {
+            Some(sample) => format!("{} sample {}", name, sample),
+            None => name,
+        }
+    }
+
+    fn lineage(&self) -> LineageNode {
+        let mut node = LineageNode::new(self.name(), self.schema(), Vec::new());
+        for col in &self.columns {
+            node.segments.extend(column_segments(&self.table, col));
+        }
+        node
+    }
+
+    // A sample without REPEATABLE picks different rows every time
+    fn repeatable(&self) -> bool {
+        self.sample.as_ref().is_none_or(|sample| sample.repeatable)
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        if let Some((_, rows)) = &self.index {
+            return Some(*rows);
+        }
+        let rows = self.table_rows()? as f64;
+        // A sample's skipped pages are already in its rate, otherwise what's left after pruning
+        // is what the filter gets applied to
+        let rows = match &self.sample {
+            Some(sample) => rows * sample.rate().fraction(),
+            None => rows - self.skipped as f64,
+        };
+        let selectivity = self.filter.as_ref().map_or(1.0, |filter| filter.selectivity());
+        Some((rows * selectivity).round() as u64)
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.readers.iter().map(|reader| reader.bytes_read).sum()
+    }
+
+    fn segments_skipped(&self) -> u64 {
+        self.readers.iter().map(|reader| reader.segments_skipped).sum()
+    }
+
+    fn memory_bytes(&self) -> usize {
+        // A batch's worth per fixed width column, a whole segment file per text one
+        self.readers.iter().map(|reader| reader.buffer.capacity()).sum()
+    }
+
+    fn orderings(&self) -> Vec<KeyOrder> {
+        let scanned = |column: &String| self.columns.contains(column).then(|| format!("{}.{}", self.table.name, column));
+        let mut orderings = Vec::new();
+        if compact::in_sort_order(&self.table) {
+            orderings.extend(self.table.sort_key.as_ref().and_then(scanned).map(KeyOrder::sorted));
+            // Ties went by the primary key, so its values are in one fixed sequence that tables
+            // clustered on this one follow. Filtering or sampling can drop keys, so only a scan of
+            // every row is the primary of it.
+            let whole = self.filter.is_none() && self.sample.is_none() && self.skips.is_empty();
+            orderings.extend(self.table.primary_key.as_ref().and_then(scanned)
+                .map(|column| KeyOrder { column, clustering: Some(self.table.name.clone()), primary: whole }));
+        }
+        if let Some(clustering) = self.table.clustering.as_ref().filter(|_| compact::follows_parent(&self.table)) {
+            orderings.extend(scanned(&clustering.column)
+                .map(|column| KeyOrder { column, clustering: Some(clustering.parent.clone()), primary: false }));
+        }
+        orderings
+    }
+}
@@ -0,0 +1,371 @@
+use super::batch::{Batch, Value};
+use super::expr::Expr;
+use super::operator::{Operator, BATCH_ROWS};
+use super::spill::{memory_budget, SpillFile, SpillScan, SpillWriter};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SortKey {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+impl SortKey {
+    pub fn new(expr: Expr, descending: bool) -> SortKey {
+        SortKey { expr, descending }
+    }
+}
+
+// Numbers compare across types, Null sorts after everything going up so it ends up first going
+// down, same as Postgres
+pub fn compare_sort_values(left: &Value, right: &Value) -> Ordering {
+    match (left.is_null(), right.is_null()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        _ => left.compare(right).unwrap_or_else(|| left.cmp(right)),
+    }
+}
+
+pub fn compare_rows(keys: &[SortKey], left: &[Value], right: &[Value]) -> Ordering {
+    for ((key, l), r) in keys.iter().zip(left).zip(right) {
+        let ordering = compare_sort_values(l, r);
+        let ordering = match key.descending {
+            true => ordering.reverse(),
+            false => ordering,
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+// Finds the order rows should go in from their key values, stable so ties keep their input order
+fn sorted_order(keys: &[SortKey], key_values: &[Vec<Value>]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..key_values.len()).collect();
+    order.sort_by(|a, b| compare_rows(keys, &key_values[*a], &key_values[*b]));
+    order
+}
+
+// The next row of one sorted run, ordered so the smallest comes off a max heap first. Ties go to
+// the earlier run, which keeps the merge stable since runs are cut in input order.
+struct MergeHead {
+    key: Vec<Value>,
+    run: usize,
+    keys: Rc<Vec<SortKey>>,
+}
+
+impl Ord for MergeHead {
+    fn cmp(&self, other: &MergeHead) -> Ordering {
+        compare_rows(&self.keys, &self.key, &other.key).then(self.run.cmp(&other.run)).reverse()
+    }
+}
+
+impl PartialOrd for MergeHead {
+    fn partial_cmp(&self, other: &MergeHead) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeHead {
+    fn eq(&self, other: &MergeHead) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead {}
+
+// k-way merge of sorted run files. Rows in a run are the input row with its sort key values
+// tacked on the end, so nothing has to be evaluated again.
+struct RunMerger {
+    runs: Vec<(SpillScan, Batch, usize)>,
+    heap: BinaryHeap<MergeHead>,
+    keys: Rc<Vec<SortKey>>,
+    names: Vec<String>,
+    held: usize,
+}
+
+impl RunMerger {
+    fn new(files: Vec<SpillFile>, names: Vec<String>, keys: Rc<Vec<SortKey>>) -> Result<RunMerger, String> {
+        let mut merger = RunMerger { runs: Vec::new(), heap: BinaryHeap::new(), keys, names, held: 0 };
+        for file in files {
+            let scan = SpillScan::new(file, merger.names.clone())?;
+            merger.runs.push((scan, Batch::default(), 0));
+            merger.advance(merger.runs.len() - 1)?;
+        }
+        Ok(merger)
+    }
+
+    // Moves a run on a row, reading its next batch when it gets to the end of one, and puts its
+    // new head in the heap
+    fn advance(&mut self, run: usize) -> Result<(), String> {
+        let (scan, batch, offset) = &mut self.runs[run];
+        if *offset >= batch.num_rows() {
+            let next = scan.next_batch()?.unwrap_or_default();
+            self.held = self.held + next.size() - batch.size();
+            *batch = next;
+            *offset = 0;
+            if batch.num_rows() == 0 {
+                return Ok(());
+            }
+        }
+        let key_start = batch.columns.len() - self.keys.len();
+        let key = batch.columns[key_start..].iter().map(|col| col[*offset].clone()).collect();
+        self.heap.push(MergeHead { key, run, keys: self.keys.clone() });
+        Ok(())
+    }
+
+    // Up to `rows` of the smallest rows left, whole, sort key values and all
+    fn next_rows(&mut self, rows: usize) -> Result<Batch, String> {
+        let mut output = Batch::new(self.names.clone(), vec![Vec::new(); self.names.len()]);
+        while output.num_rows() < rows {
+            let head = match self.heap.pop() {
+                Some(head) => head,
+                None => break,
+            };
+            let (_, batch, offset) = &mut self.runs[head.run];
+            for (col, values) in output.columns.iter_mut().zip(&batch.columns) {
+                col.push(values[*offset].clone());
+            }
+            *offset += 1;
+            self.advance(head.run)?;
+        }
+        Ok(output)
+    }
+}
+
+// ORDER BY over any number of keys. Input gets drained into memory and sorted in one go, the sort
+// is stable so ties keep their input order.
+//
+// Past the memory budget it becomes an external merge sort: whatever's buffered gets sorted and
+// written out as a run, and once the input is done the runs are merged back together. More runs
+// than MERGE_FAN_IN get merged a group at a time into longer runs first, so the merge never has
+// more than that many files open and a batch from each in memory.
+pub struct Sort {
+    input: Box<dyn Operator>,
+    keys: Rc<Vec<SortKey>>,
+    sorted: Option<Batch>,
+    offset: usize,
+    held: usize,
+    budget: usize,
+    merger: Option<RunMerger>,
+    bytes_spilled: u64,
+}
+
+const MERGE_FAN_IN: usize = 64;
+
+impl Sort {
+    pub fn new(input: Box<dyn Operator>, keys: Vec<SortKey>) -> Sort {
+        Sort {
+            input,
+            keys: Rc::new(keys),
+            sorted: None,
+            offset: 0,
+            held: 0,
+            budget: memory_budget(),
+            merger: None,
+            bytes_spilled: 0,
+        }
+    }
+
+    fn run_names(&self) -> Vec<String> {
+        self.input.schema().into_iter().chain((0..self.keys.len()).map(|index| format!("__sort{}", index))).collect()
+    }
+
+    fn write_run(&mut self, rows: &Batch, key_values: &[Vec<Value>]) -> Result<SpillFile, String> {
+        let mut writer = SpillWriter::new()?;
+        for row in sorted_order(&self.keys, key_values) {
+            let values: Vec<Value> = rows.columns.iter().map(|col| col[row].clone()).chain(key_values[row].iter().cloned()).collect();
+            writer.write_row(&values)?;
+        }
+        let file = writer.finish()?;
+        self.bytes_spilled += file.bytes;
+        Ok(file)
+    }
+
+    // Merges a group of runs into one longer run
+    fn merge_runs(&mut self, runs: Vec<SpillFile>) -> Result<SpillFile, String> {
+        let mut merger = RunMerger::new(runs, self.run_names(), self.keys.clone())?;
+        let mut writer = SpillWriter::new()?;
+        loop {
+            let batch = merger.next_rows(BATCH_ROWS)?;
+            if batch.num_rows() == 0 {
+                break;
+            }
+            for row in 0..batch.num_rows() {
+                let values: Vec<Value> = batch.columns.iter().map(|col| col[row].clone()).collect();
+                writer.write_row(&values)?;
+            }
+        }
+        let file = writer.finish()?;
+        self.bytes_spilled += file.bytes;
+        Ok(file)
+    }
+
+    fn sort_input(&mut self) -> Result<(), String> {
+        let empty = Batch::new(self.input.schema(), vec![Vec::new(); self.input.schema().len()]);
+        let mut rows = empty.clone();
+        let mut key_values: Vec<Vec<Value>> = Vec::new();
+        let mut runs: Vec<SpillFile> = Vec::new();
+        while let Some(batch) = self.input.next_batch()? {
+            let mut columns: Vec<Vec<Value>> = Vec::new();
+            for key in self.keys.iter() {
+                columns.push(key.expr.eval(&batch)?);
+            }
+            for row in 0..batch.num_rows() {
+                key_values.push(columns.iter().map(|col| col[row].clone()).collect());
+            }
+            // Rows and their keys, then a sorted copy of the rows while the first is still around
+            self.held += batch.size() * 2 + columns.iter().map(|col| col.iter().map(|val| val.size()).sum::<usize>()).sum::<usize>();
+            rows.append(batch);
+            if self.held > self.budget {
+                runs.push(self.write_run(&rows, &key_values)?);
+                rows = empty.clone();
+                key_values.clear();
+                self.held = 0;
+            }
+        }
+        if runs.is_empty() {
+            let order = sorted_order(&self.keys, &key_values);
+            let columns = rows.columns.iter()
+                .map(|col| order.iter().map(|row| col[*row].clone()).collect())
+                .collect();
+            self.sorted = Some(Batch::new(rows.names, columns));
+            return Ok(());
+        }
+        if rows.num_rows() > 0 {
+            runs.push(self.write_run(&rows, &key_values)?);
+        }
+        drop(rows);
+        self.held = 0;
+        while runs.len() > MERGE_FAN_IN {
+            let mut merged: Vec<SpillFile> = Vec::new();
+            let mut remaining = runs.into_iter().peekable();
+            while remaining.peek().is_some() {
+                let group: Vec<SpillFile> = remaining.by_ref().take(MERGE_FAN_IN).collect();
+                merged.push(self.merge_runs(group)?);
+            }
+            runs = merged;
+        }
+        self.merger = Some(RunMerger::new(runs, self.run_names(), self.keys.clone())?);
+        Ok(())
+    }
+}
+
+impl Operator for Sort {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.sorted.is_none() && self.merger.is_none() {
+            self.sort_input()?;
+        }
+        if let Some(merger) = self.merger.as_mut() {
+            let mut batch = merger.next_rows(BATCH_ROWS)?;
+            if batch.num_rows() == 0 {
+                return Ok(None);
+            }
+            // Leave off the sort key values that came along through the runs
+            let width = self.input.schema().len();
+            batch.names.truncate(width);
+            batch.columns.truncate(width);
+            return Ok(Some(batch));
+        }
+        let sorted = self.sorted.as_ref().unwrap();
+        let end = (self.offset + BATCH_ROWS).min(sorted.num_rows());
+        if self.offset >= end {
+            return Ok(None);
+        }
+        let columns = sorted.columns.iter().map(|col| col[self.offset..end].to_vec()).collect();
+        self.offset = end;
+        Ok(Some(Batch::new(sorted.names.clone(), columns)))
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.input.schema()
+    }
+
+    fn name(&self) -> String {
+        let keys: Vec<String> = self.keys.iter()
+            .map(|key| format!("{} {}", key.expr, if key.descending { "DESC" } else { "ASC" }))
+            .collect();
+        format!("Sort {}", keys.join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.held + self.merger.as_ref().map(|merger| merger.held).unwrap_or(0)
+    }
+
+    fn bytes_spilled(&self) -> u64 {
+        self.bytes_spilled
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        self.input.estimated_rows()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::operator::{collect, BatchSource};
+    use rust_decimal::Decimal;
+
+    // Order lines with few enough prices, orders and quantities that plenty of them tie on all
+    // three, and `line` to tell the ties apart
+    fn order_products(batches: usize, rows: usize) -> BatchSource {
+        let mut state: u64 = 7;
+        let mut next = |below: u64| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) % below
+        };
+        let names = ["line", "order_id", "price_per", "quantity"].map(String::from).to_vec();
+        let batches = (0..batches).map(|batch| {
+            let mut columns: Vec<Vec<Value>> = vec![Vec::new(); 4];
+            for row in 0..rows {
+                columns[0].push(Value::UInt64((batch * rows + row) as u64));
+                columns[1].push(Value::String(format!("order-{:02}", next(12))));
+                columns[2].push(match next(10) {
+                    0 => Value::Null,
+                    cents => Value::Decimal(Decimal::new(cents as i64 * 250 + 99, 2)),
+                });
+                columns[3].push(Value::UInt8(next(3) as u8 + 1));
+            }
+            Batch::new(names.clone(), columns)
+        }).collect();
+        BatchSource::new(batches)
+    }
+
+    fn sort(budget: usize) -> (Batch, u64) {
+        let keys = vec![
+            SortKey::new(Expr::Column("price_per".to_string()), true),
+            SortKey::new(Expr::Column("order_id".to_string()), false),
+            SortKey::new(Expr::Column("quantity".to_string()), false),
+        ];
+        let mut sort = Sort::new(Box::new(order_products(150, 40)), keys);
+        sort.budget = budget;
+        let sorted = collect(&mut sort).unwrap();
+        (sorted, sort.bytes_spilled())
+    }
+
+    #[test]
+    fn spilled_runs_merge_back_to_the_in_memory_order() {
+        let (in_memory, not_spilled) = sort(usize::MAX);
+        assert_eq!(not_spilled, 0);
+        // Every batch is a run of its own, 150 of them is more than one merge takes in, so some
+        // get merged into longer runs first
+        let (merged, spilled) = sort(0);
+        assert!(spilled > 0);
+        assert_eq!(merged.num_rows(), 6000);
+        assert_eq!(merged.names, in_memory.names);
+        assert!(merged.columns == in_memory.columns, "spilled sort came out in a different order");
+    }
+}
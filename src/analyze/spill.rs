@@ -0,0 +1,335 @@
+use super::batch::{Batch, Value};
+use super::operator::{Operator, BATCH_ROWS};
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use uuid::Uuid;
+
+// Scratch files for operators that can't keep everything they're holding in memory. Rows get
+// written out value by value with a tag byte in front of each, read back the same way, and the
+// file goes away once whoever's reading it is done.
+
+pub const DEFAULT_MEMORY_BUDGET: usize = 1 << 30;
+
+// How many files a spilling operator splits its rows between. Each one should come back in under
+// the budget, if not it gets split again.
+pub const SPILL_PARTITIONS: usize = 16;
+
+// Past this many rounds of splitting the keys are all the same and splitting won't help
+pub const MAX_SPILL_DEPTH: usize = 4;
+
+static MEMORY_BUDGET: AtomicUsize = AtomicUsize::new(DEFAULT_MEMORY_BUDGET);
+static SPILL_FILES: AtomicU64 = AtomicU64::new(0);
+
+// Bytes each hash aggregate, hash join or sort gets to hold before it starts spilling
+pub fn memory_budget() -> usize {
+    MEMORY_BUDGET.load(Ordering::Relaxed)
+}
+
+pub fn set_memory_budget(bytes: usize) {
+    MEMORY_BUDGET.store(bytes, Ordering::Relaxed);
+}
+
+// Which of `partitions` files a key goes to. Depth mixes in how many times these rows have
+// already been split, otherwise a partition would land in one file all over again.
+pub fn partition_of(key: &[Value], depth: usize, partitions: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    depth.hash(&mut hasher);
+    key.hash(&mut hasher);
+    (hasher.finish() % partitions as u64) as usize
+}
+
+fn spill_path() -> PathBuf {
+    let file_num = SPILL_FILES.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!("db_storage_poc_rust_spill_{}_{}", std::process::id(), file_num))
+}
+
+// HyperLogLog hashes these same bytes and sketches get stored, so a value's bytes can't change
+pub fn write_value(writer: &mut impl Write, value: &Value) -> std::io::Result<()> {
+    match value {
+        Value::Null => writer.write_all(&[0]),
+        Value::Bool(val) => writer.write_all(&[1, *val as u8]),
+        Value::Int64(val) => {
+            writer.write_all(&[2])?;
+            writer.write_all(&val.to_le_bytes())
+        },
+        Value::Int8(val) => writer.write_all(&[3, *val as u8]),
+        Value::UInt64(val) => {
+            writer.write_all(&[4])?;
+            writer.write_all(&val.to_le_bytes())
+        },
+        Value::UInt8(val) => writer.write_all(&[5, *val]),
+        Value::Decimal(val) => {
+            writer.write_all(&[6])?;
+            writer.write_all(&val.serialize())
+        },
+        Value::DateTime(val) => {
+            writer.write_all(&[7])?;
+            writer.write_all(&val.timestamp().to_le_bytes())?;
+            writer.write_all(&val.timestamp_subsec_nanos().to_le_bytes())
+        },
+        Value::String(val) => {
+            writer.write_all(&[8])?;
+            writer.write_all(&(val.len() as u64).to_le_bytes())?;
+            writer.write_all(val.as_bytes())
+        },
+        Value::Uuid(val) => {
+            writer.write_all(&[9])?;
+            writer.write_all(val.as_bytes())
+        },
+    }
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], String> {
+    let mut bytes = [0u8; N];
+    reader.read_exact(&mut bytes).map_err(|err| format!("Spill file cut short: {}", err))?;
+    Ok(bytes)
+}
+
+// None once the file is used up, which can only happen on a row boundary
+pub fn read_value(reader: &mut impl Read) -> Result<Option<Value>, String> {
+    let mut tag = [0u8; 1];
+    match reader.read_exact(&mut tag) {
+        Ok(()) => {},
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(format!("Can't read spill file: {}", err)),
+    }
+    let value = match tag[0] {
+        0 => Value::Null,
+        1 => Value::Bool(read_bytes::<1>(reader)?[0] != 0),
+        2 => Value::Int64(i64::from_le_bytes(read_bytes(reader)?)),
+        3 => Value::Int8(read_bytes::<1>(reader)?[0] as i8),
+        4 => Value::UInt64(u64::from_le_bytes(read_bytes(reader)?)),
+        5 => Value::UInt8(read_bytes::<1>(reader)?[0]),
+        6 => Value::Decimal(Decimal::deserialize(read_bytes(reader)?)),
+        7 => {
+            let seconds = i64::from_le_bytes(read_bytes(reader)?);
+            let nanos = u32::from_le_bytes(read_bytes(reader)?);
+            match Utc.timestamp_opt(seconds, nanos).single() {
+                Some(val) => Value::DateTime(val),
+                None => return Err(format!("Bad timestamp {}.{} in spill file", seconds, nanos)),
+            }
+        },
+        8 => {
+            let len = u64::from_le_bytes(read_bytes(reader)?) as usize;
+            let mut bytes = vec![0u8; len];
+            reader.read_exact(&mut bytes).map_err(|err| format!("Spill file cut short: {}", err))?;
+            Value::String(String::from_utf8(bytes).map_err(|err| format!("Bad string in spill file: {}", err))?)
+        },
+        9 => Value::Uuid(Uuid::from_bytes(read_bytes(reader)?)),
+        tag => return Err(format!("Bad value tag {} in spill file", tag)),
+    };
+    Ok(Some(value))
+}
+
+// Rows going out to a scratch file, all with the same columns
+pub struct SpillWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    rows: u64,
+}
+
+impl SpillWriter {
+    pub fn new() -> Result<SpillWriter, String> {
+        let path = spill_path();
+        let file = File::create(&path).map_err(|err| format!("Can't create spill file {:?}: {}", path, err))?;
+        Ok(SpillWriter { path, writer: BufWriter::new(file), rows: 0 })
+    }
+
+    pub fn write_row(&mut self, row: &[Value]) -> Result<(), String> {
+        for value in row {
+            write_value(&mut self.writer, value).map_err(|err| format!("Can't write spill file {:?}: {}", self.path, err))?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<SpillFile, String> {
+        self.writer.flush().map_err(|err| format!("Can't write spill file {:?}: {}", self.path, err))?;
+        let bytes = fs::metadata(&self.path).map(|meta| meta.len()).unwrap_or(0);
+        Ok(SpillFile { path: self.path.clone(), rows: self.rows, bytes })
+    }
+}
+
+// A written scratch file, deleted when dropped
+pub struct SpillFile {
+    path: PathBuf,
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Splits rows between SPILL_PARTITIONS scratch files by the hash of a key per row
+pub struct Partitions {
+    writers: Vec<SpillWriter>,
+    depth: usize,
+}
+
+impl Partitions {
+    pub fn new(depth: usize) -> Result<Partitions, String> {
+        let writers = (0..SPILL_PARTITIONS).map(|_| SpillWriter::new()).collect::<Result<_, _>>()?;
+        Ok(Partitions { writers, depth })
+    }
+
+    pub fn write_row(&mut self, key: &[Value], row: &[Value]) -> Result<(), String> {
+        let partition = partition_of(key, self.depth, self.writers.len());
+        self.writers[partition].write_row(row)
+    }
+
+    // One file per partition in order, empty ones included so two sides' partitions line up
+    pub fn finish(self) -> Result<Vec<SpillFile>, String> {
+        self.writers.into_iter().map(|writer| writer.finish()).collect()
+    }
+}
+
+// Reads a scratch file back as batches with the names the rows had going out
+pub struct SpillScan {
+    names: Vec<String>,
+    reader: BufReader<File>,
+    file: SpillFile,
+}
+
+impl SpillScan {
+    pub fn new(file: SpillFile, names: Vec<String>) -> Result<SpillScan, String> {
+        let reader = File::open(&file.path).map_err(|err| format!("Can't open spill file {:?}: {}", file.path, err))?;
+        Ok(SpillScan { names, reader: BufReader::new(reader), file })
+    }
+}
+
+impl Operator for SpillScan {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        let mut batch = Batch::new(self.names.clone(), vec![Vec::new(); self.names.len()]);
+        'rows: while batch.num_rows() < BATCH_ROWS {
+            for col in 0..self.names.len() {
+                match read_value(&mut self.reader)? {
+                    Some(value) => batch.columns[col].push(value),
+                    None if col == 0 => break 'rows,
+                    None => return Err(format!("Spill file {:?} ends partway through a row", self.file.path)),
+                }
+            }
+        }
+        match batch.num_rows() {
+            0 => Ok(None),
+            _ => Ok(Some(batch)),
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    fn name(&self) -> String {
+        format!("SpillScan {} rows", self.file.rows)
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        Some(self.file.rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn values() -> Vec<Value> {
+        vec![
+            Value::Null,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::Int64(i64::MIN),
+            Value::Int64(i64::MAX),
+            Value::Int64(-1),
+            Value::Int8(i8::MIN),
+            Value::Int8(i8::MAX),
+            Value::UInt64(0),
+            Value::UInt64(u64::MAX),
+            Value::UInt8(u8::MAX),
+            Value::Decimal(Decimal::MAX),
+            Value::Decimal(Decimal::MIN),
+            Value::Decimal(Decimal::from_str("-0.0000000000000000000000000001").unwrap()),
+            Value::Decimal(Decimal::from_str("12.500").unwrap()),
+            Value::DateTime(Utc.timestamp_opt(1_790_000_000, 123_456_789).unwrap()),
+            Value::DateTime(Utc.timestamp_opt(-86_401, 999_999_999).unwrap()),
+            Value::String(String::new()),
+            Value::String("line\nbreak, ünïcödé".to_string()),
+            Value::Uuid(Uuid::nil()),
+            Value::Uuid(Uuid::from_bytes([0xff; 16])),
+        ]
+    }
+
+    fn written(values: &[Value]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for value in values {
+            write_value(&mut bytes, value).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let values = values();
+        let bytes = written(&values);
+        let mut reader = bytes.as_slice();
+        for value in &values {
+            let read = read_value(&mut reader).unwrap().unwrap();
+            assert_eq!(&read, value);
+            // Decimals compare equal across scales, the scale has to come back too
+            assert_eq!(format!("{:?}", read), format!("{:?}", value));
+        }
+        assert_eq!(read_value(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn bytes_stay_put() {
+        // Stored HyperLogLog sketches were built from these, so they can't change
+        assert_eq!(written(&[Value::Int64(-2)]), vec![2, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(written(&[Value::String("ab".to_string())]), vec![8, 2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        assert_eq!(written(&[Value::Null, Value::Bool(true)]), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn cut_short_or_corrupt() {
+        for value in values().into_iter().filter(|value| !value.is_null()) {
+            let bytes = written(std::slice::from_ref(&value));
+            let mut reader = &bytes[..bytes.len() - 1];
+            assert!(read_value(&mut reader).is_err(), "{:?}", value);
+        }
+        assert!(read_value(&mut [42u8].as_slice()).is_err());
+        assert!(read_value(&mut [8u8, 1, 0, 0, 0, 0, 0, 0, 0, 0xff].as_slice()).is_err());
+    }
+
+    #[test]
+    fn spill_file_round_trip() {
+        let rows: Vec<Vec<Value>> = (0..BATCH_ROWS as i64 + 5)
+            .map(|i| vec![Value::Int64(i), Value::String(format!("row {}", i))])
+            .collect();
+        let mut writer = SpillWriter::new().unwrap();
+        for row in &rows {
+            writer.write_row(row).unwrap();
+        }
+        let file = writer.finish().unwrap();
+        assert_eq!(file.rows, rows.len() as u64);
+        let path = file.path.clone();
+        let mut scan = SpillScan::new(file, vec!["id".to_string(), "name".to_string()]).unwrap();
+        let mut read: Vec<Vec<Value>> = Vec::new();
+        while let Some(batch) = scan.next_batch().unwrap() {
+            for row in 0..batch.num_rows() {
+                read.push(batch.columns.iter().map(|col| col[row].clone()).collect());
+            }
+        }
+        assert_eq!(read, rows);
+        drop(scan);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,667 @@
+use super::aggregate::{AggregateExpr, AggregateFunction};
+use super::batch::Value;
+use super::explain::ExplainMode;
+use super::expr::{CompareOp, Expr};
+use super::join::JoinType;
+use super::parse::{Parser, Token};
+use super::planner::LogicalPlan;
+use super::sample::{SampleMethod, SampleRate, TableSample};
+use super::sort::SortKey;
+use super::window::WindowExpr;
+use std::collections::{HashMap, HashSet};
+
+// The SQL subset: SELECT with expressions, FROM one table with INNER/LEFT JOINs on equalities,
+// WHERE, GROUP BY, HAVING, window functions, ORDER BY and LIMIT. It's parsed with the same tokens and expression
+// parser as scan's options and turns into a LogicalPlan for the planner.
+//
+// One table can be read with TABLESAMPLE SYSTEM (percent) or BERNOULLI (percent), REPEATABLE (seed)
+// to get the same rows each time. count, sum and avg then get scaled up to the whole table, and
+// each one that's a select item by itself gets a `<name> ±` column with its 95% margin of error.
+//
+// CREATE MATERIALIZED VIEW name AS SELECT ... and DROP MATERIALIZED VIEW name manage the views in
+// view.rs, which aggregate queries get answered from when they can.
+
+// Words that end a table reference instead of being its alias
+const RESERVED: &[&str] = &["SELECT", "FROM", "JOIN", "INNER", "LEFT", "OUTER", "ON", "WHERE", "GROUP", "BY", "HAVING", "ORDER", "LIMIT", "AS", "ASC", "DESC", "TABLESAMPLE"];
+
+#[derive(Debug, Clone)]
+pub struct TableRef {
+    pub table: String,
+    pub alias: Option<String>,
+    pub sample: Option<TableSample>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JoinClause {
+    pub join_type: JoinType,
+    pub table: TableRef,
+    pub on: Expr,
+}
+
+#[derive(Debug, Clone)]
+pub enum SelectItem {
+    Wildcard,
+    Expr(String, Expr),
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderItem {
+    pub expr: Expr,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Select {
+    pub items: Vec<SelectItem>,
+    pub from: TableRef,
+    pub joins: Vec<JoinClause>,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<Expr>,
+    pub having: Option<Expr>,
+    pub order_by: Vec<OrderItem>,
+    pub limit: Option<usize>,
+}
+
+fn parse_table_ref(parser: &mut Parser) -> Result<TableRef, String> {
+    let table = parser.expect_ident()?;
+    let alias = match parser.accept_keyword("AS") {
+        true => Some(parser.expect_ident()?),
+        false => match parser.peek() {
+            Some(Token::Ident(ident)) if !RESERVED.iter().any(|word| ident.eq_ignore_ascii_case(word)) => {
+                let alias = ident.clone();
+                parser.next();
+                Some(alias)
+            },
+            _ => None,
+        },
+    };
+    let sample = match parser.accept_keyword("TABLESAMPLE") {
+        true => Some(parse_sample(parser)?),
+        false => None,
+    };
+    Ok(TableRef { table, alias, sample })
+}
+
+fn parenthesized_number(parser: &mut Parser, what: &str) -> Result<String, String> {
+    parser.expect_symbol("(")?;
+    let text = match parser.next() {
+        Some(Token::Number(text)) => text,
+        other => return Err(format!("Expected a {} but found {:?}", what, other)),
+    };
+    parser.expect_symbol(")")?;
+    Ok(text)
+}
+
+// After TABLESAMPLE, `SYSTEM (1)` or `BERNOULLI (0.5) REPEATABLE (42)`
+fn parse_sample(parser: &mut Parser) -> Result<TableSample, String> {
+    let method = match parser.next() {
+        Some(Token::Ident(name)) if name.eq_ignore_ascii_case("SYSTEM") => SampleMethod::System,
+        Some(Token::Ident(name)) if name.eq_ignore_ascii_case("BERNOULLI") => SampleMethod::Bernoulli,
+        other => return Err(format!("Expected SYSTEM or BERNOULLI after TABLESAMPLE but found {:?}", other)),
+    };
+    let percent = parenthesized_number(parser, "percentage")?;
+    let percent = percent.parse::<f64>().map_err(|_| format!("Bad TABLESAMPLE percentage {:?}", percent))?;
+    let seed = match parser.accept_keyword("REPEATABLE") {
+        true => {
+            let seed = parenthesized_number(parser, "seed")?;
+            Some(seed.parse::<u64>().map_err(|_| format!("Bad REPEATABLE seed {:?}", seed))?)
+        },
+        false => None,
+    };
+    TableSample::new(method, percent, seed)
+}
+
+fn parse_expr_list(parser: &mut Parser) -> Result<Vec<Expr>, String> {
+    let mut exprs: Vec<Expr> = vec![parser.parse_expr()?];
+    while parser.accept_symbol(",") {
+        exprs.push(parser.parse_expr()?);
+    }
+    Ok(exprs)
+}
+
+// A SELECT, optionally behind EXPLAIN or EXPLAIN ANALYZE, or making or dropping a materialized view
+pub enum Statement {
+    Query { explain: Option<ExplainMode>, select: Box<Select> },
+    // The view keeps the text of its SELECT to build itself from again
+    CreateView { name: String, sql: String },
+    DropView { name: String },
+}
+
+// The rest of the text after a keyword, if that's what it starts with
+fn after_keyword<'a>(text: &'a str, keyword: &str) -> Option<&'a str> {
+    let text = text.trim_start();
+    let word = text.get(..keyword.len())?;
+    let rest = &text[keyword.len()..];
+    match word.eq_ignore_ascii_case(keyword) && rest.starts_with(|c: char| c.is_whitespace() || c == '(') {
+        true => Some(rest),
+        false => None,
+    }
+}
+
+pub fn parse_statement(text: &str) -> Result<Statement, String> {
+    let view = after_keyword(text, "CREATE").map(|rest| (true, rest)).or(after_keyword(text, "DROP").map(|rest| (false, rest)));
+    if let Some((create, rest)) = view {
+        let rest = after_keyword(rest, "MATERIALIZED").and_then(|rest| after_keyword(rest, "VIEW"));
+        let rest = rest.ok_or("Expected MATERIALIZED VIEW after CREATE or DROP")?.trim_start();
+        let end = rest.find(|c: char| c.is_whitespace()).unwrap_or(rest.len());
+        let (name, rest) = (rest[..end].to_string(), &rest[end..]);
+        if !create {
+            return match rest.trim().trim_end_matches(';').is_empty() {
+                true => Ok(Statement::DropView { name }),
+                false => Err(format!("Unexpected {:?} after DROP MATERIALIZED VIEW {}", rest.trim(), name)),
+            };
+        }
+        let sql = after_keyword(rest, "AS").ok_or(format!("Expected AS SELECT ... after CREATE MATERIALIZED VIEW {}", name))?;
+        let sql = sql.trim().trim_end_matches(';').trim_end().to_string();
+        let mut parser = Parser::new(&sql)?;
+        parse_select(&mut parser)?;
+        parser.expect_end()?;
+        return Ok(Statement::CreateView { name, sql });
+    }
+    let mut parser = Parser::new(text)?;
+    let explain = match parser.accept_keyword("EXPLAIN") {
+        true if parser.accept_keyword("ANALYZE") => Some(ExplainMode::Analyze),
+        true => Some(ExplainMode::Plan),
+        false => None,
+    };
+    let select = parse_select(&mut parser)?;
+    parser.expect_end()?;
+    Ok(Statement::Query { explain, select: Box::new(select) })
+}
+
+fn parse_select(parser: &mut Parser) -> Result<Select, String> {
+    parser.expect_keyword("SELECT")?;
+    let mut items: Vec<SelectItem> = Vec::new();
+    loop {
+        if parser.accept_symbol("*") {
+            items.push(SelectItem::Wildcard);
+        } else {
+            let expr = parser.parse_expr()?;
+            let name = match parser.accept_keyword("AS") {
+                true => parser.expect_ident()?,
+                false => expr.to_string(),
+            };
+            items.push(SelectItem::Expr(name, expr));
+        }
+        if !parser.accept_symbol(",") {
+            break;
+        }
+    }
+
+    parser.expect_keyword("FROM")?;
+    let from = parse_table_ref(parser)?;
+    let mut joins: Vec<JoinClause> = Vec::new();
+    loop {
+        let join_type = if parser.accept_keyword("LEFT") {
+            parser.accept_keyword("OUTER");
+            JoinType::Left
+        } else if parser.accept_keyword("INNER") || parser.is_keyword("JOIN") {
+            JoinType::Inner
+        } else {
+            break;
+        };
+        parser.expect_keyword("JOIN")?;
+        let table = parse_table_ref(parser)?;
+        parser.expect_keyword("ON")?;
+        let on = parser.parse_expr()?;
+        joins.push(JoinClause { join_type, table, on });
+    }
+
+    let filter = match parser.accept_keyword("WHERE") {
+        true => Some(parser.parse_expr()?),
+        false => None,
+    };
+    let group_by = match parser.accept_keyword("GROUP") {
+        true => {
+            parser.expect_keyword("BY")?;
+            parse_expr_list(parser)?
+        },
+        false => Vec::new(),
+    };
+    let having = match parser.accept_keyword("HAVING") {
+        true => Some(parser.parse_expr()?),
+        false => None,
+    };
+    let mut order_by: Vec<OrderItem> = Vec::new();
+    if parser.accept_keyword("ORDER") {
+        parser.expect_keyword("BY")?;
+        loop {
+            let expr = parser.parse_expr()?;
+            let descending = parser.accept_keyword("DESC");
+            if !descending {
+                parser.accept_keyword("ASC");
+            }
+            order_by.push(OrderItem { expr, descending });
+            if !parser.accept_symbol(",") {
+                break;
+            }
+        }
+    }
+    let limit = match parser.accept_keyword("LIMIT") {
+        true => match parser.next() {
+            Some(Token::Number(text)) => Some(text.parse::<usize>().map_err(|_| format!("Bad LIMIT {:?}", text))?),
+            other => return Err(format!("Expected a row count after LIMIT but found {:?}", other)),
+        },
+        false => None,
+    };
+    Ok(Select { items, from, joins, filter, group_by, having, order_by, limit })
+}
+
+// Whether every column an expression uses can be found in a schema, the same way Batch::index_of
+// looks them up
+fn resolves(schema: &[String], expr: &Expr) -> bool {
+    expr.columns().iter().all(|name| schema.iter().any(|col| col == name || col.ends_with(&format!(".{}", name))))
+}
+
+fn conjuncts(expr: Expr, into: &mut Vec<Expr>) {
+    match expr {
+        Expr::And(left, right) => {
+            conjuncts(*left, into);
+            conjuncts(*right, into);
+        },
+        other => into.push(other),
+    }
+}
+
+// A table that's in the query more than once gets its columns named by alias instead, so
+// `o1.id` and `o2.id` in a self-join are two different columns. Filters on them still sink
+// through the renaming into the scan.
+fn table_scan(table: &TableRef, repeated: bool) -> Result<LogicalPlan, String> {
+    let scan = LogicalPlan::sampled_scan(&table.table, table.sample.clone())?;
+    if !repeated {
+        return Ok(scan);
+    }
+    let alias = table.alias.as_ref().ok_or(format!("Table {} is in the query more than once, give each one an alias", table.table))?;
+    let renamed = scan.schema().into_iter()
+        .map(|name| {
+            let column = name.split_once('.').map(|(_, column)| column).unwrap_or(&name);
+            (format!("{}.{}", alias, column), Expr::Column(name.clone()))
+        })
+        .collect();
+    Ok(scan.project(renamed))
+}
+
+fn join(left: LogicalPlan, right: LogicalPlan, clause: &JoinClause, on: Expr) -> Result<LogicalPlan, String> {
+    let (left_schema, right_schema) = (left.schema(), right.schema());
+    let mut conditions: Vec<Expr> = Vec::new();
+    conjuncts(on, &mut conditions);
+    // The first equality with a side on each input is the join key, the rest filter afterwards
+    let key = conditions.iter().enumerate().find_map(|(index, condition)| match condition {
+        Expr::Compare(CompareOp::Eq, l, r) if resolves(&left_schema, l) && resolves(&right_schema, r) => Some((index, *l.clone(), *r.clone())),
+        Expr::Compare(CompareOp::Eq, l, r) if resolves(&right_schema, l) && resolves(&left_schema, r) => Some((index, *r.clone(), *l.clone())),
+        _ => None,
+    });
+    let (index, left_key, right_key) = key.ok_or(format!("JOIN {} needs an equality between the two sides in its ON", clause.table.table))?;
+    conditions.remove(index);
+    if clause.join_type == JoinType::Left && !conditions.is_empty() {
+        return Err("LEFT JOIN only supports a single equality in its ON".to_string());
+    }
+    let mut plan = left.join(right, left_key, right_key, clause.join_type);
+    for condition in conditions {
+        plan = plan.filter(condition);
+    }
+    Ok(plan)
+}
+
+// Pulls aggregates out of expressions into a list for HashAggregate, leaving column references to
+// the aggregate's output in their place. Group expressions get swapped for their output column too.
+struct AggregateRewrite {
+    group_by: Vec<(String, Expr)>,
+    aggregates: Vec<AggregateExpr>,
+}
+
+impl AggregateRewrite {
+    fn rewrite(&mut self, expr: &Expr) -> Result<Expr, String> {
+        let mut error: Option<String> = None;
+        let rewritten = expr.rewrite(&mut |node| {
+            if let Some((name, _)) = self.group_by.iter().find(|(_, group)| group == node) {
+                return Some(Expr::Column(name.clone()));
+            }
+            if let Expr::Aggregate(function, inner) = node {
+                if inner.contains_aggregate() {
+                    error = Some(format!("Aggregates can't be nested, {}", node));
+                }
+                if inner.contains_window() {
+                    error = Some(format!("Window functions can't be inside aggregates, {}", node));
+                }
+                let name = node.to_string();
+                if !self.aggregates.iter().any(|agg| agg.name == name) {
+                    self.aggregates.push(AggregateExpr::new(*function, *inner.clone(), &name));
+                }
+                return Some(Expr::Column(name));
+            }
+            None
+        });
+        match error {
+            Some(error) => Err(error),
+            None => Ok(rewritten),
+        }
+    }
+}
+
+// Over a sample count, sum and avg become estimates for the whole table. Select items that are one
+// of them by itself get its margin of error as another column at the end, after the rest so ORDER
+// BY positions still mean the same thing.
+fn scale_to_sample(aggregates: &mut Vec<AggregateExpr>, mut items: Vec<(String, Expr)>, rate: SampleRate) -> Vec<(String, Expr)> {
+    for agg in aggregates.iter_mut() {
+        if let Some(scaled) = agg.function.scaled() {
+            agg.function = AggregateFunction::Estimate(scaled, rate);
+        }
+    }
+    let mut margins: Vec<(String, Expr)> = Vec::new();
+    for (name, expr) in &items {
+        let estimate = match expr {
+            Expr::Column(column) => aggregates.iter().find(|agg| agg.name == *column).cloned(),
+            _ => None,
+        };
+        if let Some(AggregateExpr { function: AggregateFunction::Estimate(scaled, rate), expr, name: column }) = estimate {
+            let margin = format!("{} ±", column);
+            if !aggregates.iter().any(|agg| agg.name == margin) {
+                aggregates.push(AggregateExpr::new(AggregateFunction::Margin(scaled, rate), expr, &margin));
+            }
+            margins.push((format!("{} ±", name), Expr::Column(margin)));
+        }
+    }
+    items.extend(margins);
+    items
+}
+
+// Pulls window functions out of expressions, leaving a reference to the column the Window node
+// adds for each
+fn rewrite_windows(expr: &Expr, windows: &mut Vec<(String, WindowExpr)>) -> Result<Expr, String> {
+    let mut error: Option<String> = None;
+    let rewritten = expr.rewrite(&mut |node| match node {
+        Expr::Window(window) => {
+            let inner = window.args.iter().chain(&window.partition_by).chain(window.order_by.iter().map(|key| &key.expr));
+            if inner.into_iter().any(|expr| expr.contains_window()) {
+                error = Some(format!("Window functions can't be nested, {}", node));
+            }
+            let name = node.to_string();
+            if !windows.iter().any(|(existing, _)| *existing == name) {
+                windows.push((name.clone(), *window.clone()));
+            }
+            Some(Expr::Column(name))
+        },
+        _ => None,
+    });
+    match error {
+        Some(error) => Err(error),
+        None => Ok(rewritten),
+    }
+}
+
+pub fn plan_select(select: &Select) -> Result<LogicalPlan, String> {
+    // Aliases get swapped for table names up front, scans name their columns table.column. Only
+    // a table that's in there more than once keeps its aliases, see table_scan.
+    let tables: Vec<&TableRef> = std::iter::once(&select.from).chain(select.joins.iter().map(|join| &join.table)).collect();
+    let repeated = |table: &TableRef| tables.iter().filter(|other| other.table == table.table).count() > 1;
+    let mut aliases: HashMap<String, String> = HashMap::new();
+    let mut seen: HashSet<&String> = HashSet::new();
+    for table in &tables {
+        if let Some(alias) = &table.alias {
+            if !seen.insert(alias) {
+                return Err(format!("Alias {} is used more than once", alias));
+            }
+            if !repeated(table) {
+                aliases.insert(alias.clone(), table.table.clone());
+            }
+        }
+    }
+    let qualify = |expr: &Expr| expr.rewrite(&mut |node| match node {
+        Expr::Column(name) => name.split_once('.')
+            .and_then(|(qualifier, column)| aliases.get(qualifier).map(|table| Expr::Column(format!("{}.{}", table, column)))),
+        _ => None,
+    });
+
+    let samples: Vec<&TableSample> = tables.iter().filter_map(|table| table.sample.as_ref()).collect();
+    if samples.len() > 1 {
+        return Err("Only one table in a query can have a TABLESAMPLE".to_string());
+    }
+    let rate = samples.first().map(|sample| sample.rate());
+
+    let mut plan = table_scan(&select.from, repeated(&select.from))?;
+    for clause in &select.joins {
+        plan = join(plan, table_scan(&clause.table, repeated(&clause.table))?, clause, qualify(&clause.on))?;
+    }
+    if let Some(filter) = &select.filter {
+        if filter.contains_aggregate() {
+            return Err("Aggregates aren't allowed in WHERE, use HAVING".to_string());
+        }
+        if filter.contains_window() {
+            return Err("Window functions aren't allowed in WHERE".to_string());
+        }
+        plan = plan.filter(qualify(filter));
+    }
+
+    let mut items: Vec<(String, Expr)> = Vec::new();
+    for item in &select.items {
+        match item {
+            SelectItem::Expr(name, expr) => items.push((name.clone(), qualify(expr))),
+            SelectItem::Wildcard => items.extend(plan.schema().into_iter().map(|name| (name.clone(), Expr::Column(name)))),
+        }
+    }
+    let order_by: Vec<(Expr, bool)> = select.order_by.iter().map(|item| (qualify(&item.expr), item.descending)).collect();
+
+    let is_aggregate = !select.group_by.is_empty() || select.having.is_some() || items.iter().any(|(_, expr)| expr.contains_aggregate());
+    let (items, order_by) = match is_aggregate {
+        false => (items, order_by),
+        true => {
+            if select.items.iter().any(|item| matches!(item, SelectItem::Wildcard)) {
+                return Err("SELECT * can't be used with GROUP BY or aggregates".to_string());
+            }
+            if select.group_by.iter().chain(&select.having).any(|expr| expr.contains_window()) {
+                return Err("Window functions aren't allowed in GROUP BY or HAVING".to_string());
+            }
+            // Group keys take the name of a select item that's the same expression
+            // GROUP BY can also name a select item by alias or position, a column of the same
+            // name wins over an alias like in Postgres
+            let schema = plan.schema();
+            let mut group_by: Vec<(String, Expr)> = Vec::new();
+            for group in select.group_by.iter().map(qualify) {
+                let group = match &group {
+                    Expr::Literal(Value::Int64(position)) => usize::try_from(*position).ok()
+                        .filter(|position| *position >= 1 && *position <= items.len())
+                        .map(|position| items[position - 1].1.clone())
+                        .ok_or(format!("GROUP BY position {} is out of range", position))?,
+                    Expr::Column(name) if !resolves(&schema, &group) => items.iter().find(|(item, _)| item == name)
+                        .map(|(_, expr)| expr.clone())
+                        .unwrap_or(group),
+                    _ => group,
+                };
+                if group.contains_aggregate() || group.contains_window() {
+                    return Err(format!("Can't GROUP BY {}", group));
+                }
+                let name = items.iter().find(|(_, expr)| *expr == group).map(|(name, _)| name.clone()).unwrap_or(group.to_string());
+                group_by.push((name, group));
+            }
+            let mut rewrite = AggregateRewrite { group_by, aggregates: Vec::new() };
+            let mut rewritten_items: Vec<(String, Expr)> = Vec::new();
+            for (name, expr) in &items {
+                rewritten_items.push((name.clone(), rewrite.rewrite(expr)?));
+            }
+            let having = match &select.having {
+                Some(having) => Some(rewrite.rewrite(&qualify(having))?),
+                None => None,
+            };
+            let mut rewritten_order: Vec<(Expr, bool)> = Vec::new();
+            for (expr, descending) in order_by {
+                // Leave select aliases alone, they're already output columns
+                let is_alias = matches!(&expr, Expr::Column(name) if items.iter().any(|(item, _)| item == name));
+                rewritten_order.push((if is_alias { expr } else { rewrite.rewrite(&expr)? }, descending));
+            }
+            let rewritten_items = match rate {
+                Some(rate) => scale_to_sample(&mut rewrite.aggregates, rewritten_items, rate),
+                None => rewritten_items,
+            };
+            plan = plan.aggregate(rewrite.group_by, rewrite.aggregates);
+            if let Some(having) = having {
+                plan = plan.filter(having);
+            }
+            (rewritten_items, rewritten_order)
+        },
+    };
+
+    // Window functions come after any grouping, so they can rank groups by their aggregates. Ones
+    // with the same PARTITION BY and ORDER BY share a Window node and the sort under it.
+    let mut windows: Vec<(String, WindowExpr)> = Vec::new();
+    let items = items.iter()
+        .map(|(name, expr)| Ok((name.clone(), rewrite_windows(expr, &mut windows)?)))
+        .collect::<Result<Vec<(String, Expr)>, String>>()?;
+    let order_by = order_by.iter()
+        .map(|(expr, descending)| Ok((rewrite_windows(expr, &mut windows)?, *descending)))
+        .collect::<Result<Vec<(Expr, bool)>, String>>()?;
+    let mut shared: Vec<Vec<(String, WindowExpr)>> = Vec::new();
+    for (name, window) in windows {
+        match shared.iter_mut().find(|functions| functions[0].1.same_window(&window)) {
+            Some(functions) => functions.push((name, window)),
+            None => shared.push(vec![(name, window)]),
+        }
+    }
+    for functions in shared {
+        plan = plan.window(functions);
+    }
+
+    // ORDER BY can name a select item by alias, position or the same expression, anything else
+    // gets computed alongside the select list and dropped after sorting
+    let mut projection = items.clone();
+    let mut sort_keys: Vec<SortKey> = Vec::new();
+    for (index, (expr, descending)) in order_by.into_iter().enumerate() {
+        let name = match &expr {
+            Expr::Literal(Value::Int64(position)) => {
+                let position = usize::try_from(*position).ok().filter(|position| *position >= 1 && *position <= items.len())
+                    .ok_or(format!("ORDER BY position {} is out of range", position))?;
+                items[position - 1].0.clone()
+            },
+            Expr::Column(name) if items.iter().any(|(item, _)| item == name) => name.clone(),
+            _ => match items.iter().find(|(_, item)| *item == expr) {
+                Some((name, _)) => name.clone(),
+                None => {
+                    let name = format!("__order_{}", index);
+                    projection.push((name.clone(), expr));
+                    name
+                },
+            },
+        };
+        sort_keys.push(SortKey::new(Expr::Column(name), descending));
+    }
+    let hidden = projection.len() > items.len();
+    plan = plan.project(projection);
+    if !sort_keys.is_empty() {
+        plan = plan.sort(sort_keys);
+    }
+    if hidden {
+        plan = plan.project(items.iter().map(|(name, _)| (name.clone(), Expr::Column(name.clone()))).collect());
+    }
+    if let Some(limit) = select.limit {
+        plan = plan.limit(limit);
+    }
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::planner::optimize;
+
+    fn select(text: &str) -> Select {
+        match parse_statement(text) {
+            Ok(Statement::Query { select, .. }) => *select,
+            Ok(_) => panic!("{} isn't a query", text),
+            Err(err) => panic!("{}: {}", text, err),
+        }
+    }
+
+    fn names(items: &[SelectItem]) -> Vec<&str> {
+        items.iter().map(|item| match item {
+            SelectItem::Wildcard => "*",
+            SelectItem::Expr(name, _) => name.as_str(),
+        }).collect()
+    }
+
+    #[test]
+    fn parses_every_clause() {
+        let query = select("select o.id, count(*) AS lines, sum(op.quantity) FROM orders AS o \
+            LEFT OUTER JOIN order_products op ON o.id = op.order_id \
+            WHERE o.tax_percent > 5 GROUP BY o.id HAVING count(*) > 1 ORDER BY lines DESC, 1 LIMIT 10;");
+        assert_eq!(names(&query.items), ["o.id", "lines", "sum(op.quantity)"]);
+        assert_eq!((query.from.table.as_str(), query.from.alias.as_deref()), ("orders", Some("o")));
+        assert_eq!(query.joins.len(), 1);
+        assert_eq!(query.joins[0].join_type, JoinType::Left);
+        assert_eq!(query.joins[0].table.alias.as_deref(), Some("op"));
+        assert_eq!(query.filter.map(|filter| filter.to_string()).as_deref(), Some("(o.tax_percent > 5)"));
+        assert_eq!(query.group_by.len(), 1);
+        assert!(query.having.is_some());
+        assert_eq!(query.order_by.iter().map(|item| item.descending).collect::<Vec<_>>(), [true, false]);
+        assert_eq!(query.limit, Some(10));
+    }
+
+    #[test]
+    fn keywords_end_a_table_reference() {
+        let query = select("SELECT * FROM orders WHERE id = 1");
+        assert_eq!(query.from.alias, None);
+        assert!(query.filter.is_some());
+        let query = select("SELECT * FROM orders JOIN customers c ON orders.customer_id = c.id");
+        assert_eq!((query.from.alias, query.joins[0].table.alias.as_deref()), (None, Some("c")));
+        assert_eq!(names(&select("SELECT * FROM orders").items), ["*"]);
+    }
+
+    #[test]
+    fn explain_and_views() {
+        assert!(matches!(parse_statement("EXPLAIN ANALYZE SELECT id FROM orders"), Ok(Statement::Query { explain: Some(ExplainMode::Analyze), .. })));
+        assert!(matches!(parse_statement("explain SELECT id FROM orders"), Ok(Statement::Query { explain: Some(ExplainMode::Plan), .. })));
+        match parse_statement("CREATE MATERIALIZED VIEW monthly AS SELECT count(*) FROM orders;") {
+            Ok(Statement::CreateView { name, sql }) => assert_eq!((name.as_str(), sql.as_str()), ("monthly", "SELECT count(*) FROM orders")),
+            _ => panic!("expected CREATE MATERIALIZED VIEW"),
+        }
+        assert!(matches!(parse_statement("DROP MATERIALIZED VIEW monthly"), Ok(Statement::DropView { name }) if name == "monthly"));
+        // Nothing can come after the name
+        assert!(parse_statement("DROP MATERIALIZED VIEW monthly now").is_err());
+        assert!(parse_statement("CREATE VIEW monthly AS SELECT 1 FROM orders").is_err());
+    }
+
+    #[test]
+    fn bad_queries_say_where() {
+        for (text, error) in [
+            ("SELECT id FROM orders LIMIT ten", "after LIMIT"),
+            ("SELECT id FROM orders LIMIT -1", "after LIMIT"),
+            ("SELECT id FROM orders JOIN order_products op", "ON"),
+            ("SELECT id orders", "FROM"),
+        ] {
+            let message = parse_statement(text).err().unwrap_or_else(|| panic!("{} parsed", text));
+            assert!(message.contains(error), "{}: {}", text, message);
+        }
+    }
+
+    fn planned(text: &str) -> Result<LogicalPlan, String> {
+        plan_select(&select(text)).map(optimize)
+    }
+
+    #[test]
+    fn aliases_of_a_table_used_once_become_its_name() {
+        let plan = planned("SELECT o.id, op.quantity FROM orders o JOIN order_products op ON o.id = op.order_id WHERE o.tax_percent > 5").unwrap();
+        assert_eq!(plan.schema(), ["o.id", "op.quantity"]);
+        match plan {
+            LogicalPlan::Project { exprs, .. } => assert_eq!(exprs[1].1, Expr::Column("order_products.quantity".to_string())),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn self_join_keeps_the_sides_apart() {
+        let plan = planned("SELECT o1.id, o2.id AS later FROM orders o1 JOIN orders o2 ON o1.customer_id = o2.customer_id \
+            WHERE o1.created < o2.created AND o2.tax_percent > 5").unwrap();
+        let LogicalPlan::Project { input, exprs } = plan else { panic!("expected the select list on top") };
+        assert_eq!(exprs.iter().map(|(_, expr)| expr.to_string()).collect::<Vec<_>>(), ["o1.id", "o2.id"]);
+        // The comparison needs both sides so it stays above the join, the other filter goes
+        // through the renaming into o2's scan
+        let LogicalPlan::Filter { input, predicate } = *input else { panic!("expected a filter over the join") };
+        assert_eq!(predicate.to_string(), "(o1.created < o2.created)");
+        let LogicalPlan::Join { right, left_key, right_key, .. } = *input else { panic!("expected the join") };
+        assert_eq!((left_key.to_string(), right_key.to_string()), ("o1.customer_id".to_string(), "o2.customer_id".to_string()));
+        let LogicalPlan::Project { input: scan, .. } = *right else { panic!("expected o2 to be renamed") };
+        assert!(matches!(*scan, LogicalPlan::Scan { ref filters, .. } if filters.len() == 1 && filters[0].to_string() == "(orders.tax_percent > 5)"), "{:?}", scan);
+
+        assert!(planned("SELECT count(*) FROM orders JOIN orders o2 ON orders.id = o2.id").unwrap_err().contains("give each one an alias"));
+        assert!(planned("SELECT count(*) FROM orders o JOIN order_products o ON o.id = o.order_id").unwrap_err().contains("more than once"));
+    }
+}
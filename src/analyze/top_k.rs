@@ -0,0 +1,130 @@
+use super::batch::{Batch, Value};
+use super::expr::Expr;
+use super::operator::{Operator, BATCH_ROWS};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+// A row held in the heap, ranked on its measure. Ties go to whichever row came first so results
+// don't shuffle around between runs.
+struct Ranked {
+    measure: Value,
+    descending: bool,
+    seq: usize,
+    row: Vec<Value>,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let by_measure = self.measure.compare(&other.measure).unwrap_or_else(|| self.measure.cmp(&other.measure));
+        let by_measure = match self.descending {
+            true => by_measure,
+            false => by_measure.reverse(),
+        };
+        by_measure.then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+// Keeps the k best rows by a measure while the input streams past, so memory stays at k rows no
+// matter how big the input is. Rows with a null measure never make the cut.
+pub struct TopK {
+    input: Box<dyn Operator>,
+    measure: Expr,
+    descending: bool,
+    k: usize,
+    result: Option<std::vec::IntoIter<Vec<Value>>>,
+    held: usize,
+}
+
+impl TopK {
+    pub fn new(input: Box<dyn Operator>, measure: Expr, descending: bool, k: usize) -> TopK {
+        TopK { input, measure, descending, k, result: None, held: 0 }
+    }
+
+    fn drain(&mut self) -> Result<Vec<Vec<Value>>, String> {
+        // Min heap of the best so far, the worst of them sits on top ready to get bumped
+        let mut heap: BinaryHeap<Reverse<Ranked>> = BinaryHeap::with_capacity(self.k + 1);
+        let mut seq = 0;
+        while let Some(batch) = self.input.next_batch()? {
+            let measures = self.measure.eval(&batch)?;
+            for (row, measure) in measures.into_iter().enumerate() {
+                seq += 1;
+                if measure.is_null() || self.k == 0 {
+                    continue;
+                }
+                let ranked = Ranked { measure, descending: self.descending, seq, row: Vec::new() };
+                if heap.len() == self.k && heap.peek().map(|worst| ranked <= worst.0).unwrap_or(false) {
+                    continue;
+                }
+                let ranked = Ranked { row: batch.columns.iter().map(|col| col[row].clone()).collect(), ..ranked };
+                heap.push(Reverse(ranked));
+                if heap.len() > self.k {
+                    heap.pop();
+                }
+            }
+        }
+        self.held = heap.iter().map(|ranked| ranked.0.measure.size() + ranked.0.row.iter().map(|val| val.size()).sum::<usize>()).sum();
+        // into_sorted_vec is ascending on Reverse, so best first
+        Ok(heap.into_sorted_vec().into_iter().map(|ranked| ranked.0.row).collect())
+    }
+}
+
+impl Operator for TopK {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.result.is_none() {
+            self.result = Some(self.drain()?.into_iter());
+        }
+        let names = self.schema();
+        let mut columns: Vec<Vec<Value>> = vec![Vec::new(); names.len()];
+        for row in self.result.as_mut().unwrap().take(BATCH_ROWS) {
+            for (col, value) in columns.iter_mut().zip(row) {
+                col.push(value);
+            }
+        }
+        match columns.first().map(|col| col.is_empty()).unwrap_or(true) {
+            true => Ok(None),
+            false => Ok(Some(Batch::new(names, columns))),
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.input.schema()
+    }
+
+    fn name(&self) -> String {
+        let direction = match self.descending {
+            true => "DESC",
+            false => "ASC",
+        };
+        format!("TopK {} by {} {}", self.k, self.measure, direction)
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.held
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        Some(self.input.estimated_rows().map(|rows| rows.min(self.k as u64)).unwrap_or(self.k as u64))
+    }
+}
@@ -0,0 +1,507 @@
+use crate::datagen::catalog::table_def;
+use crate::datagen::constants::DATA_DIRECTORY;
+use crate::datagen::partition::{number, partition_rows};
+use super::aggregate::{AggregateExpr, AggregateFunction, HashAggregate, Sum};
+use super::batch::{Batch, Value};
+use super::expr::Expr;
+use super::lineage::{column_segments, LineageNode};
+use super::operator::{Operator, BATCH_ROWS};
+use super::planner::{conjoin, push_down_filters, LogicalPlan};
+use super::scan::TableScan;
+use super::spill::{read_value, write_value};
+use super::sql::{parse_statement, plan_select, Statement};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io::{BufReader, BufWriter, Write};
+use std::ops::Range;
+
+// Materialized aggregate views: a GROUP BY over one table whose per group partial aggregates are
+// kept under _views/<name>/, along with how many of the table's rows they cover (of each partition,
+// for a partitioned table). Appends only add rows at the end of a partition, so bringing a view up
+// to date is aggregating the rows past those marks and merging them in, whether Table::write_data
+// does it right after writing or a query finds the view behind. If a partition ever has fewer rows
+// than the view covers, the view gets rebuilt from scratch.
+//
+// An aggregate query over the same table gets answered from a view when the view's WHERE
+// conditions are all among the query's, the rest only use the view's group columns, the query's
+// group keys are some of the view's, and its aggregates can be put together from what the view
+// keeps: count, sum, min, max, and avg as a sum and a count.
+
+// The partial aggregates a view keeps per group, each of some expression
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+impl Slot {
+    fn function(&self) -> AggregateFunction {
+        match self {
+            Slot::Count => AggregateFunction::Count,
+            Slot::Sum => AggregateFunction::Sum,
+            Slot::Min => AggregateFunction::Min,
+            Slot::Max => AggregateFunction::Max,
+        }
+    }
+
+    fn empty(&self) -> Value {
+        match self {
+            Slot::Count => Value::UInt64(0),
+            _ => Value::Null,
+        }
+    }
+
+    // Folds another partial for the same group in
+    fn merge(&self, into: &mut Value, value: &Value) -> Result<(), String> {
+        let replace = match self {
+            Slot::Count | Slot::Sum => {
+                let mut sum = Sum::default();
+                sum.add(into)?;
+                sum.add(value)?;
+                match (self, sum.total()) {
+                    (Slot::Count, Value::Int64(count)) => *into = Value::UInt64(count as u64),
+                    (_, total) => *into = total,
+                }
+                return Ok(());
+            },
+            Slot::Min => !value.is_null() && (into.is_null() || value < into),
+            Slot::Max => !value.is_null() && (into.is_null() || value > into),
+        };
+        if replace {
+            *into = value.clone();
+        }
+        Ok(())
+    }
+}
+
+// Group keys to the view's slots for that group
+type Groups = HashMap<Vec<Value>, Vec<Value>>;
+
+// Rows covered of each partition
+type Covered = BTreeMap<u64, u64>;
+
+#[derive(Debug, Clone)]
+pub struct View {
+    pub name: String,
+    table: String,
+    // All with column names unqualified, so they compare the same however a query wrote them
+    filters: Vec<Expr>,
+    group_by: Vec<Expr>,
+    slots: Vec<(Slot, Expr)>,
+}
+
+fn views_directory() -> String {
+    DATA_DIRECTORY.to_owned() + "_views/"
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    match !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        true => Ok(()),
+        false => Err(format!("Bad view name {:?}, use letters, digits and underscores", name)),
+    }
+}
+
+// Takes the table name off qualified column references
+fn unqualified(expr: &Expr, table: &str) -> Expr {
+    let prefix = format!("{}.", table);
+    expr.rewrite(&mut |node| match node {
+        Expr::Column(name) => name.strip_prefix(&prefix).map(|name| Expr::Column(name.to_string())),
+        _ => None,
+    })
+}
+
+impl View {
+    // A view is any GROUP BY over a single table with count, sum, min, max and avg, optionally
+    // with a WHERE. Anything above the grouping like HAVING or ORDER BY has no place in one.
+    pub fn from_sql(name: &str, sql: &str) -> Result<View, String> {
+        check_name(name)?;
+        let select = match parse_statement(sql)? {
+            Statement::Query { explain: None, select } => select,
+            _ => return Err("A materialized view has to be a SELECT".to_string()),
+        };
+        let unsupported = || "A materialized view has to be a GROUP BY over one table, without joins, HAVING, ORDER BY, LIMIT, window functions or TABLESAMPLE".to_string();
+        let (table, filters, group_by, aggregates) = match push_down_filters(plan_select(&select)?) {
+            LogicalPlan::Project { input, .. } => match *input {
+                LogicalPlan::Aggregate { input, group_by, aggregates } => match *input {
+                    LogicalPlan::Scan { table, filters, sample: None, .. } => (table, filters, group_by, aggregates),
+                    _ => return Err(unsupported()),
+                },
+                _ => return Err(unsupported()),
+            },
+            _ => return Err(unsupported()),
+        };
+        let mut slots: Vec<(Slot, Expr)> = Vec::new();
+        for agg in &aggregates {
+            let expr = unqualified(&agg.expr, &table);
+            let needed = match agg.function {
+                AggregateFunction::Count => vec![Slot::Count],
+                AggregateFunction::Sum => vec![Slot::Sum],
+                AggregateFunction::Min => vec![Slot::Min],
+                AggregateFunction::Max => vec![Slot::Max],
+                AggregateFunction::Avg => vec![Slot::Sum, Slot::Count],
+                _ => return Err(format!("{} can't be kept up to date in a materialized view, only count, sum, min, max and avg can", agg.function)),
+            };
+            for slot in needed {
+                if !slots.contains(&(slot, expr.clone())) {
+                    slots.push((slot, expr.clone()));
+                }
+            }
+        }
+        Ok(View {
+            name: name.to_string(),
+            filters: filters.iter().map(|filter| unqualified(filter, &table)).collect(),
+            group_by: group_by.iter().map(|(_, expr)| unqualified(expr, &table)).collect(),
+            slots,
+            table,
+        })
+    }
+
+    fn directory(&self) -> String {
+        views_directory() + &self.name + "/"
+    }
+
+    // The rows of each partition covered so far and every group's slots, nothing at all before the
+    // first refresh. Partitions are `partition:rows` joined with commas, views from before there
+    // were partitions just have the row count.
+    fn load(&self) -> Result<(Covered, Groups), String> {
+        let path = self.directory() + "state";
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return Ok((BTreeMap::new(), HashMap::new())),
+        };
+        let mut reader = BufReader::new(file);
+        let bad = || format!("View state {} is cut short", path);
+        let rows: Covered = match read_value(&mut reader)? {
+            Some(Value::UInt64(rows)) => BTreeMap::from([(0, rows)]),
+            Some(Value::String(partitions)) => partitions.split(',').filter(|part| !part.is_empty())
+                .map(|part| part.split_once(':').and_then(|(partition, rows)| Some((partition.parse().ok()?, rows.parse().ok()?))))
+                .collect::<Option<_>>()
+                .ok_or_else(bad)?,
+            _ => return Err(bad()),
+        };
+        let mut groups: Groups = HashMap::new();
+        while let Some(first) = read_value(&mut reader)? {
+            let mut row = vec![first];
+            for _ in 1..self.group_by.len() + self.slots.len() {
+                row.push(read_value(&mut reader)?.ok_or_else(bad)?);
+            }
+            let slots = row.split_off(self.group_by.len());
+            groups.insert(row, slots);
+        }
+        Ok((rows, groups))
+    }
+
+    // Written next to the old state and renamed over it, so a view is never half saved
+    fn save(&self, rows: &Covered, groups: &Groups) -> Result<(), String> {
+        fs::create_dir_all(self.directory()).map_err(|err| format!("Can't create view directory: {}", err))?;
+        let (path, temporary) = (self.directory() + "state", self.directory() + "state.tmp");
+        let file = fs::File::create(&temporary).map_err(|err| format!("Can't write view state {}: {}", temporary, err))?;
+        let mut writer = BufWriter::new(file);
+        let failed = |err: std::io::Error| format!("Can't write view state {}: {}", temporary, err);
+        let partitions: Vec<String> = rows.iter().map(|(partition, rows)| format!("{}:{}", partition, rows)).collect();
+        write_value(&mut writer, &Value::String(partitions.join(","))).map_err(failed)?;
+        for (key, slots) in groups {
+            for value in key.iter().chain(slots) {
+                write_value(&mut writer, value).map_err(failed)?;
+            }
+        }
+        writer.flush().map_err(failed)?;
+        fs::rename(&temporary, &path).map_err(|err| format!("Can't write view state {}: {}", path, err))
+    }
+
+    // Columns of the table anything in the view uses, or the cheapest one when that's none of them
+    fn columns(&self) -> Result<Vec<String>, String> {
+        let table = table_def(&self.table)?;
+        let exprs = self.filters.iter().chain(&self.group_by).chain(self.slots.iter().map(|(_, expr)| expr));
+        let mut columns: Vec<String> = Vec::new();
+        for name in exprs.flat_map(|expr| expr.columns()) {
+            if table.column(&name).is_some() && !columns.contains(&name) {
+                columns.push(name);
+            }
+        }
+        if columns.is_empty() {
+            let cheapest = table.columns.iter().min_by_key(|col| col.data_type.stored_size().unwrap_or(usize::MAX));
+            columns.extend(cheapest.map(|col| col.name.clone()));
+        }
+        Ok(columns)
+    }
+
+    // Aggregates whatever the table got since the last refresh into the view and saves it
+    pub fn refresh(&self) -> Result<Groups, String> {
+        let (mut covered, mut groups) = self.load()?;
+        let parts = partition_rows(&table_def(&self.table)?);
+        let total: Covered = parts.iter().map(|(part, rows)| (number(part), *rows)).collect();
+        if total == covered && fs::metadata(self.directory() + "state").is_ok() {
+            return Ok(groups);
+        }
+        if covered.iter().any(|(partition, rows)| total.get(partition).is_none_or(|total| rows > total)) {
+            covered.clear();
+            groups.clear();
+        }
+        // Everything but the new rows at the end of each partition
+        let mut skips: Vec<Range<u64>> = Vec::new();
+        let mut start = 0;
+        for (part, rows) in &parts {
+            skips.push(start..start + covered.get(&number(part)).copied().unwrap_or(0));
+            start += rows;
+        }
+        skips.push(start..u64::MAX);
+        let scan = TableScan::skipping(&self.table, &self.columns()?, conjoin(self.filters.clone()), skips)?;
+        let keys: Vec<(String, Expr)> = self.group_by.iter().enumerate().map(|(index, expr)| (format!("__key{}", index), expr.clone())).collect();
+        let partials: Vec<AggregateExpr> = self.slots.iter().enumerate()
+            .map(|(index, (slot, expr))| AggregateExpr::new(slot.function(), expr.clone(), &format!("__slot{}", index)))
+            .collect();
+        let mut aggregate = HashAggregate::new(Box::new(scan), keys, partials);
+        while let Some(batch) = aggregate.next_batch()? {
+            for row in 0..batch.num_rows() {
+                let mut values: Vec<Value> = batch.columns.iter().map(|column| column[row].clone()).collect();
+                let partial = values.split_off(self.group_by.len());
+                match groups.get_mut(&values) {
+                    Some(slots) => {
+                        for ((slot, _), (into, value)) in self.slots.iter().zip(slots.iter_mut().zip(&partial)) {
+                            slot.merge(into, value)?;
+                        }
+                    },
+                    None => {
+                        groups.insert(values, partial);
+                    },
+                }
+            }
+        }
+        self.save(&total, &groups)?;
+        Ok(groups)
+    }
+
+    fn slot(&self, slot: Slot, expr: &Expr) -> Option<usize> {
+        self.slots.iter().position(|(kept, kept_expr)| *kept == slot && kept_expr == expr)
+    }
+
+    // How to answer an aggregate over the view's table from it, None if it can't be
+    fn answers(&self, table: &str, filters: &[Expr], group_by: &[(String, Expr)], aggregates: &[AggregateExpr]) -> Option<ViewQuery> {
+        if table != self.table {
+            return None;
+        }
+        let filters: Vec<Expr> = filters.iter().map(|filter| unqualified(filter, table)).collect();
+        if !self.filters.iter().all(|filter| filters.contains(filter)) {
+            return None;
+        }
+        // Anything the query filters on beyond the view has to be about group columns alone
+        let key_columns: Vec<String> = self.group_by.iter()
+            .filter_map(|expr| match expr {
+                Expr::Column(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        let residual: Vec<Expr> = filters.into_iter().filter(|filter| !self.filters.contains(filter)).collect();
+        if !residual.iter().flat_map(|filter| filter.columns()).all(|name| key_columns.contains(&name)) {
+            return None;
+        }
+        let keys = group_by.iter()
+            .map(|(_, expr)| self.group_by.iter().position(|kept| *kept == unqualified(expr, table)))
+            .collect::<Option<Vec<usize>>>()?;
+        let outputs = aggregates.iter()
+            .map(|agg| {
+                let expr = unqualified(&agg.expr, table);
+                match agg.function {
+                    AggregateFunction::Count => self.slot(Slot::Count, &expr).map(Output::Slot),
+                    AggregateFunction::Sum => self.slot(Slot::Sum, &expr).map(Output::Slot),
+                    AggregateFunction::Min => self.slot(Slot::Min, &expr).map(Output::Slot),
+                    AggregateFunction::Max => self.slot(Slot::Max, &expr).map(Output::Slot),
+                    AggregateFunction::Avg => Some(Output::Avg(self.slot(Slot::Sum, &expr)?, self.slot(Slot::Count, &expr)?)),
+                    _ => None,
+                }
+            })
+            .collect::<Option<Vec<Output>>>()?;
+        Some(ViewQuery {
+            names: group_by.iter().map(|(name, _)| name.clone()).chain(aggregates.iter().map(|agg| agg.name.clone())).collect(),
+            keys,
+            outputs,
+            filter: conjoin(residual),
+            key_columns: self.group_by.iter().enumerate().map(|(index, expr)| match expr {
+                Expr::Column(name) => name.clone(),
+                _ => format!("__key{}", index),
+            }).collect(),
+        })
+    }
+}
+
+pub fn load(name: &str) -> Result<View, String> {
+    check_name(name)?;
+    let path = views_directory() + name + "/definition";
+    let sql = fs::read_to_string(&path).map_err(|_| format!("No materialized view named {:?}", name))?;
+    View::from_sql(name, sql.trim())
+}
+
+pub fn views() -> Result<Vec<View>, String> {
+    let mut names: Vec<String> = match fs::read_dir(views_directory()) {
+        Ok(entries) => entries.filter_map(|entry| entry.ok()).map(|entry| entry.file_name().to_string_lossy().to_string()).collect(),
+        Err(_) => Vec::new(),
+    };
+    names.sort();
+    names.iter().map(|name| load(name)).collect()
+}
+
+// Saves the definition and builds the view from everything in the table so far
+pub fn create(name: &str, sql: &str) -> Result<View, String> {
+    let view = View::from_sql(name, sql)?;
+    if fs::metadata(view.directory()).is_ok() {
+        return Err(format!("There's already a materialized view named {:?}", name));
+    }
+    fs::create_dir_all(view.directory()).map_err(|err| format!("Can't create view directory: {}", err))?;
+    let path = view.directory() + "definition";
+    fs::write(&path, format!("{}\n", sql)).map_err(|err| format!("Can't write view definition {}: {}", path, err))?;
+    // A view that can't be built shouldn't be left behind to fail every query on its table
+    if let Err(err) = view.refresh() {
+        let _ = fs::remove_dir_all(view.directory());
+        return Err(err);
+    }
+    Ok(view)
+}
+
+// The same as create, unless it's there already
+pub fn ensure(name: &str, sql: &str) -> Result<View, String> {
+    match load(name) {
+        Ok(view) => Ok(view),
+        Err(_) => create(name, sql),
+    }
+}
+
+pub fn drop(name: &str) -> Result<(), String> {
+    let view = load(name)?;
+    fs::remove_dir_all(view.directory()).map_err(|err| format!("Can't remove view {:?}: {}", name, err))
+}
+
+// Table::write_data calls this once the new rows are all down
+pub fn refresh_table(table: &str) -> Result<(), String> {
+    for view in views()?.into_iter().filter(|view| view.table == table) {
+        view.refresh()?;
+    }
+    Ok(())
+}
+
+// The first view that can answer an aggregate over a table, see View::answers
+pub fn answer(table: &str, filters: &[Expr], group_by: &[(String, Expr)], aggregates: &[AggregateExpr]) -> Result<Option<ViewScan>, String> {
+    for view in views()? {
+        if let Some(query) = view.answers(table, filters, group_by, aggregates) {
+            return Ok(Some(ViewScan { view, query, output: None }));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    Slot(usize),
+    // The sum and count slots
+    Avg(usize, usize),
+}
+
+#[derive(Debug, Clone)]
+struct ViewQuery {
+    names: Vec<String>,
+    // Which of the view's group keys each of the query's is
+    keys: Vec<usize>,
+    outputs: Vec<Output>,
+    // Conditions on group columns the view doesn't have, checked against its groups
+    filter: Option<Expr>,
+    // What the filter calls each of the view's group keys
+    key_columns: Vec<String>,
+}
+
+// Answers an aggregate from a view's groups, bringing the view up to date first. Groups get
+// merged down to the query's keys when it groups by fewer of them.
+pub struct ViewScan {
+    view: View,
+    query: ViewQuery,
+    output: Option<VecDeque<Vec<Value>>>,
+}
+
+impl ViewScan {
+    fn rows(&self) -> Result<VecDeque<Vec<Value>>, String> {
+        let groups: Vec<(Vec<Value>, Vec<Value>)> = self.view.refresh()?.into_iter().collect();
+        let kept = match &self.query.filter {
+            Some(filter) => {
+                let columns = (0..self.query.key_columns.len()).map(|index| groups.iter().map(|(key, _)| key[index].clone()).collect()).collect();
+                filter.eval_mask(&Batch::new(self.query.key_columns.clone(), columns))?
+            },
+            None => vec![true; groups.len()],
+        };
+        let mut merged: Groups = HashMap::new();
+        for ((key, slots), _) in groups.into_iter().zip(kept).filter(|(_, keep)| *keep) {
+            let key: Vec<Value> = self.query.keys.iter().map(|index| key[*index].clone()).collect();
+            match merged.get_mut(&key) {
+                Some(into) => {
+                    for ((slot, _), (into, value)) in self.view.slots.iter().zip(into.iter_mut().zip(&slots)) {
+                        slot.merge(into, value)?;
+                    }
+                },
+                None => {
+                    merged.insert(key, slots);
+                },
+            }
+        }
+        // No group keys is one row even when nothing matched, the way SQL does it
+        if self.query.keys.is_empty() && merged.is_empty() {
+            merged.insert(Vec::new(), self.view.slots.iter().map(|(slot, _)| slot.empty()).collect());
+        }
+        Ok(merged.into_iter().map(|(key, slots)| {
+            let outputs = self.query.outputs.iter().map(|output| match output {
+                Output::Slot(index) => slots[*index].clone(),
+                Output::Avg(sum, count) => match (slots[*sum].as_decimal(), slots[*count].as_decimal()) {
+                    (Some(total), Some(count)) if !count.is_zero() => Value::Decimal(total / count),
+                    _ => Value::Null,
+                },
+            });
+            key.into_iter().chain(outputs).collect()
+        }).collect())
+    }
+}
+
+impl Operator for ViewScan {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        if self.output.is_none() {
+            self.output = Some(self.rows()?);
+        }
+        let output = self.output.as_mut().unwrap();
+        if output.is_empty() {
+            return Ok(None);
+        }
+        let mut columns: Vec<Vec<Value>> = vec![Vec::new(); self.query.names.len()];
+        for row in output.drain(..output.len().min(BATCH_ROWS)) {
+            for (column, value) in columns.iter_mut().zip(row) {
+                column.push(value);
+            }
+        }
+        Ok(Some(Batch::new(self.schema(), columns)))
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.query.names.clone()
+    }
+
+    fn name(&self) -> String {
+        match &self.query.filter {
+            Some(filter) => format!("ViewScan {} [{}] filter {}", self.view.name, self.query.names.join(", "), filter),
+            None => format!("ViewScan {} [{}]", self.view.name, self.query.names.join(", ")),
+        }
+    }
+
+    fn lineage(&self) -> LineageNode {
+        let mut node = LineageNode::new(self.name(), self.schema(), Vec::new());
+        if let (Ok(table), Ok(columns)) = (table_def(&self.view.table), self.view.columns()) {
+            for column in columns {
+                node.segments.extend(column_segments(&table, &column));
+            }
+        }
+        node
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        match self.query.keys.is_empty() {
+            true => Some(1),
+            false => None,
+        }
+    }
+}
@@ -0,0 +1,671 @@
+use super::aggregate::{AggregateFunction, Sum};
+use super::batch::{Batch, Value};
+use super::expr::{arithmetic, ArithmeticOp, Expr};
+use super::operator::{Operator, BATCH_ROWS};
+use super::sort::{compare_rows, compare_sort_values, Sort, SortKey};
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fmt;
+
+// Window functions, e.g. `sum(total) OVER (PARTITION BY customer_id ORDER BY created)`. Every row
+// stays a row and gets a value computed from the other rows of its partition: its position, a
+// neighbour's value, or an aggregate over a frame of rows around it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowFunction {
+    RowNumber,
+    Rank,
+    DenseRank,
+    // How many rows back or ahead
+    Lag(usize),
+    Lead(usize),
+    // count, sum, avg, min or max over the frame
+    Aggregate(AggregateFunction),
+}
+
+impl WindowFunction {
+    pub fn from_name(name: &str) -> Option<WindowFunction> {
+        match name.to_lowercase().as_str() {
+            "row_number" => Some(WindowFunction::RowNumber),
+            "rank" => Some(WindowFunction::Rank),
+            "dense_rank" => Some(WindowFunction::DenseRank),
+            "lag" => Some(WindowFunction::Lag(1)),
+            "lead" => Some(WindowFunction::Lead(1)),
+            _ => None,
+        }
+    }
+
+    pub fn from_aggregate(function: AggregateFunction) -> Option<WindowFunction> {
+        match function {
+            AggregateFunction::Count | AggregateFunction::Sum | AggregateFunction::Avg | AggregateFunction::Min | AggregateFunction::Max => {
+                Some(WindowFunction::Aggregate(function))
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for WindowFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WindowFunction::RowNumber => write!(f, "row_number"),
+            WindowFunction::Rank => write!(f, "rank"),
+            WindowFunction::DenseRank => write!(f, "dense_rank"),
+            WindowFunction::Lag(_) => write!(f, "lag"),
+            WindowFunction::Lead(_) => write!(f, "lead"),
+            WindowFunction::Aggregate(function) => write!(f, "{}", function),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameUnits {
+    // Offsets count rows
+    Rows,
+    // Offsets are a distance from the current row's ORDER BY value, and CURRENT ROW takes in
+    // every row that ties with it
+    Range,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FrameBound {
+    UnboundedPreceding,
+    Preceding(Value),
+    CurrentRow,
+    Following(Value),
+    UnboundedFollowing,
+}
+
+impl FrameBound {
+    fn position(&self) -> u8 {
+        match self {
+            FrameBound::UnboundedPreceding => 0,
+            FrameBound::Preceding(_) => 1,
+            FrameBound::CurrentRow => 2,
+            FrameBound::Following(_) => 3,
+            FrameBound::UnboundedFollowing => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowFrame {
+    pub units: FrameUnits,
+    pub start: FrameBound,
+    pub end: FrameBound,
+}
+
+impl WindowFrame {
+    // What you get without saying, everything up to the current row and its ties
+    pub fn running() -> WindowFrame {
+        WindowFrame { units: FrameUnits::Range, start: FrameBound::UnboundedPreceding, end: FrameBound::CurrentRow }
+    }
+
+    pub fn new(units: FrameUnits, start: FrameBound, end: FrameBound, order_by: &[SortKey]) -> Result<WindowFrame, String> {
+        let frame = WindowFrame { units, start, end };
+        if frame.start == FrameBound::UnboundedFollowing || frame.end == FrameBound::UnboundedPreceding || frame.start.position() > frame.end.position() {
+            return Err(format!("Window frame {} ends before it starts", frame));
+        }
+        for bound in [&frame.start, &frame.end] {
+            if let FrameBound::Preceding(offset) | FrameBound::Following(offset) = bound {
+                let valid = match units {
+                    FrameUnits::Rows => matches!(offset.as_i128(), Some(rows) if rows >= 0),
+                    FrameUnits::Range => matches!(offset.as_decimal(), Some(distance) if distance >= Decimal::ZERO),
+                };
+                if !valid {
+                    return Err(format!("Window frame offsets can't be negative, got {}", offset));
+                }
+                if units == FrameUnits::Range && order_by.len() != 1 {
+                    return Err("RANGE with an offset needs exactly one ORDER BY key".to_string());
+                }
+            }
+        }
+        Ok(frame)
+    }
+}
+
+impl fmt::Display for FrameBound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameBound::UnboundedPreceding => write!(f, "UNBOUNDED PRECEDING"),
+            FrameBound::Preceding(offset) => write!(f, "{} PRECEDING", offset),
+            FrameBound::CurrentRow => write!(f, "CURRENT ROW"),
+            FrameBound::Following(offset) => write!(f, "{} FOLLOWING", offset),
+            FrameBound::UnboundedFollowing => write!(f, "UNBOUNDED FOLLOWING"),
+        }
+    }
+}
+
+impl fmt::Display for WindowFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = match self.units {
+            FrameUnits::Rows => "ROWS",
+            FrameUnits::Range => "RANGE",
+        };
+        write!(f, "{} BETWEEN {} AND {}", units, self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowExpr {
+    pub function: WindowFunction,
+    // The value for lag, lead and the aggregates, then the default for lag and lead
+    pub args: Vec<Expr>,
+    pub partition_by: Vec<Expr>,
+    pub order_by: Vec<SortKey>,
+    // None is the default frame, only aggregates look at it
+    pub frame: Option<WindowFrame>,
+}
+
+impl WindowExpr {
+    // Windows with the same partitioning and order can be computed off one sort
+    pub fn same_window(&self, other: &WindowExpr) -> bool {
+        self.partition_by == other.partition_by && self.order_by == other.order_by
+    }
+}
+
+impl fmt::Display for WindowExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args: Vec<String> = self.args.iter().map(|arg| arg.to_string()).collect();
+        match self.function {
+            WindowFunction::Aggregate(AggregateFunction::Count) if self.args == [Expr::Literal(Value::Bool(true))] => args = vec!["*".to_string()],
+            WindowFunction::Lag(offset) | WindowFunction::Lead(offset) if offset != 1 || args.len() > 1 => args.insert(1, offset.to_string()),
+            _ => {},
+        }
+        let mut spec: Vec<String> = Vec::new();
+        if !self.partition_by.is_empty() {
+            let keys: Vec<String> = self.partition_by.iter().map(|expr| expr.to_string()).collect();
+            spec.push(format!("PARTITION BY {}", keys.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            let keys: Vec<String> = self.order_by.iter()
+                .map(|key| if key.descending { format!("{} DESC", key.expr) } else { key.expr.to_string() })
+                .collect();
+            spec.push(format!("ORDER BY {}", keys.join(", ")));
+        }
+        if let Some(frame) = &self.frame {
+            spec.push(frame.to_string());
+        }
+        write!(f, "{}({}) OVER ({})", self.function, args.join(", "), spec.join(" "))
+    }
+}
+
+fn same_keys(left: &[Value], right: &[Value]) -> bool {
+    left.iter().zip(right).all(|(l, r)| compare_sort_values(l, r) == Ordering::Equal)
+}
+
+// Start and end of the run of tied rows each row of a partition is in
+fn peer_groups(orders: &[Vec<Value>]) -> Vec<(usize, usize)> {
+    let mut groups: Vec<(usize, usize)> = Vec::with_capacity(orders.len());
+    let mut start = 0;
+    while start < orders.len() {
+        let mut end = start + 1;
+        while end < orders.len() && same_keys(&orders[end], &orders[start]) {
+            end += 1;
+        }
+        groups.extend(std::iter::repeat_n((start, end), end - start));
+        start = end;
+    }
+    groups
+}
+
+// First row of 0..len the predicate is false for, it has to be true for every row before that
+fn partition_point(len: usize, before: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let middle = (low + high) / 2;
+        match before(middle) {
+            true => low = middle + 1,
+            false => high = middle,
+        }
+    }
+    low
+}
+
+fn row_offset(offset: &Value) -> usize {
+    offset.as_i128().and_then(|rows| usize::try_from(rows).ok()).unwrap_or(usize::MAX)
+}
+
+// The rows [start, end) of a partition in each row's frame. Since the rows are sorted, both ends
+// only ever move forward from one row to the next.
+fn frame_ranges(frame: &WindowFrame, order_by: &[SortKey], orders: &[Vec<Value>]) -> Result<Vec<(usize, usize)>, String> {
+    let len = orders.len();
+    let peers = peer_groups(orders);
+    // The current row's ORDER BY value moved back (PRECEDING) or ahead (FOLLOWING) in sort order
+    let shifted = |row: usize, offset: &Value, back: bool| -> Result<Value, String> {
+        let op = match back != order_by[0].descending {
+            true => ArithmeticOp::Subtract,
+            false => ArithmeticOp::Add,
+        };
+        arithmetic(op, &orders[row][0], offset)
+    };
+    let compare = |row: usize, target: &Value| compare_rows(&order_by[..1], &orders[row], std::slice::from_ref(target));
+    let first_not_before = |target: &Value| partition_point(len, |row| compare(row, target) == Ordering::Less);
+    let first_after = |target: &Value| partition_point(len, |row| compare(row, target) != Ordering::Greater);
+
+    let mut ranges: Vec<(usize, usize)> = Vec::with_capacity(len);
+    for (row, (peer_start, peer_end)) in peers.iter().enumerate() {
+        let start = match (&frame.start, frame.units) {
+            (FrameBound::UnboundedPreceding, _) => 0,
+            (FrameBound::CurrentRow, FrameUnits::Rows) => row,
+            (FrameBound::CurrentRow, FrameUnits::Range) => *peer_start,
+            (FrameBound::Preceding(offset), FrameUnits::Rows) => row.saturating_sub(row_offset(offset)),
+            (FrameBound::Following(offset), FrameUnits::Rows) => row.saturating_add(row_offset(offset)).min(len),
+            (FrameBound::Preceding(offset), FrameUnits::Range) => first_not_before(&shifted(row, offset, true)?),
+            (FrameBound::Following(offset), FrameUnits::Range) => first_not_before(&shifted(row, offset, false)?),
+            (FrameBound::UnboundedFollowing, _) => len,
+        };
+        let end = match (&frame.end, frame.units) {
+            (FrameBound::UnboundedFollowing, _) => len,
+            (FrameBound::CurrentRow, FrameUnits::Rows) => row + 1,
+            (FrameBound::CurrentRow, FrameUnits::Range) => *peer_end,
+            (FrameBound::Preceding(offset), FrameUnits::Rows) => (row + 1).saturating_sub(row_offset(offset)),
+            (FrameBound::Following(offset), FrameUnits::Rows) => row.saturating_add(row_offset(offset)).saturating_add(1).min(len),
+            (FrameBound::Preceding(offset), FrameUnits::Range) => first_after(&shifted(row, offset, true)?),
+            (FrameBound::Following(offset), FrameUnits::Range) => first_after(&shifted(row, offset, false)?),
+            (FrameBound::UnboundedPreceding, _) => 0,
+        };
+        ranges.push((start, end.max(start)));
+    }
+    Ok(ranges)
+}
+
+// An aggregate over a frame that rows get added to at the end and taken off the front of. Min and
+// max keep a deque of rows whose values only go up (or down) from the front, so the front is
+// always the answer and dropping rows that fall out of the frame is cheap.
+enum FrameState {
+    Count(u64),
+    Sum(Sum, u64),
+    Avg(Sum, u64),
+    Min(VecDeque<usize>),
+    Max(VecDeque<usize>),
+}
+
+impl FrameState {
+    fn new(function: AggregateFunction) -> Result<FrameState, String> {
+        match function {
+            AggregateFunction::Count => Ok(FrameState::Count(0)),
+            AggregateFunction::Sum => Ok(FrameState::Sum(Sum::default(), 0)),
+            AggregateFunction::Avg => Ok(FrameState::Avg(Sum::default(), 0)),
+            AggregateFunction::Min => Ok(FrameState::Min(VecDeque::new())),
+            AggregateFunction::Max => Ok(FrameState::Max(VecDeque::new())),
+            _ => Err(format!("{} can't be used as a window function", function)),
+        }
+    }
+
+    fn add(&mut self, values: &[Value], row: usize) -> Result<(), String> {
+        let value = &values[row];
+        if value.is_null() {
+            return Ok(());
+        }
+        match self {
+            FrameState::Count(count) => *count += 1,
+            FrameState::Sum(sum, count) | FrameState::Avg(sum, count) => {
+                sum.add(value)?;
+                *count += 1;
+            },
+            FrameState::Min(rows) => push_ordered(rows, values, row, Ordering::Less),
+            FrameState::Max(rows) => push_ordered(rows, values, row, Ordering::Greater),
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, values: &[Value], row: usize) -> Result<(), String> {
+        let value = &values[row];
+        if value.is_null() {
+            return Ok(());
+        }
+        match self {
+            FrameState::Count(count) => *count -= 1,
+            FrameState::Sum(sum, count) | FrameState::Avg(sum, count) => {
+                sum.remove(value)?;
+                *count -= 1;
+            },
+            FrameState::Min(rows) | FrameState::Max(rows) => {
+                if rows.front() == Some(&row) {
+                    rows.pop_front();
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn value(&self, values: &[Value]) -> Value {
+        match self {
+            FrameState::Count(count) => Value::UInt64(*count),
+            FrameState::Sum(_, 0) | FrameState::Avg(_, 0) => Value::Null,
+            FrameState::Sum(sum, _) => sum.total(),
+            FrameState::Avg(sum, count) => match sum.total().as_decimal() {
+                Some(total) => Value::Decimal(total / Decimal::from(*count)),
+                None => Value::Null,
+            },
+            FrameState::Min(rows) | FrameState::Max(rows) => rows.front().map(|row| values[*row].clone()).unwrap_or(Value::Null),
+        }
+    }
+}
+
+// Drops rows off the back that the new one beats or ties, they can't be the answer again
+fn push_ordered(rows: &mut VecDeque<usize>, values: &[Value], row: usize, keep: Ordering) {
+    while rows.back().map(|back| compare_sort_values(&values[*back], &values[row]) != keep).unwrap_or(false) {
+        rows.pop_back();
+    }
+    rows.push_back(row);
+}
+
+fn frame_aggregate(function: AggregateFunction, values: &[Value], ranges: &[(usize, usize)]) -> Result<Vec<Value>, String> {
+    let mut state = FrameState::new(function)?;
+    let (mut low, mut high) = (0, 0);
+    let mut output: Vec<Value> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        // Nothing in common with the last frame, start over from empty
+        if *start >= high {
+            state = FrameState::new(function)?;
+            low = *start;
+            high = *start;
+        }
+        while high < *end {
+            state.add(values, high)?;
+            high += 1;
+        }
+        while low < *start {
+            state.remove(values, low)?;
+            low += 1;
+        }
+        output.push(state.value(values));
+    }
+    Ok(output)
+}
+
+// One window function over one partition, rows already in ORDER BY order
+fn evaluate(window: &WindowExpr, orders: &[Vec<Value>], args: &[&[Value]]) -> Result<Vec<Value>, String> {
+    let len = orders.len();
+    let neighbour = |row: Option<usize>, current: usize| match row.filter(|row| *row < len) {
+        Some(row) => args[0][row].clone(),
+        None => args.get(1).map(|default| default[current].clone()).unwrap_or(Value::Null),
+    };
+    Ok(match window.function {
+        WindowFunction::RowNumber => (1..=len as u64).map(Value::UInt64).collect(),
+        WindowFunction::Rank => peer_groups(orders).iter().map(|(start, _)| Value::UInt64(*start as u64 + 1)).collect(),
+        WindowFunction::DenseRank => {
+            let mut rank = 0;
+            peer_groups(orders).iter().enumerate().map(|(row, (start, _))| {
+                if row == *start {
+                    rank += 1;
+                }
+                Value::UInt64(rank)
+            }).collect()
+        },
+        WindowFunction::Lag(offset) => (0..len).map(|row| neighbour(row.checked_sub(offset), row)).collect(),
+        WindowFunction::Lead(offset) => (0..len).map(|row| neighbour(row.checked_add(offset), row)).collect(),
+        WindowFunction::Aggregate(function) => {
+            let frame = window.frame.clone().unwrap_or_else(WindowFrame::running);
+            frame_aggregate(function, args[0], &frame_ranges(&frame, &window.order_by, orders)?)?
+        },
+    })
+}
+
+// Computes window functions that all share a PARTITION BY and ORDER BY, over input that's already
+// sorted by both. Rows are held until their partition ends, then go out with a column added for
+// each function. Only a partition at a time has to fit in memory, the sort below deals with the
+// rest, but no PARTITION BY makes the whole input one partition.
+pub struct Window {
+    input: Box<dyn Operator>,
+    functions: Vec<(String, WindowExpr)>,
+    pending: Batch,
+    // Per pending row, its partition key and ORDER BY values
+    partitions: Vec<Vec<Value>>,
+    orders: Vec<Vec<Value>>,
+    // Per function, per argument, the pending rows' values
+    args: Vec<Vec<Vec<Value>>>,
+    // Where the last pending partition starts, everything before it is complete
+    last_start: usize,
+    done: bool,
+    held: usize,
+}
+
+// Sorts the input by partition and order keys, unless there aren't any, and puts a Window on top
+pub fn plan_window(input: Box<dyn Operator>, functions: Vec<(String, WindowExpr)>) -> Box<dyn Operator> {
+    let first = &functions[0].1;
+    let keys: Vec<SortKey> = first.partition_by.iter()
+        .map(|expr| SortKey::new(expr.clone(), false))
+        .chain(first.order_by.iter().cloned())
+        .collect();
+    let input: Box<dyn Operator> = match keys.is_empty() {
+        true => input,
+        false => Box::new(Sort::new(input, keys)),
+    };
+    Box::new(Window::new(input, functions))
+}
+
+impl Window {
+    pub fn new(input: Box<dyn Operator>, functions: Vec<(String, WindowExpr)>) -> Window {
+        let schema = input.schema();
+        let args = functions.iter().map(|(_, window)| vec![Vec::new(); window.args.len()]).collect();
+        Window {
+            input,
+            functions,
+            pending: Batch::new(schema.clone(), vec![Vec::new(); schema.len()]),
+            partitions: Vec::new(),
+            orders: Vec::new(),
+            args,
+            last_start: 0,
+            done: false,
+            held: 0,
+        }
+    }
+
+    fn buffer(&mut self, batch: Batch) -> Result<(), String> {
+        let spec = &self.functions[0].1;
+        let mut partition_values: Vec<Vec<Value>> = Vec::new();
+        for expr in &spec.partition_by {
+            partition_values.push(expr.eval(&batch)?);
+        }
+        let mut order_values: Vec<Vec<Value>> = Vec::new();
+        for key in &spec.order_by {
+            order_values.push(key.expr.eval(&batch)?);
+        }
+        for (index, (_, window)) in self.functions.iter().enumerate() {
+            for (arg, expr) in window.args.iter().enumerate() {
+                self.args[index][arg].extend(expr.eval(&batch)?);
+            }
+        }
+        for row in 0..batch.num_rows() {
+            let partition: Vec<Value> = partition_values.iter().map(|col| col[row].clone()).collect();
+            if self.partitions.last().map(|last| !same_keys(last, &partition)).unwrap_or(false) {
+                self.last_start = self.partitions.len();
+            }
+            self.partitions.push(partition);
+            self.orders.push(order_values.iter().map(|col| col[row].clone()).collect());
+        }
+        self.held += batch.size();
+        self.pending.append(batch);
+        Ok(())
+    }
+
+    // Computes and hands out the first `rows` pending rows, which have to end on a partition boundary
+    fn finish(&mut self, rows: usize) -> Result<Batch, String> {
+        let take = |values: &mut Vec<Vec<Value>>| {
+            let rest = values.split_off(rows);
+            std::mem::replace(values, rest)
+        };
+        let columns: Vec<Vec<Value>> = self.pending.columns.iter_mut().map(|col| {
+            let rest = col.split_off(rows);
+            std::mem::replace(col, rest)
+        }).collect();
+        let partitions = take(&mut self.partitions);
+        let orders = take(&mut self.orders);
+        let args: Vec<Vec<Vec<Value>>> = self.args.iter_mut().map(|function| function.iter_mut().map(|arg| {
+            let rest = arg.split_off(rows);
+            std::mem::replace(arg, rest)
+        }).collect()).collect();
+        self.last_start = self.last_start.saturating_sub(rows);
+
+        let mut results: Vec<Vec<Value>> = vec![Vec::with_capacity(rows); self.functions.len()];
+        let mut start = 0;
+        while start < rows {
+            let mut end = start + 1;
+            while end < rows && same_keys(&partitions[end], &partitions[start]) {
+                end += 1;
+            }
+            for (index, (_, window)) in self.functions.iter().enumerate() {
+                let values: Vec<&[Value]> = args[index].iter().map(|arg| &arg[start..end]).collect();
+                results[index].extend(evaluate(window, &orders[start..end], &values)?);
+            }
+            start = end;
+        }
+        let batch = Batch::new(self.pending.names.clone(), columns);
+        self.held = self.held.saturating_sub(batch.size());
+        let mut names = batch.names;
+        names.extend(self.functions.iter().map(|(name, _)| name.clone()));
+        let mut columns = batch.columns;
+        columns.extend(results);
+        Ok(Batch::new(names, columns))
+    }
+}
+
+impl Operator for Window {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        while !self.done {
+            match self.input.next_batch()? {
+                Some(batch) => {
+                    self.buffer(batch)?;
+                    if self.last_start >= BATCH_ROWS {
+                        return self.finish(self.last_start).map(Some);
+                    }
+                },
+                None => self.done = true,
+            }
+        }
+        match self.partitions.len() {
+            0 => Ok(None),
+            rows => self.finish(rows).map(Some),
+        }
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.input.schema().into_iter().chain(self.functions.iter().map(|(name, _)| name.clone())).collect()
+    }
+
+    fn name(&self) -> String {
+        let functions: Vec<String> = self.functions.iter().map(|(name, window)| format!("{} AS {}", window, name)).collect();
+        format!("Window {}", functions.join(", "))
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        vec![self.input.as_ref()]
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        vec![&mut self.input]
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.held
+    }
+
+    fn estimated_rows(&self) -> Option<u64> {
+        self.input.estimated_rows()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyze::operator::{collect, BatchSource};
+
+    // Two customers, with customer 1's rows split over both batches and two of them on the same day
+    fn orders() -> BatchSource {
+        let batch = |rows: &[(i64, i64, i64)]| Batch::new(
+            vec!["customer".to_string(), "day".to_string(), "amount".to_string()],
+            vec![
+                rows.iter().map(|row| Value::Int64(row.0)).collect(),
+                rows.iter().map(|row| Value::Int64(row.1)).collect(),
+                rows.iter().map(|row| Value::Int64(row.2)).collect(),
+            ],
+        );
+        BatchSource::new(vec![
+            batch(&[(1, 1, 10), (2, 5, 7), (1, 2, 20)]),
+            batch(&[(2, 1, 5), (1, 2, 30), (1, 3, 40)]),
+        ])
+    }
+
+    fn window(function: WindowFunction, args: Vec<Expr>, frame: Option<WindowFrame>) -> WindowExpr {
+        WindowExpr {
+            function,
+            args,
+            partition_by: vec![Expr::Column("customer".to_string())],
+            order_by: vec![SortKey::new(Expr::Column("day".to_string()), false)],
+            frame,
+        }
+    }
+
+    fn sum(frame: Option<WindowFrame>) -> WindowExpr {
+        window(WindowFunction::Aggregate(AggregateFunction::Sum), vec![Expr::Column("amount".to_string())], frame)
+    }
+
+    fn frame(units: FrameUnits, start: FrameBound, end: FrameBound) -> Option<WindowFrame> {
+        let order_by = [SortKey::new(Expr::Column("day".to_string()), false)];
+        Some(WindowFrame::new(units, start, end, &order_by).unwrap())
+    }
+
+    // Runs the windows over the orders and reads each one back, rows in customer and day order
+    fn run(functions: Vec<(&str, WindowExpr)>) -> Vec<Vec<Option<i128>>> {
+        let names: Vec<String> = functions.iter().map(|(name, _)| name.to_string()).collect();
+        let functions = functions.into_iter().map(|(name, window)| (name.to_string(), window)).collect();
+        let result = collect(plan_window(Box::new(orders()), functions).as_mut()).unwrap();
+        names.iter().map(|name| result.column(name).unwrap().iter().map(|value| value.as_i128()).collect()).collect()
+    }
+
+    fn some(values: &[i128]) -> Vec<Option<i128>> {
+        values.iter().map(|value| Some(*value)).collect()
+    }
+
+    #[test]
+    fn rows_count_each_tie_range_takes_them_together() {
+        let one = Value::Int64(1);
+        let result = run(vec![
+            ("rows", sum(frame(FrameUnits::Rows, FrameBound::UnboundedPreceding, FrameBound::CurrentRow))),
+            ("range", sum(None)),
+            ("range_back", sum(frame(FrameUnits::Range, FrameBound::Preceding(one.clone()), FrameBound::CurrentRow))),
+            ("around", sum(frame(FrameUnits::Rows, FrameBound::Preceding(one.clone()), FrameBound::Following(one)))),
+            ("rank", window(WindowFunction::Rank, Vec::new(), None)),
+            ("dense_rank", window(WindowFunction::DenseRank, Vec::new(), None)),
+        ]);
+        assert_eq!(result[0], some(&[10, 30, 60, 100, 5, 12]));
+        // Both day 2 rows see each other
+        assert_eq!(result[1], some(&[10, 60, 60, 100, 5, 12]));
+        // Customer 2's days are 4 apart, so neither reaches the other, and nothing reaches back
+        // into customer 1
+        assert_eq!(result[2], some(&[10, 60, 60, 90, 5, 7]));
+        assert_eq!(result[3], some(&[30, 60, 90, 70, 12, 12]));
+        assert_eq!(result[4], some(&[1, 2, 2, 4, 1, 2]));
+        assert_eq!(result[5], some(&[1, 2, 2, 3, 1, 2]));
+    }
+
+    #[test]
+    fn lag_and_lead_stop_at_the_partition() {
+        let amount = Expr::Column("amount".to_string());
+        let result = run(vec![
+            ("lag", window(WindowFunction::Lag(1), vec![amount.clone()], None)),
+            ("lag_2", window(WindowFunction::Lag(2), vec![amount.clone(), Expr::Literal(Value::Int64(0))], None)),
+            ("lead", window(WindowFunction::Lead(1), vec![amount.clone(), Expr::Literal(Value::Int64(-1))], None)),
+            // A default can be another column of the same row
+            ("lead_3", window(WindowFunction::Lead(3), vec![amount, Expr::Column("day".to_string())], None)),
+        ]);
+        assert_eq!(result[0], vec![None, Some(10), Some(20), Some(30), None, Some(5)]);
+        assert_eq!(result[1], some(&[0, 0, 10, 20, 0, 0]));
+        assert_eq!(result[2], some(&[20, 30, 40, -1, 7, -1]));
+        assert_eq!(result[3], some(&[40, 2, 2, 3, 1, 5]));
+    }
+
+    #[test]
+    fn frames_that_end_first_are_refused() {
+        let order_by = [SortKey::new(Expr::Column("day".to_string()), false)];
+        assert!(WindowFrame::new(FrameUnits::Rows, FrameBound::CurrentRow, FrameBound::Preceding(Value::Int64(1)), &order_by).is_err());
+        assert!(WindowFrame::new(FrameUnits::Rows, FrameBound::Preceding(Value::Int64(-1)), FrameBound::CurrentRow, &order_by).is_err());
+        // A distance means nothing without exactly one thing to measure it on
+        assert!(WindowFrame::new(FrameUnits::Range, FrameBound::Preceding(Value::Int64(1)), FrameBound::CurrentRow, &[]).is_err());
+        assert!(WindowFrame::new(FrameUnits::Range, FrameBound::UnboundedPreceding, FrameBound::CurrentRow, &[]).is_ok());
+    }
+}
@@ -0,0 +1,157 @@
+use crate::analyze::batch::Value;
+use crate::analyze::hll::hash_value;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+// Bloom filters for the fixed width key columns a table asks for in the catalog, one per segment
+// file in <table>/_bloom/<column>_<file_num>, so a scan looking for one key can pass over every
+// segment that definitely doesn't have it. They're split block filters (the kind Parquet uses): a
+// value's hash picks one 32 byte block and sets a bit in each of its eight words, so checking a
+// value is a single small read per filter rather than loading the whole thing.
+//
+// A filter can't grow once it's sized, so each write into a segment appends another one sized for
+// just its rows, and a check looks in all of them. Each part starts with the segment's byte size
+// before and after the rows it has, and the number of blocks. Parts that don't run from an empty
+// file all the way to the file's current size leave some rows unaccounted for, so then the
+// segment might have anything.
+
+// About a 1% false positive rate
+const BITS_PER_VALUE: u64 = 10;
+const BLOCK_SIZE: u64 = 32;
+const PART_HEADER: u64 = 24;
+const SALT: [u32; 8] = [0x47b6137b, 0x44974d91, 0x8824ad5b, 0xa2b7289d, 0x705495c7, 0x2df1424b, 0x9efc4947, 0x5c6bfb31];
+
+fn bloom_path(table_directory: &str, column_name: &str, file_num: u64) -> String {
+    format!("{}_bloom/{}_{:020}", table_directory, column_name, file_num)
+}
+
+fn block_of(hash: u64, blocks: u64) -> u64 {
+    ((hash >> 32) * blocks) >> 32
+}
+
+fn set_bits(block: &mut [u8], hash: u64) {
+    for (word, salt) in SALT.iter().enumerate() {
+        let bit = (hash as u32).wrapping_mul(*salt) >> 27;
+        let at = word * 4;
+        let current = u32::from_le_bytes(block[at..at + 4].try_into().unwrap());
+        block[at..at + 4].copy_from_slice(&(current | (1 << bit)).to_le_bytes());
+    }
+}
+
+fn has_bits(block: &[u8], hash: u64) -> bool {
+    SALT.iter().enumerate().all(|(word, salt)| {
+        let bit = (hash as u32).wrapping_mul(*salt) >> 27;
+        u32::from_le_bytes(block[word * 4..word * 4 + 4].try_into().unwrap()) & (1 << bit) != 0
+    })
+}
+
+// Adds a filter for rows that just got written to a segment file, which took it from before bytes
+// to after
+pub fn record(table_directory: &str, column_name: &str, file_num: u64, before: u64, after: u64, values: impl Iterator<Item = Value>) -> Result<(), String> {
+    let hashes: Vec<u64> = values.filter(|value| !value.is_null()).map(|value| hash_value(&value)).collect();
+    let blocks = (hashes.len() as u64 * BITS_PER_VALUE).div_ceil(BLOCK_SIZE * 8);
+    let mut part: Vec<u8> = Vec::with_capacity((PART_HEADER + blocks * BLOCK_SIZE) as usize);
+    part.extend(before.to_le_bytes());
+    part.extend(after.to_le_bytes());
+    part.extend(blocks.to_le_bytes());
+    part.resize((PART_HEADER + blocks * BLOCK_SIZE) as usize, 0);
+    for hash in hashes {
+        let at = (PART_HEADER + block_of(hash, blocks) * BLOCK_SIZE) as usize;
+        set_bits(&mut part[at..at + BLOCK_SIZE as usize], hash);
+    }
+    let path = bloom_path(table_directory, column_name, file_num);
+    fs::create_dir_all(table_directory.to_owned() + "_bloom/").map_err(|err| format!("Can't create bloom filter directory: {}", err))?;
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)
+        .map_err(|err| format!("Can't open bloom filter {}: {}", path, err))?;
+    file.write_all(&part).map_err(|err| format!("Can't write bloom filter {}: {}", path, err))
+}
+
+// False only when the segment file, at its current size, definitely doesn't have the value
+pub fn may_contain(table_directory: &str, column_name: &str, file_num: u64, segment_bytes: u64, value: &Value) -> bool {
+    let mut file = match File::open(bloom_path(table_directory, column_name, file_num)) {
+        Ok(file) => file,
+        Err(_) => return true,
+    };
+    let hash = hash_value(value);
+    let mut covered: u64 = 0;
+    let mut found = false;
+    let mut header = [0u8; PART_HEADER as usize];
+    let mut block = [0u8; BLOCK_SIZE as usize];
+    let mut at: u64 = 0;
+    while file.read_exact(&mut header).is_ok() {
+        let field = |index: usize| u64::from_le_bytes(header[index * 8..index * 8 + 8].try_into().unwrap());
+        let (before, after, blocks) = (field(0), field(1), field(2));
+        if before != covered {
+            return true;
+        }
+        covered = after;
+        if !found && blocks > 0 {
+            let read = file.seek(SeekFrom::Start(at + PART_HEADER + block_of(hash, blocks) * BLOCK_SIZE))
+                .and_then(|_| file.read_exact(&mut block));
+            found = read.is_err() || has_bits(&block, hash);
+        }
+        at += PART_HEADER + blocks * BLOCK_SIZE;
+        if file.seek(SeekFrom::Start(at)).is_err() {
+            return true;
+        }
+    }
+    found || covered != segment_bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A table directory of its own under the temp dir, each test removes it when it's done
+    fn directory(name: &str) -> String {
+        let directory = format!("{}/db_storage_poc_rust_bloom_{}_{}/", std::env::temp_dir().display(), std::process::id(), name);
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn values(range: std::ops::Range<u64>) -> impl Iterator<Item = Value> {
+        range.map(Value::UInt64)
+    }
+
+    fn false_positives(directory: &str, segment_bytes: u64, range: std::ops::Range<u64>) -> usize {
+        values(range).filter(|value| may_contain(directory, "key", 0, segment_bytes, value)).count()
+    }
+
+    #[test]
+    fn no_false_negatives() {
+        let directory = directory("members");
+        record(&directory, "key", 0, 0, 80_000, values(0..10_000)).unwrap();
+        assert!(values(0..10_000).all(|value| may_contain(&directory, "key", 0, 80_000, &value)));
+        // About 1% of values that aren't there get through
+        assert!(false_positives(&directory, 80_000, 10_000..30_000) < 400);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn parts_add_up() {
+        let directory = directory("parts");
+        record(&directory, "key", 0, 0, 8_000, values(0..1000)).unwrap();
+        record(&directory, "key", 0, 8_000, 16_000, values(1000..2000)).unwrap();
+        assert!(values(0..2000).all(|value| may_contain(&directory, "key", 0, 16_000, &value)));
+        assert!(false_positives(&directory, 16_000, 2000..12_000) < 400);
+        // Rows written past what the parts cover could be anything
+        assert_eq!(false_positives(&directory, 24_000, 2000..2100), 100);
+        // and so could rows that went in before the first one
+        let late = directory.clone() + "late/";
+        record(&late, "key", 0, 8_000, 16_000, values(0..1000)).unwrap();
+        assert_eq!(false_positives(&late, 16_000, 2000..2100), 100);
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn missing_or_empty() {
+        let directory = directory("empty");
+        // No filter at all says nothing
+        assert!(may_contain(&directory, "key", 0, 8, &Value::UInt64(1)));
+        // A part of nothing but nulls has no blocks, and rules everything out
+        record(&directory, "key", 1, 0, 16, [Value::Null, Value::Null].into_iter()).unwrap();
+        assert!(!may_contain(&directory, "key", 1, 16, &Value::UInt64(1)));
+        assert!(may_contain(&directory, "key", 1, 24, &Value::UInt64(1)));
+        let _ = fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,182 @@
+use super::constants::DATA_DIRECTORY;
+use super::dataset::DataType;
+use super::partition::Partitioning;
+
+// The tables generate_data writes, hardcoded until there's a real metadata file format. Anything
+// reading tables back (scans, the query layers) gets column names and types from here.
+
+#[derive(Debug, Clone)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: DataType,
+}
+
+// How rows are laid out on a key column. Without a clustering name the rows are sorted ascending
+// on the key. With one, equal keys only sit next to each other, and every input sharing that
+// clustering name has its keys in the same sequence. The primary of a clustering has each key
+// exactly once, so the other inputs' keys are a subsequence of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyOrder {
+    pub column: String,
+    pub clustering: Option<String>,
+    pub primary: bool,
+}
+
+impl KeyOrder {
+    pub fn sorted(column: String) -> KeyOrder {
+        KeyOrder { column, clustering: None, primary: false }
+    }
+
+    // Whether two inputs can be merged on their keys in a single pass
+    pub fn compatible(&self, other: &KeyOrder) -> bool {
+        self.clustering == other.clustering
+    }
+}
+
+// A key column referring to another table's primary key, that compaction lays the rows out on in
+// the order the parent's compaction left the parent in (see compact.rs). The two can then be
+// merged on the key in one pass.
+#[derive(Debug, Clone)]
+pub struct Clustering {
+    pub column: String,
+    pub parent: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct TableDef {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    // Key columns that get a bloom filter per segment, for scans looking for one value
+    pub key_filters: Vec<String>,
+    // Every write goes in sorted on this column, and compaction sorts the whole table on it unless
+    // the table's clustered
+    pub sort_key: Option<String>,
+    pub clustering: Option<Clustering>,
+    // Every value of this column is in one row only, writes that would break that get turned down
+    pub primary_key: Option<String>,
+    pub partitioning: Option<Partitioning>,
+    // Set when this is just one partition of the table
+    pub partition: Option<u64>,
+}
+
+impl TableDef {
+    pub fn column(&self, name: &str) -> Option<&ColumnDef> {
+        self.columns.iter().find(|col| col.name == name)
+    }
+
+    pub fn directory(&self) -> String {
+        let table = DATA_DIRECTORY.to_owned() + &self.name + "/";
+        match (&self.partitioning, self.partition) {
+            (Some(partitioning), Some(partition)) => format!("{}_partitions/{}/", table, partitioning.name(partition)),
+            _ => table,
+        }
+    }
+
+    // One partition of the table, which reads and writes like a table of its own
+    pub fn partition(&self, partition: u64) -> TableDef {
+        TableDef { partition: Some(partition), ..self.clone() }
+    }
+
+    pub fn column_directory(&self, column_name: &str) -> String {
+        self.directory() + column_name + "/"
+    }
+}
+
+fn table(name: &str, columns: &[(&str, DataType)]) -> TableDef {
+    TableDef {
+        name: name.to_string(),
+        columns: columns
+            .iter()
+            .map(|(col_name, data_type)| ColumnDef { name: col_name.to_string(), data_type: *data_type })
+            .collect(),
+        key_filters: Vec::new(),
+        sort_key: None,
+        clustering: None,
+        primary_key: None,
+        partitioning: None,
+        partition: None,
+    }
+}
+
+fn sorted(table: TableDef, column: &str) -> TableDef {
+    TableDef {
+        sort_key: Some(column.to_string()),
+        ..table
+    }
+}
+
+fn clustered(table: TableDef, column: &str, parent: &str) -> TableDef {
+    TableDef {
+        clustering: Some(Clustering { column: column.to_string(), parent: parent.to_string() }),
+        ..table
+    }
+}
+
+fn keyed(table: TableDef, column: &str) -> TableDef {
+    TableDef {
+        primary_key: Some(column.to_string()),
+        ..table
+    }
+}
+
+fn partitioned(table: TableDef, partitioning: Partitioning) -> TableDef {
+    TableDef {
+        partitioning: Some(partitioning),
+        ..table
+    }
+}
+
+fn key_filtered(table: TableDef, columns: &[&str]) -> TableDef {
+    TableDef {
+        key_filters: columns.iter().map(|col_name| col_name.to_string()).collect(),
+        ..table
+    }
+}
+
+pub fn tables() -> Vec<TableDef> {
+    vec![
+        // address is left out on purpose, the values have a newline in them and string columns are
+        // newline delimited, so there's no way to read them back one per row
+        // Spread over buckets by id, so looking up one customer only goes through one bucket
+        partitioned(keyed(table("customers", &[
+            ("id", DataType::Uuid),
+            ("name", DataType::String),
+            ("email", DataType::String),
+            ("created", DataType::DateTime),
+        ]), "id"), Partitioning::Hash("id".to_string(), 8)),
+        keyed(table("products", &[
+            ("id", DataType::Uuid),
+            ("short_code", DataType::String),
+            ("display_name", DataType::String),
+            ("description", DataType::String),
+            ("price", DataType::Decimal),
+            ("initial_sale_date", DataType::DateTime),
+        ]), "id"),
+        // Orders sorted by when they were placed keeps each segment to a stretch of time, so
+        // zone maps can rule segments out for a date range. Orders also get a partition per month,
+        // so a date range doesn't even look at the others.
+        partitioned(keyed(key_filtered(sorted(table("orders", &[
+            ("id", DataType::Uuid),
+            ("customer_id", DataType::Uuid),
+            ("created", DataType::DateTime),
+            ("tax_percent", DataType::Decimal),
+            ("discount_amount", DataType::Decimal),
+        ]), "created"), &["customer_id"]), "id"), Partitioning::Month("created".to_string())),
+        // order_id is what it's looked up by, but every product in an order has a row, so it's
+        // not a primary key. Writes go in sorted on it, and compaction puts the rows in the order
+        // compacted orders are in instead, so the two join in one merge pass.
+        clustered(key_filtered(sorted(table("order_products", &[
+            ("order_id", DataType::Uuid),
+            ("product_id", DataType::Uuid),
+            ("quantity", DataType::UInt64),
+            ("price_per", DataType::Decimal),
+        ]), "order_id"), &["order_id"]), "order_id", "orders"),
+    ]
+}
+
+pub fn table_def(name: &str) -> Result<TableDef, String> {
+    tables()
+        .into_iter()
+        .find(|table| table.name == name)
+        .ok_or(format!("Table with name {:?} not in catalog", name))
+}
@@ -0,0 +1,224 @@
+use crate::analyze::batch::{Batch, Value};
+use crate::analyze::expr::col;
+use crate::analyze::join::{HashJoin, JoinType};
+use crate::analyze::operator::Operator;
+use crate::analyze::scan::TableScan;
+use crate::analyze::sort::{Sort, SortKey};
+use super::catalog::{table_def, TableDef};
+use super::constants::DATA_DIRECTORY;
+use super::dataset::{Column, Table, TableMetaData};
+use super::index;
+use super::lock;
+use super::partition::{self, Partitioning};
+use std::collections::HashMap;
+use std::fs;
+
+// Compaction rewrites a table sorted all the way through on its sort key, ties going by its
+// primary key so it comes out the same way every time. Writes only sort their own rows, so after
+// a few of them each segment is a handful of sorted runs; this puts them back into one. A
+// clustered table goes in the order of its parent instead: each row gets the parent's sort and
+// primary key next to it through a left join and is sorted on those, rows the parent doesn't have
+// going last. Everything comes out of a Sort (which spills past the memory budget like any other),
+// and once it has read the whole table the old directory moves aside while the rows get written
+// back in order with fresh zone maps and bloom filters, then the indexes get rebuilt and cached
+// results get thrown out. Views only know how many rows of each partition they cover, and take
+// in the ones past that when they refresh. Rows stay in the partition they were in, so a view
+// that covers all of them beforehand still does after, but one that's behind would pick up
+// whichever rows the sort left at the end. They all catch up before anything moves.
+//
+// <table>/_sorted has how many rows the table had when it was compacted, and for a clustered table
+// how many the parent had. While nothing's been written to either since, scans know the table is
+// in sort key order, or in the parent's order. A clustered table with rows the parent doesn't have
+// doesn't get one, those rows would leave a merge join with keys it never saw on the parent side.
+
+// Rows per write while putting the table back
+const WRITE_ROWS: usize = 1_000_000;
+
+fn sorted_path(table: &TableDef) -> String {
+    table.directory() + "_sorted"
+}
+
+fn sorted_marker(table: &TableDef) -> Option<Vec<u64>> {
+    fs::read_to_string(sorted_path(table)).ok()?.split_whitespace().map(|rows| rows.parse::<u64>().ok()).collect()
+}
+
+fn table_rows(table: &TableDef) -> u64 {
+    partition::partition_rows(table).iter().map(|(_, rows)| rows).sum()
+}
+
+// A compacted table nothing's been written to since is sorted on its sort key, as long as its
+// partitions (if any) come in that order too
+pub fn in_sort_order(table: &TableDef) -> bool {
+    let sort_key = match (&table.sort_key, &table.clustering) {
+        (Some(sort_key), None) => sort_key,
+        _ => return false,
+    };
+    sorted_marker(table).as_deref() == Some(&[table_rows(table)])
+        && table.partitioning.as_ref().is_none_or(|partitioning| matches!(partitioning, Partitioning::Month(column) if column == sort_key))
+}
+
+// A clustered table is still in its parent's order while neither of them has been written to
+// since it was compacted
+pub fn follows_parent(table: &TableDef) -> bool {
+    let parent = match table.clustering.as_ref().map(|clustering| table_def(&clustering.parent)) {
+        Some(Ok(parent)) => parent,
+        _ => return false,
+    };
+    match sorted_marker(table).as_deref() {
+        Some([rows, parent_rows]) => *rows == table_rows(table) && in_sort_order(&parent) && *parent_rows == table_rows(&parent),
+        _ => false,
+    }
+}
+
+// One input after the other, for a table that's partly still in the layout from before it was
+// partitioned
+struct Chained {
+    inputs: Vec<Box<dyn Operator>>,
+    current: usize,
+}
+
+impl Operator for Chained {
+    fn next_batch(&mut self) -> Result<Option<Batch>, String> {
+        while let Some(input) = self.inputs.get_mut(self.current) {
+            match input.next_batch()? {
+                Some(batch) => return Ok(Some(batch)),
+                None => self.current += 1,
+            }
+        }
+        Ok(None)
+    }
+
+    fn schema(&self) -> Vec<String> {
+        self.inputs[0].schema()
+    }
+
+    fn name(&self) -> String {
+        "Chained".to_string()
+    }
+
+    fn children(&self) -> Vec<&dyn Operator> {
+        self.inputs.iter().map(|input| input.as_ref()).collect()
+    }
+
+    fn inputs_mut(&mut self) -> Vec<&mut Box<dyn Operator>> {
+        self.inputs.iter_mut().collect()
+    }
+}
+
+// Every row of the table, rows from before it was partitioned first. Those go into partitions on
+// the way back like any other.
+fn whole_table(table: &TableDef) -> Result<Box<dyn Operator>, String> {
+    let names: Vec<String> = table.columns.iter().map(|col| col.name.clone()).collect();
+    let scan = Box::new(TableScan::over(table.clone(), &names, None, Vec::new())?);
+    match partition::unpartitioned(table) {
+        Some(flat) => Ok(Box::new(Chained { inputs: vec![Box::new(TableScan::over(flat, &names, None, Vec::new())?), scan], current: 0 })),
+        None => Ok(scan),
+    }
+}
+
+// The table's rows sorted the way they go back, on the parent's columns for a clustered one, and
+// the parent itself
+fn sorter(table: &TableDef) -> Result<(Sort, Option<TableDef>), String> {
+    let scan = whole_table(table)?;
+    let qualified = |table: &TableDef, column: &str| col(&format!("{}.{}", table.name, column));
+    let clustering = match &table.clustering {
+        Some(clustering) => clustering,
+        // Without a sort key there's only the old layout to move into partitions
+        None if table.sort_key.is_none() && partition::unpartitioned(table).is_some() => return Ok((Sort::new(scan, Vec::new()), None)),
+        None => {
+            let sort_key = table.sort_key.as_ref().ok_or(format!("Table {:?} doesn't have a sort key to compact on", table.name))?;
+            let keys = [Some(sort_key), table.primary_key.as_ref()].into_iter().flatten()
+                .map(|column| SortKey::new(qualified(table, column), false))
+                .collect();
+            return Ok((Sort::new(scan, keys), None));
+        },
+    };
+    let parent = table_def(&clustering.parent)?;
+    let (sort_key, primary_key) = match (&parent.sort_key, &parent.primary_key) {
+        (Some(sort_key), Some(primary_key)) => (sort_key.clone(), primary_key.clone()),
+        _ => return Err(format!("Table {:?} is clustered on {:?}, which needs a sort key and a primary key", table.name, parent.name)),
+    };
+    if !in_sort_order(&parent) {
+        return Err(format!("Compact {} first, {} gets laid out in the order it's in", parent.name, table.name));
+    }
+    let parent_scan = TableScan::new(&parent.name, &[primary_key.clone(), sort_key.clone()])?;
+    let join = HashJoin::new(scan, Box::new(parent_scan), qualified(table, &clustering.column), qualified(&parent, &primary_key), JoinType::Left);
+    let keys = vec![SortKey::new(qualified(&parent, &sort_key), false), SortKey::new(qualified(&parent, &primary_key), false)];
+    Ok((Sort::new(Box::new(join), keys), Some(parent)))
+}
+
+pub fn compact(table_name: &str) -> Result<u64, String> {
+    let table = table_def(table_name)?;
+    // Writes wait until the table is back in place
+    let _lock = lock::lock(table_name)?;
+    let (mut sort, parent) = sorter(&table)?;
+    crate::analyze::view::refresh_table(table_name)?;
+    // The first batch only comes out once the whole table has been read, after that the files
+    // can go
+    let first = sort.next_batch()?;
+    let directory = DATA_DIRECTORY.to_owned() + table_name;
+    let backup = directory.clone() + ".compacting";
+    let indexed = index::indexed_columns(&table);
+    fs::rename(&directory, &backup).map_err(|err| format!("Can't move {} aside: {}", directory, err))?;
+    match rewrite(&table, first, &mut sort, &indexed, parent.as_ref()) {
+        Ok(rows) => {
+            let _ = fs::remove_dir_all(&backup);
+            // Nothing to take in when the counts came out the same, like they should
+            crate::analyze::view::refresh_table(table_name)?;
+            crate::analyze::cache::invalidate_table(table_name);
+            Ok(rows)
+        },
+        Err(err) => {
+            let _ = fs::remove_dir_all(&directory);
+            let _ = fs::rename(&backup, &directory);
+            Err(err)
+        },
+    }
+}
+
+// Clustered rows come with the parent's columns after the table's own, which only get looked at
+// for rows that have a key the parent doesn't
+fn rewrite(table: &TableDef, first: Option<Batch>, sort: &mut Sort, indexed: &[String], parent: Option<&TableDef>) -> Result<u64, String> {
+    let mut pending: Vec<Vec<Value>> = vec![Vec::new(); table.columns.len()];
+    let mut rows: u64 = 0;
+    let mut strays: u64 = 0;
+    let key = table.clustering.as_ref().and_then(|clustering| table.columns.iter().position(|col| col.name == clustering.column));
+    let mut batch = first;
+    loop {
+        let done = batch.is_none();
+        if let Some(batch) = batch {
+            if let (Some(key), Some(parent_keys)) = (key, batch.columns.get(table.columns.len())) {
+                strays += batch.columns[key].iter().zip(parent_keys).filter(|(key, parent_key)| !key.is_null() && parent_key.is_null()).count() as u64;
+            }
+            for (column, values) in pending.iter_mut().zip(batch.columns) {
+                column.extend(values);
+            }
+        }
+        if pending[0].len() >= WRITE_ROWS || (done && !pending[0].is_empty()) {
+            rows += pending[0].len() as u64;
+            let mut data: HashMap<String, Column> = HashMap::new();
+            for (def, values) in table.columns.iter().zip(pending.iter_mut()) {
+                data.insert(def.name.clone(), Column::from_values(def.data_type, values)?);
+                values.clear();
+            }
+            let meta = TableMetaData { table_name: table.name.clone(), columns: table.columns.len() as u16, rows: 0 };
+            Table::new(meta, data)?.write_columns_in_order()?;
+        }
+        if done {
+            break;
+        }
+        batch = sort.next_batch()?;
+    }
+    for column in indexed {
+        index::update(&table.name, column)?;
+    }
+    let marker = match (parent, &table.sort_key) {
+        (Some(_), _) if strays > 0 => return Ok(rows),
+        (Some(parent), _) => format!("{} {}", rows, table_rows(parent)),
+        (None, Some(_)) => rows.to_string(),
+        (None, None) => return Ok(rows),
+    };
+    fs::create_dir_all(table.directory()).map_err(|err| format!("Can't create {}: {}", table.directory(), err))?;
+    fs::write(sorted_path(table), marker).map_err(|err| format!("Can't write {}: {}", sorted_path(table), err))?;
+    Ok(rows)
+}
@@ -1,8 +1,14 @@
+use crate::analyze::batch::Value;
 use super::constants::{DATA_DIRECTORY,FILE_SIZE};
+use super::stats;
+use super::index;
+use super::bloom;
+use super::lock;
+use super::catalog::{table_def, TableDef};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
 use rust_decimal::Decimal;
-use std::{collections::HashMap, fs::File};
+use std::{collections::{BTreeMap, HashMap}, fs::File};
 use std::fs;
 use std::fs::{DirEntry,OpenOptions};
 use std::io::Write;
@@ -29,6 +35,39 @@ pub struct TableMetaData {
 //    ForeignKey(&'a [Uuid]),
 //}
 
+// The type of a column without its data, the catalog describes tables with these. Bool only ever
+// shows up in query results (filters and comparisons), nothing gets stored as one yet. ForeignKey
+// columns are stored exactly like Uuid ones so they read back as Uuid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DataType {
+    Bool,
+    String,
+    Int64,
+    Int8,
+    UInt64,
+    UInt8,
+    DateTime,
+    Decimal,
+    Uuid,
+}
+
+impl DataType {
+    // Bytes per value on disk, None for the variable width ones
+    pub fn stored_size(&self) -> Option<usize> {
+        match self {
+            DataType::Bool => None,
+            DataType::String => None,
+            DataType::Int64 => Some(mem::size_of::<i64>()),
+            DataType::Int8 => Some(mem::size_of::<i8>()),
+            DataType::UInt64 => Some(mem::size_of::<u64>()),
+            DataType::UInt8 => Some(mem::size_of::<u8>()),
+            DataType::DateTime => Some(mem::size_of::<i64>()),
+            DataType::Decimal => Some(mem::size_of::<Decimal>()),
+            DataType::Uuid => Some(mem::size_of::<Uuid>()),
+        }
+    }
+}
+
 pub enum Column {
     String(Vec<String>),
     Int64(Vec<i64>),
@@ -56,9 +95,91 @@ impl Column {
         }
     }
 
-    pub fn write_data(&self, directory: &String, column_name: &String) -> Result<u64,String>
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Column::String(_) => DataType::String,
+            Column::Int64(_) => DataType::Int64,
+            Column::Int8(_) => DataType::Int8,
+            Column::UInt64(_) => DataType::UInt64,
+            Column::UInt8(_) => DataType::UInt8,
+            Column::DateTime(_) => DataType::DateTime,
+            Column::Decimal(_) => DataType::Decimal,
+            Column::Uuid(_) | Column::ForeignKey(_) => DataType::Uuid,
+        }
+    }
+
+    // The rows in the given order
+    pub fn reorder(&self, order: &[usize]) -> Column {
+        match self {
+            Column::String(val) => Column::String(order.iter().map(|row| val[*row].clone()).collect()),
+            Column::Int64(val) => Column::Int64(order.iter().map(|row| val[*row]).collect()),
+            Column::Int8(val) => Column::Int8(order.iter().map(|row| val[*row]).collect()),
+            Column::UInt64(val) => Column::UInt64(order.iter().map(|row| val[*row]).collect()),
+            Column::UInt8(val) => Column::UInt8(order.iter().map(|row| val[*row]).collect()),
+            Column::DateTime(val) => Column::DateTime(order.iter().map(|row| val[*row]).collect()),
+            Column::Decimal(val) => Column::Decimal(order.iter().map(|row| val[*row]).collect()),
+            Column::Uuid(val) => Column::Uuid(order.iter().map(|row| val[*row]).collect()),
+            Column::ForeignKey(val) => Column::ForeignKey(order.iter().map(|row| val[*row]).collect()),
+        }
+    }
+
+    // The other way from value(), for writing rows that came out of a query back to disk
+    pub fn from_values(data_type: DataType, values: &[Value]) -> Result<Column, String> {
+        let wrong = |value: &Value| format!("Can't store {} as {:?}", value, data_type);
+        Ok(match data_type {
+            DataType::String => Column::String(values.iter().map(|value| match value {
+                Value::String(val) => Ok(val.clone()),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::Int64 => Column::Int64(values.iter().map(|value| match value {
+                Value::Int64(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::Int8 => Column::Int8(values.iter().map(|value| match value {
+                Value::Int8(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::UInt64 => Column::UInt64(values.iter().map(|value| match value {
+                Value::UInt64(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::UInt8 => Column::UInt8(values.iter().map(|value| match value {
+                Value::UInt8(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::DateTime => Column::DateTime(values.iter().map(|value| match value {
+                Value::DateTime(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::Decimal => Column::Decimal(values.iter().map(|value| match value {
+                Value::Decimal(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::Uuid => Column::Uuid(values.iter().map(|value| match value {
+                Value::Uuid(val) => Ok(*val),
+                other => Err(wrong(other)),
+            }).collect::<Result<_, _>>()?),
+            DataType::Bool => return Err("Bool columns can't be stored".to_string()),
+        })
+    }
+
+    // A single row as a query side Value, the same thing reading it back off disk would give
+    pub fn value(&self, row: usize) -> Value {
+        match self {
+            Column::String(val) => Value::String(val[row].clone()),
+            Column::Int64(val) => Value::Int64(val[row]),
+            Column::Int8(val) => Value::Int8(val[row]),
+            Column::UInt64(val) => Value::UInt64(val[row]),
+            Column::UInt8(val) => Value::UInt8(val[row]),
+            Column::DateTime(val) => Value::DateTime(val[row]),
+            Column::Decimal(val) => Value::Decimal(val[row]),
+            Column::Uuid(val) | Column::ForeignKey(val) => Value::Uuid(val[row]),
+        }
+    }
+
+    pub fn write_data(&self, directory: &String, column_name: &String) -> Result<Vec<(u64, Range<usize>)>,String>
     {
-        // Write data, return the latest filename to be using
+        // Write data, return which rows went into which numbered file
         let each_size = match self {
             Column::String(_) => mem::size_of::<u8>()*20,
             Column::Int64(_) => mem::size_of::<i64>(),
@@ -72,6 +193,7 @@ impl Column {
             Column::ForeignKey(_) => mem::size_of::<Uuid>(),
         };
         let mut records_written:usize = 0;
+        let mut written: Vec<(u64, Range<usize>)> = Vec::new();
 
         // First, see what files are in there for the table/column
         // Do this on a per COLUMN basis, not table
@@ -121,6 +243,7 @@ impl Column {
                 highest += 1;
                 continue;
             }
+            let records_before = records_written;
  
             match self {
                 Column::String(val) => {
@@ -228,44 +351,142 @@ impl Column {
                     records_written = limit;
                 },
             };
+            written.push((highest, records_before..records_written));
         }
-        Ok(highest)
+        Ok(written)
     }
 }
 
 
 pub struct Table {
     pub meta: TableMetaData,
-    pub id_column: String,
     pub data: HashMap<String, Column>,
 }
 
 impl Table {
-    pub fn new(id_column: String, meta: TableMetaData, data: HashMap<String, Column>) -> Result<Table,String> {
-        if !data.contains_key(&id_column) {
-            let error = Err(format!("Column with name {:?} not in data", id_column));
-            return error;
+    pub fn new(meta: TableMetaData, data: HashMap<String, Column>) -> Result<Table,String> {
+        let mut lengths = data.iter().map(|(name, column)| (name, column.len()));
+        if let Some((first, rows)) = lengths.next() {
+            if let Some((name, other)) = lengths.find(|(_, other)| *other != rows) {
+                return Err(format!("Columns {:?} and {:?} of {:?} have {} and {} rows", first, name, meta.table_name, rows, other));
+            }
         }
-        let ret_val: Table = Table{
-            id_column: id_column,
-            meta: meta,
-            data: data,
-        };
-        
-        Ok(ret_val)
+        Ok(Table { meta, data })
     }
 
     pub fn write_data(&self) -> Result<String, String> {
+        let primary_key = table_def(&self.meta.table_name).ok()
+            .and_then(|table| table.primary_key)
+            .and_then(|column| self.data.get(&column).map(|data| (column, data)));
+        // Held until everything below is done, so no other write or compaction gets between the
+        // key check and the index taking in the new keys
+        let _lock = lock::lock(&self.meta.table_name)?;
+        // Nothing gets written if any of the keys is already taken
+        if let Some((column, data)) = &primary_key {
+            index::check_unique(&self.meta.table_name, column, (0..data.len()).map(|row| data.value(row)).collect())?;
+        }
+        self.write_columns()?;
+        // The primary key index takes in the new rows, tables without one don't get an index
+        if let Some((column, _)) = &primary_key {
+            index::update(&self.meta.table_name, column)?;
+        }
+        // Materialized views over the table pick up the new rows now rather than on their next query
+        crate::analyze::view::refresh_table(&self.meta.table_name)?;
+        // and cached results that read the table are out of date
+        crate::analyze::cache::invalidate_table(&self.meta.table_name);
+        Ok("".to_string())
+    }
+
+    // Just the segment files and what's kept per segment (zone maps, bloom filters)
+    pub fn write_columns(&self) -> Result<(), String> {
+        self.write_columns_sorted(true)
+    }
+
+    // Compaction rewrites a table with this, the rows already come in the order they go in (which
+    // isn't sort key order for a clustered table), and sorts out the rest itself
+    pub fn write_columns_in_order(&self) -> Result<(), String> {
+        self.write_columns_sorted(false)
+    }
+
+    fn write_columns_sorted(&self, sort: bool) -> Result<(), String> {
+        let table = table_def(&self.meta.table_name).ok();
+        let key_filters = table.as_ref().map(|table| table.key_filters.clone()).unwrap_or_default();
+        // Tables with a sort key get each write's rows in that order
+        let order = match sort {
+            true => self.sort_order(table.as_ref()),
+            false => None,
+        };
+        let partitioning = table.as_ref().and_then(|table| table.partitioning.clone());
+        // Rows with nothing to say which partition they go in can't be put anywhere scans look
+        if let Some(column) = partitioning.as_ref().map(|partitioning| partitioning.column()).filter(|column| !self.data.contains_key(*column)) {
+            return Err(format!("Table {:?} is partitioned on {:?}, which isn't in the data being written", self.meta.table_name, column));
+        }
+        match (table, partitioning) {
+            (Some(table), Some(partitioning)) => {
+                // Every row goes to its partition, still in sort key order there
+                let rows: Vec<usize> = order.unwrap_or_else(|| (0..self.rows()).collect());
+                let key = &self.data[partitioning.column()];
+                let mut routed: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+                for row in rows {
+                    routed.entry(partitioning.partition_of(&key.value(row))).or_default().push(row);
+                }
+                for (partition, rows) in routed {
+                    self.write_rows(&table.partition(partition).directory(), Some(&rows), &key_filters)?;
+                }
+                Ok(())
+            },
+            _ => self.write_rows(&(DATA_DIRECTORY.to_owned() + &self.meta.table_name + "/"), order.as_ref(), &key_filters),
+        }
+    }
+
+    fn rows(&self) -> usize {
+        self.data.values().next().map(|data| data.len()).unwrap_or(0)
+    }
+
+    // Writes the given rows (all of them in order for None) into the table directory
+    fn write_rows(&self, table_dir: &str, order: Option<&Vec<usize>>, key_filters: &[String]) -> Result<(), String> {
         let mut column_latest_files:HashMap<String, u64> = HashMap::new();
-        let table_dir = DATA_DIRECTORY.to_owned()+&self.meta.table_name;
-        fs::create_dir_all(&table_dir).unwrap();
+        fs::create_dir_all(table_dir).unwrap();
         for (col_name, data) in &self.data {
-            let col_dir = format!("{}",table_dir.to_owned()+"/"+&col_name+"/");
+            let reordered: Column;
+            let data = match order {
+                Some(order) => {
+                    reordered = data.reorder(order);
+                    &reordered
+                },
+                None => data,
+            };
+            let col_dir = table_dir.to_owned() + col_name + "/";
             fs::create_dir_all(&col_dir).unwrap();
-            let highest_filenum = data.write_data(&col_dir, &col_name).unwrap();
-            column_latest_files.insert(col_name.to_string(), highest_filenum);
+            let written = data.write_data(&col_dir, col_name).unwrap();
+            // Keep the zone maps up with whatever just landed in each file
+            let mut segments = stats::load(table_dir, col_name, data.data_type())?;
+            // Bloom filters need to know where the rows start in the file, so only fixed width
+            // columns get them
+            let filtered = data.data_type().stored_size().filter(|_| key_filters.contains(col_name));
+            for (file_num, rows) in &written {
+                let path = col_dir.to_owned() + &format!("{}_{:020}", col_name, file_num);
+                let bytes = fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+                if let Some(size) = filtered {
+                    let before = bytes.saturating_sub((rows.len() * size) as u64);
+                    bloom::record(table_dir, col_name, *file_num, before, bytes, rows.clone().map(|row| data.value(row)))?;
+                }
+                stats::record(&mut segments, *file_num, bytes, rows.clone().map(|row| data.value(row)));
+            }
+            stats::save(table_dir, col_name, &segments)?;
+            if let Some((highest_filenum, _)) = written.last() {
+                column_latest_files.insert(col_name.to_string(), *highest_filenum);
+            }
         }
-        Ok("".to_string())
+        Ok(())
+    }
+
+    // Rows in sort key order, None when the table doesn't have one or it's not in the data
+    fn sort_order(&self, table: Option<&TableDef>) -> Option<Vec<usize>> {
+        let key = self.data.get(table?.sort_key.as_ref()?)?;
+        let mut order: Vec<usize> = (0..key.len()).collect();
+        order.sort_by_cached_key(|row| key.value(*row));
+        Some(order)
     }
 }
 
@@ -278,3 +499,20 @@ impl Table {
 
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partitioned_write_needs_the_partition_column() {
+        let meta = TableMetaData { table_name: "orders".to_string(), columns: 1, rows: 1 };
+        let data = HashMap::from([("id".to_string(), Column::Uuid(vec![Uuid::new_v4()]))]);
+        let table = Table::new(meta, data).unwrap();
+        // Turned down before anything's written, not sent to the table directory where scans of a
+        // partitioned table never look
+        let error = table.write_columns().unwrap_err();
+        assert!(error.contains("partitioned on \"created\""), "{}", error);
+        assert!(fs::metadata(DATA_DIRECTORY.to_owned() + "orders/id").is_err());
+    }
+}
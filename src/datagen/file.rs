@@ -0,0 +1,70 @@
+use crate::analyze::batch::Value;
+use super::dataset::DataType;
+use chrono::{TimeZone, Utc};
+use rust_decimal::Decimal;
+use std::fs;
+use std::mem;
+use uuid::Builder;
+
+// Helpers for turning raw column file bytes back into values, the inverse of Column::write_data
+
+pub fn segment_file_name(column_name: &str, file_num: u64) -> String {
+    format!("{}_{:020}", column_name, file_num)
+}
+
+// Every segment file for a column in order, stops at the first gap just like the writer numbers them
+pub fn segment_paths(column_dir: &str, column_name: &str) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    let mut file_num: u64 = 0;
+    loop {
+        let path = column_dir.to_owned() + &segment_file_name(column_name, file_num);
+        if fs::metadata(&path).map(|meta| meta.is_file()).unwrap_or(false) {
+            paths.push(path);
+            file_num += 1;
+        } else {
+            break;
+        }
+    }
+    paths
+}
+
+pub fn decode_u64(buffer: &[u8]) -> Vec<u64> {
+    buffer
+        .chunks_exact(mem::size_of::<u64>())
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .collect()
+}
+
+// Decode a single fixed width value, bytes has to be exactly stored_size() long
+pub fn decode_value(bytes: &[u8], data_type: DataType) -> Value {
+    match data_type {
+        DataType::Int64 => Value::Int64(i64::from_le_bytes(bytes.try_into().unwrap())),
+        DataType::Int8 => Value::Int8(i8::from_le_bytes(bytes.try_into().unwrap())),
+        DataType::UInt64 => Value::UInt64(u64::from_le_bytes(bytes.try_into().unwrap())),
+        DataType::UInt8 => Value::UInt8(u8::from_le_bytes(bytes.try_into().unwrap())),
+        DataType::DateTime => {
+            let millis = i64::from_le_bytes(bytes.try_into().unwrap());
+            match Utc.timestamp_millis_opt(millis).single() {
+                Some(datetime) => Value::DateTime(datetime),
+                None => Value::Null,
+            }
+        },
+        DataType::Decimal => Value::Decimal(Decimal::deserialize(bytes.try_into().unwrap())),
+        DataType::Uuid => Value::Uuid(Builder::from_bytes(bytes.try_into().unwrap()).into_uuid()),
+        DataType::String | DataType::Bool => panic!("{:?} is not a fixed width type", data_type),
+    }
+}
+
+// The bytes a fixed width value is stored as, None for nulls and values of some other type
+pub fn encode_value(value: &Value, data_type: DataType) -> Option<Vec<u8>> {
+    match (value, data_type) {
+        (Value::Int64(val), DataType::Int64) => Some(val.to_le_bytes().to_vec()),
+        (Value::Int8(val), DataType::Int8) => Some(val.to_le_bytes().to_vec()),
+        (Value::UInt64(val), DataType::UInt64) => Some(val.to_le_bytes().to_vec()),
+        (Value::UInt8(val), DataType::UInt8) => Some(val.to_le_bytes().to_vec()),
+        (Value::DateTime(val), DataType::DateTime) => Some(val.timestamp_millis().to_le_bytes().to_vec()),
+        (Value::Decimal(val), DataType::Decimal) => Some(val.serialize().to_vec()),
+        (Value::Uuid(val), DataType::Uuid) => Some(val.as_bytes().to_vec()),
+        _ => None,
+    }
+}
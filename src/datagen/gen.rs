@@ -7,7 +7,7 @@ use fake::faker::lorem::raw::Word;
 use fake::faker::address::raw::{CityName, ZipCode, StateAbbr, StreetSuffix};
 use fake::faker::name::raw::{FirstName, LastName};
 use fake::faker::internet::raw::FreeEmailProvider;
-use fake::faker::company::raw::{Buzzword, CatchPhase};
+use fake::faker::company::raw::{Buzzword, CatchPhrase};
 use fake::Fake;
 use rand::rngs::SmallRng;
 use rand::{Rng, SeedableRng};
@@ -90,7 +90,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
                 data.insert("email".to_string(), Column::String(email_col));
                 data.insert("address".to_string(), Column::String(address_col));
                 data.insert("created".to_string(), Column::DateTime(created_col));
-                let customers_table: Table = Table::new(id_column.clone(), meta, data).unwrap();
+                let customers_table: Table = Table::new(meta, data).unwrap();
                 let _ = customers_table.write_data().unwrap();
                 pk_col = Vec::new();
                 email_col = Vec::new();
@@ -139,7 +139,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
         data.insert("email".to_string(), Column::String(email_col));
         data.insert("address".to_string(), Column::String(address_col));
         data.insert("created".to_string(), Column::DateTime(created_col));
-        let customers_table: Table = Table::new(id_column, meta, data).unwrap();
+        let customers_table: Table = Table::new(meta, data).unwrap();
         let _ = customers_table.write_data().unwrap();
     }
     println!("Customer IDs: {:?}", customer_ids.len());
@@ -168,7 +168,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
                 data.insert("description".to_string(), Column::String(description_col));
                 data.insert("price".to_string(), Column::Decimal(price_col));
                 data.insert("initial_sale_date".to_string(), Column::DateTime(initial_sale_date_col));
-                let products_table: Table = Table::new(id_column.clone(), meta, data).unwrap();
+                let products_table: Table = Table::new(meta, data).unwrap();
                 let _ = products_table.write_data().unwrap();
                 pk_col = Vec::new();
                 short_code_col = Vec::new();
@@ -186,7 +186,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
             });                                                                                                  
             initial_sale_date_col.push(DateTimeBetween(Utc::now() - Duration::weeks(52), Utc::now() - Duration::weeks(30)).fake());
             display_name_col.push(Buzzword(EN).fake());
-            description_col.push( CatchPhase(EN).fake());
+            description_col.push( CatchPhrase(EN).fake());
             price_col.push({
                 let n8: i64 = rng.gen_range(1..99);
                 Decimal::new((n8 * 100) + 99, 2)
@@ -205,7 +205,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
         data.insert("description".to_string(), Column::String(description_col));
         data.insert("price".to_string(), Column::Decimal(price_col));
         data.insert("initial_sale_date".to_string(), Column::DateTime(initial_sale_date_col));
-        let products_table: Table = Table::new(id_column, meta, data).unwrap();
+        let products_table: Table = Table::new(meta, data).unwrap();
         let _ = products_table.write_data().unwrap();
     }
     println!("Product IDs: {:?}", product_ids.len());
@@ -240,7 +240,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
                 data.insert("created".to_string(), Column::DateTime(created_col));
                 data.insert("tax_percent".to_string(), Column::Decimal(tax_percent_col));
                 data.insert("discount_amount".to_string(), Column::Decimal(discount_amount_col));
-                let customers_table: Table = Table::new(id_column.clone(), meta, data).unwrap();
+                let customers_table: Table = Table::new(meta, data).unwrap();
                 let _ = customers_table.write_data().unwrap();
                 pk_col = Vec::new();
                 customer_id_col = Vec::new();
@@ -257,7 +257,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
                 data_mapper.insert("product_id".to_string(), Column::Uuid(product_id_col));
                 data_mapper.insert("quantity".to_string(), Column::UInt64(quantity_col));
                 data_mapper.insert("price_per".to_string(), Column::Decimal(price_per_col));
-                let order_products_table: Table = Table::new("order_id".to_string(), meta_mapper, data_mapper).unwrap();
+                let order_products_table: Table = Table::new(meta_mapper, data_mapper).unwrap();
                 let _ = order_products_table.write_data().unwrap();
                 order_id_col = Vec::new();
                 product_id_col = Vec::new();
@@ -295,7 +295,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
         data.insert("created".to_string(), Column::DateTime(created_col));
         data.insert("tax_percent".to_string(), Column::Decimal(tax_percent_col));
         data.insert("discount_amount".to_string(), Column::Decimal(discount_amount_col));
-        let customers_table: Table = Table::new(id_column, meta, data).unwrap();
+        let customers_table: Table = Table::new(meta, data).unwrap();
         let _ = customers_table.write_data().unwrap();
 
         let meta_mapper: TableMetaData = TableMetaData{
@@ -308,7 +308,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
         data_mapper.insert("product_id".to_string(), Column::Uuid(product_id_col));
         data_mapper.insert("quantity".to_string(), Column::UInt64(quantity_col));
         data_mapper.insert("price_per".to_string(), Column::Decimal(price_per_col));
-        let order_products_table: Table = Table::new("order_id".to_string(), meta_mapper, data_mapper).unwrap();
+        let order_products_table: Table = Table::new(meta_mapper, data_mapper).unwrap();
         let _ = order_products_table.write_data().unwrap(); 
     }
     // println!("Order IDs: {:?}", order_ids.len());
@@ -328,7 +328,7 @@ pub fn generate_data(customer_count: u64, product_count: u64, order_count: u64,
     //let mut data: HashMap<String, Column> = HashMap::new();
     //data.insert(id_column.clone(), Column::Uuid(pk_col));
     //data.insert("names".to_string(), Column::String(names_col));
-    //let table: Table = Table::new(id_column, meta, data).unwrap();
+    //let table: Table = Table::new(meta, data).unwrap();
     //table.insert_data();
 }
 
@@ -0,0 +1,704 @@
+use crate::analyze::batch::Value;
+use crate::analyze::expr::{cast, CompareOp, Expr};
+use crate::analyze::operator::Operator;
+use crate::analyze::planner::split_conjuncts;
+use crate::analyze::scan::{flip, TableScan};
+use super::catalog::{table_def, TableDef};
+use super::dataset::DataType;
+use super::file::{decode_value, encode_value};
+use super::partition::{number, partition_rows, PARTITION_SHIFT};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+
+// Primary key indexes: a B+tree per table in <table>/_index/<column>, from each value of the
+// table's primary key (see the catalog) to the row it's at. Table::write_data adds the rows it
+// appends, and an index that's behind the table (made before it existed, say) catches up with
+// the rows past the ones it covers before it gets used. Lookups and range scans only read the
+// pages on the way down to the first leaf they need and then along the leaves, a handful of
+// 4 KiB reads for one key.
+// Writes to a table with a declared primary key look their keys up in it first (see check_unique).
+//
+// Entries are the key and the row together, so a key that's in more than one row is just more
+// entries next to each other. Rows of a partitioned table are the partition number and the row
+// within it packed together (see partition.rs), since a write to one partition moves the rows of
+// every one after it. Pages:
+//   0: header, the root page, how many pages there are, then how many rows of each partition are
+//      in as a count and (partition, rows) pairs
+//   leaf: 1, entry count (u16), the next leaf (0 for none), then the entries in order
+//   inner: 2, separator count (u16), the first child, then each separator entry and the child
+//          holding everything from it up to the next one
+
+pub const PAGE_SIZE: usize = 4096;
+
+const LEAF: u8 = 1;
+const INNER: u8 = 2;
+// Kind, count and the next leaf or first child
+const PAGE_HEADER: usize = 11;
+// 64 MiB of pages
+const CACHED_PAGES: usize = 16384;
+
+pub fn index_directory(table: &TableDef) -> String {
+    table.directory() + "_index/"
+}
+
+fn index_path(table: &TableDef, column: &str) -> String {
+    index_directory(table) + column
+}
+
+// Whatever a page split sends up to its parent, the first entry of the new page and where it is
+type Split = Option<((Value, u64), u64)>;
+
+pub struct BTree {
+    path: String,
+    data_type: DataType,
+    key_size: usize,
+    root: u64,
+    pages: u64,
+    // Rows of each partition in the index so far
+    pub covered: BTreeMap<u64, u64>,
+    file: File,
+    // Pages read so far, and the ones changed since the last flush
+    cache: HashMap<u64, Vec<u8>>,
+    dirty: HashSet<u64>,
+}
+
+fn read_u64(page: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(page[at..at + 8].try_into().unwrap())
+}
+
+fn write_u64(page: &mut [u8], at: usize, val: u64) {
+    page[at..at + 8].copy_from_slice(&val.to_le_bytes());
+}
+
+fn count(page: &[u8]) -> usize {
+    u16::from_le_bytes([page[1], page[2]]) as usize
+}
+
+fn set_count(page: &mut [u8], count: usize) {
+    page[1..3].copy_from_slice(&(count as u16).to_le_bytes());
+}
+
+fn compare(left: &(Value, u64), right: &(Value, u64)) -> Ordering {
+    left.0.cmp(&right.0).then(left.1.cmp(&right.1))
+}
+
+impl BTree {
+    // The index on a column if there's one
+    pub fn open(table: &TableDef, column: &str) -> Result<Option<BTree>, String> {
+        let path = index_path(table, column);
+        let mut file = match File::options().read(true).write(true).open(&path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let key_size = BTree::key_size(table, column)?;
+        let mut header = vec![0; PAGE_SIZE];
+        file.read_exact(&mut header).map_err(|err| format!("Can't read index {}: {}", path, err))?;
+        let data_type = table.column(column).map(|col| col.data_type).unwrap();
+        let covered = (0..read_u64(&header, 16) as usize)
+            .map(|index| (read_u64(&header, 24 + index * 16), read_u64(&header, 32 + index * 16)))
+            .collect();
+        Ok(Some(BTree {
+            root: read_u64(&header, 0),
+            pages: read_u64(&header, 8),
+            covered,
+            path,
+            data_type,
+            key_size,
+            file,
+            cache: HashMap::new(),
+            dirty: HashSet::new(),
+        }))
+    }
+
+    // An empty index, replacing whatever was there
+    pub fn create(table: &TableDef, column: &str) -> Result<BTree, String> {
+        let key_size = BTree::key_size(table, column)?;
+        fs::create_dir_all(index_directory(table)).map_err(|err| format!("Can't create index directory: {}", err))?;
+        let path = index_path(table, column);
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&path)
+            .map_err(|err| format!("Can't create index {}: {}", path, err))?;
+        let data_type = table.column(column).map(|col| col.data_type).unwrap();
+        let mut tree = BTree { path, data_type, key_size, root: 1, pages: 2, covered: BTreeMap::new(), file, cache: HashMap::new(), dirty: HashSet::new() };
+        let mut leaf = vec![0; PAGE_SIZE];
+        leaf[0] = LEAF;
+        tree.cache.insert(1, leaf);
+        tree.dirty.insert(1);
+        tree.flush()?;
+        Ok(tree)
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.covered.values().sum()
+    }
+
+    fn key_size(table: &TableDef, column: &str) -> Result<usize, String> {
+        let column = table.column(column).ok_or(format!("Column with name {:?} not in table {:?}", column, table.name))?;
+        column.data_type.stored_size().ok_or(format!("Can't index {}, only fixed width columns can be", column.name))
+    }
+
+    fn entry_size(&self) -> usize {
+        self.key_size + 8
+    }
+
+    fn leaf_capacity(&self) -> usize {
+        (PAGE_SIZE - PAGE_HEADER) / self.entry_size()
+    }
+
+    fn inner_capacity(&self) -> usize {
+        (PAGE_SIZE - PAGE_HEADER) / (self.entry_size() + 8)
+    }
+
+    fn page(&mut self, page: u64) -> Result<&mut Vec<u8>, String> {
+        if !self.cache.contains_key(&page) {
+            let mut bytes = vec![0; PAGE_SIZE];
+            self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))
+                .and_then(|_| self.file.read_exact(&mut bytes))
+                .map_err(|err| format!("Can't read page {} of index {}: {}", page, self.path, err))?;
+            self.cache.insert(page, bytes);
+        }
+        Ok(self.cache.get_mut(&page).unwrap())
+    }
+
+    fn new_page(&mut self, kind: u8) -> u64 {
+        let page = self.pages;
+        self.pages += 1;
+        let mut bytes = vec![0; PAGE_SIZE];
+        bytes[0] = kind;
+        self.cache.insert(page, bytes);
+        self.dirty.insert(page);
+        page
+    }
+
+    // Where the nth entry starts, leaves have entries back to back and inner pages a child after each
+    fn entry_at(&self, kind: u8, index: usize) -> usize {
+        match kind {
+            LEAF => PAGE_HEADER + index * self.entry_size(),
+            _ => PAGE_HEADER + index * (self.entry_size() + 8),
+        }
+    }
+
+    fn entry(&self, page: &[u8], at: usize) -> (Value, u64) {
+        (decode_value(&page[at..at + self.key_size], self.data_type), read_u64(page, at + self.key_size))
+    }
+
+    // How many of a page's entries (or separators) sort before or equal to the given one
+    fn position(&self, page: &[u8], entry: &(Value, u64)) -> usize {
+        let (mut low, mut high) = (0, count(page));
+        while low < high {
+            let middle = (low + high) / 2;
+            match compare(&self.entry(page, self.entry_at(page[0], middle)), entry) {
+                Ordering::Greater => high = middle,
+                _ => low = middle + 1,
+            }
+        }
+        low
+    }
+
+    fn child(&self, page: &[u8], index: usize) -> u64 {
+        match index {
+            0 => read_u64(page, 3),
+            _ => read_u64(page, self.entry_at(INNER, index - 1) + self.entry_size()),
+        }
+    }
+
+    pub fn insert(&mut self, key: &Value, row: u64) -> Result<(), String> {
+        let entry = (key.clone(), row);
+        let mut bytes = encode_value(key, self.data_type).ok_or(format!("Can't put {} in index {}", key, self.path))?;
+        bytes.extend(row.to_le_bytes());
+        if let Some((separator, page)) = self.insert_into(self.root, &entry, &bytes)? {
+            // The root split, a new one goes above it
+            let root = self.new_page(INNER);
+            let mut separator_bytes = encode_value(&separator.0, self.data_type).unwrap();
+            separator_bytes.extend(separator.1.to_le_bytes());
+            let old_root = self.root;
+            let size = self.entry_size();
+            let bytes = self.page(root)?;
+            write_u64(bytes, 3, old_root);
+            bytes[PAGE_HEADER..PAGE_HEADER + size].copy_from_slice(&separator_bytes);
+            write_u64(bytes, PAGE_HEADER + size, page);
+            set_count(bytes, 1);
+            self.root = root;
+        }
+        Ok(())
+    }
+
+    fn insert_into(&mut self, page: u64, entry: &(Value, u64), bytes: &[u8]) -> Result<Split, String> {
+        let current = self.page(page)?.clone();
+        let kind = current[0];
+        let position = self.position(&current, entry);
+        // Inner pages hand it down and take in a split from below as a separator and child
+        let (bytes, capacity) = match kind {
+            LEAF => (bytes.to_vec(), self.leaf_capacity()),
+            _ => match self.insert_into(self.child(&current, position), entry, bytes)? {
+                Some((separator, child)) => {
+                    let mut bytes = encode_value(&separator.0, self.data_type).unwrap();
+                    bytes.extend(separator.1.to_le_bytes());
+                    bytes.extend(child.to_le_bytes());
+                    (bytes, self.inner_capacity())
+                },
+                None => return Ok(None),
+            },
+        };
+        let width = bytes.len();
+        let used = count(&current);
+        let at = self.entry_at(kind, position);
+        let end = self.entry_at(kind, used);
+        // Room for one past full, a full page splits right after
+        let mut updated = current;
+        updated.resize(PAGE_SIZE * 2, 0);
+        updated.copy_within(at..end, at + width);
+        updated[at..at + width].copy_from_slice(&bytes);
+        set_count(&mut updated, used + 1);
+        self.dirty.insert(page);
+        if used < capacity {
+            updated.truncate(PAGE_SIZE);
+            self.cache.insert(page, updated);
+            return Ok(None);
+        }
+        // Full, the upper half moves to a new page. A leaf's first entry is copied up, an inner
+        // page's middle separator moves up and its child becomes the new page's first.
+        let new_page = self.new_page(kind);
+        let keep = used.div_ceil(2);
+        let split_at = self.entry_at(kind, keep);
+        let total_end = self.entry_at(kind, used + 1);
+        let mut sibling = vec![0; PAGE_SIZE];
+        sibling[0] = kind;
+        let separator = self.entry(&updated, split_at);
+        match kind {
+            LEAF => {
+                let moved = total_end - split_at;
+                sibling[PAGE_HEADER..PAGE_HEADER + moved].copy_from_slice(&updated[split_at..total_end]);
+                set_count(&mut sibling, used + 1 - keep);
+                write_u64(&mut sibling, 3, read_u64(&updated, 3));
+                write_u64(&mut updated, 3, new_page);
+            },
+            _ => {
+                let first_child = read_u64(&updated, split_at + self.entry_size());
+                let rest = split_at + width;
+                let moved = total_end - rest;
+                sibling[PAGE_HEADER..PAGE_HEADER + moved].copy_from_slice(&updated[rest..total_end]);
+                set_count(&mut sibling, used - keep);
+                write_u64(&mut sibling, 3, first_child);
+            },
+        }
+        set_count(&mut updated, keep);
+        updated.truncate(PAGE_SIZE);
+        updated[split_at..].fill(0);
+        self.cache.insert(page, updated);
+        self.cache.insert(new_page, sibling);
+        Ok(Some((separator, new_page)))
+    }
+
+    // Writes out the pages that changed and the header
+    pub fn flush(&mut self) -> Result<(), String> {
+        let failed = |err: std::io::Error| format!("Can't write index: {}", err);
+        let mut dirty: Vec<u64> = self.dirty.drain().collect();
+        dirty.sort();
+        for page in dirty {
+            self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64)).map_err(failed)?;
+            self.file.write_all(&self.cache[&page]).map_err(failed)?;
+        }
+        let mut header = vec![0; PAGE_SIZE];
+        write_u64(&mut header, 0, self.root);
+        write_u64(&mut header, 8, self.pages);
+        if 24 + self.covered.len() * 16 > PAGE_SIZE {
+            return Err(format!("Too many partitions for index {}", self.path));
+        }
+        write_u64(&mut header, 16, self.covered.len() as u64);
+        for (index, (partition, rows)) in self.covered.iter().enumerate() {
+            write_u64(&mut header, 24 + index * 16, *partition);
+            write_u64(&mut header, 32 + index * 16, *rows);
+        }
+        self.file.seek(SeekFrom::Start(0)).map_err(failed)?;
+        self.file.write_all(&header).map_err(failed)?;
+        // Everything's on disk now, so past a point the pages read so far can go
+        if self.cache.len() > CACHED_PAGES {
+            self.cache.clear();
+        }
+        self.file.flush().map_err(failed)
+    }
+
+    // Down to the first leaf that could have anything from the entry on, the very first leaf for None
+    fn leaf_for(&mut self, start: Option<&(Value, u64)>) -> Result<u64, String> {
+        let mut page = self.root;
+        loop {
+            let current = self.page(page)?.clone();
+            if current[0] == LEAF {
+                return Ok(page);
+            }
+            let index = match start {
+                Some(start) => self.position(&current, start),
+                None => 0,
+            };
+            page = self.child(&current, index);
+        }
+    }
+
+    // The first of some keys, in order, that's in the index already. Keys close together are on the
+    // same leaf, so it only goes back down from the root when the next key is past the leaf it's on.
+    pub fn first_present(&mut self, keys: &[Value]) -> Result<Option<Value>, String> {
+        let mut next = 0;
+        let mut page = match keys.first() {
+            Some(key) => self.leaf_for(Some(&(key.clone(), 0)))?,
+            None => return Ok(None),
+        };
+        loop {
+            let current = self.page(page)?.clone();
+            let entries = count(&current);
+            while next < keys.len() {
+                let key = &keys[next];
+                // Entries up to where the key's first one would go, so a match is just before or at it
+                let at = self.position(&current, &(key.clone(), 0));
+                let matches = |index: usize| index < entries && self.entry(&current, self.entry_at(LEAF, index)).0 == *key;
+                if (at > 0 && matches(at - 1)) || matches(at) {
+                    return Ok(Some(key.clone()));
+                }
+                if at == entries {
+                    break;
+                }
+                next += 1;
+            }
+            let following = read_u64(&current, 3);
+            if next == keys.len() || following == 0 {
+                return Ok(None);
+            }
+            // The key's past this leaf, somewhere from the next one on
+            page = match self.leaf_for(Some(&(keys[next].clone(), 0)))? {
+                again if again == page => following,
+                further => further,
+            };
+        }
+    }
+
+    // Rows whose keys are between the bounds, in key order
+    pub fn range(&mut self, low: Bound<&Value>, high: Bound<&Value>) -> Result<Vec<(Value, u64)>, String> {
+        let start = match low {
+            Bound::Included(key) => Some((key.clone(), 0)),
+            Bound::Excluded(key) => Some((key.clone(), u64::MAX)),
+            Bound::Unbounded => None,
+        };
+        let mut page = self.leaf_for(start.as_ref())?;
+        let mut found: Vec<(Value, u64)> = Vec::new();
+        while page != 0 {
+            let current = self.page(page)?.clone();
+            for index in 0..count(&current) {
+                let (key, row) = self.entry(&current, self.entry_at(LEAF, index));
+                let below = match low {
+                    Bound::Included(low) => key < *low,
+                    Bound::Excluded(low) => key <= *low,
+                    Bound::Unbounded => false,
+                };
+                let above = match high {
+                    Bound::Included(high) => key > *high,
+                    Bound::Excluded(high) => key >= *high,
+                    Bound::Unbounded => false,
+                };
+                if above {
+                    return Ok(found);
+                }
+                if !below {
+                    found.push((key, row));
+                }
+            }
+            page = read_u64(&current, 3);
+        }
+        Ok(found)
+    }
+}
+
+// Columns of a table that have an index, which is only ever the primary key. One made on another
+// column before that was the rule just gets left alone.
+pub fn indexed_columns(table: &TableDef) -> Vec<String> {
+    table.primary_key.iter()
+        .filter(|column| fs::metadata(index_path(table, column)).is_ok())
+        .cloned()
+        .collect()
+}
+
+// Brings the index on a table's column up to every row in it, making it if there isn't one.
+// Rows only get appended, so that's indexing whatever's past the rows it has of each partition. If
+// a partition ever has fewer rows than that, or is gone, it gets built again from scratch.
+pub fn update(table_name: &str, column: &str) -> Result<BTree, String> {
+    let table = table_def(table_name)?;
+    let parts = partition_rows(&table);
+    let consistent = |tree: &BTree| tree.covered.iter().all(|(partition, covered)| {
+        parts.iter().any(|(part, rows)| number(part) == *partition && covered <= rows)
+    });
+    let mut tree = match BTree::open(&table, column)? {
+        Some(tree) if consistent(&tree) => tree,
+        _ => BTree::create(&table, column)?,
+    };
+    for (part, rows) in parts {
+        let partition = number(&part);
+        let covered = tree.covered.get(&partition).copied().unwrap_or(0);
+        if covered == rows {
+            continue;
+        }
+        let mut scan = TableScan::over(part, &[column.to_string()], None, vec![0..covered, rows..u64::MAX])?;
+        let mut row = covered;
+        while let Some(batch) = scan.next_batch()? {
+            // In key order they land on the same few leaves one after the other
+            let mut entries: Vec<(Value, u64)> = batch.columns[0].iter().cloned()
+                .zip((row..).map(|row| partition << PARTITION_SHIFT | row))
+                .filter(|(key, _)| !key.is_null())
+                .collect();
+            entries.sort_by(compare);
+            for (key, at) in &entries {
+                tree.insert(key, *at)?;
+            }
+            row += batch.num_rows() as u64;
+            tree.covered.insert(partition, row);
+            tree.flush()?;
+        }
+    }
+    Ok(tree)
+}
+
+// Turns down keys about to be written to a table's primary key that it already has, or that are
+// in the write more than once, going through the index (brought up to date first) for the ones
+// already there. Nulls don't count, the index leaves them out. The error names the key, and the
+// rows of the write that have it when it's in there twice.
+pub fn check_unique(table_name: &str, column: &str, keys: Vec<Value>) -> Result<(), String> {
+    let mut rows: Vec<(Value, usize)> = keys.into_iter().enumerate()
+        .filter(|(_, key)| !key.is_null())
+        .map(|(row, key)| (key, row))
+        .collect();
+    rows.sort();
+    if let Some(pair) = rows.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(format!("Duplicate primary key {}.{} = {}, rows {} and {} being written both have it", table_name, column, pair[0].0, pair[0].1, pair[1].1));
+    }
+    let keys: Vec<Value> = rows.into_iter().map(|(key, _)| key).collect();
+    let mut tree = update(table_name, column)?;
+    match tree.first_present(&keys)? {
+        Some(key) => Err(format!("Duplicate primary key {}.{} = {}, the table already has a row with it", table_name, column, key)),
+        None => Ok(()),
+    }
+}
+
+// Rows an indexed column's conditions leave, when there's an index for them and they leave few
+// enough that seeking to each one beats reading through. Comparisons of the column with a literal
+// narrow the range, the scan still applies every condition to the rows it gets.
+pub fn matching_rows(table_name: &str, filters: &[Expr]) -> Result<Option<(String, Vec<u64>)>, String> {
+    let table = table_def(table_name)?;
+    let conjuncts: Vec<Expr> = filters.iter().cloned().flat_map(split_conjuncts).collect();
+    for column in indexed_columns(&table) {
+        let data_type = table.column(&column).unwrap().data_type;
+        let is_column = |name: &str| name == column || name.ends_with(&format!(".{}", column));
+        let (mut low, mut high): (Bound<Value>, Bound<Value>) = (Bound::Unbounded, Bound::Unbounded);
+        for conjunct in &conjuncts {
+            let (op, value) = match conjunct {
+                Expr::Compare(op, left, right) => match (left.as_ref(), right.as_ref()) {
+                    (Expr::Column(name), Expr::Literal(value)) if is_column(name) => (*op, value),
+                    (Expr::Literal(value), Expr::Column(name)) if is_column(name) => (flip(*op), value),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let value = match cast(value, data_type) {
+                Ok(value) if !value.is_null() => value,
+                _ => continue,
+            };
+            match op {
+                CompareOp::Eq => {
+                    low = tighter(low, Bound::Included(value.clone()), Ordering::Greater);
+                    high = tighter(high, Bound::Included(value), Ordering::Less);
+                },
+                CompareOp::Gt => low = tighter(low, Bound::Excluded(value), Ordering::Greater),
+                CompareOp::GtEq => low = tighter(low, Bound::Included(value), Ordering::Greater),
+                CompareOp::Lt => high = tighter(high, Bound::Excluded(value), Ordering::Less),
+                CompareOp::LtEq => high = tighter(high, Bound::Included(value), Ordering::Less),
+                CompareOp::NotEq => {},
+            }
+        }
+        if let (Bound::Unbounded, Bound::Unbounded) = (&low, &high) {
+            continue;
+        }
+        let mut tree = update(table_name, &column)?;
+        let found = tree.range(low.as_ref(), high.as_ref())?;
+        // Past a tenth of the table, reading it all through is about as quick
+        if found.len() as u64 > tree.rows() / 10 {
+            return Ok(None);
+        }
+        // Where each partition starts in the table as a scan reads it
+        let mut starts: HashMap<u64, u64> = HashMap::new();
+        let mut start = 0;
+        for (part, rows) in partition_rows(&table) {
+            starts.insert(number(&part), start);
+            start += rows;
+        }
+        let mask = (1 << PARTITION_SHIFT) - 1;
+        let mut rows: Vec<u64> = found.into_iter().map(|(_, row)| starts.get(&(row >> PARTITION_SHIFT)).copied().unwrap_or(0) + (row & mask)).collect();
+        rows.sort();
+        return Ok(Some((column, rows)));
+    }
+    Ok(None)
+}
+
+// Whichever of two bounds on the same side leaves less, `keeps` being how a value of the new one
+// compares to the old when it's the narrower
+fn tighter(old: Bound<Value>, new: Bound<Value>, keeps: Ordering) -> Bound<Value> {
+    let (old_value, new_value) = match (&old, &new) {
+        (Bound::Unbounded, _) => return new,
+        (Bound::Included(old_value) | Bound::Excluded(old_value), Bound::Included(new_value) | Bound::Excluded(new_value)) => (old_value, new_value),
+        _ => return old,
+    };
+    match new_value.cmp(old_value) {
+        Ordering::Equal if matches!(new, Bound::Excluded(_)) => new,
+        order if order == keeps => new,
+        _ => old,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::datagen::catalog::ColumnDef;
+    use crate::datagen::constants::DATA_DIRECTORY;
+    use rand::rngs::SmallRng;
+    use rand::seq::SliceRandom;
+    use rand::{Rng, SeedableRng};
+    use std::ops::RangeBounds;
+
+    // A table of just a key column under the data directory, gone again once the test's done
+    struct Scratch(TableDef);
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.directory());
+            let _ = fs::remove_dir(DATA_DIRECTORY);
+        }
+    }
+
+    fn scratch(name: &str, data_type: DataType) -> Scratch {
+        let table = TableDef {
+            name: format!("_test_index_{}", name),
+            columns: vec![ColumnDef { name: "key".to_string(), data_type }],
+            // Nothing else about the table matters to the tree
+            ..table_def("products").unwrap()
+        };
+        let _ = fs::remove_dir_all(table.directory());
+        Scratch(table)
+    }
+
+    fn keys(entries: &[(Value, u64)]) -> Vec<Value> {
+        entries.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    // Plenty of duplicate keys going in out of order, enough for leaves and inner pages to split
+    fn filled(table: &TableDef, entries: usize) -> (BTree, Vec<(Value, u64)>) {
+        let mut rng = SmallRng::seed_from_u64(46);
+        let mut expected: Vec<(Value, u64)> = (0..entries as u64).map(|row| (Value::UInt64(rng.gen_range(0..entries as u64 / 4)), row)).collect();
+        expected.shuffle(&mut rng);
+        let mut tree = BTree::create(table, "key").unwrap();
+        for (key, row) in &expected {
+            tree.insert(key, *row).unwrap();
+        }
+        tree.covered.insert(0, entries as u64);
+        tree.flush().unwrap();
+        expected.sort_by(compare);
+        (tree, expected)
+    }
+
+    #[test]
+    fn splits_keep_everything_in_order() {
+        let scratch = scratch("splits", DataType::UInt64);
+        let (mut tree, expected) = filled(&scratch.0, 100_000);
+        // Leaves hold 255 entries and inner pages 170 children, so this is three levels deep
+        assert!(tree.pages > 400);
+        assert_eq!(tree.range(Bound::Unbounded, Bound::Unbounded).unwrap(), expected);
+        // and all of it came out to disk
+        let mut reopened = BTree::open(&scratch.0, "key").unwrap().unwrap();
+        assert_eq!(reopened.rows(), 100_000);
+        assert_eq!(reopened.range(Bound::Unbounded, Bound::Unbounded).unwrap(), expected);
+    }
+
+    #[test]
+    fn range_bounds() {
+        let scratch = scratch("range", DataType::UInt64);
+        let (mut tree, expected) = filled(&scratch.0, 20_000);
+        let key = |value: u64| Value::UInt64(value);
+        let bounds = [
+            (Bound::Included(key(100)), Bound::Included(key(100))),
+            (Bound::Included(key(100)), Bound::Excluded(key(200))),
+            (Bound::Excluded(key(100)), Bound::Included(key(200))),
+            (Bound::Excluded(key(100)), Bound::Excluded(key(101))),
+            (Bound::Unbounded, Bound::Excluded(key(37))),
+            (Bound::Included(key(4990)), Bound::Unbounded),
+            (Bound::Included(key(6000)), Bound::Unbounded),
+            (Bound::Included(key(300)), Bound::Included(key(200))),
+        ];
+        for (low, high) in bounds {
+            let wanted: Vec<(Value, u64)> = expected.iter()
+                .filter(|(value, _)| (low.as_ref(), high.as_ref()).contains(value))
+                .cloned()
+                .collect();
+            assert_eq!(tree.range(low.as_ref(), high.as_ref()).unwrap(), wanted, "{:?} to {:?}", low, high);
+        }
+    }
+
+    #[test]
+    fn first_present() {
+        let scratch = scratch("present", DataType::UInt64);
+        let mut tree = BTree::create(&scratch.0, "key").unwrap();
+        let key = |value: u64| Value::UInt64(value);
+        assert_eq!(tree.first_present(&[key(1)]).unwrap(), None);
+        // Even keys only, one row each
+        for value in (0..20_000).step_by(2) {
+            tree.insert(&key(value), value).unwrap();
+        }
+        let odd = |values: std::ops::Range<u64>, step: usize| -> Vec<Value> { values.filter(|value| value % 2 == 1).step_by(step).map(key).collect() };
+        assert_eq!(tree.first_present(&[]).unwrap(), None);
+        assert_eq!(tree.first_present(&odd(0..20_000, 1)).unwrap(), None);
+        assert_eq!(tree.first_present(&odd(0..20_000, 997)).unwrap(), None);
+        assert_eq!(tree.first_present(&[key(20_000), key(u64::MAX)]).unwrap(), None);
+        assert_eq!(tree.first_present(&[key(0)]).unwrap(), Some(key(0)));
+        assert_eq!(tree.first_present(&[key(19_998)]).unwrap(), Some(key(19_998)));
+        // Dense runs of misses either side of a hit, and a hit far past a sparse few
+        let mut dense = odd(0..12_000, 1);
+        dense.push(key(12_000));
+        dense.extend(odd(12_000..20_000, 1));
+        assert_eq!(tree.first_present(&dense).unwrap(), Some(key(12_000)));
+        let mut sparse = odd(0..1000, 100);
+        sparse.extend([key(17_777), key(17_778), key(30_000)]);
+        assert_eq!(tree.first_present(&sparse).unwrap(), Some(key(17_778)));
+    }
+
+    #[test]
+    fn first_present_with_duplicates_across_leaves() {
+        let scratch = scratch("duplicates", DataType::UInt64);
+        let (mut tree, expected) = filled(&scratch.0, 50_000);
+        let present: Vec<Value> = keys(&expected);
+        let missing: Vec<Value> = (0..50_000 / 4).map(|value| Value::UInt64(value as u64)).filter(|value| present.binary_search(value).is_err()).collect();
+        assert_eq!(tree.first_present(&missing).unwrap(), None);
+        // Every key that's there, however many rows it's in and wherever its entries start, gets found
+        for value in present.iter().step_by(101) {
+            assert_eq!(tree.first_present(std::slice::from_ref(value)).unwrap().as_ref(), Some(value));
+            let mut with_misses = missing.clone();
+            with_misses.push(value.clone());
+            with_misses.sort();
+            assert_eq!(tree.first_present(&with_misses).unwrap().as_ref(), Some(value));
+        }
+    }
+
+    #[test]
+    fn uuid_keys() {
+        let scratch = scratch("uuid", DataType::Uuid);
+        let mut tree = BTree::create(&scratch.0, "key").unwrap();
+        let mut rng = SmallRng::seed_from_u64(47);
+        let mut ids: Vec<Value> = (0..5000).map(|_| Value::Uuid(uuid::Uuid::from_u128(rng.gen()))).collect();
+        for (row, id) in ids.iter().enumerate() {
+            tree.insert(id, row as u64).unwrap();
+        }
+        let found = tree.range(Bound::Unbounded, Bound::Unbounded).unwrap();
+        ids.sort();
+        assert_eq!(keys(&found), ids);
+        let other = Value::Uuid(uuid::Uuid::from_u128(rng.gen()));
+        assert_eq!(tree.first_present(std::slice::from_ref(&other)).unwrap(), None);
+        let mut probe = vec![other, ids[2500].clone()];
+        probe.sort();
+        assert_eq!(tree.first_present(&probe).unwrap(), Some(ids[2500].clone()));
+    }
+}
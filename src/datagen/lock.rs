@@ -0,0 +1,80 @@
+use super::constants::DATA_DIRECTORY;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// One writer at a time per table. Writing checks the primary key, appends segments and brings the
+// index up, and compaction moves the whole table aside, so two of those at once could let a
+// duplicate key through or lose rows. The lock is a file in _locks/ (not the table directory,
+// compaction moves that) created only if it isn't there, with the holder's process id in it,
+// and removed when the TableLock goes. A process that dies holding one leaves it behind, the
+// error when waiting runs out says which file to remove.
+
+// Long enough for a big write to get done, short enough that a leftover lock doesn't look like a
+// hang
+const WAIT: Duration = Duration::from_secs(60);
+const POLL: Duration = Duration::from_millis(50);
+
+pub struct TableLock {
+    path: String,
+}
+
+impl Drop for TableLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn locks_directory() -> String {
+    DATA_DIRECTORY.to_owned() + "_locks/"
+}
+
+pub fn lock(table_name: &str) -> Result<TableLock, String> {
+    lock_within(table_name, WAIT)
+}
+
+fn lock_within(table_name: &str, wait: Duration) -> Result<TableLock, String> {
+    fs::create_dir_all(locks_directory()).map_err(|err| format!("Can't create {}: {}", locks_directory(), err))?;
+    let path = locks_directory() + table_name;
+    let start = Instant::now();
+    loop {
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                // Only there to say who has it
+                let _ = write!(file, "{}", std::process::id());
+                return Ok(TableLock { path });
+            },
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                if start.elapsed() >= wait {
+                    let holder = fs::read_to_string(&path).unwrap_or_default();
+                    return Err(format!("Table {:?} is locked by process {} ({}), remove the file if that process isn't running anymore", table_name, holder.trim(), path));
+                }
+                thread::sleep(POLL);
+            },
+            Err(err) => return Err(format!("Can't lock table {:?} with {}: {}", table_name, path, err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_holder_at_a_time() {
+        let name = "_test_lock";
+        let held = lock_within(name, Duration::ZERO).unwrap();
+        let error = lock_within(name, Duration::from_millis(120)).err().unwrap();
+        assert!(error.contains(&std::process::id().to_string()), "{}", error);
+
+        // The second one gets it as soon as the first lets go
+        let waiting = thread::spawn(move || lock_within(name, Duration::from_secs(10)).map(|_| ()));
+        thread::sleep(Duration::from_millis(100));
+        drop(held);
+        waiting.join().unwrap().unwrap();
+        assert!(fs::metadata(locks_directory() + name).is_err());
+        let _ = fs::remove_dir(locks_directory());
+        let _ = fs::remove_dir(DATA_DIRECTORY);
+    }
+}
@@ -2,3 +2,10 @@ pub mod gen;
 pub mod dataset;
 pub mod constants;
 pub mod file;
+pub mod catalog;
+pub mod stats;
+pub mod index;
+pub mod bloom;
+pub mod compact;
+pub mod partition;
+pub mod lock;
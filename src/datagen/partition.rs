@@ -0,0 +1,171 @@
+use crate::analyze::batch::Value;
+use crate::analyze::expr::{cast, CompareOp, Expr};
+use crate::analyze::hll::hash_value;
+use crate::analyze::planner::split_conjuncts;
+use crate::analyze::scan::may_match;
+use super::catalog::TableDef;
+use super::dataset::DataType;
+use super::file::segment_paths;
+use super::stats::{self, SegmentStats};
+use chrono::{Datelike, TimeZone, Utc};
+use std::fs;
+
+// Partitioned tables keep their rows split up by a column, by calendar month (UTC) of a DateTime or
+// by a hash of any value into a fixed number of buckets. Each partition is a table directory of
+// its own under <table>/_partitions/<name>/, with its own segments, zone maps and bloom filters,
+// and writes send every row to the one it belongs in. Reading the whole table goes through the
+// partitions one after the other in key order, so row numbers run on from one to the next, and a
+// scan skips every partition its filter can't match before looking at any segment.
+//
+// Partitions have a number as well as a name, the month counted from year 0 or the bucket. Things
+// that hold on to rows across writes (indexes, views) go by partition number and row within the
+// partition, since writing to one partition moves every row after it.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Partitioning {
+    Month(String),
+    Hash(String, u64),
+}
+
+// Rows within a partition get the low bits of a position, the partition number the rest
+pub const PARTITION_SHIFT: u32 = 40;
+
+impl Partitioning {
+    pub fn column(&self) -> &str {
+        match self {
+            Partitioning::Month(column) | Partitioning::Hash(column, _) => column,
+        }
+    }
+
+    // Which partition a value goes in, nulls with the first month or bucket
+    pub fn partition_of(&self, value: &Value) -> u64 {
+        match (self, value) {
+            (Partitioning::Month(_), Value::DateTime(time)) => time.year() as u64 * 12 + time.month0() as u64,
+            (Partitioning::Month(_), _) => 0,
+            (Partitioning::Hash(_, buckets), value) => hash_value(value) % buckets,
+        }
+    }
+
+    pub fn name(&self, partition: u64) -> String {
+        match self {
+            Partitioning::Month(_) => format!("{:04}-{:02}", partition / 12, partition % 12 + 1),
+            Partitioning::Hash(_, _) => format!("{:03}", partition),
+        }
+    }
+
+    fn parse(&self, name: &str) -> Option<u64> {
+        match self {
+            Partitioning::Month(_) => {
+                let (year, month) = name.split_once('-')?;
+                let (year, month) = (year.parse::<u64>().ok()?, month.parse::<u64>().ok()?);
+                (1..=12).contains(&month).then_some(year * 12 + month - 1)
+            },
+            Partitioning::Hash(_, buckets) => name.parse::<u64>().ok().filter(|bucket| bucket < buckets),
+        }
+    }
+
+    // Whether any row in the partition could pass the filter. A month is a range of times, so it
+    // gets checked like a segment's zone map, a bucket only has a chance at an equality on the
+    // column whose value hashes into it.
+    pub fn may_match(&self, partition: u64, filter: &Expr, data_type: DataType) -> bool {
+        let is_column = |name: &str| name == self.column() || name.ends_with(&format!(".{}", self.column()));
+        match self {
+            Partitioning::Month(_) => {
+                let start = |month: u64| Utc.with_ymd_and_hms((month / 12) as i32, (month % 12) as u32 + 1, 1, 0, 0, 0).single();
+                let (first, next) = match (start(partition), start(partition + 1)) {
+                    (Some(first), Some(next)) => (first, next),
+                    _ => return true,
+                };
+                let last = next - chrono::Duration::milliseconds(1);
+                may_match(filter, &|name: &str| is_column(name).then_some((Value::DateTime(first), Value::DateTime(last))))
+            },
+            Partitioning::Hash(_, _) => split_conjuncts(filter.clone()).iter().all(|conjunct| {
+                let value = match conjunct {
+                    Expr::Compare(CompareOp::Eq, left, right) => match (left.as_ref(), right.as_ref()) {
+                        (Expr::Column(name), Expr::Literal(value)) | (Expr::Literal(value), Expr::Column(name)) if is_column(name) => value,
+                        _ => return true,
+                    },
+                    _ => return true,
+                };
+                match cast(value, data_type) {
+                    Ok(value) if !value.is_null() => self.partition_of(&value) == partition,
+                    _ => true,
+                }
+            }),
+        }
+    }
+}
+
+pub fn partitions_directory(table: &TableDef) -> String {
+    table.directory() + "_partitions/"
+}
+
+// Every partition there is, in order, or just the table itself when it isn't partitioned (or is
+// already one partition)
+pub fn partitions(table: &TableDef) -> Vec<TableDef> {
+    let partitioning = match (&table.partitioning, table.partition) {
+        (Some(partitioning), None) => partitioning,
+        _ => return vec![table.clone()],
+    };
+    let mut found: Vec<u64> = match fs::read_dir(partitions_directory(table)) {
+        Ok(dir) => dir.filter_map(|entry| entry.ok())
+            .filter_map(|entry| partitioning.parse(&entry.file_name().to_string_lossy()))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    found.sort();
+    found.into_iter().map(|partition| table.partition(partition)).collect()
+}
+
+// A table written before it was partitioned has its columns straight under <table>/, where
+// nothing that goes through the partitions looks. That layout as a table of its own, when there's
+// anything in it.
+pub fn unpartitioned(table: &TableDef) -> Option<TableDef> {
+    if table.partitioning.is_none() || table.partition.is_some() {
+        return None;
+    }
+    let flat = TableDef { partitioning: None, ..table.clone() };
+    flat.columns.iter()
+        .any(|col| !segment_paths(&flat.column_directory(&col.name), &col.name).is_empty())
+        .then_some(flat)
+}
+
+// Segment files of a column across every partition, in the order a scan reads them
+pub fn column_paths(table: &TableDef, column: &str) -> Vec<String> {
+    partitions(table).iter()
+        .flat_map(|part| segment_paths(&part.column_directory(column), column))
+        .collect()
+}
+
+// Rows in each partition, going by the size of a fixed width column's files
+pub fn partition_rows(table: &TableDef) -> Vec<(TableDef, u64)> {
+    let fixed = table.columns.iter().find_map(|col| col.data_type.stored_size().map(|size| (col.name.clone(), size)));
+    partitions(table).into_iter()
+        .map(|part| {
+            let rows = match &fixed {
+                Some((column, size)) => segment_paths(&part.column_directory(column), column).iter()
+                    .map(|path| fs::metadata(path).map(|meta| meta.len()).unwrap_or(0) / *size as u64)
+                    .sum(),
+                None => 0,
+            };
+            (part, rows)
+        })
+        .collect()
+}
+
+// Zone maps for every segment of a column across the partitions along with the directory of the
+// partition each one is in, None if any partition's are missing or out of date
+pub fn current_stats(table: &TableDef, column: &str, data_type: DataType) -> Option<Vec<(String, SegmentStats)>> {
+    let mut all: Vec<(String, SegmentStats)> = Vec::new();
+    for part in partitions(table) {
+        let paths = segment_paths(&part.column_directory(column), column);
+        let segments = stats::current(&part.directory(), column, data_type, &paths)?;
+        all.extend(segments.into_iter().map(|segment| (part.directory(), segment)));
+    }
+    Some(all)
+}
+
+// A partition's number, 0 for a table that isn't partitioned
+pub fn number(table: &TableDef) -> u64 {
+    table.partition.unwrap_or(0)
+}
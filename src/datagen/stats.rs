@@ -0,0 +1,159 @@
+use crate::analyze::batch::Value;
+use crate::analyze::expr::cast;
+use crate::analyze::hll::{HyperLogLog, DEFAULT_PRECISION};
+use super::dataset::DataType;
+use std::fs;
+
+// Zone maps: for each segment file of a column, how many rows it holds and its smallest and
+// biggest values. They live in <table>/_stats/<column> instead of next to the segments, since the
+// writer takes anything in a column directory to be a segment file. The file size goes in too, so
+// stats that fell behind their file (written by something that didn't keep them up) get ignored.
+// Each segment also gets a HyperLogLog sketch of its values, so approximate distinct counts can be
+// put together from the segments a query covers without reading them.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentStats {
+    pub file_num: u64,
+    pub rows: u64,
+    pub bytes: u64,
+    pub min: Value,
+    pub max: Value,
+    pub distinct: Option<HyperLogLog>,
+}
+
+impl SegmentStats {
+    // Folds more rows that went into the same file into the stats. Stats from before sketches were
+    // kept don't have one for the older rows, so the segment doesn't get one at all.
+    fn add(&mut self, rows: u64, bytes: u64, min: &Value, max: &Value, distinct: HyperLogLog) {
+        self.rows += rows;
+        self.bytes = bytes;
+        if self.min.is_null() || (!min.is_null() && min < &self.min) {
+            self.min = min.clone();
+        }
+        if self.max.is_null() || (!max.is_null() && max > &self.max) {
+            self.max = max.clone();
+        }
+        if let Some(sketch) = self.distinct.as_mut() {
+            if sketch.merge(&distinct).is_err() {
+                self.distinct = None;
+            }
+        }
+    }
+}
+
+pub fn stats_directory(table_directory: &str) -> String {
+    table_directory.to_owned() + "_stats/"
+}
+
+fn stats_path(table_directory: &str, column_name: &str) -> String {
+    stats_directory(table_directory) + column_name
+}
+
+// Text, a few `key value` lines per segment:
+//   segment <file_num> <rows> <bytes> / min <value> / max <value> / distinct <precision> <hex>
+// min and max are left out when every value was null (or there weren't any). Text values can have
+// line breaks in them (addresses do), so those and backslashes get escaped.
+fn escape(value: &Value) -> String {
+    value.to_string().replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => unescaped.push(c),
+            },
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+pub fn load(table_directory: &str, column_name: &str, data_type: DataType) -> Result<Vec<SegmentStats>, String> {
+    let path = stats_path(table_directory, column_name);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut segments: Vec<SegmentStats> = Vec::new();
+    for line in text.lines() {
+        let bad = || format!("Bad stats line {:?} in {}", line, path);
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match (key, segments.last_mut()) {
+            ("segment", _) => {
+                let numbers: Vec<u64> = value.split(' ').map(|num| num.parse::<u64>()).collect::<Result<_, _>>().map_err(|_| bad())?;
+                match numbers[..] {
+                    [file_num, rows, bytes] => segments.push(SegmentStats { file_num, rows, bytes, min: Value::Null, max: Value::Null, distinct: None }),
+                    _ => return Err(bad()),
+                }
+            },
+            ("min", Some(segment)) => segment.min = cast(&Value::String(unescape(value)), data_type).map_err(|_| bad())?,
+            ("max", Some(segment)) => segment.max = cast(&Value::String(unescape(value)), data_type).map_err(|_| bad())?,
+            ("distinct", Some(segment)) => segment.distinct = Some(HyperLogLog::from_text(value).map_err(|_| bad())?),
+            _ => return Err(bad()),
+        }
+    }
+    Ok(segments)
+}
+
+pub fn save(table_directory: &str, column_name: &str, segments: &[SegmentStats]) -> Result<(), String> {
+    fs::create_dir_all(stats_directory(table_directory)).map_err(|err| format!("Can't create stats directory: {}", err))?;
+    let mut text = String::new();
+    for segment in segments {
+        text += &format!("segment {} {} {}\n", segment.file_num, segment.rows, segment.bytes);
+        if !segment.min.is_null() {
+            text += &format!("min {}\nmax {}\n", escape(&segment.min), escape(&segment.max));
+        }
+        if let Some(distinct) = &segment.distinct {
+            text += &format!("distinct {}\n", distinct.to_text());
+        }
+    }
+    let path = stats_path(table_directory, column_name);
+    fs::write(&path, text).map_err(|err| format!("Can't write stats {}: {}", path, err))
+}
+
+// Adds rows that just got written to a segment file, bytes being the file's size afterwards
+pub fn record(segments: &mut Vec<SegmentStats>, file_num: u64, bytes: u64, values: impl Iterator<Item = Value>) {
+    let mut rows: u64 = 0;
+    let (mut min, mut max) = (Value::Null, Value::Null);
+    let mut distinct = HyperLogLog::new(DEFAULT_PRECISION);
+    for value in values {
+        rows += 1;
+        if value.is_null() {
+            continue;
+        }
+        distinct.insert(&value);
+        if min.is_null() || value < min {
+            min = value.clone();
+        }
+        if max.is_null() || value > max {
+            max = value;
+        }
+    }
+    match segments.iter_mut().find(|segment| segment.file_num == file_num) {
+        Some(segment) => segment.add(rows, bytes, &min, &max, distinct),
+        None => {
+            segments.push(SegmentStats { file_num, rows, bytes, min, max, distinct: Some(distinct) });
+            segments.sort_by_key(|segment| segment.file_num);
+        },
+    }
+}
+
+// Stats for every segment file of a column in order, or None if any are missing or out of date
+pub fn current(table_directory: &str, column_name: &str, data_type: DataType, paths: &[String]) -> Option<Vec<SegmentStats>> {
+    let segments = load(table_directory, column_name, data_type).ok()?;
+    if segments.len() != paths.len() {
+        return None;
+    }
+    let up_to_date = segments.iter().zip(paths).enumerate().all(|(file_num, (segment, path))| {
+        segment.file_num == file_num as u64 && fs::metadata(path).map(|meta| meta.len() == segment.bytes).unwrap_or(false)
+    });
+    match up_to_date {
+        true => Some(segments),
+        false => None,
+    }
+}
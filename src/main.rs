@@ -10,6 +10,15 @@ use clap::{Parser, Subcommand};
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
+    /// MiB each hash aggregate, hash join or sort may hold before spilling to temporary files
+    #[clap(long, global = true, default_value_t = 1024)]
+    memory_budget: usize,
+    /// MiB of query results kept under demo_data/_cache to answer the same queries again
+    #[clap(long, global = true, default_value_t = 256)]
+    cache_size: usize,
+    /// Run everything from scratch, neither reading nor keeping cached results
+    #[clap(long, global = true)]
+    no_cache: bool,
 }
 
 #[derive(Subcommand)]
@@ -31,28 +40,128 @@ enum Commands {
         export_parquet: bool,
     },
     Analyze {
+        /// Print rows, bytes, time and memory for every operator of each result set
+        #[clap(long)]
+        explain_analyze: bool,
+        /// IANA time zone whose calendar months the report goes by, e.g. America/New_York
+        #[clap(long, default_value = "UTC")]
+        time_zone: String,
+        /// Keep orders per customer per month as a materialized view for the time zone, so later
+        /// runs only read orders written since
+        #[clap(long)]
+        keep_view: bool,
     },
     Average {
     },
+    /// Scan a table with an optional filter and computed columns
+    Scan {
+        table: String,
+        /// Table to join against, needs --on as well
+        #[clap(short, long, requires = "on")]
+        join: Option<String>,
+        /// Equality between the two tables, e.g. "id = order_id"
+        #[clap(long)]
+        on: Option<String>,
+        /// inner, left, semi or anti
+        #[clap(long, default_value = "inner")]
+        join_type: String,
+        /// Comma separated expressions, e.g. "order_id, price_per * quantity AS total"
+        #[clap(short, long)]
+        select: Option<String>,
+        /// Filter expression, e.g. "quantity > 5 AND price_per < 10"
+        #[clap(short, long)]
+        filter: Option<String>,
+        /// Group key expressions, the select list is then aggregates like "sum(quantity) AS qty"
+        #[clap(short, long)]
+        group_by: Option<String>,
+        #[clap(short, long)]
+        limit: Option<usize>,
+        /// Print the operator tree instead of running it
+        #[clap(long, conflicts_with = "explain-analyze")]
+        explain: bool,
+        /// Run it and print what every operator did instead of the rows
+        #[clap(long)]
+        explain_analyze: bool,
+    },
+    /// Run an array language program, e.g. "+/ order_products.quantity"
+    Query {
+        #[clap(allow_hyphen_values = true)]
+        program: String,
+    },
+    /// Run a SELECT statement, e.g. "SELECT tax_percent, count(*) FROM orders GROUP BY tax_percent", put
+    /// EXPLAIN or EXPLAIN ANALYZE in front to see the plan or what each operator did
+    Sql {
+        statement: String,
+    },
+    /// Rewrite a table sorted on its sort key (orders on created), or in its parent's order
+    /// (order_products after orders)
+    Compact {
+        table: String,
+    },
+    /// Print the tree of operations and segment files behind a stored result, or list them all
+    Lineage {
+        result: Option<String>,
+    },
 }
 
 
 fn main() {
     let cli = Cli::parse();
+    analyze::spill::set_memory_budget(cli.memory_budget * 1024 * 1024);
+    analyze::cache::set_cache_limit(match cli.no_cache {
+        true => 0,
+        false => cli.cache_size * 1024 * 1024,
+    });
 
     match &cli.command {
         Commands::Generate { customer_count, product_count, order_count, max_products, export_parquet } => {
             println!("'db_storage_poc_rust generate' was used, customer_count is: {:?}\nmax_products is: {:?}", customer_count, max_products);
             datagen::gen::generate_data(*customer_count, *product_count, *order_count, *max_products, *export_parquet);
         },
-        Commands::Analyze {} => {
+        Commands::Analyze { explain_analyze, time_zone, keep_view } => {
             println!("'db_storage_poc_rust analyze' was used, now looking at all the data available.");
-            analyze::process::process_data();
+            analyze::process::process_data(*explain_analyze, *keep_view, time_zone);
         },
         Commands::Average {} => {
             println!("'db_storage_poc_rust average' was used, doing the fastest single-column average with order_products quantity.");
             analyze::process::process_average();
         },
+        Commands::Scan { table, join, on, join_type, select, filter, group_by, limit, explain, explain_analyze } => {
+            let join = join.as_ref().map(|join_table| analyze::process::ScanJoin {
+                table: join_table.to_string(),
+                on: on.clone().unwrap_or_default(),
+                join_type: join_type.to_string(),
+            });
+            let explain = match (explain, explain_analyze) {
+                (true, _) => Some(analyze::explain::ExplainMode::Plan),
+                (_, true) => Some(analyze::explain::ExplainMode::Analyze),
+                _ => None,
+            };
+            if let Err(error) = analyze::process::process_scan(table, join, select, filter, group_by, limit, explain) {
+                println!("Scan failed: {}", error);
+            }
+        },
+        Commands::Query { program } => {
+            if let Err(error) = analyze::process::process_query(program) {
+                println!("Query failed: {}", error);
+            }
+        },
+        Commands::Sql { statement } => {
+            if let Err(error) = analyze::process::process_sql(statement) {
+                println!("SQL failed: {}", error);
+            }
+        },
+        Commands::Compact { table } => {
+            match datagen::compact::compact(table) {
+                Ok(rows) => println!("Compacted {} rows of {}", rows, table),
+                Err(error) => println!("Compact failed: {}", error),
+            }
+        },
+        Commands::Lineage { result } => {
+            if let Err(error) = analyze::process::process_lineage(result) {
+                println!("Lineage failed: {}", error);
+            }
+        },
     }
 }
 
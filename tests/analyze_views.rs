@@ -0,0 +1,31 @@
+// analyze only keeps a materialized view when asked to, but uses one that's there either way
+
+mod common;
+
+use common::DataDirectory;
+
+#[test]
+fn analyze_leaves_views_alone_unless_asked() {
+    let data = DataDirectory::new("analyze_views");
+    data.run(&["generate", "-c", "50", "-p", "10", "-o", "500"]);
+    let views = data.0.join("demo_data").join("_views");
+    // Everything after the timings, sorted since ties in the top products lists come in any order
+    let report = |output: String| {
+        let mut lines: Vec<String> = output.lines().skip_while(|line| !line.starts_with("Customers:")).map(str::to_string).collect();
+        lines.sort();
+        lines
+    };
+
+    let plain = report(data.run(&["analyze", "--time-zone", "America/Santiago"]));
+    assert!(plain.len() > 10, "{:?}", plain);
+    assert!(!views.exists());
+
+    let kept = report(data.run(&["analyze", "--time-zone", "America/Santiago", "--keep-view"]));
+    assert!(views.join("customer_months_America_Santiago").is_dir());
+    assert_eq!(plain, kept);
+
+    // The next plain run answers from the view instead of reading orders again
+    let explained = data.run(&["analyze", "--time-zone", "America/Santiago", "--explain-analyze"]);
+    assert!(explained.contains("ViewScan customer_months_America_Santiago"), "{}", explained);
+    assert!(!views.join("customer_months_UTC").exists());
+}
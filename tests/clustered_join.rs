@@ -0,0 +1,50 @@
+// orders and order_products join in one merge pass once both are compacted, orders sorted on
+// created and order_products laid out in that order
+
+mod common;
+
+use common::DataDirectory;
+
+const JOIN: &str = "SELECT count(*), sum(op.quantity), sum(op.price_per), min(o.created) FROM orders o JOIN order_products op ON o.id = op.order_id";
+
+fn join_operator(data: &DataDirectory) -> String {
+    let plan = data.run(&["sql", &format!("EXPLAIN {}", JOIN)]);
+    plan.lines().map(str::trim).find(|line| line.contains("Join")).unwrap_or_default().to_string()
+}
+
+#[test]
+fn orders_and_order_products_merge_after_compaction() {
+    let data = DataDirectory::new("clustered_join");
+    data.run(&["generate", "-c", "200", "-p", "50", "-o", "3000"]);
+    assert!(join_operator(&data).starts_with("HashJoin Inner"), "{}", join_operator(&data));
+    let hashed = data.sql(JOIN);
+
+    data.run(&["compact", "orders"]);
+    data.run(&["compact", "order_products"]);
+    assert!(join_operator(&data).starts_with("MergeJoin Inner on orders.id = order_products.order_id (clustered)"), "{}", join_operator(&data));
+    assert_eq!(data.sql(JOIN), hashed);
+
+    // A filter on orders can drop orders that order_products still has, the merge can't skip them
+    let filtered = data.run(&["sql", &format!("EXPLAIN {} WHERE o.tax_percent > 5", JOIN)]);
+    assert!(filtered.contains("HashJoin Inner"), "{}", filtered);
+
+    // Recompacting orders on its own leaves it the same, so order_products still lines up
+    data.run(&["compact", "orders"]);
+    assert!(join_operator(&data).starts_with("MergeJoin"), "{}", join_operator(&data));
+
+    // New orders go wherever their dates put them, so the layout isn't known anymore until both
+    // get compacted again
+    data.run(&["generate", "-c", "10", "-p", "5", "-o", "100"]);
+    assert!(join_operator(&data).starts_with("HashJoin Inner"), "{}", join_operator(&data));
+    data.run(&["compact", "orders"]);
+    assert!(join_operator(&data).starts_with("HashJoin Inner"), "{}", join_operator(&data));
+    data.run(&["compact", "order_products"]);
+    assert!(join_operator(&data).starts_with("MergeJoin"), "{}", join_operator(&data));
+}
+
+#[test]
+fn order_products_waits_for_orders_to_be_compacted() {
+    let data = DataDirectory::new("clustered_join_order");
+    data.run(&["generate", "-c", "20", "-p", "10", "-o", "200"]);
+    assert!(data.fail(&["compact", "order_products"]).contains("Compact orders first"));
+}
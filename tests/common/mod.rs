@@ -0,0 +1,57 @@
+// Tables live under ./demo_data, so each test runs the binary in a directory of its own. Not
+// every test uses all of it.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+pub struct DataDirectory(pub PathBuf);
+
+impl Drop for DataDirectory {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl DataDirectory {
+    pub fn new(name: &str) -> DataDirectory {
+        let path = std::env::temp_dir().join(format!("db_storage_poc_rust_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        DataDirectory(path)
+    }
+
+    pub fn table(&self, name: &str) -> PathBuf {
+        self.0.join("demo_data").join(name)
+    }
+
+    fn output(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_db_storage_poc_rust"))
+            .arg("--no-cache")
+            .args(args)
+            .current_dir(&self.0)
+            .output()
+            .unwrap()
+    }
+
+    // Commands print their errors and still exit cleanly, so failing is going by what they print
+    pub fn run(&self, args: &[&str]) -> String {
+        let output = self.output(args);
+        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+        assert!(output.status.success() && !stdout.contains(" failed: "), "{:?} failed: {}{}", args, stdout, String::from_utf8_lossy(&output.stderr));
+        stdout
+    }
+
+    pub fn fail(&self, args: &[&str]) -> String {
+        let stdout = String::from_utf8_lossy(&self.output(args).stdout).to_string();
+        assert!(stdout.contains(" failed: "), "{:?} didn't fail: {}", args, stdout);
+        stdout
+    }
+
+    // Just the result table of a sql query, not the timings after it
+    pub fn sql(&self, statement: &str) -> String {
+        let output = self.run(&["sql", statement]);
+        output.lines().take_while(|line| !line.starts_with("Time Spent")).collect::<Vec<&str>>().join("\n")
+    }
+}
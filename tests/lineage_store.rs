@@ -0,0 +1,42 @@
+// Ad hoc results only keep the latest lineage, and a compaction shows up in the lineage of what
+// read the table before it even when the files come out the same size
+
+mod common;
+
+use common::DataDirectory;
+use std::fs;
+
+#[test]
+fn only_the_latest_ad_hoc_lineage_is_kept() {
+    let data = DataDirectory::new("lineage_retention");
+    data.run(&["generate", "-c", "20", "-p", "5", "-o", "50"]);
+    let lineage = data.0.join("demo_data").join("_lineage");
+    fs::create_dir_all(&lineage).unwrap();
+    for minute in 0..120 {
+        fs::write(lineage.join(format!("sql.20200101T{:02}{:02}00000", minute / 60, minute % 60)), "result old\n").unwrap();
+    }
+    fs::write(lineage.join("analyze.customers"), "result kept\n").unwrap();
+
+    let output = data.run(&["sql", "SELECT count(*) FROM products"]);
+    let latest = output.lines().find_map(|line| line.strip_prefix("Lineage: ")).unwrap().to_string();
+    let mut names: Vec<String> = fs::read_dir(&lineage).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+    names.sort();
+    assert_eq!(names.len(), 101, "{:?}", names);
+    assert!(names.contains(&latest) && names.contains(&"analyze.customers".to_string()));
+    // The oldest 21 went, counting the new one
+    assert!(!names.contains(&"sql.20200101T002000000".to_string()) && names.contains(&"sql.20200101T002100000".to_string()), "{:?}", names);
+}
+
+#[test]
+fn rewritten_segments_show_in_lineage() {
+    let data = DataDirectory::new("lineage_versions");
+    data.run(&["generate", "-c", "20", "-p", "5", "-o", "50"]);
+    let output = data.run(&["sql", "SELECT count(*), max(tax_percent) FROM orders"]);
+    let name = output.lines().find_map(|line| line.strip_prefix("Lineage: ")).unwrap().to_string();
+    assert!(!data.run(&["lineage", &name]).contains(" since)"));
+
+    // One write went in, so compacting puts the same bytes back
+    data.run(&["compact", "orders"]);
+    let tree = data.run(&["lineage", &name]);
+    assert!(tree.contains("(rewritten since)"), "{}", tree);
+}
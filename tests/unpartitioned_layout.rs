@@ -0,0 +1,60 @@
+// Tables written before partitioning have every column's segments straight under <table>/. Scans
+// turn them down rather than coming back empty, and compacting moves the rows into partitions.
+
+mod common;
+
+use common::DataDirectory;
+use std::fs;
+use std::path::Path;
+
+// Puts a partitioned table back the way it was before partitioning: each column's segments from
+// every partition concatenated into one file under <table>/<column>/, no stats
+fn flatten(table: &Path, columns: &[&str]) {
+    let partitions = table.join("_partitions");
+    let mut names: Vec<String> = fs::read_dir(&partitions).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+    names.sort();
+    for column in columns {
+        let mut bytes: Vec<u8> = Vec::new();
+        for name in &names {
+            let directory = partitions.join(name).join(column);
+            for file in 0.. {
+                match fs::read(directory.join(format!("{}_{:020}", column, file))) {
+                    Ok(segment) => bytes.extend(segment),
+                    Err(_) => break,
+                }
+            }
+        }
+        fs::create_dir_all(table.join(column)).unwrap();
+        fs::write(table.join(column).join(format!("{}_{:020}", column, 0)), bytes).unwrap();
+    }
+    fs::remove_dir_all(partitions).unwrap();
+    let _ = fs::remove_dir_all(table.join("_index"));
+}
+
+#[test]
+fn old_layout_is_an_error_until_compacted() {
+    let data = DataDirectory::new("unpartitioned_layout");
+    data.run(&["generate", "-c", "100", "-p", "20", "-o", "1000"]);
+    let orders = "SELECT count(*), sum(tax_percent), min(created), max(created) FROM orders";
+    let customers = "SELECT count(*), min(email), max(created) FROM customers";
+    let (orders_before, customers_before) = (data.sql(orders), data.sql(customers));
+
+    flatten(&data.table("orders"), &["id", "customer_id", "created", "tax_percent", "discount_amount"]);
+    flatten(&data.table("customers"), &["id", "name", "email", "created"]);
+    for statement in [orders, customers] {
+        let error = data.fail(&["sql", statement]);
+        assert!(error.contains("from before it was partitioned"), "{}", error);
+    }
+    let error = data.fail(&["analyze"]);
+    assert!(error.contains("from before it was partitioned"), "{}", error);
+
+    // customers has no sort key, compacting it only moves it into partitions
+    data.run(&["compact", "orders"]);
+    data.run(&["compact", "customers"]);
+    assert!(!data.table("orders").join("created").exists());
+    assert_eq!(data.sql(orders), orders_before);
+    assert_eq!(data.sql(customers), customers_before);
+    let plan = data.run(&["sql", "EXPLAIN SELECT count(*) FROM orders WHERE created >= DATE '2100-01-01'"]);
+    assert!(plan.contains("partitions 0/"), "{}", plan);
+    data.run(&["analyze"]);
+}